@@ -1,5 +1,5 @@
 use anyhow::Context;
-use rusqlite::{Connection, OpenFlags};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -56,11 +56,33 @@ impl Engine {
         conn.pragma_update(None, "journal_mode", "WAL")?;
         conn.pragma_update(None, "foreign_keys", "ON")?;
         conn.pragma_update(None, "synchronous", "NORMAL")?;
+        // Several step workers write concurrently; wait for the lock instead of failing fast.
+        conn.busy_timeout(std::time::Duration::from_secs(10))?;
 
         migrate(&conn)?;
         Ok(conn)
     }
 
+    /// Applied and pending migrations, read without changing the database (or creating it).
+    pub fn migration_status(&self) -> anyhow::Result<MigrationStatus> {
+        if !self.db_path.exists() {
+            return Ok(MigrationStatus {
+                current: 0,
+                latest: SCHEMA_VERSION,
+                applied: vec![],
+                pending: MIGRATIONS
+                    .iter()
+                    .map(|m| (m.version, m.name.to_string()))
+                    .collect(),
+            });
+        }
+        let conn = Connection::open_with_flags(&self.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("open sqlite db: {}", self.db_path.display()))?;
+        let status = migration_status(&conn)?;
+        refuse_downgrade(&status)?;
+        Ok(status)
+    }
+
     pub fn list_entities(&self) -> anyhow::Result<Vec<Entity>> {
         let conn = self.open()?;
         let mut stmt = conn.prepare(
@@ -82,7 +104,30 @@ impl Engine {
                 rev: row.get(9)?,
             })
         })?;
-        Ok(rows.filter_map(Result::ok).collect())
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_entity(&self, id: &str) -> anyhow::Result<Option<Entity>> {
+        let conn = self.open()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, kind, x, y, w, h, payload_json, created_at_ms, updated_at_ms, rev
+             FROM entities WHERE id=?1",
+        )?;
+        let mut rows = stmt.query_map([id], |row| {
+            Ok(Entity {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                x: row.get(2)?,
+                y: row.get(3)?,
+                w: row.get(4)?,
+                h: row.get(5)?,
+                payload_json: row.get(6)?,
+                created_at_ms: row.get(7)?,
+                updated_at_ms: row.get(8)?,
+                rev: row.get(9)?,
+            })
+        })?;
+        Ok(rows.next().transpose()?)
     }
 
     pub fn create_entity(
@@ -345,6 +390,159 @@ impl Engine {
         Ok(n > 0)
     }
 
+    pub fn list_workflows(&self) -> anyhow::Result<Vec<Workflow>> {
+        let conn = self.open()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, name, format, source, builtin, created_at_ms, updated_at_ms, rev
+             FROM workflows
+             ORDER BY builtin DESC, id ASC",
+        )?;
+        let rows = stmt.query_map([], workflow_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_workflow(&self, id: &str) -> anyhow::Result<Option<Workflow>> {
+        let conn = self.open()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, name, format, source, builtin, created_at_ms, updated_at_ms, rev
+             FROM workflows WHERE id=?1",
+        )?;
+        let mut rows = stmt.query_map([id], workflow_from_row)?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Insert or replace a workflow document. The caller is responsible for validating
+    /// `source`; the engine only stores it.
+    pub fn upsert_workflow(
+        &self,
+        id: &str,
+        name: &str,
+        format: &str,
+        source: &str,
+        builtin: bool,
+    ) -> anyhow::Result<Workflow> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        let now = now_ms();
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM workflows WHERE id = ?1)",
+            [id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            tx.execute(
+                "UPDATE workflows
+                 SET name=?2, format=?3, source=?4, updated_at_ms=?5, rev=rev+1
+                 WHERE id=?1",
+                (id, name, format, source, now),
+            )?;
+        } else {
+            tx.execute(
+                "INSERT INTO workflows (id, name, format, source, builtin, created_at_ms, updated_at_ms, rev)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, 1)",
+                (id, name, format, source, builtin, now),
+            )?;
+        }
+        append_event_tx(
+            &tx,
+            if exists {
+                "workflow.updated"
+            } else {
+                "workflow.created"
+            },
+            Some(id),
+            serde_json::json!({ "id": id, "name": name, "format": format }),
+        )?;
+        let wf = tx.query_row(
+            "SELECT id, name, format, source, builtin, created_at_ms, updated_at_ms, rev
+             FROM workflows WHERE id=?1",
+            [id],
+            workflow_from_row,
+        )?;
+        tx.commit()?;
+        Ok(wf)
+    }
+
+    /// Insert a builtin workflow, or upgrade a builtin row the user has not edited: its source
+    /// still matches the one last seeded (`builtin_source`), or, for rows seeded before that was
+    /// recorded, it was never updated (`rev` 1). Returns whether the row changed.
+    pub fn seed_workflow(
+        &self,
+        id: &str,
+        name: &str,
+        format: &str,
+        source: &str,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let now = now_ms();
+        let row: Option<(String, bool, Option<String>, i64)> = tx
+            .query_row(
+                "SELECT source, builtin, builtin_source, rev FROM workflows WHERE id=?1",
+                [id],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .optional()?;
+        let changed = match row {
+            None => {
+                tx.execute(
+                    "INSERT INTO workflows (id, name, format, source, builtin, builtin_source, created_at_ms, updated_at_ms, rev)
+                     VALUES (?1, ?2, ?3, ?4, 1, ?4, ?5, ?5, 1)",
+                    (id, name, format, source, now),
+                )?;
+                true
+            }
+            Some((current, true, seeded, rev)) => {
+                let unedited = match &seeded {
+                    Some(seeded) => *seeded == current,
+                    None => rev == 1,
+                };
+                if unedited && current != source {
+                    tx.execute(
+                        "UPDATE workflows
+                         SET name=?2, format=?3, source=?4, builtin_source=?4, updated_at_ms=?5, rev=rev+1
+                         WHERE id=?1",
+                        (id, name, format, source, now),
+                    )?;
+                    append_event_tx(
+                        &tx,
+                        "workflow.updated",
+                        Some(id),
+                        serde_json::json!({ "id": id, "name": name, "format": format, "builtin_upgrade": true }),
+                    )?;
+                    true
+                } else {
+                    if unedited && seeded.is_none() {
+                        tx.execute(
+                            "UPDATE workflows SET builtin_source=source WHERE id=?1",
+                            [id],
+                        )?;
+                    }
+                    false
+                }
+            }
+            Some(_) => false,
+        };
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    pub fn delete_workflow(&self, id: &str) -> anyhow::Result<bool> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        let n = tx.execute("DELETE FROM workflows WHERE id=?1", [id])?;
+        if n > 0 {
+            append_event_tx(
+                &tx,
+                "workflow.deleted",
+                Some(id),
+                serde_json::json!({ "id": id }),
+            )?;
+        }
+        tx.commit()?;
+        Ok(n > 0)
+    }
+
     pub fn list_belts(&self) -> anyhow::Result<Vec<Belt>> {
         let conn = self.open()?;
         let mut stmt = conn.prepare(
@@ -437,6 +635,30 @@ impl Engine {
             conn.query_row("SELECT MAX(seq) FROM event_log", [], |row| row.get(0))?;
         Ok(rev.unwrap_or(0))
     }
+
+    /// Tail `event_log` by `seq` (exclusive), oldest first. This is an index range scan on the
+    /// primary key, so it stays cheap no matter how large the log grows.
+    pub fn list_events_since(&self, since_seq: i64, limit: usize) -> anyhow::Result<Vec<EventRow>> {
+        let conn = self.open()?;
+        let mut stmt = conn.prepare(
+            "SELECT seq, ts_ms, kind, entity_id, payload_json
+             FROM event_log
+             WHERE seq > ?1
+             ORDER BY seq ASC
+             LIMIT ?2",
+        )?;
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let rows = stmt.query_map((since_seq, limit), |row| {
+            Ok(EventRow {
+                seq: row.get(0)?,
+                ts_ms: row.get(1)?,
+                kind: row.get(2)?,
+                entity_id: row.get(3)?,
+                payload_json: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -465,6 +687,41 @@ pub struct Quest {
     pub rev: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Workflow {
+    pub id: String,
+    pub name: String,
+    /// `yaml` or `toml`.
+    pub format: String,
+    pub source: String,
+    pub builtin: bool,
+    pub created_at_ms: i64,
+    pub updated_at_ms: i64,
+    pub rev: i64,
+}
+
+fn workflow_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Workflow> {
+    Ok(Workflow {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        format: row.get(2)?,
+        source: row.get(3)?,
+        builtin: row.get(4)?,
+        created_at_ms: row.get(5)?,
+        updated_at_ms: row.get(6)?,
+        rev: row.get(7)?,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventRow {
+    pub seq: i64,
+    pub ts_ms: i64,
+    pub kind: String,
+    pub entity_id: Option<String>,
+    pub payload_json: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Belt {
     pub id: String,
@@ -477,13 +734,30 @@ pub struct Belt {
     pub rev: i64,
 }
 
-fn migrate(conn: &Connection) -> anyhow::Result<()> {
-    // Lightweight migrations. We use `user_version` + IF NOT EXISTS + best-effort ALTERs,
-    // because the schema is still young and we want installs to be resilient.
-    let v: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+/// One numbered schema change. Each is applied once, in its own transaction, and recorded in
+/// `schema_migrations`; `user_version` tracks the highest applied number.
+///
+/// Databases created before this table existed (no history rows) replay every migration once,
+/// so each one must also be safe on a schema that already has its tables or columns.
+struct Migration {
+    version: i64,
+    name: &'static str,
+    up: Up,
+}
 
-    if v < 1 {
-        conn.execute_batch(
+enum Up {
+    Sql(&'static str),
+    /// `(table, column, declaration)`; columns that already exist are skipped.
+    Columns(&'static [(&'static str, &'static str, &'static str)]),
+    Rust(fn(&Connection) -> anyhow::Result<()>),
+}
+
+/// Append only: never renumber, edit or remove a migration that has shipped.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        up: Up::Sql(
             r#"
 CREATE TABLE IF NOT EXISTS events (
   id TEXT PRIMARY KEY,
@@ -566,8 +840,6 @@ CREATE TABLE IF NOT EXISTS runs (
   updated_at TEXT NOT NULL
 );
 
-CREATE INDEX IF NOT EXISTS idx_runs_entity_id ON runs(entity_id);
-
 CREATE TABLE IF NOT EXISTS steps (
   id TEXT PRIMARY KEY,
   run_id TEXT NOT NULL REFERENCES runs(id),
@@ -581,69 +853,6 @@ CREATE TABLE IF NOT EXISTS steps (
   updated_at TEXT NOT NULL
 );
 
-	CREATE TABLE IF NOT EXISTS belts (
-	  id TEXT PRIMARY KEY,
-	  a_id TEXT NOT NULL,
-	  b_id TEXT NOT NULL,
-	  kind TEXT NOT NULL DEFAULT 'link',
-	  path_json TEXT NOT NULL DEFAULT '[]',
-	  created_at_ms INTEGER NOT NULL,
-	  updated_at_ms INTEGER NOT NULL,
-	  rev INTEGER NOT NULL DEFAULT 0
-	);
-CREATE INDEX IF NOT EXISTS idx_belts_updated_at ON belts(updated_at_ms);
-CREATE INDEX IF NOT EXISTS idx_belts_a_id ON belts(a_id);
-CREATE INDEX IF NOT EXISTS idx_belts_b_id ON belts(b_id);
-
-CREATE TABLE IF NOT EXISTS library_artifacts (
-  id TEXT PRIMARY KEY,
-  agent_id TEXT NOT NULL,
-  base_id TEXT,
-  run_id TEXT,
-  source_event TEXT,
-  hierarchy_json TEXT NOT NULL DEFAULT '{}',
-  document_md TEXT NOT NULL DEFAULT '',
-  content_hash TEXT NOT NULL,
-  version INTEGER NOT NULL DEFAULT 1,
-  created_at_ms INTEGER NOT NULL,
-  rev INTEGER NOT NULL DEFAULT 0
-);
-CREATE INDEX IF NOT EXISTS idx_library_artifacts_agent ON library_artifacts(agent_id, created_at_ms DESC);
-CREATE INDEX IF NOT EXISTS idx_library_artifacts_base ON library_artifacts(base_id, created_at_ms DESC);
-CREATE INDEX IF NOT EXISTS idx_library_artifacts_run ON library_artifacts(run_id, created_at_ms DESC);
-"#,
-        )?;
-
-        conn.pragma_update(None, "user_version", 1_i64)?;
-    }
-
-    // Best-effort column additions for existing DBs.
-    ensure_column(conn, "entities", "rev", "INTEGER NOT NULL DEFAULT 0")?;
-    ensure_column(conn, "entities", "w", "INTEGER NOT NULL DEFAULT 1")?;
-    ensure_column(conn, "entities", "h", "INTEGER NOT NULL DEFAULT 1")?;
-    ensure_column(conn, "agents", "rev", "INTEGER NOT NULL DEFAULT 0")?;
-    ensure_column(conn, "worktrees", "rev", "INTEGER NOT NULL DEFAULT 0")?;
-    ensure_column(conn, "runs", "entity_id", "TEXT")?;
-    // Quests table introduced in v1 but might be missing in older dev DBs.
-    conn.execute_batch(
-        r#"
-CREATE TABLE IF NOT EXISTS quests (
-  id TEXT PRIMARY KEY,
-  title TEXT NOT NULL,
-  kind TEXT NOT NULL DEFAULT 'human',
-  state TEXT NOT NULL DEFAULT 'open',
-  body TEXT NOT NULL DEFAULT '',
-  created_at_ms INTEGER NOT NULL,
-  updated_at_ms INTEGER NOT NULL,
-  rev INTEGER NOT NULL DEFAULT 0
-);
-CREATE INDEX IF NOT EXISTS idx_quests_updated_at ON quests(updated_at_ms);
-"#,
-    )?;
-
-    // Belts table might be missing in older dev DBs; keep it self-healing.
-    conn.execute_batch(
-        r#"
 CREATE TABLE IF NOT EXISTS belts (
   id TEXT PRIMARY KEY,
   a_id TEXT NOT NULL,
@@ -675,12 +884,63 @@ CREATE INDEX IF NOT EXISTS idx_library_artifacts_agent ON library_artifacts(agen
 CREATE INDEX IF NOT EXISTS idx_library_artifacts_base ON library_artifacts(base_id, created_at_ms DESC);
 CREATE INDEX IF NOT EXISTS idx_library_artifacts_run ON library_artifacts(run_id, created_at_ms DESC);
 "#,
-    )?;
-
-    ensure_column(conn, "belts", "path_json", "TEXT NOT NULL DEFAULT '[]'")?;
-
-    conn.execute_batch(
-        r#"
+        ),
+    },
+    Migration {
+        version: 2,
+        name: "revisions_footprints_and_run_entities",
+        up: Up::Columns(&[
+            ("entities", "rev", "INTEGER NOT NULL DEFAULT 0"),
+            ("entities", "w", "INTEGER NOT NULL DEFAULT 1"),
+            ("entities", "h", "INTEGER NOT NULL DEFAULT 1"),
+            ("agents", "rev", "INTEGER NOT NULL DEFAULT 0"),
+            ("worktrees", "rev", "INTEGER NOT NULL DEFAULT 0"),
+            ("worktrees", "run_id", "TEXT"),
+            ("runs", "entity_id", "TEXT"),
+            ("belts", "path_json", "TEXT NOT NULL DEFAULT '[]'"),
+        ]),
+    },
+    // Column added by migration 2 on older DBs, so its index can only follow it.
+    Migration {
+        version: 3,
+        name: "runs_entity_index",
+        up: Up::Sql("CREATE INDEX IF NOT EXISTS idx_runs_entity_id ON runs(entity_id);"),
+    },
+    // Worker ownership + liveness for running steps; timeout is snapshotted from the workflow.
+    Migration {
+        version: 4,
+        name: "step_workers",
+        up: Up::Columns(&[
+            ("steps", "worker_id", "TEXT"),
+            ("steps", "started_at_ms", "INTEGER"),
+            ("steps", "heartbeat_at_ms", "INTEGER"),
+            ("steps", "timeout_sec", "INTEGER"),
+        ]),
+    },
+    // Story fan-out: per-story steps carry their story id and an explicit dependency list
+    // (step row ids); rows without one keep the linear `step_index` ordering.
+    Migration {
+        version: 5,
+        name: "story_fan_out",
+        up: Up::Columns(&[
+            ("steps", "story_id", "TEXT"),
+            ("steps", "depends_on_json", "TEXT"),
+        ]),
+    },
+    // Retry bookkeeping: attempts made so far and the earliest time the next one may start.
+    Migration {
+        version: 6,
+        name: "step_retries",
+        up: Up::Columns(&[
+            ("steps", "attempt", "INTEGER NOT NULL DEFAULT 0"),
+            ("steps", "next_attempt_at_ms", "INTEGER"),
+        ]),
+    },
+    Migration {
+        version: 7,
+        name: "skill_graphs",
+        up: Up::Sql(
+            r#"
 CREATE TABLE IF NOT EXISTS skill_graphs (
   id TEXT PRIMARY KEY,
   pack_name TEXT NOT NULL,
@@ -726,8 +986,316 @@ CREATE INDEX IF NOT EXISTS idx_skill_nodes_graph ON skill_nodes(graph_id);
 CREATE INDEX IF NOT EXISTS idx_skill_assignments_scope ON skill_assignments(scope_kind, scope_ref);
 CREATE UNIQUE INDEX IF NOT EXISTS idx_skill_assignments_unique ON skill_assignments(graph_id, node_id, scope_kind, IFNULL(scope_ref,''));
 "#,
+        ),
+    },
+    // Declarative workflow documents (YAML/TOML); runs snapshot their steps at creation time.
+    Migration {
+        version: 8,
+        name: "workflows",
+        up: Up::Sql(
+            r#"
+CREATE TABLE IF NOT EXISTS workflows (
+  id TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  format TEXT NOT NULL DEFAULT 'yaml',
+  source TEXT NOT NULL,
+  builtin INTEGER NOT NULL DEFAULT 0,
+  created_at_ms INTEGER NOT NULL,
+  updated_at_ms INTEGER NOT NULL,
+  rev INTEGER NOT NULL DEFAULT 0
+);
+"#,
+        ),
+    },
+    // Structured fields parsed from agent replies (STATUS, STORIES_JSON, BUILD_CMD, ...) and the
+    // stories a plan expanded into.
+    Migration {
+        version: 9,
+        name: "step_outputs_and_stories",
+        up: Up::Sql(
+            r#"
+CREATE TABLE IF NOT EXISTS step_outputs (
+  step_row_id TEXT PRIMARY KEY,
+  run_id TEXT NOT NULL,
+  step_id TEXT NOT NULL,
+  status TEXT,
+  outputs_json TEXT NOT NULL,
+  created_at_ms INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_step_outputs_run ON step_outputs(run_id);
+CREATE TABLE IF NOT EXISTS run_stories (
+  run_id TEXT NOT NULL,
+  story_id TEXT NOT NULL,
+  position INTEGER NOT NULL,
+  title TEXT NOT NULL,
+  story_json TEXT NOT NULL,
+  PRIMARY KEY (run_id, story_id)
+);
+"#,
+        ),
+    },
+    // API tokens: only a SHA-256 of each token is stored; revoked rows are kept for the audit trail.
+    Migration {
+        version: 10,
+        name: "api_tokens",
+        up: Up::Sql(
+            r#"
+CREATE TABLE IF NOT EXISTS api_tokens (
+  id TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE,
+  created_at_ms INTEGER NOT NULL,
+  last_used_at_ms INTEGER,
+  revoked_at_ms INTEGER
+);
+"#,
+        ),
+    },
+    // GitHub webhook deliveries, keyed by `X-GitHub-Delivery`, with the verified payload so a
+    // delivery can be replayed.
+    Migration {
+        version: 11,
+        name: "github_deliveries",
+        up: Up::Sql(
+            r#"
+CREATE TABLE IF NOT EXISTS github_deliveries (
+  id TEXT PRIMARY KEY,
+  event TEXT NOT NULL,
+  action TEXT,
+  repo TEXT,
+  status TEXT NOT NULL,
+  outcome_json TEXT NOT NULL DEFAULT '{}',
+  error TEXT,
+  payload_json TEXT NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 1,
+  duplicates INTEGER NOT NULL DEFAULT 0,
+  received_at_ms INTEGER NOT NULL,
+  processed_at_ms INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_github_deliveries_received ON github_deliveries(received_at_ms);
+"#,
+        ),
+    },
+    // Pull requests of the built-in local forge (a bare repo as `origin`), keyed by that repo.
+    Migration {
+        version: 12,
+        name: "local_forge",
+        up: Up::Sql(
+            r#"
+CREATE TABLE IF NOT EXISTS forge_pull_requests (
+  repo TEXT NOT NULL,
+  number INTEGER NOT NULL,
+  head TEXT NOT NULL,
+  base TEXT NOT NULL,
+  title TEXT NOT NULL,
+  body TEXT NOT NULL DEFAULT '',
+  state TEXT NOT NULL,
+  created_at_ms INTEGER NOT NULL,
+  updated_at_ms INTEGER NOT NULL,
+  PRIMARY KEY (repo, number)
+);
+CREATE TABLE IF NOT EXISTS forge_pr_comments (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  repo TEXT NOT NULL,
+  number INTEGER NOT NULL,
+  body TEXT NOT NULL,
+  created_at_ms INTEGER NOT NULL
+);
+"#,
+        ),
+    },
+    // Review comments and CI results from the forge, matched to the run that owns the PR.
+    Migration {
+        version: 13,
+        name: "run_feedback",
+        up: Up::Sql(
+            r#"
+CREATE TABLE IF NOT EXISTS run_feedback (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  run_id TEXT NOT NULL,
+  source TEXT NOT NULL,
+  kind TEXT NOT NULL,
+  external_id TEXT NOT NULL,
+  author TEXT,
+  state TEXT,
+  body TEXT NOT NULL DEFAULT '',
+  url TEXT,
+  actionable INTEGER NOT NULL DEFAULT 0,
+  followup_step_id TEXT,
+  created_at_ms INTEGER NOT NULL,
+  UNIQUE (run_id, external_id)
+);
+CREATE INDEX IF NOT EXISTS idx_run_feedback_run ON run_feedback(run_id, id);
+"#,
+        ),
+    },
+    // CI feedback is judged per check: only a check's latest result counts.
+    Migration {
+        version: 14,
+        name: "run_feedback_check_name",
+        up: Up::Columns(&[("run_feedback", "check_name", "TEXT")]),
+    },
+    // Per-base merge queue: one row per run, worked head first.
+    Migration {
+        version: 15,
+        name: "merge_queue",
+        up: Up::Sql(
+            r#"
+CREATE TABLE IF NOT EXISTS merge_queue (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  base_id TEXT NOT NULL,
+  run_id TEXT NOT NULL UNIQUE,
+  branch TEXT NOT NULL,
+  pr_url TEXT NOT NULL,
+  pr_number INTEGER NOT NULL,
+  test_cmd TEXT,
+  status TEXT NOT NULL,
+  error TEXT,
+  merged_sha TEXT,
+  enqueued_at_ms INTEGER NOT NULL,
+  started_at_ms INTEGER,
+  finished_at_ms INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_merge_queue_base ON merge_queue(base_id, status, id);
+"#,
+        ),
+    },
+    Migration {
+        version: 16,
+        name: "canonical_footprints",
+        up: Up::Rust(backfill_footprints),
+    },
+    // Source a builtin workflow was last seeded with, to tell user edits from stale builtins.
+    Migration {
+        version: 17,
+        name: "workflow_builtin_source",
+        up: Up::Columns(&[("workflows", "builtin_source", "TEXT")]),
+    },
+];
+
+/// Highest migration this build knows; databases beyond it are refused.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub applied_at_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationStatus {
+    /// `user_version` of the database (0 when it does not exist yet).
+    pub current: i64,
+    /// [`SCHEMA_VERSION`] of this build.
+    pub latest: i64,
+    pub applied: Vec<AppliedMigration>,
+    /// `(version, name)` of migrations `open()` would apply.
+    pub pending: Vec<(i64, String)>,
+}
+
+const HISTORY_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_migrations (
+  version INTEGER PRIMARY KEY,
+  name TEXT NOT NULL,
+  applied_at_ms INTEGER NOT NULL
+);
+"#;
+
+fn history(conn: &Connection) -> anyhow::Result<Vec<AppliedMigration>> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type='table' AND name='schema_migrations')",
+        [],
+        |r| r.get(0),
     )?;
+    if !exists {
+        return Ok(vec![]);
+    }
+    let mut stmt = conn
+        .prepare("SELECT version, name, applied_at_ms FROM schema_migrations ORDER BY version")?;
+    let rows = stmt.query_map([], |r| {
+        Ok(AppliedMigration {
+            version: r.get(0)?,
+            name: r.get(1)?,
+            applied_at_ms: r.get(2)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn migration_status(conn: &Connection) -> anyhow::Result<MigrationStatus> {
+    let current: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let applied = history(conn)?;
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+        .map(|m| (m.version, m.name.to_string()))
+        .collect();
+    Ok(MigrationStatus {
+        current,
+        latest: SCHEMA_VERSION,
+        applied,
+        pending,
+    })
+}
+
+fn refuse_downgrade(status: &MigrationStatus) -> anyhow::Result<()> {
+    let newest = status
+        .applied
+        .iter()
+        .map(|a| a.version)
+        .max()
+        .unwrap_or(0)
+        .max(status.current);
+    if newest > SCHEMA_VERSION {
+        anyhow::bail!(
+            "db_schema_newer: database is at schema {newest} but this build only knows up to {SCHEMA_VERSION}; upgrade clawdorio or restore a backup"
+        );
+    }
+    Ok(())
+}
+
+fn migrate(conn: &Connection) -> anyhow::Result<()> {
+    let v: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if v == SCHEMA_VERSION {
+        return Ok(());
+    }
+    conn.execute_batch(HISTORY_TABLE)?;
+    refuse_downgrade(&migration_status(conn)?)?;
 
+    for m in MIGRATIONS {
+        // IMMEDIATE so two processes opening a fresh DB at once apply each migration once.
+        let tx =
+            rusqlite::Transaction::new_unchecked(conn, rusqlite::TransactionBehavior::Immediate)?;
+        let done: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM schema_migrations WHERE version=?1)",
+            [m.version],
+            |r| r.get(0),
+        )?;
+        if done {
+            continue;
+        }
+        let applied = match &m.up {
+            Up::Sql(sql) => tx.execute_batch(sql).map_err(anyhow::Error::from),
+            Up::Columns(cols) => cols
+                .iter()
+                .try_for_each(|(table, col, decl)| ensure_column(&tx, table, col, decl)),
+            Up::Rust(f) => f(&tx),
+        };
+        applied.with_context(|| format!("migration {} {}", m.version, m.name))?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at_ms) VALUES (?1, ?2, ?3)",
+            (m.version, m.name, now_ms()),
+        )?;
+        let current: i64 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        tx.pragma_update(None, "user_version", current.max(m.version))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn backfill_footprints(conn: &Connection) -> anyhow::Result<()> {
     // Backfill footprints for early dev DBs that stored everything as 1x1.
     // Only touch rows that still look like defaults.
     conn.execute_batch(
@@ -749,22 +1317,20 @@ UPDATE entities SET w=3, h=4 WHERE kind IN ('feature','research','warehouse','un
             (now_ms(), "entities.base_footprint", "{}"),
         )?;
     }
-
     Ok(())
 }
 
 fn ensure_column(conn: &Connection, table: &str, col: &str, decl: &str) -> anyhow::Result<()> {
-    let sql = format!("ALTER TABLE {table} ADD COLUMN {col} {decl}");
-    match conn.execute(&sql, []) {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            // Ignore "duplicate column name".
-            if e.to_string().to_lowercase().contains("duplicate column") {
-                return Ok(());
-            }
-            Err(e).with_context(|| format!("ensure column {table}.{col}"))
-        }
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name=?2)",
+        (table, col),
+        |r| r.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {col} {decl}"), [])
+            .with_context(|| format!("add column {table}.{col}"))?;
     }
+    Ok(())
 }
 
 fn append_event_tx(
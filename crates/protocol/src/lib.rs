@@ -1,18 +1,13 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Swap {
+    #[default]
     Replace,
     Merge,
 }
 
-impl Default for Swap {
-    fn default() -> Self {
-        Self::Replace
-    }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Patch {
     pub target: String,
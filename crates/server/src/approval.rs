@@ -0,0 +1,258 @@
+//! Human approval gates.
+//!
+//! A workflow step with `agent: internal/approval` is never executed: when it becomes runnable
+//! the worker parks it in `awaiting_approval` until someone calls
+//! `POST /api/runs/{id}/steps/{step_id}/approve` or `.../reject`. Only the gate's dependents wait;
+//! the run stays `running`, so independent DAG branches keep going and the run can be paused. A
+//! rejection re-queues the step(s) the gate depends on with the reviewer's comment, which is
+//! appended to their prompt.
+
+use super::{internal_error, now_ms_i64, now_rfc3339, parse_payload, AppState, PendingStep};
+use axum::http::StatusCode;
+use axum::Json;
+use clawdorio_engine::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub(crate) const APPROVAL_AGENT: &str = "internal/approval";
+
+/// Marks a claimed approval step as waiting for a human decision.
+pub(crate) fn park(engine: &Engine, step: &PendingStep) -> anyhow::Result<()> {
+    let mut conn = engine.open()?;
+    let tx = conn.transaction()?;
+    let now = now_rfc3339();
+    let updated = tx.execute(
+        "UPDATE steps SET status='awaiting_approval', worker_id=NULL, updated_at=?1 WHERE id=?2 AND status='running'",
+        (&now, &step.step_row_id),
+    )?;
+    if updated == 0 {
+        tx.commit()?;
+        return Ok(());
+    }
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'step.awaiting_approval', ?2, ?3)",
+        (
+            now_ms_i64(),
+            &step.step_row_id,
+            serde_json::json!({ "run_id": step.run_id, "step_id": step.step_id }).to_string(),
+        ),
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PendingApproval {
+    pub(crate) step_id: String,
+    pub(crate) step_row_id: String,
+    pub(crate) requested_at: String,
+    /// Stories awaiting sign-off, when an earlier step planned some.
+    pub(crate) stories: Option<serde_json::Value>,
+}
+
+/// The gate a run is parked on, if any.
+pub(crate) fn pending_approval(
+    conn: &rusqlite::Connection,
+    run_id: &str,
+    run_ctx: &serde_json::Value,
+) -> Option<PendingApproval> {
+    conn.query_row(
+        "SELECT step_id, id, updated_at FROM steps
+         WHERE run_id=?1 AND status='awaiting_approval'
+         ORDER BY step_index ASC LIMIT 1",
+        [run_id],
+        |r| {
+            Ok(PendingApproval {
+                step_id: r.get(0)?,
+                step_row_id: r.get(1)?,
+                requested_at: r.get(2)?,
+                stories: run_ctx.get("stories").cloned(),
+            })
+        },
+    )
+    .ok()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ApprovalInput {
+    #[serde(default)]
+    pub(crate) comment: Option<String>,
+    #[serde(default)]
+    pub(crate) by: Option<String>,
+}
+
+pub(crate) async fn api_run_step_approve(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path((run_id, step_id)): axum::extract::Path<(String, String)>,
+    input: Option<Json<ApprovalInput>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let input = input.map(|Json(i)| i).unwrap_or_default();
+    decide(&state.engine, &run_id, &step_id, true, &input).map(Json)
+}
+
+pub(crate) async fn api_run_step_reject(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path((run_id, step_id)): axum::extract::Path<(String, String)>,
+    input: Option<Json<ApprovalInput>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let input = input.map(|Json(i)| i).unwrap_or_default();
+    decide(&state.engine, &run_id, &step_id, false, &input).map(Json)
+}
+
+/// Resolves the gate `step_key` (workflow step id or step row id) of `run_id`.
+fn decide(
+    engine: &Engine,
+    run_id: &str,
+    step_key: &str,
+    approve: bool,
+    input: &ApprovalInput,
+) -> Result<serde_json::Value, (StatusCode, String)> {
+    let db = |ctx: &'static str| move |e: rusqlite::Error| internal_error(ctx)(e.into());
+    let comment = input
+        .comment
+        .as_deref()
+        .map(str::trim)
+        .filter(|c| !c.is_empty());
+    if !approve && comment.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "comment_required".to_string()));
+    }
+    let by = input.by.as_deref().unwrap_or("dashboard");
+
+    let mut conn = engine.open().map_err(internal_error("engine.open"))?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(db("db.transaction"))?;
+    let gate: Option<(String, String, i64, Option<String>)> = tx
+        .query_row(
+            "SELECT id, step_id, step_index, depends_on_json FROM steps
+             WHERE run_id=?1 AND (step_id=?2 OR id=?2) AND status='awaiting_approval'
+             LIMIT 1",
+            (run_id, step_key),
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .ok();
+    let Some((row_id, step_id, step_index, depends_on)) = gate else {
+        let exists: bool = tx
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM steps WHERE run_id=?1 AND (step_id=?2 OR id=?2))",
+                (run_id, step_key),
+                |r| r.get(0),
+            )
+            .map_err(db("db.approval_probe"))?;
+        return Err(if exists {
+            (StatusCode::CONFLICT, "not_awaiting_approval".to_string())
+        } else {
+            (StatusCode::NOT_FOUND, "not_found".to_string())
+        });
+    };
+
+    let now = now_rfc3339();
+    let mut rewound: Vec<String> = vec![];
+    let (kind, run_status) = if approve {
+        let note = match comment {
+            Some(c) => format!("approved by {by}: {c}"),
+            None => format!("approved by {by}"),
+        };
+        tx.execute(
+            "UPDATE steps SET status='done', output_text=?1, updated_at=?2 WHERE id=?3",
+            (&note, &now, &row_id),
+        )
+        .map_err(db("db.approve_step"))?;
+        let remaining: i64 = tx
+            .query_row(
+                "SELECT COUNT(*) FROM steps WHERE run_id=?1 AND status NOT IN ('done','skipped')",
+                [run_id],
+                |r| r.get(0),
+            )
+            .map_err(db("db.remaining_steps"))?;
+        (
+            "step.approved",
+            if remaining == 0 { "done" } else { "running" },
+        )
+    } else {
+        let comment = comment.unwrap_or_default();
+        // Re-run what the gate was reviewing: its dependencies (or the previous step in
+        // linear runs), each told why it was sent back.
+        rewound = match depends_on.and_then(|d| serde_json::from_str::<Vec<String>>(&d).ok()) {
+            Some(ids) => ids,
+            None => tx
+                .query_row(
+                    "SELECT id FROM steps WHERE run_id=?1 AND step_index < ?2 ORDER BY step_index DESC LIMIT 1",
+                    (run_id, step_index),
+                    |r| r.get(0),
+                )
+                .map(|id| vec![id])
+                .unwrap_or_default(),
+        };
+        for id in &rewound {
+            let input_json: String = tx
+                .query_row("SELECT input_json FROM steps WHERE id=?1", [id], |r| {
+                    r.get(0)
+                })
+                .map_err(db("db.rewind_input"))?;
+            let mut input = parse_payload(&input_json);
+            input["approval_feedback"] = serde_json::json!(comment);
+            tx.execute(
+                "UPDATE steps SET status='queued', output_text=NULL, next_attempt_at_ms=NULL, input_json=?1, updated_at=?2
+                 WHERE id=?3 AND status != 'running'",
+                (input.to_string(), &now, id),
+            )
+            .map_err(db("db.rewind_step"))?;
+        }
+        tx.execute(
+            "UPDATE steps SET status='queued', output_text=?1, updated_at=?2 WHERE id=?3",
+            (format!("rejected by {by}: {comment}"), &now, &row_id),
+        )
+        .map_err(db("db.reject_step"))?;
+        ("step.rejected", "running")
+    };
+    // A paused run stays paused; `awaiting_approval` is what older versions parked whole runs in.
+    tx.execute(
+        "UPDATE runs SET status=?1, updated_at=?2
+         WHERE id=?3 AND (?1 = 'done' OR status = 'awaiting_approval')",
+        (run_status, &now, run_id),
+    )
+    .map_err(db("db.update_run"))?;
+    let run_status: String = tx
+        .query_row("SELECT status FROM runs WHERE id=?1", [run_id], |r| {
+            r.get(0)
+        })
+        .map_err(db("db.run_status"))?;
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, ?2, ?3, ?4)",
+        (
+            now_ms_i64(),
+            kind,
+            &row_id,
+            serde_json::json!({
+                "run_id": run_id,
+                "step_id": step_id,
+                "by": by,
+                "comment": comment,
+                "rewound_steps": rewound,
+            })
+            .to_string(),
+        ),
+    )
+    .map_err(db("db.insert_event"))?;
+    if run_status == "done" {
+        tx.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.done', ?2, ?3)",
+            (
+                now_ms_i64(),
+                run_id,
+                serde_json::json!({ "run_id": run_id }).to_string(),
+            ),
+        )
+        .map_err(db("db.insert_event"))?;
+    }
+    tx.commit().map_err(db("db.commit"))?;
+    Ok(serde_json::json!({
+        "ok": true,
+        "run_id": run_id,
+        "step_id": step_id,
+        "decision": if approve { "approved" } else { "rejected" },
+        "run_status": run_status,
+        "rewound_steps": rewound,
+    }))
+}
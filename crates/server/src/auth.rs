@@ -0,0 +1,352 @@
+//! Bearer-token authentication.
+//!
+//! With `[auth] enabled = true`, every `/api` call needs `Authorization: Bearer <token>` (or
+//! `?access_token=` for `EventSource`, which cannot set headers). Tokens are random, shown once
+//! at creation, and stored as SHA-256 hashes in `api_tokens`. Scopes are ordered:
+//! `read` may call `GET` endpoints, `operator` may also mutate, and `admin` may also manage
+//! tokens. Every mutating call is written to `event_log` as `api.audit` with the caller.
+
+use super::{internal_error, now_ms_i64, AppState};
+use axum::extract::ConnectInfo;
+use axum::http::{Method, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use clawdorio_engine::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const TOKEN_PREFIX: &str = "clw_";
+/// `last_used_at_ms` is refreshed at most this often per token.
+const LAST_USED_RESOLUTION_MS: i64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    Operator,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Operator => "operator",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "read" => Some(Self::Read),
+            "operator" => Some(Self::Operator),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    /// Require a bearer token on `/api` routes.
+    pub enabled: bool,
+    /// Let loopback clients (the desktop app, local scripts) through without a token. Off by
+    /// default: tunnels and reverse proxies on the same host also connect from loopback.
+    pub loopback_bypass: bool,
+}
+
+/// Who made a request; added to request extensions by [`require_token`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Caller {
+    pub token_id: Option<String>,
+    pub name: String,
+    pub scope: Scope,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scope: Scope,
+    pub created_at_ms: i64,
+    pub last_used_at_ms: Option<i64>,
+    pub revoked_at_ms: Option<i64>,
+}
+
+fn hash_token(token: &str) -> String {
+    let mut h = Sha256::new();
+    h.update(token.as_bytes());
+    format!("{:x}", h.finalize())
+}
+
+pub(crate) fn random_hex(bytes: usize) -> anyhow::Result<String> {
+    let mut buf = vec![0u8; bytes];
+    getrandom::getrandom(&mut buf).map_err(|e| anyhow::anyhow!("getrandom: {e}"))?;
+    Ok(buf.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Creates a token and returns it with the only copy of its secret.
+pub fn create_token(
+    engine: &Engine,
+    name: &str,
+    scope: Scope,
+) -> anyhow::Result<(TokenInfo, String)> {
+    let name = name.trim();
+    if name.is_empty() {
+        anyhow::bail!("name_required");
+    }
+    let secret = format!("{TOKEN_PREFIX}{}", random_hex(24)?);
+    let info = TokenInfo {
+        id: format!("tok-{}", random_hex(6)?),
+        name: name.to_string(),
+        scope,
+        created_at_ms: now_ms_i64(),
+        last_used_at_ms: None,
+        revoked_at_ms: None,
+    };
+    let conn = engine.open()?;
+    conn.execute(
+        "INSERT INTO api_tokens (id, name, scope, token_hash, created_at_ms) VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            &info.id,
+            &info.name,
+            scope.as_str(),
+            hash_token(&secret),
+            info.created_at_ms,
+        ),
+    )?;
+    conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'token.created', ?2, ?3)",
+        (
+            info.created_at_ms,
+            &info.id,
+            serde_json::json!({ "name": info.name, "scope": scope }).to_string(),
+        ),
+    )?;
+    Ok((info, secret))
+}
+
+pub fn list_tokens(engine: &Engine) -> anyhow::Result<Vec<TokenInfo>> {
+    let conn = engine.open()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, name, scope, created_at_ms, last_used_at_ms, revoked_at_ms
+         FROM api_tokens ORDER BY created_at_ms ASC",
+    )?;
+    let rows = stmt.query_map([], |r| {
+        Ok(TokenInfo {
+            id: r.get(0)?,
+            name: r.get(1)?,
+            scope: Scope::parse(&r.get::<_, String>(2)?).unwrap_or(Scope::Read),
+            created_at_ms: r.get(3)?,
+            last_used_at_ms: r.get(4)?,
+            revoked_at_ms: r.get(5)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Revokes a token; returns false when no active token has that id.
+pub fn revoke_token(engine: &Engine, id: &str) -> anyhow::Result<bool> {
+    let conn = engine.open()?;
+    let now = now_ms_i64();
+    let n = conn.execute(
+        "UPDATE api_tokens SET revoked_at_ms=?1 WHERE id=?2 AND revoked_at_ms IS NULL",
+        (now, id),
+    )?;
+    if n > 0 {
+        conn.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'token.revoked', ?2, '{}')",
+            (now, id),
+        )?;
+    }
+    Ok(n > 0)
+}
+
+/// Resolves an active token to its caller.
+fn lookup(engine: &Engine, secret: &str) -> anyhow::Result<Option<Caller>> {
+    let conn = engine.open()?;
+    let row: Option<(String, String, String, Option<i64>)> = conn
+        .query_row(
+            "SELECT id, name, scope, last_used_at_ms FROM api_tokens
+             WHERE token_hash=?1 AND revoked_at_ms IS NULL",
+            [hash_token(secret)],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .ok();
+    let Some((id, name, scope, last_used)) = row else {
+        return Ok(None);
+    };
+    let now = now_ms_i64();
+    if last_used.is_none_or(|t| now - t >= LAST_USED_RESOLUTION_MS) {
+        conn.execute(
+            "UPDATE api_tokens SET last_used_at_ms=?1 WHERE id=?2",
+            (now, &id),
+        )?;
+    }
+    Ok(Some(Caller {
+        token_id: Some(id),
+        name,
+        scope: Scope::parse(&scope).unwrap_or(Scope::Read),
+    }))
+}
+
+/// Scope a request needs, or `None` for routes that stay public (the dashboard shell, sprites,
+/// health, and the GitHub webhook, which GitHub cannot send a token to).
+pub(crate) fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    if !path.starts_with("/api/") || path == "/api/github/webhook" {
+        return None;
+    }
+    if path == "/api/tokens" || path.starts_with("/api/tokens/") || path == "/api/export" {
+        return Some(Scope::Admin);
+    }
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        Some(Scope::Read)
+    } else {
+        Some(Scope::Operator)
+    }
+}
+
+fn bearer(req: &axum::http::Request<axum::body::Body>) -> Option<String> {
+    if let Some(v) = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+    {
+        return v
+            .strip_prefix("Bearer ")
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty());
+    }
+    req.uri().query().and_then(|q| {
+        q.split('&')
+            .filter_map(|kv| kv.split_once('='))
+            .find(|(k, _)| *k == "access_token")
+            .map(|(_, v)| v.to_string())
+            .filter(|t| !t.is_empty())
+    })
+}
+
+fn deny(status: StatusCode, code: &str) -> axum::response::Response {
+    (status, code.to_string()).into_response()
+}
+
+pub(crate) async fn require_token(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    mut req: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let auth = &state.config.auth;
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let Some(needed) = required_scope(&method, &path) else {
+        return next.run(req).await;
+    };
+    if !auth.enabled {
+        return next.run(req).await;
+    }
+    // A proxy that forwards for someone else connects from loopback too; never trust that.
+    let proxied = ["forwarded", "x-forwarded-for", "x-real-ip"]
+        .iter()
+        .any(|h| req.headers().contains_key(*h));
+    let loopback = !proxied
+        && req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(peer)| peer.ip().is_loopback());
+    let caller = match bearer(&req) {
+        Some(secret) => {
+            let engine = state.engine.clone();
+            match tokio::task::spawn_blocking(move || lookup(&engine, &secret)).await {
+                Ok(Ok(Some(caller))) => caller,
+                Ok(Ok(None)) => return deny(StatusCode::UNAUTHORIZED, "invalid_token"),
+                _ => return deny(StatusCode::INTERNAL_SERVER_ERROR, "auth_lookup_failed"),
+            }
+        }
+        None if auth.loopback_bypass && loopback => Caller {
+            token_id: None,
+            name: "loopback".to_string(),
+            scope: Scope::Admin,
+        },
+        None => return deny(StatusCode::UNAUTHORIZED, "token_required"),
+    };
+    if caller.scope < needed {
+        return deny(StatusCode::FORBIDDEN, "insufficient_scope");
+    }
+    req.extensions_mut().insert(caller.clone());
+    let resp = next.run(req).await;
+    if needed > Scope::Read {
+        let engine = state.engine.clone();
+        let status = resp.status().as_u16();
+        let _ = tokio::task::spawn_blocking(move || {
+            audit(&engine, &caller, method.as_str(), &path, status)
+        })
+        .await;
+    }
+    resp
+}
+
+fn audit(
+    engine: &Engine,
+    caller: &Caller,
+    method: &str,
+    path: &str,
+    status: u16,
+) -> anyhow::Result<()> {
+    let conn = engine.open()?;
+    conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'api.audit', ?2, ?3)",
+        (
+            now_ms_i64(),
+            caller.token_id.as_deref().unwrap_or(&caller.name),
+            serde_json::json!({
+                "token_id": caller.token_id,
+                "token_name": caller.name,
+                "scope": caller.scope,
+                "method": method,
+                "path": path,
+                "status": status,
+            })
+            .to_string(),
+        ),
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TokenCreateInput {
+    pub(crate) name: String,
+    pub(crate) scope: Scope,
+}
+
+pub(crate) async fn api_tokens_list(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Result<Json<Vec<TokenInfo>>, (StatusCode, String)> {
+    list_tokens(&state.engine)
+        .map(Json)
+        .map_err(internal_error("auth.list_tokens"))
+}
+
+pub(crate) async fn api_tokens_create(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(input): Json<TokenCreateInput>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if input.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "name_required".to_string()));
+    }
+    let (info, token) = create_token(&state.engine, &input.name, input.scope)
+        .map_err(internal_error("auth.create_token"))?;
+    Ok(Json(serde_json::json!({ "token": token, "info": info })))
+}
+
+pub(crate) async fn api_tokens_revoke(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if !revoke_token(&state.engine, &id).map_err(internal_error("auth.revoke_token"))? {
+        return Err((StatusCode::NOT_FOUND, "not_found".to_string()));
+    }
+    Ok(Json(serde_json::json!({ "ok": true, "id": id })))
+}
@@ -0,0 +1,491 @@
+//! Whole-world export and import.
+//!
+//! An export is taken from an online SQLite backup of the live database, so it is consistent
+//! even while workers are writing, and is written as one JSON document: every row of the world
+//! tables (board, quests, workflows, runs and their steps, library artifacts, skill graphs)
+//! keyed by column name, plus the archive format version and the schema version it was read
+//! from. Machine-local state (tokens, webhook deliveries, worktrees, the local forge, the merge
+//! queue and the event log) is left out.
+//!
+//! Import refuses archives from a newer schema than this binary's, then inserts everything in
+//! one transaction. Ids that already exist in the target database are replaced with fresh ones
+//! and every reference to them (belts, runs, steps, run contexts, artifacts, skills) is
+//! rewritten, so an archive can be merged into a board that is already in use. Unfinished runs
+//! come in `paused` with their steps released, so nothing starts against the exporting
+//! machine's checkouts until an operator resumes them.
+
+use super::{internal_error, now_ms_i64, now_rfc3339, AppState};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use clawdorio_engine::Engine;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const FORMAT: &str = "clawdorio-export";
+/// Bumped when the archive layout itself changes (not for schema changes).
+pub const FORMAT_VERSION: i64 = 1;
+
+/// How an exported column takes part in id remapping.
+#[derive(Clone, Copy)]
+enum Col {
+    /// The row's id in the given namespace.
+    Key(&'static str),
+    /// An id in the given namespace.
+    Ref(&'static str),
+    /// A JSON array of ids in the given namespace.
+    RefList(&'static str),
+    /// An id of an entity or a run, whichever it turns out to be.
+    AnyRef,
+    /// A run context (or a step input copied from one) with `base_id`/`entity_id`/`run_id`.
+    Context,
+    /// An AUTOINCREMENT id; dropped on import.
+    Serial,
+}
+
+struct Table {
+    name: &'static str,
+    cols: &'static [(&'static str, Col)],
+}
+
+/// Exported tables, parents first so inserts satisfy foreign keys.
+const TABLES: &[Table] = &[
+    Table {
+        name: "entities",
+        cols: &[("id", Col::Key("entity"))],
+    },
+    Table {
+        name: "belts",
+        cols: &[
+            ("id", Col::Key("belt")),
+            ("a_id", Col::Ref("entity")),
+            ("b_id", Col::Ref("entity")),
+        ],
+    },
+    Table {
+        name: "quests",
+        cols: &[("id", Col::Key("quest"))],
+    },
+    Table {
+        name: "workflows",
+        cols: &[("id", Col::Key("workflow"))],
+    },
+    Table {
+        name: "runs",
+        cols: &[
+            ("id", Col::Key("run")),
+            ("workflow_id", Col::Ref("workflow")),
+            ("entity_id", Col::Ref("entity")),
+            ("context_json", Col::Context),
+        ],
+    },
+    Table {
+        name: "steps",
+        cols: &[
+            ("id", Col::Key("step")),
+            ("run_id", Col::Ref("run")),
+            ("input_json", Col::Context),
+            ("depends_on_json", Col::RefList("step")),
+        ],
+    },
+    Table {
+        name: "step_outputs",
+        cols: &[
+            ("step_row_id", Col::Ref("step")),
+            ("run_id", Col::Ref("run")),
+        ],
+    },
+    Table {
+        name: "run_stories",
+        cols: &[("run_id", Col::Ref("run"))],
+    },
+    Table {
+        name: "run_feedback",
+        cols: &[
+            ("id", Col::Serial),
+            ("run_id", Col::Ref("run")),
+            ("followup_step_id", Col::Ref("step")),
+        ],
+    },
+    Table {
+        name: "library_artifacts",
+        cols: &[
+            ("id", Col::Key("artifact")),
+            ("base_id", Col::Ref("entity")),
+            ("run_id", Col::Ref("run")),
+        ],
+    },
+    Table {
+        name: "skill_graphs",
+        cols: &[("id", Col::Key("graph"))],
+    },
+    Table {
+        name: "skill_nodes",
+        cols: &[("id", Col::Key("node")), ("graph_id", Col::Ref("graph"))],
+    },
+    Table {
+        name: "skill_edges",
+        cols: &[
+            ("graph_id", Col::Ref("graph")),
+            ("from_node_id", Col::Ref("node")),
+            ("to_node_id", Col::Ref("node")),
+        ],
+    },
+    Table {
+        name: "skill_assignments",
+        cols: &[
+            ("id", Col::Key("assignment")),
+            ("graph_id", Col::Ref("graph")),
+            ("node_id", Col::Ref("node")),
+            ("scope_ref", Col::AnyRef),
+        ],
+    },
+];
+
+fn schema_version(conn: &Connection) -> anyhow::Result<i64> {
+    Ok(conn.pragma_query_value(None, "user_version", |r| r.get(0))?)
+}
+
+/// Copies the live database to `dest` with SQLite's online backup API.
+pub fn snapshot(engine: &Engine, dest: &Path) -> anyhow::Result<()> {
+    if dest.exists() {
+        anyhow::bail!("destination_exists: {}", dest.display());
+    }
+    engine.open()?.backup(DatabaseName::Main, dest, None)?;
+    Ok(())
+}
+
+fn to_json(v: SqlValue) -> anyhow::Result<Value> {
+    Ok(match v {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(i) => Value::from(i),
+        SqlValue::Real(f) => Value::from(f),
+        SqlValue::Text(s) => Value::String(s),
+        SqlValue::Blob(_) => anyhow::bail!("unsupported_blob_column"),
+    })
+}
+
+fn to_sql(v: &Value) -> SqlValue {
+    match v {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(i64::from(*b)),
+        Value::Number(n) => n
+            .as_i64()
+            .map(SqlValue::Integer)
+            .unwrap_or_else(|| SqlValue::Real(n.as_f64().unwrap_or(0.0))),
+        Value::String(s) => SqlValue::Text(s.clone()),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+/// Builds the export archive from a consistent snapshot of the database.
+pub fn export(engine: &Engine) -> anyhow::Result<Value> {
+    let tmp = std::env::temp_dir().join(format!(
+        "clawdorio-export-{}.db",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    snapshot(engine, &tmp)?;
+    let result = (|| {
+        let conn = Connection::open_with_flags(&tmp, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut tables = Map::new();
+        for t in TABLES {
+            let mut stmt = conn.prepare(&format!("SELECT * FROM {} ORDER BY rowid", t.name))?;
+            let names: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+            let mut rows = stmt.query([])?;
+            let mut out = vec![];
+            while let Some(row) = rows.next()? {
+                let mut obj = Map::new();
+                for (i, name) in names.iter().enumerate() {
+                    obj.insert(name.clone(), to_json(row.get::<_, SqlValue>(i)?)?);
+                }
+                out.push(Value::Object(obj));
+            }
+            tables.insert(t.name.to_string(), Value::Array(out));
+        }
+        Ok(serde_json::json!({
+            "format": FORMAT,
+            "format_version": FORMAT_VERSION,
+            "schema_version": schema_version(&conn)?,
+            "exported_at": now_rfc3339(),
+            "tables": tables,
+        }))
+    })();
+    let _ = std::fs::remove_file(&tmp);
+    result
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    /// Rows inserted per table.
+    pub tables: BTreeMap<String, usize>,
+    /// Ids that collided with existing rows and were replaced.
+    pub remapped: usize,
+    /// Workflows already present with the same source, reused instead of copied.
+    pub workflows_reused: usize,
+    /// Imported runs that were unfinished and are now `paused`.
+    pub runs_paused: usize,
+}
+
+/// Old id -> new id, per namespace.
+#[derive(Default)]
+struct IdMap(HashMap<&'static str, HashMap<String, String>>);
+
+impl IdMap {
+    fn get(&self, ns: &str, id: &str) -> Option<&String> {
+        self.0.get(ns).and_then(|m| m.get(id))
+    }
+
+    fn map(&self, ns: &str, v: &Value) -> Value {
+        match v.as_str().and_then(|id| self.get(ns, id)) {
+            Some(new) => Value::String(new.clone()),
+            None => v.clone(),
+        }
+    }
+
+    fn map_any(&self, v: &Value) -> Value {
+        ["entity", "run"]
+            .iter()
+            .find_map(|ns| v.as_str().and_then(|id| self.get(ns, id)))
+            .map(|new| Value::String(new.clone()))
+            .unwrap_or_else(|| v.clone())
+    }
+}
+
+/// A fresh id shaped like `old`: `ent-<ms>-<n>` ids keep their prefix, names get a suffix.
+fn fresh_id(old: &str, stamp: i64, n: usize) -> String {
+    match old.split_once('-') {
+        Some((prefix, rest)) if rest.starts_with(|c: char| c.is_ascii_digit()) => {
+            format!("{prefix}-{stamp}-i{n}")
+        }
+        _ => format!("{old}-imported-{n}"),
+    }
+}
+
+fn rows<'a>(archive: &'a Value, table: &str) -> &'a [Value] {
+    archive
+        .pointer(&format!("/tables/{table}"))
+        .and_then(|v| v.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or(&[])
+}
+
+/// Rejects archives this binary cannot read.
+fn check_archive(archive: &Value, target_schema: i64) -> anyhow::Result<()> {
+    if archive.get("format").and_then(|v| v.as_str()) != Some(FORMAT) {
+        anyhow::bail!("not_an_export: missing \"format\": \"{FORMAT}\"");
+    }
+    let format_version = archive
+        .get("format_version")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    if format_version < 1 || format_version > FORMAT_VERSION {
+        anyhow::bail!(
+            "unsupported_format_version: {format_version} (this build reads up to {FORMAT_VERSION})"
+        );
+    }
+    let schema = archive
+        .get("schema_version")
+        .and_then(|v| v.as_i64())
+        .ok_or_else(|| anyhow::anyhow!("missing_schema_version"))?;
+    if schema > target_schema {
+        anyhow::bail!(
+            "archive_schema_too_new: archive is schema {schema}, this database is {target_schema}; upgrade clawdorio-server first"
+        );
+    }
+    Ok(())
+}
+
+pub fn import(engine: &Engine, archive: &Value) -> anyhow::Result<ImportReport> {
+    let mut conn = engine.open()?;
+    check_archive(archive, schema_version(&conn)?)?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let mut report = ImportReport::default();
+    let stamp = now_ms_i64();
+
+    // Pass 1: pick new ids for rows whose id is taken.
+    let mut ids = IdMap::default();
+    let mut reused_workflows = vec![];
+    let mut n = 0;
+    for t in TABLES {
+        let Some((key_col, ns)) = t.cols.iter().find_map(|(c, kind)| match kind {
+            Col::Key(ns) => Some((*c, *ns)),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let exists_sql = format!("SELECT EXISTS(SELECT 1 FROM {} WHERE {key_col}=?1)", t.name);
+        for row in rows(archive, t.name) {
+            let Some(old) = row.get(key_col).and_then(|v| v.as_str()) else {
+                anyhow::bail!("invalid_row: {}.{key_col} missing", t.name);
+            };
+            let taken: bool = tx.query_row(&exists_sql, [old], |r| r.get(0))?;
+            if !taken {
+                continue;
+            }
+            if t.name == "workflows" {
+                let same: bool = tx.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM workflows WHERE id=?1 AND source=?2)",
+                    (
+                        old,
+                        row.get("source").and_then(|v| v.as_str()).unwrap_or(""),
+                    ),
+                    |r| r.get(0),
+                )?;
+                if same {
+                    reused_workflows.push(old.to_string());
+                    continue;
+                }
+            }
+            let new = loop {
+                n += 1;
+                let candidate = fresh_id(old, stamp, n);
+                if !tx.query_row(&exists_sql, [&candidate], |r| r.get::<_, bool>(0))? {
+                    break candidate;
+                }
+            };
+            ids.0.entry(ns).or_default().insert(old.to_string(), new);
+            report.remapped += 1;
+        }
+    }
+    report.workflows_reused = reused_workflows.len();
+
+    // Pass 2: rewrite references and insert.
+    let mut imported_runs = vec![];
+    for t in TABLES {
+        let target_cols: Vec<String> = {
+            let mut stmt = tx.prepare(&format!("PRAGMA table_info({})", t.name))?;
+            let cols = stmt.query_map([], |r| r.get::<_, String>(1))?;
+            cols.collect::<Result<_, _>>()?
+        };
+        let mut inserted = 0;
+        for row in rows(archive, t.name) {
+            let Some(obj) = row.as_object() else {
+                anyhow::bail!("invalid_row: {} row is not an object", t.name);
+            };
+            if t.name == "workflows"
+                && obj
+                    .get("id")
+                    .and_then(|v| v.as_str())
+                    .is_some_and(|id| reused_workflows.iter().any(|w| w == id))
+            {
+                continue;
+            }
+            let mut cols = vec![];
+            let mut vals = vec![];
+            for (name, value) in obj {
+                if !target_cols.iter().any(|c| c == name) {
+                    anyhow::bail!(
+                        "unknown_column: {}.{name} (archive is from a newer schema)",
+                        t.name
+                    );
+                }
+                let kind = t.cols.iter().find(|(c, _)| c == name).map(|(_, k)| *k);
+                let value = match kind {
+                    Some(Col::Serial) => continue,
+                    Some(Col::Key(ns)) | Some(Col::Ref(ns)) => ids.map(ns, value),
+                    Some(Col::AnyRef) => ids.map_any(value),
+                    Some(Col::RefList(ns)) => match value
+                        .as_str()
+                        .and_then(|s| serde_json::from_str::<Vec<Value>>(s).ok())
+                    {
+                        Some(list) => Value::String(
+                            Value::Array(list.iter().map(|v| ids.map(ns, v)).collect()).to_string(),
+                        ),
+                        None => value.clone(),
+                    },
+                    Some(Col::Context) => match value
+                        .as_str()
+                        .and_then(|s| serde_json::from_str::<Value>(s).ok())
+                    {
+                        Some(mut ctx) if ctx.is_object() => {
+                            for (key, ns) in [
+                                ("base_id", "entity"),
+                                ("entity_id", "entity"),
+                                ("run_id", "run"),
+                            ] {
+                                if let Some(v) = ctx.get(key).cloned() {
+                                    ctx[key] = ids.map(ns, &v);
+                                }
+                            }
+                            Value::String(ctx.to_string())
+                        }
+                        _ => value.clone(),
+                    },
+                    None => value.clone(),
+                };
+                cols.push(name.as_str());
+                vals.push(to_sql(&value));
+            }
+            let placeholders: Vec<String> = (1..=cols.len()).map(|i| format!("?{i}")).collect();
+            tx.execute(
+                &format!(
+                    "INSERT INTO {} ({}) VALUES ({})",
+                    t.name,
+                    cols.join(", "),
+                    placeholders.join(", ")
+                ),
+                rusqlite::params_from_iter(vals),
+            )
+            .map_err(|e| anyhow::anyhow!("insert_failed: {}: {e}", t.name))?;
+            if t.name == "runs" {
+                if let Some(id) = obj.get("id") {
+                    imported_runs.push(ids.map("run", id));
+                }
+            }
+            inserted += 1;
+        }
+        report.tables.insert(t.name.to_string(), inserted);
+    }
+
+    // Workers, heartbeats and worktree paths belong to the exporting machine.
+    let now = now_rfc3339();
+    for run_id in imported_runs.iter().filter_map(|v| v.as_str()) {
+        report.runs_paused += tx.execute(
+            "UPDATE runs SET status='paused', updated_at=?1
+             WHERE id=?2 AND status NOT IN ('done','failed','cancelled')",
+            (&now, run_id),
+        )?;
+        tx.execute(
+            "UPDATE steps SET status=CASE WHEN status='running' THEN 'queued' ELSE status END,
+                    worker_id=NULL, heartbeat_at_ms=NULL, started_at_ms=NULL
+             WHERE run_id=?1",
+            [run_id],
+        )?;
+    }
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'world.imported', NULL, ?2)",
+        (
+            now_ms_i64(),
+            serde_json::json!({
+                "schema_version": archive.get("schema_version"),
+                "exported_at": archive.get("exported_at"),
+                "tables": report.tables,
+                "remapped": report.remapped,
+                "runs_paused": report.runs_paused,
+            })
+            .to_string(),
+        ),
+    )?;
+    tx.commit()?;
+    Ok(report)
+}
+
+pub(crate) async fn api_export(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let engine = state.engine.clone();
+    let archive = tokio::task::spawn_blocking(move || export(&engine))
+        .await
+        .map_err(|e| internal_error("backup.export")(anyhow::anyhow!(e)))?
+        .map_err(internal_error("backup.export"))?;
+    let filename = format!(
+        "attachment; filename=\"clawdorio-export-{}.json\"",
+        now_ms_i64()
+    );
+    Ok(([(header::CONTENT_DISPOSITION, filename)], Json(archive)))
+}
@@ -0,0 +1,305 @@
+//! Server configuration.
+//!
+//! `clawdorio-server` reads a TOML file (`--config`, `$CLAWDORIO_CONFIG`, or
+//! `~/.clawdorio/config.toml` when present); every key is optional and falls back to the
+//! defaults printed by `clawdorio-server print-default-config`. Environment variables and CLI
+//! flags override the file. The validated result is carried on [`AppState`](super::AppState).
+
+use super::auth::AuthSection;
+use super::feedback::FeedbackSection;
+use super::forge::ForgeSection;
+use super::github::GithubSection;
+use super::merge_queue::MergeQueueSection;
+use super::workspace::WorkspaceConfig;
+use super::worktrees::{GcPolicy, DEFAULT_RETENTION_HOURS};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ServerSection,
+    pub auth: AuthSection,
+    pub github: GithubSection,
+    pub forge: ForgeSection,
+    pub workers: WorkersSection,
+    pub auto_rebase: AutoRebaseSection,
+    pub pr_comments: PrCommentsSection,
+    pub feedback: FeedbackSection,
+    pub merge_queue: MergeQueueSection,
+    pub placement: PlacementSection,
+    pub worktrees: WorktreesSection,
+    pub workspace: WorkspaceConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    /// Host/interface to bind (use 0.0.0.0 to expose on LAN/hosted env).
+    pub host: IpAddr,
+    pub port: u16,
+    /// SQLite DB path; `--db` and `$CLAWDORIO_DB` take precedence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<PathBuf>,
+    /// Peers allowed besides loopback, as CIDR ranges (`100.64.0.0/10` is Tailscale).
+    pub allow_cidrs: Vec<String>,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        Self {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 39333,
+            db: None,
+            allow_cidrs: vec!["100.64.0.0/10".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkersSection {
+    /// Steps that may execute concurrently across all bases.
+    pub max_workers: usize,
+    /// Steps that may execute concurrently for runs on the same base.
+    pub max_workers_per_base: usize,
+    /// Runloop tick, and how long an idle worker waits before polling for work again.
+    pub poll_interval_ms: u64,
+    /// Idle time after which queued work is re-emitted in case workers look stuck.
+    pub reemit_idle_ms: u64,
+    /// Idle time between checks for moved default branches (auto-rebase).
+    pub rebase_check_idle_ms: u64,
+}
+
+impl Default for WorkersSection {
+    fn default() -> Self {
+        Self {
+            max_workers: 4,
+            max_workers_per_base: 1,
+            poll_interval_ms: 700,
+            reemit_idle_ms: 21_000,
+            rebase_check_idle_ms: 14_000,
+        }
+    }
+}
+
+impl WorkersSection {
+    /// Whole runloop ticks covering `ms` (at least one).
+    pub(crate) fn ticks(&self, ms: u64) -> u32 {
+        ms.div_ceil(self.poll_interval_ms.max(1))
+            .clamp(1, u32::MAX as u64) as u32
+    }
+}
+
+/// Auto-rebase settings given to a base when its repo is attached; `PATCH
+/// /api/bases/{id}/auto-rebase` changes them per base.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoRebaseSection {
+    pub enabled: bool,
+    pub interval_sec: i64,
+}
+
+impl Default for AutoRebaseSection {
+    fn default() -> Self {
+        Self {
+            enabled: super::DEFAULT_AUTO_REBASE_ENABLED,
+            interval_sec: super::DEFAULT_AUTO_REBASE_INTERVAL_SEC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrCommentsSection {
+    /// Minimum time between two comment-triggered reemits on the same base.
+    pub reemit_min_interval_ms: i64,
+}
+
+impl Default for PrCommentsSection {
+    fn default() -> Self {
+        Self {
+            reemit_min_interval_ms: 15_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlacementSection {
+    /// How far (in tiles) a non-base building may be placed from the nearest base.
+    pub max_base_distance: i64,
+}
+
+impl Default for PlacementSection {
+    fn default() -> Self {
+        Self {
+            max_base_distance: 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorktreesSection {
+    /// Hours to keep the worktree of a done, cancelled or merged run before pruning it.
+    pub retention_hours: u64,
+    /// Also delete a pruned run's local `clawdorio/*` branch.
+    pub prune_branches: bool,
+}
+
+impl Default for WorktreesSection {
+    fn default() -> Self {
+        Self {
+            retention_hours: DEFAULT_RETENTION_HOURS,
+            prune_branches: false,
+        }
+    }
+}
+
+impl WorktreesSection {
+    pub fn gc_policy(&self, workspace: &WorkspaceConfig) -> GcPolicy {
+        GcPolicy {
+            workspace: workspace.clone(),
+            ..GcPolicy::from_hours(self.retention_hours, self.prune_branches)
+        }
+    }
+}
+
+/// `$CLAWDORIO_CONFIG`, else `~/.clawdorio/config.toml`.
+pub fn default_config_path() -> PathBuf {
+    std::env::var("CLAWDORIO_CONFIG")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            dirs::home_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join(".clawdorio")
+                .join("config.toml")
+        })
+}
+
+impl ServerConfig {
+    /// Reads `path` (which must exist) or the default config file (if present), then applies
+    /// the environment. Returns the file that was read, if any.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<(Self, Option<PathBuf>)> {
+        let file = match path {
+            Some(p) => Some(p.to_path_buf()),
+            None => Some(default_config_path()).filter(|p| p.exists()),
+        };
+        let mut cfg = match &file {
+            Some(p) => {
+                let raw = std::fs::read_to_string(p)
+                    .map_err(|e| anyhow::anyhow!("config {}: {e}", p.display()))?;
+                Self::parse(&raw).map_err(|e| anyhow::anyhow!("config {}: {e}", p.display()))?
+            }
+            None => Self::default(),
+        };
+        cfg.github.apply_env();
+        cfg.workspace.apply_env()?;
+        Ok((cfg, file))
+    }
+
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(raw)?)
+    }
+
+    /// Checks every section, reporting all problems at once; expands `~` in paths.
+    pub fn finish(mut self) -> anyhow::Result<Self> {
+        let mut errors: Vec<String> = vec![];
+        let w = &self.workers;
+        if w.max_workers == 0 {
+            errors.push("workers.max_workers must be >= 1".to_string());
+        }
+        if w.max_workers_per_base == 0 {
+            errors.push("workers.max_workers_per_base must be >= 1".to_string());
+        }
+        if w.poll_interval_ms < 50 {
+            errors.push("workers.poll_interval_ms must be >= 50".to_string());
+        }
+        if self.auto_rebase.interval_sec < 30 {
+            errors.push("auto_rebase.interval_sec must be >= 30".to_string());
+        }
+        if self.pr_comments.reemit_min_interval_ms < 0 {
+            errors.push("pr_comments.reemit_min_interval_ms must be >= 0".to_string());
+        }
+        if self.merge_queue.test_timeout_sec == 0 {
+            errors.push("merge_queue.test_timeout_sec must be >= 1".to_string());
+        }
+        if self.placement.max_base_distance < 1 {
+            errors.push("placement.max_base_distance must be >= 1".to_string());
+        }
+        for cidr in &self.server.allow_cidrs {
+            if let Err(e) = IpRange::parse(cidr) {
+                errors.push(format!("server.allow_cidrs: {e}"));
+            }
+        }
+        errors.extend(self.forge.errors());
+        match self.workspace.clone().finish() {
+            Ok(ws) => self.workspace = ws,
+            Err(e) => errors.push(e.to_string()),
+        }
+        if !errors.is_empty() {
+            anyhow::bail!("invalid config:\n  {}", errors.join("\n  "));
+        }
+        Ok(self)
+    }
+
+    /// Parsed `server.allow_cidrs` (invalid entries were rejected by [`Self::finish`]).
+    pub(crate) fn allowed_ranges(&self) -> Vec<IpRange> {
+        self.server
+            .allow_cidrs
+            .iter()
+            .filter_map(|c| IpRange::parse(c).ok())
+            .collect()
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+/// An IPv4 or IPv6 CIDR range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IpRange {
+    net: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub(crate) fn parse(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.trim(), None),
+        };
+        let net: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow::anyhow!("{s}: not an IP address"))?;
+        let bits = if net.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u32>()
+                .ok()
+                .filter(|p| *p <= bits)
+                .ok_or_else(|| anyhow::anyhow!("{s}: prefix must be 0..={bits}"))?,
+            None => bits,
+        };
+        Ok(Self { net, prefix })
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! Agent-assisted rebase conflicts.
+//!
+//! When the auto-rebase sweep reaches a branch whose run still has its worktree, the rebase
+//! happens there instead of in a throwaway worktree (see [`scratch`](super::scratch)). A
+//! conflict is left in place and handed to a `resolve_conflicts` agent step (conflicting hunks
+//! and the run's task in its prompt), followed by an internal `rebase_push` step that checks the
+//! rebase was finished, re-runs the run's `TEST_CMD` and force-pushes the branch with a lease on
+//! the SHA it had before the sweep.
+
+use super::scratch::{git, push_with_lease, run_test_cmd};
+use super::{now_ms_i64, now_rfc3339, parse_payload, worktrees, PendingStep};
+use crate::executor::CancelToken;
+use rusqlite::OptionalExtension;
+use serde_json::Value;
+use std::path::Path;
+
+/// Agent of `resolve_conflicts` when the run has no `implement` step to borrow one from.
+const DEFAULT_RESOLVE_AGENT: &str = "feature-dev/developer";
+/// Resolution attempts per run before conflicts go back to needing a human.
+const MAX_RESOLUTIONS: i64 = 3;
+/// Cap on the conflicting hunks copied into the agent prompt.
+const MAX_HUNK_CHARS: usize = 20_000;
+
+const RESOLVE_CONFLICTS_PROMPT: &str = "Finish rebasing the branch onto the default branch.
+
+TASK:
+{task}
+
+REPO: {repo}
+BRANCH: {branch}
+PR: {pr}
+
+The rebase stopped on conflicts; the worktree is mid-rebase.
+
+Requirements:
+- resolve every conflict below, keeping both the upstream change and the intent of the task
+- git add the resolved files and run `GIT_EDITOR=true git rebase --continue` until it finishes
+- run tests
+- do not push; the branch is pushed after this step
+
+Reply with:
+STATUS: done
+CHANGES: ...
+";
+
+/// What the sweep did with one branch.
+pub(crate) enum Outcome {
+    /// Rebased cleanly and pushed.
+    Rebased,
+    /// Conflicts were handed to the run; holds the `resolve_conflicts` step row id.
+    Resolving(String),
+    /// The run is busy or already resolving; left alone this sweep.
+    Skipped(String),
+    Failed(String),
+}
+
+/// The run that owns `branch`, with the worktree it is checked out in.
+pub(crate) struct RunCheckout {
+    run_id: String,
+    status: String,
+    worktree: String,
+}
+
+pub(crate) fn run_checkout(
+    conn: &rusqlite::Connection,
+    branch: &str,
+) -> anyhow::Result<Option<RunCheckout>> {
+    let row: Option<(String, String, String)> = conn
+        .query_row(
+            "SELECT id, status, context_json FROM runs
+             WHERE json_extract(context_json, '$.branch')=?1
+             ORDER BY created_at DESC LIMIT 1",
+            [branch],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .optional()?;
+    Ok(row.and_then(|(run_id, status, ctx)| {
+        let worktree = parse_payload(&ctx)
+            .get("worktree_path")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)?;
+        let head = git(&worktree, &["symbolic-ref", "--quiet", "--short", "HEAD"]).ok()?;
+        (Path::new(&worktree).is_dir() && head == branch).then_some(RunCheckout {
+            run_id,
+            status,
+            worktree,
+        })
+    }))
+}
+
+/// Rebases `branch` onto `origin/<default_branch>` inside the run's worktree. The caller has
+/// already fetched; worktrees share the base repo's refs.
+pub(crate) fn rebase_in_run_worktree(
+    conn: &mut rusqlite::Connection,
+    checkout: &RunCheckout,
+    branch: &str,
+    default_branch: &str,
+    cancel: &CancelToken,
+) -> anyhow::Result<Outcome> {
+    let in_flight: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM steps WHERE run_id=?1 AND status IN ('queued','pending','waiting','running')",
+        [&checkout.run_id],
+        |r| r.get(0),
+    )?;
+    if in_flight > 0 || checkout.status != "done" {
+        return Ok(Outcome::Skipped(format!(
+            "{branch}: run {} is {}",
+            checkout.run_id, checkout.status
+        )));
+    }
+    if worktrees::is_dirty(&checkout.worktree) {
+        return Ok(Outcome::Failed(format!(
+            "{branch}: worktree has local changes"
+        )));
+    }
+    let wt = checkout.worktree.as_str();
+    let lease = git(wt, &["rev-parse", "--verify", &format!("origin/{branch}")])
+        .map_err(|_| anyhow::anyhow!("branch_missing: origin/{branch}"))?;
+    // The lease only protects what we fetched, so the rebase must start from exactly that:
+    // catch up with commits pushed from elsewhere, and never rebase a branch that diverged.
+    let head = git(wt, &["rev-parse", "HEAD"])?;
+    if head != lease {
+        if git(wt, &["merge-base", "--is-ancestor", &head, &lease]).is_err() {
+            return Ok(Outcome::Failed(format!(
+                "{branch}: worktree has diverged from origin/{branch}"
+            )));
+        }
+        if let Err(e) = git(wt, &["merge", "--ff-only", "--quiet", &lease]) {
+            return Ok(Outcome::Failed(format!("{branch}: {e}")));
+        }
+    }
+    let upstream = format!("origin/{default_branch}");
+    if git(wt, &["rebase", &upstream]).is_ok() {
+        return Ok(match push_with_lease(wt, branch, &lease, cancel) {
+            Ok(_) => Outcome::Rebased,
+            Err(e) => Outcome::Failed(format!("{branch}: {e}")),
+        });
+    }
+
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let used: i64 = tx.query_row(
+        "SELECT COUNT(*) FROM steps WHERE run_id=?1 AND step_id='resolve_conflicts'",
+        [&checkout.run_id],
+        |r| r.get(0),
+    )?;
+    if used >= MAX_RESOLUTIONS {
+        let _ = git(wt, &["rebase", "--abort"]);
+        return Ok(Outcome::Failed(format!(
+            "{branch}: rebase conflict after {used} resolution attempts"
+        )));
+    }
+    let files = git(wt, &["diff", "--name-only", "--diff-filter=U"]).unwrap_or_default();
+    let mut hunks = git(wt, &["diff"]).unwrap_or_default();
+    if hunks.len() > MAX_HUNK_CHARS {
+        let cut = (0..=MAX_HUNK_CHARS)
+            .rev()
+            .find(|i| hunks.is_char_boundary(*i))
+            .unwrap_or(0);
+        hunks.truncate(cut);
+        hunks.push_str("\n... (truncated)");
+    }
+    let step_row = queue_resolution(
+        &tx,
+        &checkout.run_id,
+        &format!("Conflicting files:\n{files}\n\n{hunks}"),
+        &lease,
+        default_branch,
+    )?;
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.rebase_conflict', ?2, ?3)",
+        (
+            now_ms_i64(),
+            &checkout.run_id,
+            serde_json::json!({
+                "run_id": checkout.run_id,
+                "branch": branch,
+                "upstream": upstream,
+                "files": files.lines().collect::<Vec<_>>(),
+                "step_row_id": step_row,
+            })
+            .to_string(),
+        ),
+    )?;
+    tx.commit()?;
+    Ok(Outcome::Resolving(step_row))
+}
+
+/// Appends `resolve_conflicts` and `rebase_push` to the run and puts it back in the queue.
+fn queue_resolution(
+    tx: &rusqlite::Transaction,
+    run_id: &str,
+    conflicts: &str,
+    lease: &str,
+    default_branch: &str,
+) -> anyhow::Result<String> {
+    let ctx_json: String =
+        tx.query_row("SELECT context_json FROM runs WHERE id=?1", [run_id], |r| {
+            r.get(0)
+        })?;
+    let ctx = parse_payload(&ctx_json);
+    let agent = tx
+        .query_row(
+            "SELECT agent_id FROM steps WHERE run_id=?1 AND step_id='implement'
+             ORDER BY step_index LIMIT 1",
+            [run_id],
+            |r| r.get(0),
+        )
+        .optional()?
+        .unwrap_or_else(|| DEFAULT_RESOLVE_AGENT.to_string());
+    let next_index: i64 = tx.query_row(
+        "SELECT COALESCE(MAX(step_index), -1) + 1 FROM steps WHERE run_id=?1",
+        [run_id],
+        |r| r.get(0),
+    )?;
+    let nanos = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+    let resolve_row = format!("step-{nanos}-resolve_conflicts");
+    let mut resolve_input = ctx.clone();
+    resolve_input["prompt_template"] = Value::String(RESOLVE_CONFLICTS_PROMPT.to_string());
+    resolve_input["rebase_conflicts"] = Value::String(conflicts.to_string());
+    let mut push_input = ctx;
+    push_input["action"] = Value::String("rebase_push".to_string());
+    push_input["lease_sha"] = Value::String(lease.to_string());
+    push_input["default_branch"] = Value::String(default_branch.to_string());
+    let ts = now_rfc3339();
+    for (row, step, agent, input, index) in [
+        (
+            resolve_row.clone(),
+            "resolve_conflicts",
+            agent.as_str(),
+            resolve_input,
+            next_index,
+        ),
+        (
+            format!("step-{nanos}-rebase_push"),
+            "rebase_push",
+            "internal/pr",
+            push_input,
+            next_index + 1,
+        ),
+    ] {
+        tx.execute(
+            "INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 'queued', ?6, NULL, ?7, ?7)",
+            rusqlite::params![row, run_id, step, agent, index, input.to_string(), &ts],
+        )?;
+    }
+    tx.execute(
+        "UPDATE runs SET status='queued', updated_at=?1 WHERE id=?2 AND status='done'",
+        (&ts, run_id),
+    )?;
+    Ok(resolve_row)
+}
+
+/// The `rebase_push` step: the agent must have finished the rebase; tests are re-run before the
+/// branch is force-pushed.
+pub(crate) fn execute_push(
+    step: &PendingStep,
+    ctx: &Value,
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
+    let input = parse_payload(&step.input_json);
+    let field = |key: &str| {
+        input
+            .get(key)
+            .or_else(|| ctx.get(key))
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string()
+    };
+    let (wt, branch, lease) = (field("worktree_path"), field("branch"), field("lease_sha"));
+    let default_branch = Some(field("default_branch"))
+        .filter(|b| !b.is_empty())
+        .unwrap_or_else(|| "main".to_string());
+    if wt.is_empty() || branch.is_empty() || lease.is_empty() {
+        anyhow::bail!("missing_rebase_context");
+    }
+    for dir in ["rebase-merge", "rebase-apply"] {
+        let path = git(&wt, &["rev-parse", "--git-path", dir])?;
+        if Path::new(&wt).join(path).exists() {
+            anyhow::bail!("rebase_unfinished: {wt} is still mid-rebase");
+        }
+    }
+    let upstream = format!("origin/{default_branch}");
+    if git(&wt, &["merge-base", "--is-ancestor", &upstream, "HEAD"]).is_err() {
+        anyhow::bail!("rebase_unfinished: HEAD does not contain {upstream}");
+    }
+    if worktrees::is_dirty(&wt) {
+        anyhow::bail!("worktree_dirty: resolve_conflicts left uncommitted changes");
+    }
+    if let Some(cmd) = ctx.get("test_cmd").and_then(|v| v.as_str()) {
+        run_test_cmd(&wt, cmd, cancel)?;
+    }
+    let sha = push_with_lease(&wt, &branch, &lease, cancel)?;
+    Ok(format!("rebased {branch} onto {upstream} and pushed {sha}"))
+}
@@ -0,0 +1,467 @@
+//! Agent executors.
+//!
+//! The runloop hands every non-internal step to an `AgentExecutor`, picked by the longest
+//! registered prefix of the step's `agent_id`. Anything unmatched falls through to the OpenClaw
+//! CLI, which is what every step used before executors were pluggable.
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+const CHILD_POLL_MS: u64 = 200;
+
+/// Set by `POST /api/runs/{id}/cancel`; executors must stop (and kill their child) once set.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Everything an executor needs to run one step. `message` is the fully rendered prompt
+/// (step template + skill context).
+#[derive(Debug, Clone)]
+pub struct StepRequest {
+    pub run_id: String,
+    pub step_id: String,
+    pub agent_id: String,
+    pub task: String,
+    pub message: String,
+    pub worktree_path: String,
+    pub branch: String,
+    pub pr_url: String,
+    pub context: serde_json::Value,
+    /// Server-enforced budget for the step; executors may pass it on to their runtime.
+    pub timeout_sec: u64,
+    pub cancel: CancelToken,
+}
+
+/// A runtime that can execute a step and return the agent's raw reply.
+pub trait AgentExecutor: Send + Sync {
+    fn name(&self) -> &str;
+    fn execute(&self, req: &StepRequest) -> anyhow::Result<String>;
+}
+
+/// `openclaw agent --agent <id> --message <msg> --json --timeout <step timeout>`.
+#[derive(Debug, Clone)]
+pub struct OpenClawExecutor {
+    pub program: String,
+}
+
+impl Default for OpenClawExecutor {
+    fn default() -> Self {
+        Self {
+            program: "openclaw".to_string(),
+        }
+    }
+}
+
+impl AgentExecutor for OpenClawExecutor {
+    fn name(&self) -> &str {
+        "openclaw"
+    }
+
+    fn execute(&self, req: &StepRequest) -> anyhow::Result<String> {
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("agent")
+            .arg("--agent")
+            .arg(&req.agent_id)
+            .arg("--message")
+            .arg(&req.message)
+            .arg("--json")
+            .arg("--timeout")
+            .arg(req.timeout_sec.to_string());
+        let out = run_child(cmd, None, &req.cancel)?;
+        if !out.status.success() {
+            return Err(anyhow::anyhow!(
+                "openclaw_failed: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(openclaw_reply_text(&String::from_utf8_lossy(&out.stdout)))
+    }
+}
+
+/// `--json` wraps the agent's reply in an envelope; the reply parser wants the text itself.
+/// Payload texts (`result.payloads[].text`, or top-level `payloads`) are joined in order,
+/// falling back to a string `reply`/`text`/`result`. Anything else is returned unchanged.
+pub fn openclaw_reply_text(stdout: &str) -> String {
+    let Ok(v) = serde_json::from_str::<serde_json::Value>(stdout.trim()) else {
+        return stdout.to_string();
+    };
+    let payloads = v
+        .pointer("/result/payloads")
+        .or_else(|| v.get("payloads"))
+        .and_then(|p| p.as_array());
+    if let Some(payloads) = payloads {
+        let texts: Vec<&str> = payloads
+            .iter()
+            .filter_map(|p| p.get("text").and_then(|t| t.as_str()))
+            .collect();
+        if !texts.is_empty() {
+            return texts.join("\n");
+        }
+    }
+    ["reply", "text", "result"]
+        .iter()
+        .find_map(|k| v.get(*k).and_then(|t| t.as_str()))
+        .map(str::to_string)
+        .unwrap_or_else(|| stdout.to_string())
+}
+
+/// Runs an arbitrary program. `args`, `stdin` and `env` values are templates; see
+/// [`render_template`] for the placeholders. The step worktree is used as cwd when it exists.
+#[derive(Debug, Clone, Default)]
+pub struct CommandExecutor {
+    pub program: String,
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+    pub env: BTreeMap<String, String>,
+}
+
+impl AgentExecutor for CommandExecutor {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn execute(&self, req: &StepRequest) -> anyhow::Result<String> {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(self.args.iter().map(|a| render_template(a, req)));
+        for (k, v) in &self.env {
+            cmd.env(k, render_template(v, req));
+        }
+        if !req.worktree_path.is_empty() && Path::new(&req.worktree_path).is_dir() {
+            cmd.current_dir(&req.worktree_path);
+        }
+        let stdin = self.stdin.as_ref().map(|tpl| render_template(tpl, req));
+        let out = run_child(cmd, stdin, &req.cancel)
+            .map_err(|e| anyhow::anyhow!("command_failed: {}: {e}", self.program))?;
+        if !out.status.success() {
+            return Err(anyhow::anyhow!(
+                "command_failed: {}: {}",
+                self.program,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    }
+}
+
+/// Deterministic executor for tests and dry runs: replies are queued per `step_id` and
+/// consumed in order; once a queue is empty the default reply is returned.
+#[derive(Debug)]
+pub struct ScriptedExecutor {
+    default_reply: String,
+    replies: Mutex<HashMap<String, VecDeque<Result<String, String>>>>,
+    calls: Mutex<Vec<StepRequest>>,
+}
+
+impl Default for ScriptedExecutor {
+    fn default() -> Self {
+        Self::new("STATUS: done\n")
+    }
+}
+
+impl ScriptedExecutor {
+    pub fn new(default_reply: &str) -> Self {
+        Self {
+            default_reply: default_reply.to_string(),
+            replies: Mutex::new(HashMap::new()),
+            calls: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn reply(self, step_id: &str, reply: &str) -> Self {
+        self.push(step_id, Ok(reply.to_string()));
+        self
+    }
+
+    pub fn fail(self, step_id: &str, err: &str) -> Self {
+        self.push(step_id, Err(err.to_string()));
+        self
+    }
+
+    /// Every request seen so far, in execution order.
+    pub fn calls(&self) -> Vec<StepRequest> {
+        self.calls.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    fn push(&self, step_id: &str, reply: Result<String, String>) {
+        if let Ok(mut m) = self.replies.lock() {
+            m.entry(step_id.to_string()).or_default().push_back(reply);
+        }
+    }
+}
+
+impl AgentExecutor for ScriptedExecutor {
+    fn name(&self) -> &str {
+        "scripted"
+    }
+
+    fn execute(&self, req: &StepRequest) -> anyhow::Result<String> {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(req.clone());
+        }
+        let next = self
+            .replies
+            .lock()
+            .ok()
+            .and_then(|mut m| m.get_mut(&req.step_id).and_then(|q| q.pop_front()));
+        match next {
+            Some(Ok(reply)) => Ok(reply),
+            Some(Err(err)) => Err(anyhow::anyhow!(err)),
+            None => Ok(self.default_reply.clone()),
+        }
+    }
+}
+
+/// Spawns `cmd` (in its own process group on unix), feeds `stdin`, and waits for it while
+/// polling `cancel`. On cancel the whole group is killed and `cancelled` is returned.
+pub fn run_child(
+    mut cmd: Command,
+    stdin: Option<String>,
+    cancel: &CancelToken,
+) -> anyhow::Result<Output> {
+    cmd.stdin(if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("spawn_failed: {e}"))?;
+
+    // Pipes are drained on threads so a chatty child can't block on a full pipe while we poll.
+    let feeder = match (stdin, child.stdin.take()) {
+        (Some(input), Some(mut pipe)) => Some(std::thread::spawn(move || {
+            let _ = pipe.write_all(input.as_bytes());
+        })),
+        _ => None,
+    };
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        pipe.map(|mut r| {
+            std::thread::spawn(move || {
+                let mut buf = vec![];
+                let _ = r.read_to_end(&mut buf);
+                buf
+            })
+        })
+    };
+    let out_h = drain(
+        child
+            .stdout
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    );
+    let err_h = drain(
+        child
+            .stderr
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    );
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancel.is_cancelled() {
+            #[cfg(unix)]
+            {
+                let _ = Command::new("kill")
+                    .arg("-KILL")
+                    .arg("--")
+                    .arg(format!("-{}", child.id()))
+                    .status();
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("cancelled");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(CHILD_POLL_MS));
+    };
+
+    if let Some(h) = feeder {
+        let _ = h.join();
+    }
+    Ok(Output {
+        status,
+        stdout: out_h.and_then(|h| h.join().ok()).unwrap_or_default(),
+        stderr: err_h.and_then(|h| h.join().ok()).unwrap_or_default(),
+    })
+}
+
+/// Prefix-keyed executor table. Lookup is longest-prefix-wins; unmatched agent ids use the
+/// fallback (OpenClaw by default).
+#[derive(Clone)]
+pub struct ExecutorRegistry {
+    routes: Vec<(String, Arc<dyn AgentExecutor>)>,
+    fallback: Arc<dyn AgentExecutor>,
+}
+
+impl Default for ExecutorRegistry {
+    fn default() -> Self {
+        Self::with_fallback(Arc::new(OpenClawExecutor::default()))
+    }
+}
+
+impl ExecutorRegistry {
+    pub fn with_fallback(fallback: Arc<dyn AgentExecutor>) -> Self {
+        Self {
+            routes: vec![],
+            fallback,
+        }
+    }
+
+    pub fn register(&mut self, prefix: &str, exec: Arc<dyn AgentExecutor>) {
+        self.routes.retain(|(p, _)| p != prefix);
+        self.routes.push((prefix.to_string(), exec));
+    }
+
+    pub fn resolve(&self, agent_id: &str) -> Arc<dyn AgentExecutor> {
+        self.routes
+            .iter()
+            .filter(|(p, _)| agent_id.starts_with(p.as_str()))
+            .max_by_key(|(p, _)| p.len())
+            .map(|(_, e)| e.clone())
+            .unwrap_or_else(|| self.fallback.clone())
+    }
+
+    /// Loads `$CLAWDORIO_EXECUTORS` (YAML) if set, else `~/.clawdorio/executors.yaml` if it
+    /// exists, else the default registry.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let path = match std::env::var("CLAWDORIO_EXECUTORS") {
+            Ok(p) if !p.trim().is_empty() => PathBuf::from(p),
+            _ => {
+                let p = dirs::home_dir()
+                    .unwrap_or_else(|| PathBuf::from("."))
+                    .join(".clawdorio")
+                    .join("executors.yaml");
+                if !p.exists() {
+                    return Ok(Self::default());
+                }
+                p
+            }
+        };
+        let raw = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("executors_read_failed: {}: {e}", path.display()))?;
+        Self::from_yaml(&raw)
+    }
+
+    pub fn from_yaml(raw: &str) -> anyhow::Result<Self> {
+        let file: ExecutorsFile = serde_yaml::from_str(raw)
+            .map_err(|e| anyhow::anyhow!("executors_parse_failed: {e}"))?;
+        let mut reg = match file.fallback {
+            Some(spec) => Self::with_fallback(spec.build()?),
+            None => Self::default(),
+        };
+        for route in file.executors {
+            if route.prefix.trim().is_empty() {
+                anyhow::bail!("executors_parse_failed: empty prefix");
+            }
+            reg.register(&route.prefix, route.spec.build()?);
+        }
+        Ok(reg)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExecutorsFile {
+    #[serde(default)]
+    executors: Vec<ExecutorRoute>,
+    #[serde(default)]
+    fallback: Option<ExecutorSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExecutorRoute {
+    prefix: String,
+    #[serde(flatten)]
+    spec: ExecutorSpec,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ExecutorSpec {
+    Openclaw {
+        #[serde(default)]
+        program: Option<String>,
+    },
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        stdin: Option<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+    Scripted {
+        #[serde(default)]
+        reply: Option<String>,
+    },
+}
+
+impl ExecutorSpec {
+    fn build(self) -> anyhow::Result<Arc<dyn AgentExecutor>> {
+        Ok(match self {
+            ExecutorSpec::Openclaw { program } => Arc::new(OpenClawExecutor {
+                program: program.unwrap_or_else(|| OpenClawExecutor::default().program),
+            }),
+            ExecutorSpec::Command {
+                program,
+                args,
+                stdin,
+                env,
+            } => {
+                if program.trim().is_empty() {
+                    anyhow::bail!("executors_parse_failed: command executor needs a program");
+                }
+                Arc::new(CommandExecutor {
+                    program,
+                    args,
+                    stdin,
+                    env,
+                })
+            }
+            ExecutorSpec::Scripted { reply } => match reply {
+                Some(r) => Arc::new(ScriptedExecutor::new(&r)),
+                None => Arc::new(ScriptedExecutor::default()),
+            },
+        })
+    }
+}
+
+/// Expands `{agent_id}`, `{run_id}`, `{step_id}`, `{task}`, `{message}`, `{repo}`, `{branch}`
+/// and `{pr}` in a command template, in one pass so values are never expanded again.
+pub fn render_template(tpl: &str, req: &StepRequest) -> String {
+    crate::workflow::fill_placeholders(
+        tpl,
+        &[
+            ("agent_id", &req.agent_id),
+            ("run_id", &req.run_id),
+            ("step_id", &req.step_id),
+            ("repo", &req.worktree_path),
+            ("branch", &req.branch),
+            ("pr", &req.pr_url),
+            ("task", &req.task),
+            ("message", &req.message),
+        ],
+    )
+}
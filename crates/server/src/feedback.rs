@@ -0,0 +1,654 @@
+//! PR feedback intake.
+//!
+//! Review comments and CI results delivered through the GitHub webhook are matched to the run
+//! that owns the PR (its branch `clawdorio/<run_id>`, or the run's `pr_url`) and stored in
+//! `run_feedback`. With `[feedback] review_followup` or `ci_followup` enabled, actionable
+//! feedback also appends a follow-up step to the run (`address_review` or `fix_ci`, then
+//! `pr_update`, which pushes the branch) with the feedback in the agent prompt. Feedback that
+//! arrives while a follow-up is still queued is added to that step instead of queueing another.
+
+use super::{internal_error, now_ms_i64, now_rfc3339, parse_payload, AppState};
+use axum::http::StatusCode;
+use axum::Json;
+use clawdorio_engine::Engine;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Agent of follow-up steps when the run has no `implement` step to borrow one from.
+const DEFAULT_FOLLOWUP_AGENT: &str = "feature-dev/developer";
+
+const ADDRESS_REVIEW_PROMPT: &str = "Address the review feedback on the PR.
+
+TASK:
+{task}
+
+REPO: {repo}
+BRANCH: {branch}
+PR: {pr}
+
+Requirements:
+- address every point in the PR feedback below, or explain why not
+- run tests
+- commit
+
+Reply with:
+STATUS: done
+CHANGES: ...
+";
+
+const FIX_CI_PROMPT: &str = "Fix the failing CI checks on the PR.
+
+TASK:
+{task}
+
+REPO: {repo}
+BRANCH: {branch}
+PR: {pr}
+
+Requirements:
+- reproduce the failures listed in the PR feedback below
+- fix them and run tests
+- commit
+
+Reply with:
+STATUS: done
+CHANGES: ...
+";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedbackSection {
+    /// Queue an `address_review` step when a review requests changes or leaves comments.
+    pub review_followup: bool,
+    /// Queue a `fix_ci` step when a check or commit status fails.
+    pub ci_followup: bool,
+    /// Follow-up steps one run may get, so a check that keeps failing cannot loop forever.
+    pub max_followups: u32,
+    /// Agent for follow-up steps; default: the agent of the run's `implement` step.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+}
+
+impl Default for FeedbackSection {
+    fn default() -> Self {
+        Self {
+            review_followup: false,
+            ci_followup: false,
+            max_followups: 3,
+            agent: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Source {
+    Review,
+    Ci,
+}
+
+impl Source {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Review => "review",
+            Self::Ci => "ci",
+        }
+    }
+
+    fn followup_step(self) -> &'static str {
+        match self {
+            Self::Review => "address_review",
+            Self::Ci => "fix_ci",
+        }
+    }
+}
+
+/// One piece of feedback from a webhook payload, before it is matched to a run.
+#[derive(Debug, Clone)]
+pub(crate) struct Incoming {
+    pub(crate) source: Source,
+    pub(crate) kind: String,
+    /// Stable id on the forge side, so redelivered events are stored once.
+    pub(crate) external_id: String,
+    pub(crate) author: Option<String>,
+    /// Review state (`changes_requested`, ...) or CI conclusion (`failure`, ...).
+    pub(crate) state: Option<String>,
+    pub(crate) body: String,
+    pub(crate) url: Option<String>,
+    /// Check run, suite or status context that CI feedback belongs to.
+    pub(crate) check_name: Option<String>,
+    pub(crate) branches: Vec<String>,
+    pub(crate) pr_url: Option<String>,
+    pub(crate) actionable: bool,
+}
+
+fn s<'a>(v: &'a Value, path: &[&str]) -> Option<&'a str> {
+    path.iter()
+        .try_fold(v, |v, k| v.get(k))
+        .and_then(|v| v.as_str())
+}
+
+fn is_bot(user: Option<&Value>) -> bool {
+    user.and_then(|u| u.get("type")).and_then(|t| t.as_str()) == Some("Bot")
+}
+
+fn ci_failed(conclusion: &str) -> bool {
+    matches!(
+        conclusion,
+        "failure" | "timed_out" | "action_required" | "error"
+    )
+}
+
+/// Head branches of the PRs a check belongs to.
+fn check_branches(obj: &Value) -> Vec<String> {
+    let mut out: Vec<String> = obj
+        .get("pull_requests")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|pr| s(pr, &["head", "ref"]))
+        .map(str::to_string)
+        .collect();
+    if let Some(b) = s(obj, &["head_branch"]) {
+        out.push(b.to_string());
+    }
+    out
+}
+
+/// Extracts feedback from a webhook; `None` for events that carry none and comments by bots.
+/// Checks that are queued or running are recorded with state `pending` so the merge queue waits
+/// for them.
+pub(crate) fn from_webhook(event: &str, payload: &Value) -> Option<Incoming> {
+    let action = s(payload, &["action"]).unwrap_or("");
+    let pr = payload.get("pull_request");
+    let pr_branch = || pr.and_then(|p| s(p, &["head", "ref"])).map(str::to_string);
+    let pr_url = || pr.and_then(|p| s(p, &["html_url"])).map(str::to_string);
+    let id = |v: Option<&Value>| {
+        v.and_then(|v| v.get("id"))
+            .map(|id| id.to_string())
+            .unwrap_or_default()
+    };
+    match event {
+        "pull_request_review" if action == "submitted" => {
+            let review = payload.get("review")?;
+            if is_bot(review.get("user")) {
+                return None;
+            }
+            let state = s(review, &["state"]).unwrap_or("").to_ascii_lowercase();
+            let body = s(review, &["body"]).unwrap_or("").trim().to_string();
+            Some(Incoming {
+                source: Source::Review,
+                kind: event.to_string(),
+                external_id: format!("review:{}", id(Some(review))),
+                author: s(review, &["user", "login"]).map(str::to_string),
+                actionable: state == "changes_requested"
+                    || (state == "commented" && !body.is_empty()),
+                state: Some(state),
+                body,
+                url: s(review, &["html_url"]).map(str::to_string),
+                check_name: None,
+                branches: pr_branch().into_iter().collect(),
+                pr_url: pr_url(),
+            })
+        }
+        "pull_request_review_comment" if action == "created" => {
+            let c = payload.get("comment")?;
+            if is_bot(c.get("user")) {
+                return None;
+            }
+            let body = s(c, &["body"]).unwrap_or("").trim();
+            let line = ["line", "original_line"]
+                .into_iter()
+                .find_map(|k| c.get(k).and_then(|v| v.as_i64()));
+            let at = match (s(c, &["path"]), line) {
+                (Some(p), Some(l)) => format!("{p}:{l}: "),
+                (Some(p), None) => format!("{p}: "),
+                _ => String::new(),
+            };
+            Some(Incoming {
+                source: Source::Review,
+                kind: event.to_string(),
+                external_id: format!("review_comment:{}", id(Some(c))),
+                author: s(c, &["user", "login"]).map(str::to_string),
+                state: None,
+                actionable: !body.is_empty(),
+                body: format!("{at}{body}"),
+                url: s(c, &["html_url"]).map(str::to_string),
+                check_name: None,
+                branches: pr_branch().into_iter().collect(),
+                pr_url: pr_url(),
+            })
+        }
+        // Top-level PR conversation comments arrive as issue comments on the PR.
+        "issue_comment" if action == "created" => {
+            let issue = payload.get("issue")?;
+            issue.get("pull_request")?;
+            let c = payload.get("comment")?;
+            if is_bot(c.get("user")) {
+                return None;
+            }
+            let body = s(c, &["body"]).unwrap_or("").trim().to_string();
+            Some(Incoming {
+                source: Source::Review,
+                kind: event.to_string(),
+                external_id: format!("issue_comment:{}", id(Some(c))),
+                author: s(c, &["user", "login"]).map(str::to_string),
+                state: None,
+                actionable: !body.is_empty(),
+                body,
+                url: s(c, &["html_url"]).map(str::to_string),
+                check_name: None,
+                branches: vec![],
+                pr_url: s(issue, &["html_url"]).map(str::to_string),
+            })
+        }
+        "check_run" | "check_suite" if action != "completed" => {
+            let obj = payload.get(event)?;
+            let status = s(obj, &["status"]).unwrap_or("");
+            if status.is_empty() || status == "completed" {
+                return None;
+            }
+            let name = s(obj, &["name"])
+                .or_else(|| s(obj, &["app", "name"]))
+                .unwrap_or(event);
+            Some(Incoming {
+                source: Source::Ci,
+                kind: event.to_string(),
+                // Re-runs reuse the id, so the pending marker is keyed by when it was reported.
+                external_id: format!(
+                    "{event}:{}:{status}:{}",
+                    id(Some(obj)),
+                    s(obj, &["updated_at"])
+                        .or_else(|| s(obj, &["started_at"]))
+                        .unwrap_or("")
+                ),
+                author: s(obj, &["app", "slug"]).map(str::to_string),
+                actionable: false,
+                state: Some("pending".to_string()),
+                body: format!("{name}: {status}"),
+                url: s(obj, &["html_url"])
+                    .or_else(|| s(obj, &["details_url"]))
+                    .map(str::to_string),
+                check_name: Some(format!("{event}:{name}")),
+                branches: check_branches(obj),
+                pr_url: None,
+            })
+        }
+        "check_run" | "check_suite" => {
+            let obj = payload.get(event)?;
+            let conclusion = s(obj, &["conclusion"]).unwrap_or("").to_string();
+            let name = s(obj, &["name"])
+                .or_else(|| s(obj, &["app", "name"]))
+                .unwrap_or(event);
+            let mut body = format!("{name}: {conclusion}");
+            for detail in [s(obj, &["output", "title"]), s(obj, &["output", "summary"])]
+                .into_iter()
+                .flatten()
+                .filter(|d| !d.trim().is_empty())
+            {
+                body.push('\n');
+                body.push_str(detail.trim());
+            }
+            Some(Incoming {
+                source: Source::Ci,
+                kind: event.to_string(),
+                external_id: format!("{event}:{}", id(Some(obj))),
+                author: s(obj, &["app", "slug"]).map(str::to_string),
+                actionable: ci_failed(&conclusion),
+                state: Some(conclusion),
+                body,
+                url: s(obj, &["html_url"])
+                    .or_else(|| s(obj, &["details_url"]))
+                    .map(str::to_string),
+                check_name: Some(format!("{event}:{name}")),
+                branches: check_branches(obj),
+                pr_url: None,
+            })
+        }
+        "status" => {
+            let state = s(payload, &["state"]).unwrap_or("").to_string();
+            let context = s(payload, &["context"]).unwrap_or("status");
+            let mut body = format!("{context}: {state}");
+            if let Some(d) = s(payload, &["description"]).filter(|d| !d.trim().is_empty()) {
+                body.push('\n');
+                body.push_str(d.trim());
+            }
+            Some(Incoming {
+                source: Source::Ci,
+                kind: event.to_string(),
+                external_id: format!("status:{}", id(Some(payload))),
+                author: s(payload, &["sender", "login"]).map(str::to_string),
+                actionable: ci_failed(&state),
+                state: Some(state),
+                body,
+                url: s(payload, &["target_url"]).map(str::to_string),
+                check_name: Some(format!("status:{context}")),
+                branches: payload
+                    .get("branches")
+                    .and_then(|v| v.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|b| s(b, &["name"]))
+                    .map(str::to_string)
+                    .collect(),
+                pr_url: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The run a PR belongs to: by its `clawdorio/<run_id>` branch, else by `pr_url`.
+fn linked_run(conn: &rusqlite::Connection, fb: &Incoming) -> anyhow::Result<Option<String>> {
+    for branch in &fb.branches {
+        let Some(run_id) = branch.strip_prefix("clawdorio/") else {
+            continue;
+        };
+        let found: Option<String> = conn
+            .query_row("SELECT id FROM runs WHERE id=?1", [run_id], |r| r.get(0))
+            .optional()?;
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    let Some(url) = fb.pr_url.as_deref() else {
+        return Ok(None);
+    };
+    Ok(conn
+        .query_row(
+            "SELECT id FROM runs WHERE json_extract(context_json, '$.pr_url')=?1
+             ORDER BY created_at DESC LIMIT 1",
+            [url],
+            |r| r.get(0),
+        )
+        .optional()?)
+}
+
+/// What [`ingest`] did with one piece of feedback.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Ingested {
+    pub(crate) run_id: Option<String>,
+    /// False when no run matched or the feedback was already stored.
+    pub(crate) stored: bool,
+    /// The follow-up step the feedback was queued on, if any.
+    pub(crate) followup_step_id: Option<String>,
+}
+
+/// Stores feedback on its run and queues a follow-up step when configured.
+pub(crate) fn ingest(
+    engine: &Engine,
+    cfg: &FeedbackSection,
+    fb: &Incoming,
+) -> anyhow::Result<Ingested> {
+    let mut conn = engine.open()?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let Some(run_id) = linked_run(&tx, fb)? else {
+        return Ok(Ingested::default());
+    };
+    let now = now_ms_i64();
+    let inserted = tx.execute(
+        "INSERT OR IGNORE INTO run_feedback
+           (run_id, source, kind, external_id, author, state, body, url, actionable, check_name,
+            created_at_ms)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        rusqlite::params![
+            &run_id,
+            fb.source.as_str(),
+            &fb.kind,
+            &fb.external_id,
+            &fb.author,
+            &fb.state,
+            &fb.body,
+            &fb.url,
+            fb.actionable,
+            &fb.check_name,
+            now,
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(Ingested {
+            run_id: Some(run_id),
+            ..Default::default()
+        });
+    }
+    let feedback_id = tx.last_insert_rowid();
+    let enabled = match fb.source {
+        Source::Review => cfg.review_followup,
+        Source::Ci => cfg.ci_followup,
+    };
+    let followup_step_id = if enabled && fb.actionable {
+        queue_followup(&tx, cfg, &run_id, fb)?
+    } else {
+        None
+    };
+    if let Some(step) = &followup_step_id {
+        tx.execute(
+            "UPDATE run_feedback SET followup_step_id=?1 WHERE id=?2",
+            (step, feedback_id),
+        )?;
+    }
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.feedback', ?2, ?3)",
+        (
+            now,
+            &run_id,
+            serde_json::json!({
+                "run_id": run_id,
+                "feedback_id": feedback_id,
+                "source": fb.source,
+                "kind": fb.kind,
+                "state": fb.state,
+                "actionable": fb.actionable,
+                "followup_step_id": followup_step_id,
+            })
+            .to_string(),
+        ),
+    )?;
+    tx.commit()?;
+    Ok(Ingested {
+        run_id: Some(run_id),
+        stored: true,
+        followup_step_id,
+    })
+}
+
+fn feedback_line(fb: &Incoming) -> String {
+    let mut line = format!("- [{}", fb.kind);
+    if let Some(state) = fb.state.as_deref().filter(|s| !s.is_empty()) {
+        line.push(' ');
+        line.push_str(state);
+    }
+    line.push(']');
+    if let Some(author) = &fb.author {
+        line.push_str(&format!(" @{author}"));
+    }
+    line.push_str(": ");
+    line.push_str(&fb.body.replace('\n', "\n  "));
+    if let Some(url) = &fb.url {
+        line.push_str(&format!("\n  ({url})"));
+    }
+    line
+}
+
+/// Appends feedback to the run's queued follow-up step, or queues a new one followed by a
+/// `pr_update` push. Returns the follow-up step's row id.
+fn queue_followup(
+    tx: &rusqlite::Transaction,
+    cfg: &FeedbackSection,
+    run_id: &str,
+    fb: &Incoming,
+) -> anyhow::Result<Option<String>> {
+    let (status, ctx_json): (String, String) = tx.query_row(
+        "SELECT status, context_json FROM runs WHERE id=?1",
+        [run_id],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    let ctx = parse_payload(&ctx_json);
+    if !matches!(status.as_str(), "queued" | "running" | "done")
+        || ctx.get("pr_merged").and_then(|v| v.as_bool()) == Some(true)
+    {
+        return Ok(None);
+    }
+    // A pruned worktree leaves nothing to push from; queueing would only fail the run.
+    let worktree = ctx
+        .get("worktree_path")
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    if worktree.is_empty() || !std::path::Path::new(worktree).is_dir() {
+        tx.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.feedback_unrunnable', ?2, ?3)",
+            (
+                now_ms_i64(),
+                run_id,
+                serde_json::json!({
+                    "run_id": run_id,
+                    "source": fb.source,
+                    "worktree_path": worktree,
+                    "reason": "worktree_missing",
+                })
+                .to_string(),
+            ),
+        )?;
+        return Ok(None);
+    }
+    let step_id = fb.source.followup_step();
+    let line = feedback_line(fb);
+    let pending: Option<(String, String)> = tx
+        .query_row(
+            "SELECT id, input_json FROM steps
+             WHERE run_id=?1 AND step_id=?2 AND status IN ('queued','pending','waiting')
+             ORDER BY step_index DESC LIMIT 1",
+            (run_id, step_id),
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?;
+    if let Some((id, input_json)) = pending {
+        let mut input = parse_payload(&input_json);
+        let prev = input
+            .get("pr_feedback")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        input["pr_feedback"] = Value::String(format!("{prev}\n{line}"));
+        tx.execute(
+            "UPDATE steps SET input_json=?1, updated_at=?2 WHERE id=?3",
+            (input.to_string(), now_rfc3339(), &id),
+        )?;
+        return Ok(Some(id));
+    }
+
+    let used: i64 = tx.query_row(
+        "SELECT COUNT(*) FROM steps WHERE run_id=?1 AND step_id IN ('address_review','fix_ci')",
+        [run_id],
+        |r| r.get(0),
+    )?;
+    if used >= i64::from(cfg.max_followups) {
+        return Ok(None);
+    }
+    let agent = match cfg.agent.as_deref().filter(|a| !a.trim().is_empty()) {
+        Some(a) => a.to_string(),
+        None => tx
+            .query_row(
+                "SELECT agent_id FROM steps WHERE run_id=?1 AND step_id='implement'
+                 ORDER BY step_index LIMIT 1",
+                [run_id],
+                |r| r.get(0),
+            )
+            .optional()?
+            .unwrap_or_else(|| DEFAULT_FOLLOWUP_AGENT.to_string()),
+    };
+    let next_index: i64 = tx.query_row(
+        "SELECT COALESCE(MAX(step_index), -1) + 1 FROM steps WHERE run_id=?1",
+        [run_id],
+        |r| r.get(0),
+    )?;
+    let nanos = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+    let followup_row = format!("step-{nanos}-{step_id}");
+    let push_row = format!("step-{nanos}-pr_update");
+    let mut input = ctx.clone();
+    input["prompt_template"] = Value::String(
+        match fb.source {
+            Source::Review => ADDRESS_REVIEW_PROMPT,
+            Source::Ci => FIX_CI_PROMPT,
+        }
+        .to_string(),
+    );
+    input["pr_feedback"] = Value::String(line);
+    let ts = now_rfc3339();
+    // No `depends_on_json`: both wait on every earlier step of the run by index.
+    for (row, step, agent, input, index) in [
+        (&followup_row, step_id, agent.as_str(), input, next_index),
+        (&push_row, "pr_update", "internal/pr", ctx, next_index + 1),
+    ] {
+        tx.execute(
+            "INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 'queued', ?6, NULL, ?7, ?7)",
+            rusqlite::params![row, run_id, step, agent, index, input.to_string(), &ts],
+        )?;
+    }
+    tx.execute(
+        "UPDATE runs SET status='queued', updated_at=?1 WHERE id=?2 AND status='done'",
+        (&ts, run_id),
+    )?;
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.followup', ?2, ?3)",
+        (
+            now_ms_i64(),
+            run_id,
+            serde_json::json!({ "run_id": run_id, "step_id": step_id, "step_row_id": followup_row })
+                .to_string(),
+        ),
+    )?;
+    Ok(Some(followup_row))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FeedbackView {
+    pub(crate) id: i64,
+    pub(crate) run_id: String,
+    pub(crate) source: String,
+    pub(crate) kind: String,
+    pub(crate) author: Option<String>,
+    pub(crate) state: Option<String>,
+    pub(crate) body: String,
+    pub(crate) url: Option<String>,
+    pub(crate) actionable: bool,
+    pub(crate) followup_step_id: Option<String>,
+    pub(crate) created_at_ms: i64,
+}
+
+pub(crate) fn list_feedback(engine: &Engine, run_id: &str) -> anyhow::Result<Vec<FeedbackView>> {
+    let conn = engine.open()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, run_id, source, kind, author, state, body, url, actionable, followup_step_id,
+                created_at_ms
+         FROM run_feedback WHERE run_id=?1 ORDER BY id",
+    )?;
+    let rows = stmt.query_map([run_id], |r| {
+        Ok(FeedbackView {
+            id: r.get(0)?,
+            run_id: r.get(1)?,
+            source: r.get(2)?,
+            kind: r.get(3)?,
+            author: r.get(4)?,
+            state: r.get(5)?,
+            body: r.get(6)?,
+            url: r.get(7)?,
+            actionable: r.get(8)?,
+            followup_step_id: r.get(9)?,
+            created_at_ms: r.get(10)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+pub(crate) async fn api_run_feedback(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(run_id): axum::extract::Path<String>,
+) -> Result<Json<Vec<FeedbackView>>, (StatusCode, String)> {
+    list_feedback(&state.engine, &run_id)
+        .map(Json)
+        .map_err(internal_error("feedback.list"))
+}
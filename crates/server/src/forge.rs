@@ -0,0 +1,1273 @@
+//! Code forges.
+//!
+//! Pull-request operations (open a PR, find one by branch, list open ones, changed files,
+//! comments, status, merge) go through a `Forge`, picked by the host of the repo's `origin` remote:
+//! hosts listed under `[forge]` use the Gitea/Forgejo or GitLab REST API, everything else the
+//! default (the GitHub CLI unless configured otherwise). The file-backed and local forges need no
+//! server at all, for tests and offline setups.
+
+use clawdorio_engine::Engine;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrState {
+    Open,
+    Closed,
+    Merged,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PullRequest {
+    pub number: i64,
+    pub url: String,
+    pub head: String,
+    pub base: String,
+    pub title: String,
+    pub state: PrState,
+    #[serde(default)]
+    pub head_sha: Option<String>,
+    /// `None` while the forge has not computed it.
+    #[serde(default)]
+    pub mergeable: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewPullRequest {
+    pub head: String,
+    pub base: String,
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrFile {
+    pub path: String,
+    pub additions: i64,
+    pub deletions: i64,
+    /// Unified diff of the file, when the forge returns one.
+    #[serde(default)]
+    pub patch: String,
+}
+
+/// PR operations against the forge hosting a local repository. `repo` is the path of a local
+/// checkout; implementations find the remote project from its `origin`.
+pub trait Forge: Send + Sync {
+    fn name(&self) -> &str;
+    fn create_pr(&self, repo: &str, pr: &NewPullRequest) -> anyhow::Result<PullRequest>;
+    fn find_pr_by_head(&self, repo: &str, head: &str) -> anyhow::Result<Option<PullRequest>>;
+    fn list_open_prs(&self, repo: &str) -> anyhow::Result<Vec<PullRequest>>;
+    fn changed_files(&self, repo: &str, number: i64) -> anyhow::Result<Vec<PrFile>>;
+    fn post_comment(&self, repo: &str, number: i64, body: &str) -> anyhow::Result<()>;
+    fn pr_status(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest>;
+    /// Merges an open PR (merge commit, or fast-forward where the forge does that) and returns
+    /// its new state.
+    fn merge_pr(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest>;
+}
+
+/// Host and project path of a remote URL (`git@host:group/repo.git`,
+/// `https://host/group/repo`, `ssh://git@host:2222/group/repo.git`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remote {
+    pub host: String,
+    pub path: String,
+}
+
+pub fn parse_remote(url: &str) -> Option<Remote> {
+    let u = url.trim().trim_end_matches('/').trim_end_matches(".git");
+    let (host, path) = if let Some((_, rest)) = u.split_once("://") {
+        let (authority, path) = rest.split_once('/')?;
+        let host = authority.rsplit('@').next()?;
+        (host.split(':').next()?, path)
+    } else {
+        // scp-like syntax: [user@]host:path
+        let (authority, path) = u.split_once(':')?;
+        (authority.rsplit('@').next()?, path)
+    };
+    let path = path.trim_matches('/');
+    if host.is_empty() || !path.contains('/') {
+        return None;
+    }
+    Some(Remote {
+        host: host.to_ascii_lowercase(),
+        path: path.to_string(),
+    })
+}
+
+fn origin_url(repo: &str) -> anyhow::Result<String> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .arg("remote")
+        .arg("get-url")
+        .arg("origin")
+        .output()?;
+    if !out.status.success() {
+        anyhow::bail!("git_remote_missing");
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+pub fn origin_remote(repo: &str) -> anyhow::Result<Remote> {
+    let url = origin_url(repo)?;
+    parse_remote(&url).ok_or_else(|| anyhow::anyhow!("repo_parse_failed: {url}"))
+}
+
+/// PR number from a web URL on any supported forge (`/pull/7`, `/pulls/7`,
+/// `/-/merge_requests/7`).
+pub fn pr_number_from_url(url: &str) -> Option<i64> {
+    let parts: Vec<&str> = url.trim_end_matches('/').split('/').collect();
+    if parts.len() < 2 || !matches!(parts[parts.len() - 2], "pull" | "pulls" | "merge_requests") {
+        return None;
+    }
+    parts.last()?.parse::<i64>().ok()
+}
+
+/// Added and removed lines of a unified diff.
+fn count_diff(patch: &str) -> (i64, i64) {
+    patch.lines().fold((0, 0), |(a, d), l| {
+        if l.starts_with('+') && !l.starts_with("+++") {
+            (a + 1, d)
+        } else if l.starts_with('-') && !l.starts_with("---") {
+            (a, d + 1)
+        } else {
+            (a, d)
+        }
+    })
+}
+
+/// The `gh` CLI, run inside the checkout (github.com and GitHub Enterprise).
+#[derive(Debug, Clone)]
+pub struct GhCliForge {
+    pub program: String,
+}
+
+impl Default for GhCliForge {
+    fn default() -> Self {
+        Self {
+            program: "gh".to_string(),
+        }
+    }
+}
+
+const GH_PR_FIELDS: &str = "number,url,headRefName,baseRefName,title,state,headRefOid";
+
+impl GhCliForge {
+    fn run(&self, repo: &str, args: &[&str], fail_code: &str) -> anyhow::Result<Vec<u8>> {
+        let out = Command::new(&self.program)
+            .args(args)
+            .current_dir(repo)
+            .output()
+            .map_err(|_| anyhow::anyhow!("gh_missing: install gh and run gh auth login"))?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
+            let lower = stderr.to_lowercase();
+            if lower.contains("not logged") || lower.contains("authentication") {
+                anyhow::bail!("github_auth_required: {stderr}");
+            }
+            if lower.contains("forbidden") || lower.contains("resource not accessible") {
+                anyhow::bail!("github_permission_required: {stderr}");
+            }
+            anyhow::bail!("{fail_code}: {stderr}");
+        }
+        Ok(out.stdout)
+    }
+
+    fn parse_pr(v: &serde_json::Value) -> PullRequest {
+        let s = |k: &str| v.get(k).and_then(|x| x.as_str()).unwrap_or("").to_string();
+        PullRequest {
+            number: v.get("number").and_then(|x| x.as_i64()).unwrap_or(0),
+            url: s("url"),
+            head: s("headRefName"),
+            base: s("baseRefName"),
+            title: s("title"),
+            state: match s("state").as_str() {
+                "MERGED" => PrState::Merged,
+                "CLOSED" => PrState::Closed,
+                _ => PrState::Open,
+            },
+            head_sha: Some(s("headRefOid")).filter(|x| !x.is_empty()),
+            mergeable: match s("mergeable").as_str() {
+                "MERGEABLE" => Some(true),
+                "CONFLICTING" => Some(false),
+                _ => None,
+            },
+        }
+    }
+
+    fn list(&self, repo: &str, extra: &[&str]) -> anyhow::Result<Vec<PullRequest>> {
+        let mut args = vec!["pr", "list", "--json", GH_PR_FIELDS, "--limit", "200"];
+        args.extend_from_slice(extra);
+        let out = self.run(repo, &args, "gh_pr_list_failed")?;
+        let v: serde_json::Value = serde_json::from_slice(&out).unwrap_or_default();
+        Ok(v.as_array()
+            .map(|a| a.iter().map(Self::parse_pr).collect())
+            .unwrap_or_default())
+    }
+}
+
+impl Forge for GhCliForge {
+    fn name(&self) -> &str {
+        "gh"
+    }
+
+    fn create_pr(&self, repo: &str, pr: &NewPullRequest) -> anyhow::Result<PullRequest> {
+        let out = self.run(
+            repo,
+            &[
+                "pr", "create", "--head", &pr.head, "--base", &pr.base, "--title", &pr.title,
+                "--body", &pr.body,
+            ],
+            "gh_pr_create_failed",
+        )?;
+        let url = String::from_utf8_lossy(&out).trim().to_string();
+        Ok(PullRequest {
+            number: pr_number_from_url(&url).unwrap_or(0),
+            url,
+            head: pr.head.clone(),
+            base: pr.base.clone(),
+            title: pr.title.clone(),
+            state: PrState::Open,
+            head_sha: None,
+            mergeable: None,
+        })
+    }
+
+    fn find_pr_by_head(&self, repo: &str, head: &str) -> anyhow::Result<Option<PullRequest>> {
+        Ok(self
+            .list(repo, &["--state", "open", "--head", head])?
+            .into_iter()
+            .next())
+    }
+
+    fn list_open_prs(&self, repo: &str) -> anyhow::Result<Vec<PullRequest>> {
+        self.list(repo, &["--state", "open"])
+    }
+
+    fn changed_files(&self, repo: &str, number: i64) -> anyhow::Result<Vec<PrFile>> {
+        let n = number.to_string();
+        let out = self.run(
+            repo,
+            &["pr", "view", &n, "--json", "files"],
+            "gh_pr_view_failed",
+        )?;
+        let v: serde_json::Value = serde_json::from_slice(&out).unwrap_or_default();
+        Ok(v.get("files")
+            .and_then(|x| x.as_array())
+            .map(|files| {
+                files
+                    .iter()
+                    .map(|f| PrFile {
+                        path: f
+                            .get("path")
+                            .and_then(|x| x.as_str())
+                            .unwrap_or("")
+                            .to_string(),
+                        additions: f.get("additions").and_then(|x| x.as_i64()).unwrap_or(0),
+                        deletions: f.get("deletions").and_then(|x| x.as_i64()).unwrap_or(0),
+                        patch: f
+                            .get("patch")
+                            .and_then(|x| x.as_str())
+                            .unwrap_or("")
+                            .to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn post_comment(&self, repo: &str, number: i64, body: &str) -> anyhow::Result<()> {
+        let n = number.to_string();
+        self.run(
+            repo,
+            &["pr", "comment", &n, "--body", body],
+            "gh_pr_comment_failed",
+        )?;
+        Ok(())
+    }
+
+    fn pr_status(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        let n = number.to_string();
+        let fields = format!("{GH_PR_FIELDS},mergeable");
+        let out = self.run(
+            repo,
+            &["pr", "view", &n, "--json", &fields],
+            "gh_pr_view_failed",
+        )?;
+        let v: serde_json::Value = serde_json::from_slice(&out).unwrap_or_default();
+        Ok(Self::parse_pr(&v))
+    }
+
+    fn merge_pr(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        let n = number.to_string();
+        self.run(repo, &["pr", "merge", &n, "--merge"], "gh_pr_merge_failed")?;
+        self.pr_status(repo, number)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestFlavor {
+    /// Gitea and Forgejo (`/api/v1`).
+    Gitea,
+    /// GitLab merge requests (`/api/v4`).
+    Gitlab,
+}
+
+/// Gitea/Forgejo or GitLab over their REST APIs.
+pub struct RestForge {
+    pub flavor: RestFlavor,
+    /// e.g. `https://git.example.com/api/v1` or `https://gitlab.example.com/api/v4`.
+    pub api_url: String,
+    pub token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl RestForge {
+    pub fn new(flavor: RestFlavor, api_url: &str, token: Option<String>) -> Self {
+        Self {
+            flavor,
+            api_url: api_url.trim_end_matches('/').to_string(),
+            token,
+            agent: ureq::AgentBuilder::new()
+                .timeout(std::time::Duration::from_secs(30))
+                .build(),
+        }
+    }
+
+    fn project_url(&self, repo: &str) -> anyhow::Result<String> {
+        let path = origin_remote(repo)?.path;
+        Ok(match self.flavor {
+            RestFlavor::Gitea => format!("{}/repos/{path}", self.api_url),
+            RestFlavor::Gitlab => format!("{}/projects/{}", self.api_url, path.replace('/', "%2F")),
+        })
+    }
+
+    fn pr_url(&self, repo: &str, number: i64) -> anyhow::Result<String> {
+        let project = self.project_url(repo)?;
+        Ok(match self.flavor {
+            RestFlavor::Gitea => format!("{project}/pulls/{number}"),
+            RestFlavor::Gitlab => format!("{project}/merge_requests/{number}"),
+        })
+    }
+
+    fn call(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        match self.send(method, url, body)? {
+            Some(resp) => Ok(resp.into_json().unwrap_or_default()),
+            None => anyhow::bail!("forge_request_failed: {method} {url}: 404"),
+        }
+    }
+
+    /// `None` when the forge answers 404; other error statuses are errors.
+    fn send(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<serde_json::Value>,
+    ) -> anyhow::Result<Option<ureq::Response>> {
+        let mut req = self.agent.request(method, url);
+        if let Some(token) = &self.token {
+            req = match self.flavor {
+                RestFlavor::Gitea => req.set("Authorization", &format!("token {token}")),
+                RestFlavor::Gitlab => req.set("PRIVATE-TOKEN", token),
+            };
+        }
+        let res = match body {
+            Some(b) => req.send_json(b),
+            None => req.call(),
+        };
+        match res {
+            Ok(resp) => Ok(Some(resp)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(code @ (401 | 403), _)) => {
+                anyhow::bail!("forge_auth_required: {method} {url}: {code}")
+            }
+            Err(ureq::Error::Status(code, resp)) => {
+                let text = resp.into_string().unwrap_or_default();
+                anyhow::bail!(
+                    "forge_request_failed: {method} {url}: {code} {}",
+                    text.trim()
+                )
+            }
+            Err(e) => anyhow::bail!("forge_unreachable: {method} {url}: {e}"),
+        }
+    }
+
+    fn parse_pr(&self, v: &serde_json::Value) -> PullRequest {
+        let s = |v: &serde_json::Value, k: &str| {
+            v.get(k).and_then(|x| x.as_str()).unwrap_or("").to_string()
+        };
+        match self.flavor {
+            RestFlavor::Gitea => {
+                let head = v.get("head").cloned().unwrap_or_default();
+                let base = v.get("base").cloned().unwrap_or_default();
+                let merged = v.get("merged").and_then(|x| x.as_bool()).unwrap_or(false);
+                PullRequest {
+                    number: v.get("number").and_then(|x| x.as_i64()).unwrap_or(0),
+                    url: s(v, "html_url"),
+                    head: s(&head, "ref"),
+                    base: s(&base, "ref"),
+                    title: s(v, "title"),
+                    state: match (merged, s(v, "state").as_str()) {
+                        (true, _) => PrState::Merged,
+                        (false, "closed") => PrState::Closed,
+                        _ => PrState::Open,
+                    },
+                    head_sha: Some(s(&head, "sha")).filter(|x| !x.is_empty()),
+                    mergeable: v.get("mergeable").and_then(|x| x.as_bool()),
+                }
+            }
+            RestFlavor::Gitlab => PullRequest {
+                number: v.get("iid").and_then(|x| x.as_i64()).unwrap_or(0),
+                url: s(v, "web_url"),
+                head: s(v, "source_branch"),
+                base: s(v, "target_branch"),
+                title: s(v, "title"),
+                state: match s(v, "state").as_str() {
+                    "merged" => PrState::Merged,
+                    "closed" | "locked" => PrState::Closed,
+                    _ => PrState::Open,
+                },
+                head_sha: Some(s(v, "sha")).filter(|x| !x.is_empty()),
+                mergeable: match s(v, "merge_status").as_str() {
+                    "can_be_merged" => Some(true),
+                    "cannot_be_merged" => Some(false),
+                    _ => None,
+                },
+            },
+        }
+    }
+
+    fn parse_list(&self, v: &serde_json::Value) -> Vec<PullRequest> {
+        v.as_array()
+            .map(|a| a.iter().map(|p| self.parse_pr(p)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Every page of a PR listing: Gitea links the next page in `Link`, GitLab also sends
+    /// `X-Next-Page`.
+    fn list_pages(&self, url: &str, query: &[(&str, &str)]) -> anyhow::Result<Vec<PullRequest>> {
+        let first = self.agent.get(url).query_pairs(query.iter().copied());
+        let mut next = Some(first.url().to_string());
+        let mut out = vec![];
+        for _ in 0..MAX_LIST_PAGES {
+            let Some(page_url) = next.take() else { break };
+            let Some(resp) = self.send("GET", &page_url, None)? else {
+                anyhow::bail!("forge_request_failed: GET {page_url}: 404");
+            };
+            next = next_link(resp.header("link")).or_else(|| {
+                let page = resp
+                    .header("x-next-page")
+                    .filter(|p| !p.trim().is_empty())?;
+                Some(first.clone().query("page", page.trim()).url().to_string())
+            });
+            out.extend(self.parse_list(&resp.into_json().unwrap_or_default()));
+        }
+        Ok(out)
+    }
+}
+
+/// Cap on pages fetched by one listing, in case a server keeps linking to more.
+const MAX_LIST_PAGES: usize = 100;
+
+/// The `rel="next"` target of an RFC 8288 `Link` header.
+fn next_link(header: Option<&str>) -> Option<String> {
+    header?.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        params
+            .split(';')
+            .any(|p| matches!(p.trim(), "rel=\"next\"" | "rel=next"))
+            .then(|| {
+                target
+                    .trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+impl Forge for RestForge {
+    fn name(&self) -> &str {
+        match self.flavor {
+            RestFlavor::Gitea => "gitea",
+            RestFlavor::Gitlab => "gitlab",
+        }
+    }
+
+    fn create_pr(&self, repo: &str, pr: &NewPullRequest) -> anyhow::Result<PullRequest> {
+        let project = self.project_url(repo)?;
+        let v = match self.flavor {
+            RestFlavor::Gitea => self.call(
+                "POST",
+                &format!("{project}/pulls"),
+                Some(serde_json::json!({
+                    "head": pr.head, "base": pr.base, "title": pr.title, "body": pr.body,
+                })),
+            )?,
+            RestFlavor::Gitlab => self.call(
+                "POST",
+                &format!("{project}/merge_requests"),
+                Some(serde_json::json!({
+                    "source_branch": pr.head, "target_branch": pr.base,
+                    "title": pr.title, "description": pr.body,
+                })),
+            )?,
+        };
+        Ok(self.parse_pr(&v))
+    }
+
+    fn find_pr_by_head(&self, repo: &str, head: &str) -> anyhow::Result<Option<PullRequest>> {
+        let project = self.project_url(repo)?;
+        match self.flavor {
+            RestFlavor::Gitlab => Ok(self
+                .list_pages(
+                    &format!("{project}/merge_requests"),
+                    &[("state", "opened"), ("source_branch", head)],
+                )?
+                .into_iter()
+                .next()),
+            RestFlavor::Gitea => {
+                // Gitea looks PRs up by base and head; try the default branch first and only
+                // page through every open PR for one that targets another base.
+                let base = self
+                    .call("GET", &project, None)?
+                    .get("default_branch")
+                    .and_then(|b| b.as_str())
+                    .unwrap_or("")
+                    .to_string();
+                if !base.is_empty() {
+                    let url = format!("{project}/pulls/{base}/{head}");
+                    if let Some(resp) = self.send("GET", &url, None)? {
+                        let pr = self.parse_pr(&resp.into_json().unwrap_or_default());
+                        if pr.state == PrState::Open && pr.head == head {
+                            return Ok(Some(pr));
+                        }
+                    }
+                }
+                Ok(self
+                    .list_open_prs(repo)?
+                    .into_iter()
+                    .find(|p| p.head == head))
+            }
+        }
+    }
+
+    fn list_open_prs(&self, repo: &str) -> anyhow::Result<Vec<PullRequest>> {
+        let project = self.project_url(repo)?;
+        match self.flavor {
+            RestFlavor::Gitea => self.list_pages(
+                &format!("{project}/pulls"),
+                &[("state", "open"), ("limit", "50")],
+            ),
+            RestFlavor::Gitlab => self.list_pages(
+                &format!("{project}/merge_requests"),
+                &[("state", "opened"), ("per_page", "100")],
+            ),
+        }
+    }
+
+    fn changed_files(&self, repo: &str, number: i64) -> anyhow::Result<Vec<PrFile>> {
+        let pr = self.pr_url(repo, number)?;
+        Ok(match self.flavor {
+            RestFlavor::Gitea => self
+                .call("GET", &format!("{pr}/files"), None)?
+                .as_array()
+                .map(|files| {
+                    files
+                        .iter()
+                        .map(|f| PrFile {
+                            path: f
+                                .get("filename")
+                                .and_then(|x| x.as_str())
+                                .unwrap_or("")
+                                .to_string(),
+                            additions: f.get("additions").and_then(|x| x.as_i64()).unwrap_or(0),
+                            deletions: f.get("deletions").and_then(|x| x.as_i64()).unwrap_or(0),
+                            patch: String::new(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            RestFlavor::Gitlab => self
+                .call("GET", &format!("{pr}/changes"), None)?
+                .get("changes")
+                .and_then(|x| x.as_array())
+                .map(|changes| {
+                    changes
+                        .iter()
+                        .map(|c| {
+                            let patch = c.get("diff").and_then(|x| x.as_str()).unwrap_or("");
+                            let (additions, deletions) = count_diff(patch);
+                            PrFile {
+                                path: c
+                                    .get("new_path")
+                                    .and_then(|x| x.as_str())
+                                    .unwrap_or("")
+                                    .to_string(),
+                                additions,
+                                deletions,
+                                patch: patch.to_string(),
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+
+    fn post_comment(&self, repo: &str, number: i64, body: &str) -> anyhow::Result<()> {
+        let url = match self.flavor {
+            // Gitea PR comments live on the PR's issue.
+            RestFlavor::Gitea => format!("{}/issues/{number}/comments", self.project_url(repo)?),
+            RestFlavor::Gitlab => format!("{}/notes", self.pr_url(repo, number)?),
+        };
+        self.call("POST", &url, Some(serde_json::json!({ "body": body })))?;
+        Ok(())
+    }
+
+    fn pr_status(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        let v = self.call("GET", &self.pr_url(repo, number)?, None)?;
+        Ok(self.parse_pr(&v))
+    }
+
+    fn merge_pr(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        let pr = self.pr_url(repo, number)?;
+        match self.flavor {
+            RestFlavor::Gitea => self.call(
+                "POST",
+                &format!("{pr}/merge"),
+                Some(serde_json::json!({ "Do": "merge" })),
+            )?,
+            RestFlavor::Gitlab => self.call("PUT", &format!("{pr}/merge"), None)?,
+        };
+        self.pr_status(repo, number)
+    }
+}
+
+/// PRs kept in `<root>/<host>/<owner>/<repo>.json`; changed files come from `git diff` between
+/// the local base and head branches. Comments are stored alongside each PR.
+pub struct FileForge {
+    pub root: PathBuf,
+    lock: Mutex<()>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileForgeRepo {
+    next_number: i64,
+    prs: Vec<FilePr>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FilePr {
+    #[serde(flatten)]
+    pr: PullRequest,
+    body: String,
+    #[serde(default)]
+    comments: Vec<String>,
+}
+
+impl FileForge {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn state_path(&self, repo: &str) -> anyhow::Result<PathBuf> {
+        // A plain path remote (e.g. a local bare repo) is filed under `local/`.
+        let url = origin_url(repo)?;
+        let remote = parse_remote(&url).unwrap_or_else(|| Remote {
+            host: "local".to_string(),
+            path: url
+                .trim_start_matches("file://")
+                .trim_end_matches(".git")
+                .trim_matches('/')
+                .to_string(),
+        });
+        Ok(self
+            .root
+            .join(&remote.host)
+            .join(format!("{}.json", remote.path)))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<FileForgeRepo> {
+        match std::fs::read_to_string(path) {
+            Ok(raw) => Ok(serde_json::from_str(&raw)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(FileForgeRepo::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads the repo's state, applies `f`, and writes it back.
+    fn update<T>(
+        &self,
+        repo: &str,
+        f: impl FnOnce(&mut FileForgeRepo, &Path) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let path = self.state_path(repo)?;
+        let mut state = Self::read(&path)?;
+        let out = f(&mut state, &path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(&state)?)?;
+        Ok(out)
+    }
+
+    fn find(&self, repo: &str, number: i64) -> anyhow::Result<FilePr> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        Self::read(&self.state_path(repo)?)?
+            .prs
+            .into_iter()
+            .find(|p| p.pr.number == number)
+            .ok_or_else(|| anyhow::anyhow!("pr_not_found: {number}"))
+    }
+
+    fn git(repo: &str, args: &[&str]) -> anyhow::Result<String> {
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(args)
+            .output()?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "git {}: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    }
+}
+
+impl Forge for FileForge {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn create_pr(&self, repo: &str, new: &NewPullRequest) -> anyhow::Result<PullRequest> {
+        let head_sha = Self::git(repo, &["rev-parse", &new.head])
+            .ok()
+            .map(|s| s.trim().to_string());
+        self.update(repo, |state, path| {
+            if state
+                .prs
+                .iter()
+                .any(|p| p.pr.head == new.head && p.pr.state == PrState::Open)
+            {
+                anyhow::bail!("pr_exists: {}", new.head);
+            }
+            state.next_number = state.next_number.max(0) + 1;
+            let number = state.next_number;
+            let pr = PullRequest {
+                number,
+                url: format!(
+                    "file://{}/pulls/{number}",
+                    path.with_extension("").display()
+                ),
+                head: new.head.clone(),
+                base: new.base.clone(),
+                title: new.title.clone(),
+                state: PrState::Open,
+                head_sha,
+                mergeable: None,
+            };
+            state.prs.push(FilePr {
+                pr: pr.clone(),
+                body: new.body.clone(),
+                comments: vec![],
+            });
+            Ok(pr)
+        })
+    }
+
+    fn find_pr_by_head(&self, repo: &str, head: &str) -> anyhow::Result<Option<PullRequest>> {
+        Ok(self
+            .list_open_prs(repo)?
+            .into_iter()
+            .find(|p| p.head == head))
+    }
+
+    fn list_open_prs(&self, repo: &str) -> anyhow::Result<Vec<PullRequest>> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(Self::read(&self.state_path(repo)?)?
+            .prs
+            .into_iter()
+            .map(|p| p.pr)
+            .filter(|p| p.state == PrState::Open)
+            .collect())
+    }
+
+    fn changed_files(&self, repo: &str, number: i64) -> anyhow::Result<Vec<PrFile>> {
+        let pr = self.find(repo, number)?.pr;
+        let range = format!("{}...{}", pr.base, pr.head);
+        let numstat = Self::git(repo, &["diff", "--numstat", &range])?;
+        numstat
+            .lines()
+            .filter_map(|l| {
+                let mut cols = l.splitn(3, '\t');
+                Some((cols.next()?, cols.next()?, cols.next()?))
+            })
+            .map(|(a, d, path)| {
+                Ok(PrFile {
+                    path: path.to_string(),
+                    additions: a.parse().unwrap_or(0),
+                    deletions: d.parse().unwrap_or(0),
+                    patch: Self::git(repo, &["diff", &range, "--", path])?,
+                })
+            })
+            .collect()
+    }
+
+    fn post_comment(&self, repo: &str, number: i64, body: &str) -> anyhow::Result<()> {
+        self.update(repo, |state, _| {
+            let pr = state
+                .prs
+                .iter_mut()
+                .find(|p| p.pr.number == number)
+                .ok_or_else(|| anyhow::anyhow!("pr_not_found: {number}"))?;
+            pr.comments.push(body.to_string());
+            Ok(())
+        })
+    }
+
+    fn pr_status(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        Ok(self.find(repo, number)?.pr)
+    }
+
+    /// Records the merge only; branches are left as they are.
+    fn merge_pr(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        self.update(repo, |state, _| {
+            let pr = state
+                .prs
+                .iter_mut()
+                .find(|p| p.pr.number == number)
+                .ok_or_else(|| anyhow::anyhow!("pr_not_found: {number}"))?;
+            if pr.pr.state != PrState::Open {
+                anyhow::bail!("pr_not_open: {number}");
+            }
+            pr.pr.state = PrState::Merged;
+            Ok(pr.pr.clone())
+        })
+    }
+}
+
+/// The built-in local forge: `origin` is a bare repository on disk (a path or `file://` URL)
+/// and pull requests live in the server DB (`forge_pull_requests`). Changed files, head SHAs and
+/// mergeability are read from the bare repo, so a whole run can be exercised without a network.
+#[derive(Clone)]
+pub struct LocalForge {
+    engine: Engine,
+}
+
+const LOCAL_PR_COLUMNS: &str = "number, head, base, title, state";
+
+impl LocalForge {
+    pub fn new(engine: Engine) -> Self {
+        Self { engine }
+    }
+
+    /// Path of the bare repo behind `repo`'s `origin`.
+    fn bare(repo: &str) -> anyhow::Result<String> {
+        let url = origin_url(repo)?;
+        let path = url.strip_prefix("file://").unwrap_or(&url);
+        if !Path::new(path).join("HEAD").is_file() {
+            anyhow::bail!("local_forge_remote: origin is not a local bare repo: {url}");
+        }
+        Ok(path.to_string())
+    }
+
+    fn git(bare: &str, args: &[&str]) -> anyhow::Result<std::process::Output> {
+        Ok(Command::new("git")
+            .arg("--git-dir")
+            .arg(bare)
+            .args(args)
+            .env("GIT_AUTHOR_NAME", "Clawdorio")
+            .env("GIT_AUTHOR_EMAIL", "clawdorio@localhost")
+            .env("GIT_COMMITTER_NAME", "Clawdorio")
+            .env("GIT_COMMITTER_EMAIL", "clawdorio@localhost")
+            .output()?)
+    }
+
+    fn rev(bare: &str, branch: &str) -> Option<String> {
+        let out = Self::git(
+            bare,
+            &["rev-parse", "--verify", &format!("refs/heads/{branch}")],
+        )
+        .ok()?;
+        out.status
+            .success()
+            .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+    }
+
+    /// `Some(true)` when `head` merges cleanly into `base`, `Some(false)` on conflicts.
+    fn mergeable(bare: &str, base: &str, head: &str) -> Option<bool> {
+        let out = Self::git(bare, &["merge-tree", "--write-tree", base, head]).ok()?;
+        match out.status.code() {
+            Some(0) => Some(true),
+            Some(1) => Some(false),
+            _ => None,
+        }
+    }
+
+    fn row(bare: &str, r: &rusqlite::Row<'_>) -> rusqlite::Result<PullRequest> {
+        let number: i64 = r.get(0)?;
+        let state: String = r.get(4)?;
+        Ok(PullRequest {
+            number,
+            url: format!("local://{bare}/pulls/{number}"),
+            head: r.get(1)?,
+            base: r.get(2)?,
+            title: r.get(3)?,
+            state: match state.as_str() {
+                "merged" => PrState::Merged,
+                "closed" => PrState::Closed,
+                _ => PrState::Open,
+            },
+            head_sha: None,
+            mergeable: None,
+        })
+    }
+
+    fn get(&self, bare: &str, number: i64) -> anyhow::Result<PullRequest> {
+        self.engine
+            .open()?
+            .query_row(
+                &format!(
+                    "SELECT {LOCAL_PR_COLUMNS} FROM forge_pull_requests WHERE repo=?1 AND number=?2"
+                ),
+                (bare, number),
+                |r| Self::row(bare, r),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow::anyhow!("pr_not_found: {number}"))
+    }
+
+    /// Comments posted on a PR, oldest first.
+    pub fn comments(&self, repo: &str, number: i64) -> anyhow::Result<Vec<String>> {
+        let bare = Self::bare(repo)?;
+        let conn = self.engine.open()?;
+        let mut stmt = conn.prepare(
+            "SELECT body FROM forge_pr_comments WHERE repo=?1 AND number=?2 ORDER BY id",
+        )?;
+        let rows = stmt.query_map((&bare, number), |r| r.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+impl Forge for LocalForge {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn create_pr(&self, repo: &str, new: &NewPullRequest) -> anyhow::Result<PullRequest> {
+        let bare = Self::bare(repo)?;
+        if Self::rev(&bare, &new.head).is_none() {
+            anyhow::bail!("local_forge_branch_missing: {} was not pushed", new.head);
+        }
+        let mut conn = self.engine.open()?;
+        // The duplicate check and the number allocation share one write lock, so concurrent
+        // creates for the same head get `pr_exists` rather than a second PR or a key conflict.
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let open: Option<i64> = tx
+            .query_row(
+                "SELECT number FROM forge_pull_requests WHERE repo=?1 AND head=?2 AND state='open'",
+                (&bare, &new.head),
+                |r| r.get(0),
+            )
+            .optional()?;
+        if let Some(number) = open {
+            anyhow::bail!("pr_exists: #{number}");
+        }
+        let number: i64 = tx.query_row(
+            "SELECT COALESCE(MAX(number), 0) + 1 FROM forge_pull_requests WHERE repo=?1",
+            [&bare],
+            |r| r.get(0),
+        )?;
+        let now = super::now_ms_i64();
+        tx.execute(
+            "INSERT INTO forge_pull_requests (repo, number, head, base, title, body, state, created_at_ms, updated_at_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'open', ?7, ?7)",
+            (&bare, number, &new.head, &new.base, &new.title, &new.body, now),
+        )?;
+        tx.commit()?;
+        self.pr_status(repo, number)
+    }
+
+    fn find_pr_by_head(&self, repo: &str, head: &str) -> anyhow::Result<Option<PullRequest>> {
+        Ok(self
+            .list_open_prs(repo)?
+            .into_iter()
+            .find(|p| p.head == head))
+    }
+
+    fn list_open_prs(&self, repo: &str) -> anyhow::Result<Vec<PullRequest>> {
+        let bare = Self::bare(repo)?;
+        let conn = self.engine.open()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {LOCAL_PR_COLUMNS} FROM forge_pull_requests
+             WHERE repo=?1 AND state='open' ORDER BY number"
+        ))?;
+        let rows = stmt.query_map([&bare], |r| Self::row(&bare, r))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn changed_files(&self, repo: &str, number: i64) -> anyhow::Result<Vec<PrFile>> {
+        let bare = Self::bare(repo)?;
+        let pr = self.get(&bare, number)?;
+        let range = format!("{}...{}", pr.base, pr.head);
+        let out = Self::git(&bare, &["diff", "--numstat", &range])?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "git diff {range}: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter_map(|l| {
+                let mut cols = l.splitn(3, '\t');
+                Some((cols.next()?, cols.next()?, cols.next()?))
+            })
+            .map(|(a, d, path)| {
+                let patch = Self::git(&bare, &["diff", &range, "--", path])?;
+                Ok(PrFile {
+                    path: path.to_string(),
+                    additions: a.parse().unwrap_or(0),
+                    deletions: d.parse().unwrap_or(0),
+                    patch: String::from_utf8_lossy(&patch.stdout).to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn post_comment(&self, repo: &str, number: i64, body: &str) -> anyhow::Result<()> {
+        let bare = Self::bare(repo)?;
+        self.get(&bare, number)?;
+        self.engine.open()?.execute(
+            "INSERT INTO forge_pr_comments (repo, number, body, created_at_ms) VALUES (?1, ?2, ?3, ?4)",
+            (&bare, number, body, super::now_ms_i64()),
+        )?;
+        Ok(())
+    }
+
+    fn pr_status(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        let bare = Self::bare(repo)?;
+        let mut pr = self.get(&bare, number)?;
+        pr.head_sha = Self::rev(&bare, &pr.head);
+        if pr.state == PrState::Open {
+            pr.mergeable = Self::mergeable(&bare, &pr.base, &pr.head);
+        }
+        Ok(pr)
+    }
+
+    /// Merges into the base branch inside the bare repo, fast-forwarding when possible.
+    fn merge_pr(&self, repo: &str, number: i64) -> anyhow::Result<PullRequest> {
+        let bare = Self::bare(repo)?;
+        let pr = self.get(&bare, number)?;
+        if pr.state != PrState::Open {
+            anyhow::bail!("pr_not_open: {number}");
+        }
+        let (Some(base), Some(head)) = (Self::rev(&bare, &pr.base), Self::rev(&bare, &pr.head))
+        else {
+            anyhow::bail!("local_forge_branch_missing: {} or {}", pr.base, pr.head);
+        };
+        let ff = Self::git(&bare, &["merge-base", "--is-ancestor", &base, &head])?;
+        let merged = if ff.status.success() {
+            head
+        } else {
+            let tree = Self::git(&bare, &["merge-tree", "--write-tree", &base, &head])?;
+            if !tree.status.success() {
+                anyhow::bail!("merge_conflict: #{number}");
+            }
+            let tree = String::from_utf8_lossy(&tree.stdout);
+            let tree = tree.lines().next().unwrap_or("").to_string();
+            let msg = format!("Merge pull request #{number} from {}", pr.head);
+            let commit = Self::git(
+                &bare,
+                &["commit-tree", &tree, "-p", &base, "-p", &head, "-m", &msg],
+            )?;
+            if !commit.status.success() {
+                anyhow::bail!(
+                    "git commit-tree: {}",
+                    String::from_utf8_lossy(&commit.stderr).trim()
+                );
+            }
+            String::from_utf8_lossy(&commit.stdout).trim().to_string()
+        };
+        let update = Self::git(
+            &bare,
+            &[
+                "update-ref",
+                &format!("refs/heads/{}", pr.base),
+                &merged,
+                &base,
+            ],
+        )?;
+        if !update.status.success() {
+            anyhow::bail!(
+                "git update-ref: {}",
+                String::from_utf8_lossy(&update.stderr).trim()
+            );
+        }
+        self.engine.open()?.execute(
+            "UPDATE forge_pull_requests SET state='merged', updated_at_ms=?1 WHERE repo=?2 AND number=?3",
+            (super::now_ms_i64(), &bare, number),
+        )?;
+        self.pr_status(repo, number)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForgeKind {
+    Github,
+    Gitea,
+    Gitlab,
+    File,
+    /// [`LocalForge`]: a bare repo as `origin`, PRs in the server DB.
+    Local,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForgeHost {
+    /// Remote host as it appears in `origin` (e.g. `git.example.com`).
+    pub host: String,
+    pub kind: ForgeKind,
+    /// REST base URL; required for `gitea` and `gitlab`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
+    /// Environment variable holding the API token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForgeSection {
+    /// Forge for hosts not listed in `hosts`: `github` (the gh CLI), `file` or `local`.
+    pub default: ForgeKind,
+    /// Where the `file` forge keeps its PRs.
+    pub file_root: PathBuf,
+    pub hosts: Vec<ForgeHost>,
+}
+
+impl Default for ForgeSection {
+    fn default() -> Self {
+        Self {
+            default: ForgeKind::Github,
+            file_root: PathBuf::from("~/.clawdorio/forge"),
+            hosts: vec![],
+        }
+    }
+}
+
+impl ForgeSection {
+    /// Problems that stop startup; checked by `ServerConfig::finish`.
+    pub(crate) fn errors(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if matches!(self.default, ForgeKind::Gitea | ForgeKind::Gitlab) {
+            errors.push(
+                "forge.default must be github, file or local (list REST hosts under forge.hosts)"
+                    .to_string(),
+            );
+        }
+        for h in &self.hosts {
+            if h.host.trim().is_empty() {
+                errors.push("forge.hosts: host is required".to_string());
+            }
+            let needs_api = matches!(h.kind, ForgeKind::Gitea | ForgeKind::Gitlab);
+            if needs_api && h.api_url.as_deref().is_none_or(|u| u.trim().is_empty()) {
+                errors.push(format!("forge.hosts: {} needs api_url", h.host));
+            }
+        }
+        errors
+    }
+}
+
+/// Forges by remote host; unmatched hosts (and repos without a parseable `origin`) use the
+/// fallback.
+#[derive(Clone)]
+pub struct ForgeRegistry {
+    hosts: Vec<(String, Arc<dyn Forge>)>,
+    fallback: Arc<dyn Forge>,
+}
+
+impl Default for ForgeRegistry {
+    fn default() -> Self {
+        Self::with_fallback(Arc::new(GhCliForge::default()))
+    }
+}
+
+impl ForgeRegistry {
+    pub fn with_fallback(fallback: Arc<dyn Forge>) -> Self {
+        Self {
+            hosts: vec![],
+            fallback,
+        }
+    }
+
+    pub fn register(&mut self, host: &str, forge: Arc<dyn Forge>) {
+        let host = host.trim().to_ascii_lowercase();
+        self.hosts.retain(|(h, _)| *h != host);
+        self.hosts.push((host, forge));
+    }
+
+    pub fn resolve(&self, repo: &str) -> Arc<dyn Forge> {
+        let Ok(remote) = origin_remote(repo) else {
+            return self.fallback.clone();
+        };
+        self.hosts
+            .iter()
+            .find(|(h, _)| *h == remote.host)
+            .map(|(_, f)| f.clone())
+            .unwrap_or_else(|| self.fallback.clone())
+    }
+
+    /// Builds the registry; `engine` backs the `local` forge.
+    pub fn from_config(cfg: &ForgeSection, engine: &Engine) -> Self {
+        let file_root = super::workspace::expand_home(&cfg.file_root);
+        let build = |kind: ForgeKind, api_url: Option<&str>, token_env: Option<&str>| {
+            let token = token_env
+                .and_then(|k| std::env::var(k).ok())
+                .filter(|t| !t.trim().is_empty());
+            let forge: Arc<dyn Forge> = match kind {
+                ForgeKind::Github => Arc::new(GhCliForge::default()),
+                ForgeKind::Gitea => Arc::new(RestForge::new(
+                    RestFlavor::Gitea,
+                    api_url.unwrap_or_default(),
+                    token,
+                )),
+                ForgeKind::Gitlab => Arc::new(RestForge::new(
+                    RestFlavor::Gitlab,
+                    api_url.unwrap_or_default(),
+                    token,
+                )),
+                ForgeKind::File => Arc::new(FileForge::new(file_root.clone())),
+                ForgeKind::Local => Arc::new(LocalForge::new(engine.clone())),
+            };
+            forge
+        };
+        let mut reg = Self::with_fallback(build(cfg.default, None, None));
+        for h in &cfg.hosts {
+            reg.register(
+                &h.host,
+                build(h.kind, h.api_url.as_deref(), h.token_env.as_deref()),
+            );
+        }
+        reg
+    }
+}
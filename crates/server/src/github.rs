@@ -0,0 +1,455 @@
+//! GitHub webhook intake.
+//!
+//! `POST /api/github/webhook` checks `X-Hub-Signature-256` against `[github] webhook_secret`
+//! (unsigned deliveries need `allow_unsigned`),
+//! records each delivery in `github_deliveries` (keyed by `X-GitHub-Delivery`, so GitHub retries
+//! are not processed twice), then queues auto-rebase sweeps, flags merged PRs and hands review
+//! comments and CI results to [`feedback`](super::feedback). Stored payloads can be replayed
+//! with `POST /api/github/deliveries/{id}/redeliver`.
+
+use super::feedback::{self, FeedbackSection};
+use super::{
+    detect_default_branch, internal_error, mark_runs_pr_merged, matching_bases_by_repo, now_ms_i64,
+    parse_payload, queue_base_rebase_sweep, repo_path_from_payload, AppState,
+};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use clawdorio_engine::Engine;
+use hmac::{Hmac, Mac};
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+
+/// Oldest deliveries beyond this many are dropped from the log.
+const DELIVERY_LOG_LIMIT: i64 = 1000;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GithubSection {
+    /// Shared secret of the repository webhook; `$CLAWDORIO_GITHUB_WEBHOOK_SECRET` overrides it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<String>,
+    /// Process deliveries without a secret configured. Anyone who can reach the server can
+    /// then queue sweeps and follow-ups, so this has to be asked for.
+    pub allow_unsigned: bool,
+}
+
+impl GithubSection {
+    pub(crate) fn apply_env(&mut self) {
+        if let Some(v) = std::env::var("CLAWDORIO_GITHUB_WEBHOOK_SECRET")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+        {
+            self.webhook_secret = Some(v);
+        }
+    }
+
+    fn secret(&self) -> Option<&str> {
+        self.webhook_secret.as_deref().filter(|s| !s.is_empty())
+    }
+
+    /// Startup warning when the webhook accepts unsigned deliveries.
+    pub fn unsigned_warning(&self) -> Option<&'static str> {
+        (self.secret().is_none() && self.allow_unsigned).then_some(
+            "[github] allow_unsigned is on and no webhook_secret is set: \
+             anyone who can reach /api/github/webhook can queue work",
+        )
+    }
+}
+
+/// Checks a `sha256=<hex>` signature header over the raw body (constant-time compare).
+pub(crate) fn verify_signature(secret: &str, body: &[u8], header: &str) -> bool {
+    let Some(hex) = header.trim().strip_prefix("sha256=") else {
+        return false;
+    };
+    let Some(expected) = decode_hex(hex) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// What processing a delivery did.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Outcome {
+    pub(crate) queued: usize,
+    pub(crate) merged_runs: usize,
+    /// Review comments and CI results stored on a run.
+    pub(crate) feedback: usize,
+    pub(crate) followups: usize,
+}
+
+/// Applies one event; `None` for event types Clawdorio does not act on.
+fn process_event(
+    engine: &Engine,
+    feedback_cfg: &FeedbackSection,
+    event: &str,
+    payload: &serde_json::Value,
+) -> anyhow::Result<Option<Outcome>> {
+    let repo_full = payload
+        .get("repository")
+        .and_then(|r| r.get("full_name"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    let mut out = Outcome::default();
+
+    match event {
+        "push" => {
+            let ref_name = payload.get("ref").and_then(|v| v.as_str()).unwrap_or("");
+            let after = payload.get("after").and_then(|v| v.as_str());
+            for base in matching_bases_by_repo(engine, repo_full)? {
+                let default = detect_default_branch(
+                    &repo_path_from_payload(&parse_payload(&base.payload_json)).unwrap_or_default(),
+                )
+                .unwrap_or_else(|_| "main".to_string());
+                if ref_name == format!("refs/heads/{default}")
+                    && queue_base_rebase_sweep(engine, &base.id, "webhook.push", after)?
+                {
+                    out.queued += 1;
+                }
+            }
+        }
+        "pull_request" => {
+            let pr = payload.get("pull_request");
+            let action = payload.get("action").and_then(|v| v.as_str()).unwrap_or("");
+            let merged = pr
+                .and_then(|v| v.get("merged"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            if action == "closed" && merged {
+                if let Some(url) = pr.and_then(|v| v.get("html_url")).and_then(|v| v.as_str()) {
+                    out.merged_runs = mark_runs_pr_merged(engine, url)?;
+                }
+            }
+            if matches!(action, "synchronize" | "opened" | "reopened")
+                || (action == "closed" && merged)
+            {
+                let upstream_sha = pr
+                    .and_then(|v| v.get("base"))
+                    .and_then(|v| v.get("sha"))
+                    .and_then(|v| v.as_str());
+                for base in matching_bases_by_repo(engine, repo_full)? {
+                    if queue_base_rebase_sweep(
+                        engine,
+                        &base.id,
+                        "webhook.pull_request",
+                        upstream_sha,
+                    )? {
+                        out.queued += 1;
+                    }
+                }
+            }
+        }
+        "pull_request_review"
+        | "pull_request_review_comment"
+        | "issue_comment"
+        | "check_run"
+        | "check_suite"
+        | "status" => {
+            let Some(fb) = feedback::from_webhook(event, payload) else {
+                return Ok(None);
+            };
+            let r = feedback::ingest(engine, feedback_cfg, &fb)?;
+            out.feedback += usize::from(r.stored);
+            out.followups += usize::from(r.stored && r.followup_step_id.is_some());
+        }
+        _ => return Ok(None),
+    }
+    Ok(Some(out))
+}
+
+/// Processes a stored delivery and records the result on its row.
+fn run_delivery(
+    engine: &Engine,
+    feedback_cfg: &FeedbackSection,
+    id: &str,
+) -> anyhow::Result<DeliveryView> {
+    let (event, payload_json): (String, String) = engine.open()?.query_row(
+        "SELECT event, payload_json FROM github_deliveries WHERE id=?1",
+        [id],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    let payload = parse_payload(&payload_json);
+    let (status, outcome, error) = match process_event(engine, feedback_cfg, &event, &payload) {
+        Ok(Some(o)) => ("processed", o, None),
+        Ok(None) => ("ignored", Outcome::default(), None),
+        Err(e) => ("failed", Outcome::default(), Some(e.to_string())),
+    };
+    let now = now_ms_i64();
+    let conn = engine.open()?;
+    conn.execute(
+        "UPDATE github_deliveries SET status=?1, outcome_json=?2, error=?3, processed_at_ms=?4
+         WHERE id=?5",
+        (status, serde_json::to_string(&outcome)?, &error, now, id),
+    )?;
+    conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'github.delivery', ?2, ?3)",
+        (
+            now,
+            id,
+            serde_json::json!({
+                "delivery_id": id,
+                "event": event,
+                "status": status,
+                "queued": outcome.queued,
+                "error": error,
+            })
+            .to_string(),
+        ),
+    )?;
+    get_delivery(engine, id)?.ok_or_else(|| anyhow::anyhow!("delivery vanished: {id}"))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DeliveryView {
+    pub(crate) id: String,
+    pub(crate) event: String,
+    pub(crate) action: Option<String>,
+    pub(crate) repo: Option<String>,
+    pub(crate) status: String,
+    pub(crate) outcome: serde_json::Value,
+    pub(crate) error: Option<String>,
+    pub(crate) attempts: i64,
+    pub(crate) duplicates: i64,
+    pub(crate) received_at_ms: i64,
+    pub(crate) processed_at_ms: Option<i64>,
+}
+
+const DELIVERY_COLUMNS: &str = "id, event, action, repo, status, outcome_json, error, attempts,
+    duplicates, received_at_ms, processed_at_ms";
+
+fn delivery_from_row(r: &rusqlite::Row<'_>) -> rusqlite::Result<DeliveryView> {
+    Ok(DeliveryView {
+        id: r.get(0)?,
+        event: r.get(1)?,
+        action: r.get(2)?,
+        repo: r.get(3)?,
+        status: r.get(4)?,
+        outcome: parse_payload(&r.get::<_, String>(5)?),
+        error: r.get(6)?,
+        attempts: r.get(7)?,
+        duplicates: r.get(8)?,
+        received_at_ms: r.get(9)?,
+        processed_at_ms: r.get(10)?,
+    })
+}
+
+fn get_delivery(engine: &Engine, id: &str) -> anyhow::Result<Option<DeliveryView>> {
+    Ok(engine
+        .open()?
+        .query_row(
+            &format!("SELECT {DELIVERY_COLUMNS} FROM github_deliveries WHERE id=?1"),
+            [id],
+            delivery_from_row,
+        )
+        .optional()?)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+}
+
+fn reject(
+    engine: &Engine,
+    delivery: Option<&str>,
+    event: &str,
+    reason: &str,
+) -> (StatusCode, String) {
+    let conn = engine.open();
+    if let Ok(conn) = conn {
+        let _ = conn.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'github.delivery_rejected', ?2, ?3)",
+            (
+                now_ms_i64(),
+                delivery,
+                serde_json::json!({ "delivery_id": delivery, "event": event, "reason": reason })
+                    .to_string(),
+            ),
+        );
+    }
+    (StatusCode::UNAUTHORIZED, reason.to_string())
+}
+
+pub(crate) async fn api_github_webhook(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let engine = &state.engine;
+    let event = header(&headers, "x-github-event").unwrap_or("");
+    let delivery = header(&headers, "x-github-delivery");
+
+    match state.config.github.secret() {
+        Some(secret) => match header(&headers, "x-hub-signature-256") {
+            None => return Err(reject(engine, delivery, event, "signature_required")),
+            Some(sig) if !verify_signature(secret, &body, sig) => {
+                return Err(reject(engine, delivery, event, "invalid_signature"))
+            }
+            Some(_) => {}
+        },
+        // With API auth on, the webhook is the only unauthenticated mutating route.
+        None if state.config.auth.enabled || !state.config.github.allow_unsigned => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "webhook_secret_not_configured".to_string(),
+            ))
+        }
+        None => {}
+    }
+
+    let payload: serde_json::Value = serde_json::from_slice(&body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid_json".to_string()))?;
+    let id = match delivery {
+        Some(d) => d.to_string(),
+        None => format!(
+            "local-{}",
+            super::auth::random_hex(8).map_err(internal_error("github.delivery_id"))?
+        ),
+    };
+
+    let db = |ctx: &'static str| move |e: rusqlite::Error| internal_error(ctx)(e.into());
+    let conn = engine.open().map_err(internal_error("engine.open"))?;
+    // Each statement claims the delivery atomically, so concurrent retries of one id cannot
+    // both process it (or both try to insert it).
+    let inserted = conn
+        .execute(
+            "INSERT INTO github_deliveries (id, event, action, repo, status, payload_json, received_at_ms)
+             VALUES (?1, ?2, ?3, ?4, 'received', ?5, ?6)
+             ON CONFLICT(id) DO NOTHING",
+            (
+                &id,
+                event,
+                payload.get("action").and_then(|v| v.as_str()),
+                payload
+                    .get("repository")
+                    .and_then(|r| r.get("full_name"))
+                    .and_then(|v| v.as_str()),
+                payload.to_string(),
+                now_ms_i64(),
+            ),
+        )
+        .map_err(db("github.delivery_insert"))?;
+    if inserted > 0 {
+        conn.execute(
+            "DELETE FROM github_deliveries WHERE id IN (
+               SELECT id FROM github_deliveries ORDER BY received_at_ms DESC LIMIT -1 OFFSET ?1)",
+            [DELIVERY_LOG_LIMIT],
+        )
+        .map_err(db("github.delivery_trim"))?;
+    } else {
+        // GitHub retries failed deliveries with the same id; only those are processed again.
+        let retried = conn
+            .execute(
+                "UPDATE github_deliveries SET status='received', attempts=attempts+1, payload_json=?1
+                 WHERE id=?2 AND status='failed'",
+                (payload.to_string(), &id),
+            )
+            .map_err(db("github.delivery_retry"))?;
+        if retried == 0 {
+            conn.execute(
+                "UPDATE github_deliveries SET duplicates=duplicates+1 WHERE id=?1",
+                [&id],
+            )
+            .map_err(db("github.delivery_duplicate"))?;
+            return Ok(Json(serde_json::json!({
+                "ok": true,
+                "event": event,
+                "delivery_id": id,
+                "duplicate": true,
+                "queued": 0,
+            })));
+        }
+    }
+
+    let view = run_delivery(engine, &state.config.feedback, &id)
+        .map_err(internal_error("github.run_delivery"))?;
+    if view.status == "failed" {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            view.error.unwrap_or_else(|| "delivery_failed".to_string()),
+        ));
+    }
+    Ok(Json(serde_json::json!({
+        "ok": true,
+        "event": event,
+        "delivery_id": id,
+        "status": view.status,
+        "queued": view.outcome.get("queued").and_then(|v| v.as_u64()).unwrap_or(0),
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeliveryListQuery {
+    pub(crate) status: Option<String>,
+    pub(crate) event: Option<String>,
+    pub(crate) limit: Option<i64>,
+}
+
+pub(crate) async fn api_deliveries_list(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(q): axum::extract::Query<DeliveryListQuery>,
+) -> Result<Json<Vec<DeliveryView>>, (StatusCode, String)> {
+    let db = |ctx: &'static str| move |e: rusqlite::Error| internal_error(ctx)(e.into());
+    let conn = state.engine.open().map_err(internal_error("engine.open"))?;
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {DELIVERY_COLUMNS} FROM github_deliveries
+             WHERE (?1 IS NULL OR status=?1) AND (?2 IS NULL OR event=?2)
+             ORDER BY received_at_ms DESC, id DESC LIMIT ?3"
+        ))
+        .map_err(db("github.deliveries_prepare"))?;
+    let rows = stmt
+        .query_map(
+            (&q.status, &q.event, q.limit.unwrap_or(50).clamp(1, 500)),
+            delivery_from_row,
+        )
+        .map_err(db("github.deliveries_query"))?;
+    Ok(Json(
+        rows.collect::<Result<_, _>>()
+            .map_err(db("github.deliveries_row"))?,
+    ))
+}
+
+/// Replays a stored delivery through the same processing as a fresh one.
+pub(crate) async fn api_delivery_redeliver(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<DeliveryView>, (StatusCode, String)> {
+    let engine = &state.engine;
+    if get_delivery(engine, &id)
+        .map_err(internal_error("github.get_delivery"))?
+        .is_none()
+    {
+        return Err((StatusCode::NOT_FOUND, "not_found".to_string()));
+    }
+    engine
+        .open()
+        .and_then(|conn| {
+            Ok(conn.execute(
+                "UPDATE github_deliveries SET attempts=attempts+1 WHERE id=?1",
+                [&id],
+            )?)
+        })
+        .map_err(internal_error("github.redeliver"))?;
+    run_delivery(engine, &state.config.feedback, &id)
+        .map(Json)
+        .map_err(internal_error("github.run_delivery"))
+}
@@ -3,18 +3,21 @@ use axum::http::HeaderMap;
 use axum::http::HeaderValue;
 use axum::{
     middleware,
+    response::sse::{Event, KeepAlive, Sse},
     response::{Html, IntoResponse},
     routing::delete,
     routing::get,
     routing::post,
     Json, Router,
 };
-use clawdorio_engine::{Belt, Engine, Entity, Quest};
+use clawdorio_engine::{Belt, Engine, Entity, EventRow, Quest};
+use futures_util::Stream;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -26,19 +29,71 @@ use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::services::ServeDir;
 use tower_http::set_header::SetResponseHeaderLayer;
 
+mod approval;
+pub mod auth;
+pub mod backup;
+pub mod config;
+mod conflicts;
+pub mod executor;
+mod feedback;
+pub mod forge;
+pub mod github;
+mod merge_queue;
+pub mod pool;
+mod reply;
+mod retry;
+mod scratch;
+mod stories;
 #[cfg(test)]
 mod tests;
+mod ui;
+mod workflow;
+pub mod workspace;
+pub mod worktrees;
+
+use executor::{CancelToken, ExecutorRegistry, StepRequest};
+use forge::{Forge, ForgeRegistry};
+use pool::{Heartbeat, WorkerLimits, WorkerPool, WorkerPoolView, STEP_STALE_AFTER_MS};
+use reply::StepReply;
+use retry::{ErrorClass, RetryPolicy};
 
 #[derive(Clone)]
 pub struct AppState {
     pub engine: Engine,
+    pub executors: Arc<ExecutorRegistry>,
+    pub workers: Arc<WorkerPool>,
+    pub forges: Arc<ForgeRegistry>,
+    pub config: Arc<config::ServerConfig>,
+}
+
+impl AppState {
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            engine,
+            executors: Arc::new(ExecutorRegistry::default()),
+            workers: Arc::new(WorkerPool::new(WorkerLimits::default())),
+            forges: Arc::new(ForgeRegistry::default()),
+            config: Arc::new(config::ServerConfig::default()),
+        }
+    }
 }
 
 const DEFAULT_AUTO_REBASE_ENABLED: bool = true;
 const DEFAULT_AUTO_REBASE_INTERVAL_SEC: i64 = 900;
 const AUTO_REBASE_MAX_RETRIES: i64 = 3;
+const EVENT_STREAM_POLL_MS: u64 = 400;
+const EVENT_STREAM_BATCH: usize = 200;
+const DEFAULT_STEP_TIMEOUT_SEC: u64 = 3600;
+/// How often the stale-step reaper runs.
+const REAPER_INTERVAL_MS: u64 = 7_000;
+/// How often worktrees are reconciled (and garbage-collected).
+const WORKTREE_RECONCILE_MS: u64 = 600_000;
+/// How often bases' merge queues look for newly approved runs.
+const MERGE_QUEUE_TICK_MS: u64 = 15_000;
 
 pub fn build_router(state: AppState) -> Router {
+    let allowed: Arc<Vec<config::IpRange>> = Arc::new(state.config.allowed_ranges());
+    let state = Arc::new(state);
     let sprites_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
         .join("assets")
         .join("rts-sprites");
@@ -53,6 +108,9 @@ pub fn build_router(state: AppState) -> Router {
         .route("/", get(dashboard))
         .route("/health", get(health))
         .route("/api/state", get(api_state))
+        .route("/api/export", get(backup::api_export))
+        .route("/api/events/stream", get(api_events_stream))
+        .route("/api/ui/stream", get(ui::api_ui_stream))
         .route("/api/buildings", get(api_buildings))
         .route("/api/local-repos", get(api_local_repos))
         .route(
@@ -64,12 +122,38 @@ pub fn build_router(state: AppState) -> Router {
             delete(api_entities_delete).patch(api_entities_update_pos),
         )
         .route("/api/entities/{id}/repo", post(api_entities_attach_repo))
+        .route(
+            "/api/entities/{id}/workflow",
+            post(api_entities_set_workflow),
+        )
         .route("/api/belts", get(api_belts_list).post(api_belts_create))
         .route("/api/belts/{id}", delete(api_belts_delete))
         .route("/api/quests", get(api_quests_list).post(api_quests_upsert))
         .route("/api/quests/{id}", delete(api_quests_delete))
         .route("/api/runs", get(api_runs_list))
         .route("/api/runs/{id}/steps", get(api_run_steps))
+        .route("/api/runs/{id}/feedback", get(feedback::api_run_feedback))
+        .route("/api/runs/{id}/cancel", post(api_run_cancel))
+        .route("/api/runs/{id}/pause", post(api_run_pause))
+        .route("/api/runs/{id}/resume", post(api_run_resume))
+        .route(
+            "/api/tokens",
+            get(auth::api_tokens_list).post(auth::api_tokens_create),
+        )
+        .route("/api/tokens/{id}", delete(auth::api_tokens_revoke))
+        .route("/api/worktrees", get(worktrees::api_worktrees_list))
+        .route(
+            "/api/worktrees/{id}/prune",
+            post(worktrees::api_worktree_prune),
+        )
+        .route(
+            "/api/runs/{id}/steps/{step_id}/approve",
+            post(approval::api_run_step_approve),
+        )
+        .route(
+            "/api/runs/{id}/steps/{step_id}/reject",
+            post(approval::api_run_step_reject),
+        )
         .route("/api/pr-feed", get(api_pr_feed))
         .route("/api/pr-feed/{run_id}/files", get(api_pr_feed_files))
         .route("/api/prs/comment", post(api_pr_comment))
@@ -79,6 +163,16 @@ pub fn build_router(state: AppState) -> Router {
         .route("/api/library/memory", get(api_library_memory_list))
         .route("/api/library/memory/{id}", get(api_library_memory_detail))
         .route("/api/feature/build", post(api_feature_build))
+        .route(
+            "/api/workflows",
+            get(workflow::api_workflows_list).post(workflow::api_workflows_create),
+        )
+        .route(
+            "/api/workflows/{id}",
+            get(workflow::api_workflows_get)
+                .put(workflow::api_workflows_update)
+                .delete(workflow::api_workflows_delete),
+        )
         .route("/api/skills/import", post(api_skills_import))
         .route("/api/skills/graphs", get(api_skills_graphs_list))
         .route("/api/skills/nodes", get(api_skills_nodes_list))
@@ -88,7 +182,12 @@ pub fn build_router(state: AppState) -> Router {
         .route("/api/skills/preview", get(api_skills_preview))
         .route("/api/skills/cli", post(api_skills_cli))
         .route("/api/workers/reemit", post(api_workers_reemit_global))
-        .route("/api/github/webhook", post(api_github_webhook))
+        .route("/api/github/webhook", post(github::api_github_webhook))
+        .route("/api/github/deliveries", get(github::api_deliveries_list))
+        .route(
+            "/api/github/deliveries/{id}/redeliver",
+            post(github::api_delivery_redeliver),
+        )
         .route(
             "/api/bases/{id}/workers/reemit",
             post(api_workers_reemit_base),
@@ -98,10 +197,20 @@ pub fn build_router(state: AppState) -> Router {
             "/api/bases/{id}/auto-rebase",
             get(api_base_auto_rebase_get).patch(api_base_auto_rebase_patch),
         )
+        .route(
+            "/api/bases/{id}/merge-queue",
+            get(merge_queue::api_base_merge_queue).patch(merge_queue::api_base_merge_queue_patch),
+        )
+        .route(
+            "/api/bases/{id}/workspace",
+            get(api_base_workspace_get).patch(api_base_workspace_patch),
+        )
         .nest_service("/rts-sprites", sprites)
-        .with_state(Arc::new(state))
-        // Local security: allow only loopback + Tailscale by default.
-        .layer(middleware::from_fn(ip_allowlist))
+        .with_state(state.clone())
+        // Bearer tokens when `[auth] enabled` (see `auth`); runs after the peer check below.
+        .layer(middleware::from_fn_with_state(state, auth::require_token))
+        // Local security: allow only loopback + `server.allow_cidrs` (Tailscale by default).
+        .layer(middleware::from_fn_with_state(allowed, ip_allowlist))
         // This service is expected to be local-only and may control a local agent swarm.
         // Never use `Access-Control-Allow-Origin: *` here; it makes it easier for a random
         // website in your browser to probe/exfiltrate local state.
@@ -120,6 +229,8 @@ async fn dashboard() -> Html<&'static str> {
 struct ApiState {
     rev: i64,
     working_agents: i64,
+    workers: WorkerPoolView,
+    placement_max_base_distance: i64,
     entities: Vec<Entity>,
     quests: Vec<Quest>,
     belts: Vec<Belt>,
@@ -151,12 +262,105 @@ async fn api_state(
     Ok(Json(ApiState {
         rev,
         working_agents,
+        workers: state.workers.view(),
+        placement_max_base_distance: state.config.placement.max_base_distance,
         entities,
         quests,
         belts,
     }))
 }
 
+#[derive(Debug, Deserialize)]
+struct EventStreamQuery {
+    #[serde(default)]
+    since_seq: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct StreamEvent {
+    seq: i64,
+    ts_ms: i64,
+    kind: String,
+    entity_id: Option<String>,
+    payload: serde_json::Value,
+}
+
+impl From<EventRow> for StreamEvent {
+    fn from(row: EventRow) -> Self {
+        Self {
+            seq: row.seq,
+            ts_ms: row.ts_ms,
+            kind: row.kind,
+            entity_id: row.entity_id,
+            payload: parse_payload(&row.payload_json),
+        }
+    }
+}
+
+async fn api_events_stream(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: HeaderMap,
+    axum::extract::Query(q): axum::extract::Query<EventStreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (axum::http::StatusCode, String)> {
+    let cursor = resolve_stream_cursor(&state.engine, &headers, q.since_seq)
+        .map_err(internal_error("resolve_stream_cursor"))?;
+    let stream =
+        futures_util::StreamExt::map(event_log_stream(state.engine.clone(), cursor), |ev| {
+            let id = ev.seq.to_string();
+            Ok(Event::default()
+                .id(id)
+                .json_data(&ev)
+                .unwrap_or_else(|_| Event::default().id(ev.seq.to_string()).data("{}")))
+        });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Where an event stream starts: `Last-Event-ID` (browser reconnect) wins over `since_seq`,
+/// and with neither we start at the current head so clients only see new rows.
+fn resolve_stream_cursor(
+    engine: &Engine,
+    headers: &HeaderMap,
+    since_seq: Option<i64>,
+) -> anyhow::Result<i64> {
+    let resume = headers
+        .get("last-event-id")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.trim().parse::<i64>().ok());
+    match resume.or(since_seq) {
+        Some(seq) => Ok(seq.max(0)),
+        None => engine.get_rev(),
+    }
+}
+
+/// Tails `event_log` after `cursor`, yielding rows in `seq` order as they are written.
+fn event_log_stream(engine: Engine, cursor: i64) -> impl Stream<Item = StreamEvent> {
+    futures_util::stream::unfold(
+        (engine, cursor, VecDeque::<EventRow>::new()),
+        |(engine, mut cursor, mut buf)| async move {
+            loop {
+                if let Some(row) = buf.pop_front() {
+                    cursor = row.seq;
+                    return Some((StreamEvent::from(row), (engine, cursor, buf)));
+                }
+                let eng = engine.clone();
+                let rows = tokio::task::spawn_blocking(move || {
+                    eng.list_events_since(cursor, EVENT_STREAM_BATCH)
+                })
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .unwrap_or_default();
+                if rows.is_empty() {
+                    tokio::time::sleep(std::time::Duration::from_millis(EVENT_STREAM_POLL_MS))
+                        .await;
+                    continue;
+                }
+                buf.extend(rows);
+            }
+        },
+    )
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct BuildingSpec {
     kind: String,
@@ -173,14 +377,15 @@ async fn api_buildings() -> Json<Vec<BuildingSpec>> {
     Json(building_specs())
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct LocalRepo {
-    path: String,
-    name: String,
-}
-
-async fn api_local_repos() -> Json<Vec<LocalRepo>> {
-    Json(discover_local_repos())
+async fn api_local_repos(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Json<Vec<workspace::LocalRepo>> {
+    let cfg = state.config.clone();
+    Json(
+        tokio::task::spawn_blocking(move || cfg.workspace.discover_repos())
+            .await
+            .unwrap_or_default(),
+    )
 }
 
 async fn api_entities_list(
@@ -252,11 +457,14 @@ async fn api_entities_create(
             ));
         }
         payload["repo_path"] = serde_json::Value::String(repo_path.to_string());
-        payload["auto_rebase_enabled"] = serde_json::Value::Bool(DEFAULT_AUTO_REBASE_ENABLED);
+        payload["auto_rebase_enabled"] = serde_json::Value::Bool(state.config.auto_rebase.enabled);
         payload["auto_rebase_interval_sec"] =
-            serde_json::Value::Number(DEFAULT_AUTO_REBASE_INTERVAL_SEC.into());
+            serde_json::Value::Number(state.config.auto_rebase.interval_sec.into());
+        payload["merge_queue_enabled"] = serde_json::Value::Bool(state.config.merge_queue.enabled);
     } else {
-        let Some(base_id) = nearest_base_id(&entities, input.x, input.y, fp.0, fp.1, 12) else {
+        let max_dist = state.config.placement.max_base_distance;
+        let Some(base_id) = nearest_base_id(&entities, input.x, input.y, fp.0, fp.1, max_dist)
+        else {
             return Err((
                 axum::http::StatusCode::BAD_REQUEST,
                 "requires_base".to_string(),
@@ -359,13 +567,14 @@ async fn api_entities_update_pos(
     if overlaps_any_belt(&belts, input.x, input.y, fp.0, fp.1) {
         return Err((axum::http::StatusCode::CONFLICT, "overlap_belt".to_string()));
     }
-    if cur.kind != "base" {
-        if nearest_base_id(&others, input.x, input.y, fp.0, fp.1, 12).is_none() {
-            return Err((
-                axum::http::StatusCode::BAD_REQUEST,
-                "requires_base".to_string(),
-            ));
-        }
+    let max_dist = state.config.placement.max_base_distance;
+    if cur.kind != "base"
+        && nearest_base_id(&others, input.x, input.y, fp.0, fp.1, max_dist).is_none()
+    {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "requires_base".to_string(),
+        ));
     }
     let ent = state
         .engine
@@ -428,11 +637,14 @@ async fn api_entities_attach_repo(
     }
     payload["repo_path"] = serde_json::Value::String(repo_path.to_string());
     if payload.get("auto_rebase_enabled").is_none() {
-        payload["auto_rebase_enabled"] = serde_json::Value::Bool(DEFAULT_AUTO_REBASE_ENABLED);
+        payload["auto_rebase_enabled"] = serde_json::Value::Bool(state.config.auto_rebase.enabled);
     }
     if payload.get("auto_rebase_interval_sec").is_none() {
         payload["auto_rebase_interval_sec"] =
-            serde_json::Value::Number(DEFAULT_AUTO_REBASE_INTERVAL_SEC.into());
+            serde_json::Value::Number(state.config.auto_rebase.interval_sec.into());
+    }
+    if payload.get("merge_queue_enabled").is_none() {
+        payload["merge_queue_enabled"] = serde_json::Value::Bool(state.config.merge_queue.enabled);
     }
     let updated = state
         .engine
@@ -442,6 +654,49 @@ async fn api_entities_attach_repo(
     Ok(Json(updated))
 }
 
+#[derive(Debug, Deserialize)]
+struct SetWorkflowInput {
+    workflow_id: String,
+}
+
+async fn api_entities_set_workflow(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Json(input): Json<SetWorkflowInput>,
+) -> Result<Json<Entity>, (axum::http::StatusCode, String)> {
+    let workflow_id = input.workflow_id.trim();
+    let known = workflow::load_workflow(&state.engine, workflow_id)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()))?
+        .is_some();
+    if !known {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "unknown_workflow".to_string(),
+        ));
+    }
+    let entities = state
+        .engine
+        .list_entities()
+        .map_err(internal_error("engine.list_entities"))?;
+    let Some(ent) = entities.iter().find(|e| e.id == id) else {
+        return Err((axum::http::StatusCode::NOT_FOUND, "not_found".to_string()));
+    };
+    if ent.kind != "feature" {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "not_a_factory".to_string(),
+        ));
+    }
+    let mut payload = parse_payload(&ent.payload_json);
+    payload["workflow_id"] = serde_json::Value::String(workflow_id.to_string());
+    let updated = state
+        .engine
+        .update_entity_payload(&id, &payload.to_string())
+        .map_err(internal_error("engine.update_entity_payload"))?
+        .ok_or((axum::http::StatusCode::NOT_FOUND, "not_found".to_string()))?;
+    Ok(Json(updated))
+}
+
 async fn api_quests_list(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
 ) -> Result<Json<Vec<Quest>>, (axum::http::StatusCode, String)> {
@@ -628,6 +883,21 @@ struct StepRow {
     status: String,
     output_text: Option<String>,
     updated_at: String,
+    worker_id: Option<String>,
+    heartbeat_at_ms: Option<i64>,
+    timeout_sec: Option<i64>,
+    /// Fields parsed from the agent reply (`step_outputs`), if any.
+    outputs: Option<serde_json::Value>,
+    story_id: Option<String>,
+    /// Step row ids this step waits on (DAG runs only).
+    depends_on: Option<Vec<String>>,
+    /// Progress of the story this step belongs to.
+    story: Option<stories::StoryProgress>,
+    /// Attempts started so far and the step's retry budget.
+    attempt: i64,
+    max_attempts: u32,
+    /// Set while a retry is backing off.
+    next_attempt_at_ms: Option<i64>,
 }
 
 async fn api_run_steps(
@@ -635,38 +905,186 @@ async fn api_run_steps(
     axum::extract::Path(run_id): axum::extract::Path<String>,
 ) -> Result<Json<Vec<StepRow>>, (axum::http::StatusCode, String)> {
     let conn = state.engine.open().map_err(internal_error("engine.open"))?;
-    let mut stmt = conn
-        .prepare(
-            "SELECT id, step_id, agent_id, step_index, status, output_text, updated_at
-             FROM steps
-             WHERE run_id = ?1
-             ORDER BY step_index ASC",
+    let rows = load_run_steps(&conn, &run_id).map_err(|e| {
+        (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("db.query_steps: {e}"),
         )
-        .map_err(|e| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                format!("db.prepare_steps: {e}"),
+    })?;
+    Ok(Json(rows))
+}
+
+fn load_run_steps(conn: &rusqlite::Connection, run_id: &str) -> rusqlite::Result<Vec<StepRow>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.id, s.step_id, s.agent_id, s.step_index, s.status, s.output_text, s.updated_at,
+                s.worker_id, s.heartbeat_at_ms, s.timeout_sec, o.outputs_json,
+                s.story_id, s.depends_on_json, s.attempt, s.next_attempt_at_ms, s.input_json
+         FROM steps s
+         LEFT JOIN step_outputs o ON o.step_row_id = s.id
+         WHERE s.run_id = ?1
+         ORDER BY s.step_index ASC",
+    )?;
+    let rows = stmt.query_map([run_id], |row| {
+        Ok(StepRow {
+            id: row.get(0)?,
+            step_id: row.get(1)?,
+            agent_id: row.get(2)?,
+            step_index: row.get(3)?,
+            status: row.get(4)?,
+            output_text: row.get(5)?,
+            updated_at: row.get(6)?,
+            worker_id: row.get(7)?,
+            heartbeat_at_ms: row.get(8)?,
+            timeout_sec: row.get(9)?,
+            outputs: row
+                .get::<_, Option<String>>(10)?
+                .and_then(|j| serde_json::from_str(&j).ok()),
+            story_id: row.get(11)?,
+            depends_on: row
+                .get::<_, Option<String>>(12)?
+                .and_then(|j| serde_json::from_str(&j).ok()),
+            story: None,
+            attempt: row.get(13)?,
+            max_attempts: RetryPolicy::for_step(
+                &row.get::<_, String>(1)?,
+                &parse_payload(&row.get::<_, String>(15)?),
             )
-        })?;
-    let rows = stmt
-        .query_map([run_id], |row| {
-            Ok(StepRow {
-                id: row.get(0)?,
-                step_id: row.get(1)?,
-                agent_id: row.get(2)?,
-                step_index: row.get(3)?,
-                status: row.get(4)?,
-                output_text: row.get(5)?,
-                updated_at: row.get(6)?,
-            })
+            .max_attempts,
+            next_attempt_at_ms: row.get(14)?,
         })
-        .map_err(|e| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                format!("db.query_steps: {e}"),
-            )
-        })?;
-    Ok(Json(rows.filter_map(Result::ok).collect()))
+    })?;
+    let progress = stories::load_story_progress(conn, run_id)?;
+    Ok(rows
+        .filter_map(Result::ok)
+        .map(|mut step| {
+            step.story = step
+                .story_id
+                .as_ref()
+                .and_then(|id| progress.get(id).cloned());
+            step
+        })
+        .collect())
+}
+
+async fn api_run_cancel(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    let cancelled_steps = transition_run(&state.engine, &id, RunControl::Cancel)?;
+    // Flip tokens after the rows are marked so the worker's late finalize is a no-op.
+    let signalled = state.workers.cancel_run(&id);
+    Ok(Json(serde_json::json!({
+        "ok": true,
+        "run_id": id,
+        "status": "cancelled",
+        "cancelled_steps": cancelled_steps,
+        "killed_in_flight": signalled,
+    })))
+}
+
+async fn api_run_pause(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    transition_run(&state.engine, &id, RunControl::Pause)?;
+    Ok(Json(
+        serde_json::json!({ "ok": true, "run_id": id, "status": "paused" }),
+    ))
+}
+
+async fn api_run_resume(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    transition_run(&state.engine, &id, RunControl::Resume)?;
+    Ok(Json(
+        serde_json::json!({ "ok": true, "run_id": id, "status": "running" }),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunControl {
+    Cancel,
+    Pause,
+    Resume,
+}
+
+/// Applies an operator transition to a run. Pause only stops further claims (the in-flight
+/// step finishes); cancel also marks every unfinished step `cancelled`. Returns the number of
+/// steps cancelled.
+fn transition_run(
+    engine: &Engine,
+    run_id: &str,
+    action: RunControl,
+) -> Result<usize, (axum::http::StatusCode, String)> {
+    let mut conn = engine.open().map_err(internal_error("engine.open"))?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|e| internal_error("db.transaction")(e.into()))?;
+    let status: Option<String> = tx
+        .query_row("SELECT status FROM runs WHERE id=?1", [run_id], |r| {
+            r.get(0)
+        })
+        .ok();
+    let Some(status) = status else {
+        return Err((axum::http::StatusCode::NOT_FOUND, "not_found".to_string()));
+    };
+    let allowed = match action {
+        RunControl::Cancel => !matches!(status.as_str(), "done" | "cancelled"),
+        RunControl::Pause => matches!(status.as_str(), "queued" | "running" | "awaiting_approval"),
+        RunControl::Resume => status == "paused",
+    };
+    if !allowed {
+        return Err((
+            axum::http::StatusCode::CONFLICT,
+            format!("invalid_transition: run is {status}"),
+        ));
+    }
+
+    let now = now_rfc3339();
+    let mut cancelled = 0usize;
+    let (new_status, kind) = match action {
+        RunControl::Cancel => {
+            cancelled = tx
+                .execute(
+                    "UPDATE steps SET status='cancelled', updated_at=?1
+                     WHERE run_id=?2 AND status NOT IN ('done','skipped','cancelled')",
+                    (&now, run_id),
+                )
+                .map_err(|e| internal_error("db.cancel_steps")(e.into()))?;
+            ("cancelled", "run.cancelled")
+        }
+        RunControl::Pause => ("paused", "run.paused"),
+        RunControl::Resume => {
+            let started: bool = tx
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM steps WHERE run_id=?1 AND status NOT IN ('queued','pending','waiting'))",
+                    [run_id],
+                    |r| r.get(0),
+                )
+                .map_err(|e| internal_error("db.resume_probe")(e.into()))?;
+            (if started { "running" } else { "queued" }, "run.resumed")
+        }
+    };
+    tx.execute(
+        "UPDATE runs SET status=?1, updated_at=?2 WHERE id=?3",
+        (new_status, &now, run_id),
+    )
+    .map_err(|e| internal_error("db.update_run")(e.into()))?;
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, ?2, ?3, ?4)",
+        (
+            now_ms_i64(),
+            kind,
+            run_id,
+            serde_json::json!({ "run_id": run_id, "from": status, "cancelled_steps": cancelled })
+                .to_string(),
+        ),
+    )
+    .map_err(|e| internal_error("db.insert_event")(e.into()))?;
+    tx.commit()
+        .map_err(|e| internal_error("db.commit")(e.into()))?;
+    Ok(cancelled)
 }
 
 #[derive(Debug, Deserialize)]
@@ -706,6 +1124,8 @@ struct PrCard {
     updated_at: String,
     title: String,
     changed_files: PrChangedSummary,
+    /// Set while the run is parked on an approval gate.
+    approval: Option<approval::PendingApproval>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -806,18 +1226,10 @@ async fn api_pr_feed(
         let pr_number = v
             .get("pr_number")
             .and_then(|x| x.as_i64())
-            .or_else(|| pr_url.as_deref().and_then(parse_pr_number_from_url));
+            .or_else(|| pr_url.as_deref().and_then(forge::pr_number_from_url));
 
         let changed_files = if let (Some(repo), Some(num)) = (repo.as_deref(), pr_number) {
-            match gh_pr_changed_files_summary(repo, &num.to_string()) {
-                Ok(v) => v,
-                Err(e) => PrChangedSummary {
-                    total_files: 0,
-                    sample: vec![],
-                    source: "fallback".to_string(),
-                    warning: Some(e),
-                },
-            }
+            pr_changed_files_summary(state.forges.resolve(repo).as_ref(), repo, num)
         } else {
             PrChangedSummary {
                 total_files: 0,
@@ -827,6 +1239,11 @@ async fn api_pr_feed(
             }
         };
 
+        let approval = if matches!(status.as_str(), "running" | "paused" | "awaiting_approval") {
+            approval::pending_approval(&conn, &run_id, &v)
+        } else {
+            None
+        };
         cards.push(PrCard {
             run_id,
             factory_id,
@@ -844,6 +1261,7 @@ async fn api_pr_feed(
                 .trim()
                 .to_string(),
             changed_files,
+            approval,
         });
     }
     Ok(Json(cards))
@@ -874,26 +1292,35 @@ async fn api_pr_feed_files(
         axum::http::StatusCode::BAD_REQUEST,
         "base_repo_missing".to_string(),
     ))?;
-    let pr_selector = ctx
+    let pr_number = ctx
         .get("pr_number")
         .and_then(|x| x.as_i64())
-        .map(|n| n.to_string())
         .or_else(|| {
             ctx.get("pr_url")
                 .and_then(|x| x.as_str())
-                .map(|s| s.to_string())
+                .and_then(forge::pr_number_from_url)
         })
         .ok_or((
             axum::http::StatusCode::BAD_REQUEST,
             "pr_missing".to_string(),
         ))?;
 
-    let files = gh_pr_file_snippets(
-        repo,
-        &pr_selector,
-        q.max_patch_chars.unwrap_or(1600).clamp(200, 8000),
-    )
-    .map_err(|e| (axum::http::StatusCode::FAILED_DEPENDENCY, e))?;
+    let max_patch_chars = q.max_patch_chars.unwrap_or(1600).clamp(200, 8000);
+    let forge = state.forges.clone();
+    let repo = repo.to_string();
+    let files =
+        tokio::task::spawn_blocking(move || forge.resolve(&repo).changed_files(&repo, pr_number))
+            .await
+            .map_err(|e| internal_error("forge.changed_files")(e.into()))?
+            .map_err(|e| (axum::http::StatusCode::FAILED_DEPENDENCY, e.to_string()))?
+            .into_iter()
+            .map(|f| PrFileView {
+                path: f.path,
+                additions: f.additions,
+                deletions: f.deletions,
+                snippet: f.patch.chars().take(max_patch_chars).collect(),
+            })
+            .collect();
     Ok(Json(files))
 }
 
@@ -972,7 +1399,7 @@ async fn api_pr_comment(
         let pr_number = v
             .get("pr_number")
             .and_then(|x| x.as_i64())
-            .or_else(|| pr_url.and_then(parse_pr_number_from_url));
+            .or_else(|| pr_url.and_then(forge::pr_number_from_url));
         if input.pr_url.as_deref() == pr_url
             || (input.pr_number.is_some() && input.pr_number == pr_number)
         {
@@ -1007,10 +1434,11 @@ async fn api_pr_comment(
             .ok();
         if let Some(last) = last_ts {
             let elapsed = now_ms_i64() - last;
-            if elapsed < 15_000 {
+            let min_interval = state.config.pr_comments.reemit_min_interval_ms;
+            if elapsed < min_interval {
                 return Err((
                     axum::http::StatusCode::TOO_MANY_REQUESTS,
-                    format!("rate_limited: retry_after_ms={}", 15_000 - elapsed),
+                    format!("rate_limited: retry_after_ms={}", min_interval - elapsed),
                 ));
             }
         }
@@ -1039,7 +1467,7 @@ async fn api_pr_comment(
         )
     })?;
 
-    let report = reemit_workers(&state.engine, base_id.as_deref())
+    let report = reemit_workers(&state.engine, base_id.as_deref(), true)
         .map_err(internal_error("reemit_workers"))?;
 
     Ok(Json(serde_json::json!({
@@ -1767,116 +2195,30 @@ fn render_doc_markdown(root: &DocNode) -> String {
     }
     out
 }
-fn parse_pr_number_from_url(url: &str) -> Option<i64> {
-    let parts: Vec<&str> = url.trim_end_matches('/').split('/').collect();
-    if parts.len() < 2 || parts[parts.len() - 2] != "pull" {
-        return None;
-    }
-    parts.last()?.parse::<i64>().ok()
-}
-
-fn gh_pr_changed_files_summary(repo: &str, selector: &str) -> Result<PrChangedSummary, String> {
-    let out = Command::new("gh")
-        .arg("pr")
-        .arg("view")
-        .arg(selector)
-        .arg("--json")
-        .arg("files")
-        .current_dir(repo)
-        .output()
-        .map_err(|_| "gh_missing: install gh and run gh auth login".to_string())?;
-    if !out.status.success() {
-        let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
-        if stderr.to_lowercase().contains("not logged")
-            || stderr.to_lowercase().contains("authentication")
-        {
-            return Err(format!("github_auth_required: {stderr}"));
-        }
-        return Err(format!("gh_pr_view_failed: {stderr}"));
+fn pr_changed_files_summary(forge: &dyn Forge, repo: &str, number: i64) -> PrChangedSummary {
+    match forge.changed_files(repo, number) {
+        Ok(files) => PrChangedSummary {
+            total_files: files.len(),
+            sample: files.into_iter().map(|f| f.path).take(5).collect(),
+            source: forge.name().to_string(),
+            warning: None,
+        },
+        Err(e) => PrChangedSummary {
+            total_files: 0,
+            sample: vec![],
+            source: "fallback".to_string(),
+            warning: Some(e.to_string()),
+        },
     }
-    let v: serde_json::Value =
-        serde_json::from_slice(&out.stdout).unwrap_or_else(|_| serde_json::json!({}));
-    let files = v
-        .get("files")
-        .and_then(|x| x.as_array())
-        .cloned()
-        .unwrap_or_default();
-    Ok(PrChangedSummary {
-        total_files: files.len(),
-        sample: files
-            .iter()
-            .filter_map(|f| {
-                f.get("path")
-                    .and_then(|x| x.as_str())
-                    .map(|s| s.to_string())
-            })
-            .take(5)
-            .collect(),
-        source: "gh".to_string(),
-        warning: None,
-    })
-}
-
-fn gh_pr_file_snippets(
-    repo: &str,
-    selector: &str,
-    max_patch_chars: usize,
-) -> Result<Vec<PrFileView>, String> {
-    let out = Command::new("gh")
-        .arg("pr")
-        .arg("view")
-        .arg(selector)
-        .arg("--json")
-        .arg("files")
-        .current_dir(repo)
-        .output()
-        .map_err(|_| "gh_missing: install gh and run gh auth login".to_string())?;
-    if !out.status.success() {
-        let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
-        if stderr.to_lowercase().contains("not logged")
-            || stderr.to_lowercase().contains("authentication")
-        {
-            return Err(format!("github_auth_required: {stderr}"));
-        }
-        if stderr.to_lowercase().contains("forbidden")
-            || stderr.to_lowercase().contains("resource not accessible")
-        {
-            return Err(format!("github_permission_required: {stderr}"));
-        }
-        return Err(format!("gh_pr_view_failed: {stderr}"));
-    }
-    let v: serde_json::Value =
-        serde_json::from_slice(&out.stdout).unwrap_or_else(|_| serde_json::json!({}));
-    let files = v
-        .get("files")
-        .and_then(|x| x.as_array())
-        .cloned()
-        .unwrap_or_default();
-    Ok(files
-        .into_iter()
-        .map(|f| PrFileView {
-            path: f
-                .get("path")
-                .and_then(|x| x.as_str())
-                .unwrap_or("")
-                .to_string(),
-            additions: f.get("additions").and_then(|x| x.as_i64()).unwrap_or(0),
-            deletions: f.get("deletions").and_then(|x| x.as_i64()).unwrap_or(0),
-            snippet: f
-                .get("patch")
-                .and_then(|x| x.as_str())
-                .unwrap_or("")
-                .chars()
-                .take(max_patch_chars)
-                .collect(),
-        })
-        .collect())
 }
 
 #[derive(Debug, Deserialize)]
 struct FeatureBuildInput {
     entity_id: String,
     prompt: String,
+    /// Overrides the forge's `workflow_id` for this run.
+    #[serde(default)]
+    workflow_id: Option<String>,
 }
 
 async fn api_feature_build(
@@ -1927,6 +2269,26 @@ async fn api_feature_build(
             "missing_base".to_string(),
         ));
     };
+    // Workflow: explicit request > forge setting > feature-dev.
+    let workflow_id = input
+        .workflow_id
+        .as_deref()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .or_else(|| {
+            parse_payload(&factory.payload_json)
+                .get("workflow_id")
+                .and_then(|v| v.as_str())
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        })
+        .unwrap_or_else(|| workflow::DEFAULT_WORKFLOW_ID.to_string());
+    let wf = workflow::load_workflow(&state.engine, &workflow_id)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()))?
+        .ok_or((
+            axum::http::StatusCode::BAD_REQUEST,
+            "unknown_workflow".to_string(),
+        ))?;
     let base_payload: serde_json::Value =
         serde_json::from_str(&base.payload_json).unwrap_or_else(|_| serde_json::json!({}));
     let repo_path = base_payload
@@ -1946,11 +2308,8 @@ async fn api_feature_build(
         ));
     }
 
-    // Create a new worktree for this factory run under ~/.openclaw/workspace.
-    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-    let ws_root = std::path::Path::new(&home)
-        .join(".openclaw")
-        .join("workspace");
+    // Create a new worktree for this factory run under the base's worktree root.
+    let ws_root = state.config.workspace.worktree_root_for(&base_payload);
     let wt_dir = ws_root.join(format!("clawdorio-{}", run_id));
     let wt_dir_s = wt_dir.to_string_lossy().to_string();
     let branch = format!("clawdorio/{}", run_id);
@@ -1987,15 +2346,15 @@ async fn api_feature_build(
         ));
     }
 
-    let ctx = serde_json::json!({
+    let ctx_value = serde_json::json!({
         "entity_id": input.entity_id,
         "base_id": base.id,
         "base_repo_path": repo_path.clone(),
         "worktree_path": wt_dir_s.clone(),
         "branch": branch.clone(),
         "prompt": task,
-    })
-    .to_string();
+    });
+    let ctx = ctx_value.to_string();
 
     let mut conn = state.engine.open().map_err(internal_error("engine.open"))?;
     let tx = conn.transaction().map_err(|e| {
@@ -2007,8 +2366,8 @@ async fn api_feature_build(
 
     tx.execute(
         "INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at)
-         VALUES (?1, 'feature-dev', ?2, 'queued', ?3, ?4, ?5, ?5)",
-        (&run_id, &task, &input.entity_id, &ctx, &ts),
+         VALUES (?1, ?2, ?3, 'queued', ?4, ?5, ?6, ?6)",
+        (&run_id, &wf.id, &task, &input.entity_id, &ctx, &ts),
     )
     .map_err(|e| {
         (
@@ -2019,12 +2378,12 @@ async fn api_feature_build(
 
     // Persist worktree row (actual observed machine state).
     let wt_id = format!("wt-{}", now.unix_timestamp_nanos());
-    let desired = serde_json::json!({ "kind": "worktree", "base_repo_path": repo_path.clone(), "branch": branch.clone() }).to_string();
+    let desired = serde_json::json!({ "kind": "worktree", "state": "present", "run_id": run_id.clone(), "base_repo_path": repo_path.clone(), "path": wt_dir_s.clone(), "branch": branch.clone() }).to_string();
     let observed = serde_json::json!({ "path": wt_dir_s.clone(), "branch": branch.clone(), "base_repo_path": repo_path.clone() }).to_string();
     tx.execute(
-        "INSERT INTO worktrees (id, repo_path, desired_json, observed_json, observed_at_ms, updated_at_ms, rev)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?5, 0)",
-        (&wt_id, &repo_path, &desired, &observed, now_ms),
+        "INSERT INTO worktrees (id, repo_path, run_id, desired_json, observed_json, observed_at_ms, updated_at_ms, rev)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, 0)",
+        (&wt_id, &repo_path, &run_id, &desired, &observed, now_ms),
     )
     .map_err(|e| {
         (
@@ -2033,30 +2392,58 @@ async fn api_feature_build(
         )
     })?;
 
-    // Seed Antfarm-like 7-agent chain (execution is driven by listeners; DB is the queue).
-    let steps = [
-        ("plan", "feature-dev/planner"),
-        ("setup", "feature-dev/setup"),
-        ("implement", "feature-dev/developer"),
-        ("verify", "feature-dev/verifier"),
-        ("test", "feature-dev/tester"),
-        ("pr", "internal/pr"),
-        ("review", "feature-dev/reviewer"),
-    ];
-    for (idx, (step_id, agent_id)) in steps.iter().enumerate() {
-        let step_row_id = format!("step-{}-{}", now.unix_timestamp_nanos(), idx);
+    // Seed the workflow's step chain (execution is driven by listeners; DB is the queue).
+    // Each step row snapshots its prompt template so later workflow edits don't leak into this run.
+    // Bases can route their agents to a different runtime by namespacing agent ids
+    // (e.g. `agent_prefix: "codex"` -> `codex/feature-dev/developer`); see `executor`.
+    let agent_prefix = base_payload
+        .get("agent_prefix")
+        .and_then(|v| v.as_str())
+        .map(|s| s.trim().trim_end_matches('/'))
+        .filter(|s| !s.is_empty());
+    // Steps are inserted in dependency order with explicit `depends_on_json` edges (row ids);
+    // workflows without `depends_on` get a linear chain.
+    let order = wf
+        .topo_order()
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()))?;
+    let deps = wf.dependencies();
+    let row_ids: Vec<String> = (0..wf.steps.len())
+        .map(|idx| format!("step-{}-{}", now.unix_timestamp_nanos(), idx))
+        .collect();
+    for (step_index, &idx) in order.iter().enumerate() {
+        let step = &wf.steps[idx];
+        let step_row_id = &row_ids[idx];
+        let depends_on: Vec<&String> = deps[idx].iter().map(|&d| &row_ids[d]).collect();
+        let agent_id = match agent_prefix {
+            Some(p) if !step.agent.starts_with("internal/") => format!("{p}/{}", step.agent),
+            _ => step.agent.clone(),
+        };
+        let mut input_json = ctx_value.clone();
+        input_json["workflow_id"] = serde_json::Value::String(wf.id.clone());
+        if let Some(tpl) = &step.prompt {
+            input_json["prompt_template"] = serde_json::Value::String(tpl.clone());
+        }
+        if step.per_story {
+            input_json["per_story"] = serde_json::Value::Bool(true);
+        }
+        if let Some(retry) = &step.retry {
+            input_json["retry"] = serde_json::json!(retry);
+        }
+        let timeout_sec = step.timeout_sec.or(wf.timeout_sec).map(|t| t as i64);
         tx.execute(
-            "INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text, created_at, updated_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, 'queued', ?6, NULL, ?7, ?7)",
-            (
-                &step_row_id,
+            "INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text, timeout_sec, depends_on_json, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 'queued', ?6, NULL, ?7, ?8, ?9, ?9)",
+            rusqlite::params![
+                step_row_id,
                 &run_id,
-                *step_id,
-                *agent_id,
-                idx as i64,
-                ctx.clone(),
+                &step.id,
+                &agent_id,
+                step_index as i64,
+                input_json.to_string(),
+                timeout_sec,
+                serde_json::json!(depends_on).to_string(),
                 &ts,
-            ),
+            ],
         )
         .map_err(|e| {
             (
@@ -2093,6 +2480,7 @@ async fn api_feature_build(
     Ok(Json(serde_json::json!({
         "ok": true,
         "run_id": run_id,
+        "workflow_id": wf.id,
         "worktree_path": wt_dir_s,
     })))
 }
@@ -2617,7 +3005,8 @@ fn relevance_score(node: &SkillNodeRow, terms: &[String], depth: usize) -> i64 {
 async fn api_workers_reemit_global(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
 ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let report = reemit_workers(&state.engine, None).map_err(internal_error("reemit_workers"))?;
+    let report =
+        reemit_workers(&state.engine, None, true).map_err(internal_error("reemit_workers"))?;
     Ok(Json(
         serde_json::json!({ "ok": true, "scope": "global", "report": report }),
     ))
@@ -2627,7 +3016,7 @@ async fn api_workers_reemit_base(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
     axum::extract::Path(base_id): axum::extract::Path<String>,
 ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let report = reemit_workers(&state.engine, Some(base_id.as_str()))
+    let report = reemit_workers(&state.engine, Some(base_id.as_str()), true)
         .map_err(internal_error("reemit_workers"))?;
     Ok(Json(
         serde_json::json!({ "ok": true, "scope": "base", "base_id": base_id, "report": report }),
@@ -2687,92 +3076,123 @@ async fn api_base_auto_rebase_patch(
     }))
 }
 
-async fn api_bases_sync_now(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Path(base_id): axum::extract::Path<String>,
-) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let queued = queue_base_rebase_sweep(&state.engine, &base_id, "manual.sync_now", None)
-        .map_err(internal_error("queue_base_rebase_sweep"))?;
-    Ok(Json(
-        serde_json::json!({ "ok": true, "base_id": base_id, "queued": queued }),
-    ))
+#[derive(Debug, Serialize)]
+struct BaseWorkspaceView {
+    /// The base's own override, if set.
+    worktree_root: Option<String>,
+    /// Where new run worktrees for this base are created.
+    effective_worktree_root: String,
 }
 
-async fn api_github_webhook(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    headers: HeaderMap,
-    Json(payload): Json<serde_json::Value>,
-) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let event = headers
-        .get("x-github-event")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("");
+#[derive(Debug, Deserialize)]
+struct BaseWorkspacePatch {
+    /// Absolute directory; empty or `null` clears the override.
+    worktree_root: Option<String>,
+}
 
-    let repo_full = payload
-        .get("repository")
-        .and_then(|r| r.get("full_name"))
-        .and_then(|v| v.as_str())
-        .unwrap_or("");
+fn base_workspace_view(state: &AppState, payload: &serde_json::Value) -> BaseWorkspaceView {
+    BaseWorkspaceView {
+        worktree_root: payload
+            .get("worktree_root")
+            .and_then(|v| v.as_str())
+            .map(str::to_string),
+        effective_worktree_root: state
+            .config
+            .workspace
+            .worktree_root_for(payload)
+            .to_string_lossy()
+            .to_string(),
+    }
+}
 
-    let mut queued = 0usize;
+async fn api_base_workspace_get(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(base_id): axum::extract::Path<String>,
+) -> Result<Json<BaseWorkspaceView>, (axum::http::StatusCode, String)> {
+    let ent = find_base_entity(&state.engine, &base_id)?;
+    Ok(Json(base_workspace_view(
+        &state,
+        &parse_payload(&ent.payload_json),
+    )))
+}
 
-    if event == "push" {
-        let ref_name = payload.get("ref").and_then(|v| v.as_str()).unwrap_or("");
-        let after = payload.get("after").and_then(|v| v.as_str());
-        for base in matching_bases_by_repo(&state.engine, repo_full)
-            .map_err(internal_error("matching_bases_by_repo"))?
-        {
-            let default = detect_default_branch(
-                &repo_path_from_payload(&parse_payload(&base.payload_json)).unwrap_or_default(),
-            )
-            .unwrap_or_else(|_| "main".to_string());
-            if ref_name == format!("refs/heads/{default}") {
-                if queue_base_rebase_sweep(&state.engine, &base.id, "webhook.push", after)
-                    .map_err(internal_error("queue_base_rebase_sweep"))?
-                {
-                    queued += 1;
-                }
+async fn api_base_workspace_patch(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(base_id): axum::extract::Path<String>,
+    Json(input): Json<BaseWorkspacePatch>,
+) -> Result<Json<BaseWorkspaceView>, (axum::http::StatusCode, String)> {
+    let ent = find_base_entity(&state.engine, &base_id)?;
+    let mut payload = parse_payload(&ent.payload_json);
+    match input
+        .worktree_root
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        Some(root) => {
+            if !workspace::expand_home(std::path::Path::new(root)).is_absolute() {
+                return Err((
+                    axum::http::StatusCode::BAD_REQUEST,
+                    "worktree_root must be an absolute path".to_string(),
+                ));
             }
+            payload["worktree_root"] = serde_json::Value::String(root.to_string());
         }
-    }
-
-    if event == "pull_request" {
-        let action = payload.get("action").and_then(|v| v.as_str()).unwrap_or("");
-        let merged = payload
-            .get("pull_request")
-            .and_then(|v| v.get("merged"))
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-        let should = matches!(action, "synchronize" | "opened" | "reopened")
-            || (action == "closed" && merged);
-        if should {
-            let upstream_sha = payload
-                .get("pull_request")
-                .and_then(|v| v.get("base"))
-                .and_then(|v| v.get("sha"))
-                .and_then(|v| v.as_str());
-            for base in matching_bases_by_repo(&state.engine, repo_full)
-                .map_err(internal_error("matching_bases_by_repo"))?
-            {
-                if queue_base_rebase_sweep(
-                    &state.engine,
-                    &base.id,
-                    "webhook.pull_request",
-                    upstream_sha,
-                )
-                .map_err(internal_error("queue_base_rebase_sweep"))?
-                {
-                    queued += 1;
-                }
+        None => {
+            if let Some(obj) = payload.as_object_mut() {
+                obj.remove("worktree_root");
             }
         }
     }
+    state
+        .engine
+        .update_entity_payload(&base_id, &payload.to_string())
+        .map_err(internal_error("engine.update_entity_payload"))?;
+    Ok(Json(base_workspace_view(&state, &payload)))
+}
 
+async fn api_bases_sync_now(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(base_id): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    let queued = queue_base_rebase_sweep(&state.engine, &base_id, "manual.sync_now", None)
+        .map_err(internal_error("queue_base_rebase_sweep"))?;
     Ok(Json(
-        serde_json::json!({"ok": true, "event": event, "queued": queued}),
+        serde_json::json!({ "ok": true, "base_id": base_id, "queued": queued }),
     ))
 }
 
+/// Flags runs whose PR was merged so their worktrees become eligible for GC.
+fn mark_runs_pr_merged(engine: &Engine, pr_url: &str) -> anyhow::Result<usize> {
+    let mut conn = engine.open()?;
+    let tx = conn.transaction()?;
+    let runs: Vec<(String, String)> = {
+        let mut stmt = tx.prepare(
+            "SELECT id, context_json FROM runs WHERE json_extract(context_json, '$.pr_url') = ?1",
+        )?;
+        let rows = stmt.query_map([pr_url], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+    for (run_id, ctx) in &runs {
+        let mut v = parse_payload(ctx);
+        v["pr_merged"] = serde_json::Value::Bool(true);
+        tx.execute(
+            "UPDATE runs SET context_json=?1, updated_at=?2 WHERE id=?3",
+            (v.to_string(), now_rfc3339(), run_id),
+        )?;
+        tx.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.pr_merged', ?2, ?3)",
+            (
+                now_ms_i64(),
+                run_id,
+                serde_json::json!({ "run_id": run_id, "pr_url": pr_url }).to_string(),
+            ),
+        )?;
+    }
+    tx.commit()?;
+    Ok(runs.len())
+}
+
 #[derive(Debug, Serialize)]
 struct ReemitReport {
     scanned_runs: usize,
@@ -2781,7 +3201,14 @@ struct ReemitReport {
     touched_runs: usize,
 }
 
-fn reemit_workers(engine: &Engine, base_id: Option<&str>) -> anyhow::Result<ReemitReport> {
+/// Re-queues stuck work. `requeue_failed` also revives failed runs, starting their failed steps
+/// over with a fresh attempt count; only operator requests set it, so the idle safety net never
+/// overrides a retry policy that gave up.
+fn reemit_workers(
+    engine: &Engine,
+    base_id: Option<&str>,
+    requeue_failed: bool,
+) -> anyhow::Result<ReemitReport> {
     let mut conn = engine.open()?;
     let tx = conn.transaction()?;
     let mut scanned_runs = 0usize;
@@ -2791,9 +3218,13 @@ fn reemit_workers(engine: &Engine, base_id: Option<&str>) -> anyhow::Result<Reem
 
     let run_rows: Vec<(String, Option<String>, String)> = {
         let mut stmt = tx.prepare(
-            "SELECT id, entity_id, status FROM runs WHERE status IN ('queued','running','failed') ORDER BY created_at ASC",
+            "SELECT id, entity_id, status FROM runs
+             WHERE status IN ('queued','running') OR (?1 AND status='failed')
+             ORDER BY created_at ASC",
         )?;
-        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        let rows = stmt.query_map([requeue_failed], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
         rows.filter_map(Result::ok).collect()
     };
 
@@ -2837,34 +3268,35 @@ fn reemit_workers(engine: &Engine, base_id: Option<&str>) -> anyhow::Result<Reem
                  WHERE run_id=?2 AND status IN ('pending','waiting')",
                 (&now_rfc3339(), &run_id),
             )?;
-            queued_steps += c as usize;
+            queued_steps += c;
         } else {
-            // stale-running fallback: allow operator to re-emit and recover crashed workers
+            // stale-running fallback: recover crashed workers, but never re-queue a step whose
+            // worker is still heartbeating (that would double-run it).
             let c = tx.execute(
-                "UPDATE steps SET status='queued', updated_at=?1
-                 WHERE run_id=?2 AND status='running'",
-                (&now_rfc3339(), &run_id),
+                "UPDATE steps SET status='queued', worker_id=NULL, updated_at=?1
+                 WHERE run_id=?2 AND status='running'
+                   AND (heartbeat_at_ms IS NULL OR heartbeat_at_ms < ?3)",
+                (&now_rfc3339(), &run_id, now_ms_i64() - STEP_STALE_AFTER_MS),
             )?;
             if c > 0 {
-                reset_running_steps += c as usize;
-                queued_steps += c as usize;
+                reset_running_steps += c;
+                queued_steps += c;
             }
         }
 
-        let has_failed: i64 = tx.query_row(
-            "SELECT COUNT(*) FROM steps WHERE run_id=?1 AND status='failed'",
-            [&run_id],
-            |r| r.get(0),
-        )?;
-        if has_failed > 0 {
-            let c = tx.execute(
-                "UPDATE steps SET status='queued', output_text=NULL, updated_at=?1
-                 WHERE run_id=?2 AND step_index >= (
-                    SELECT COALESCE(MIN(step_index), 0) FROM steps WHERE run_id=?2 AND status='failed'
-                 )",
-                (&now_rfc3339(), &run_id),
-            )?;
-            queued_steps += c as usize;
+        if requeue_failed {
+            // Failed steps and what depends on them; independent DAG branches keep their results
+            // and in-flight steps are left to their workers.
+            let rows = load_step_edges(&tx, &run_id)?;
+            let failed: Vec<&StepEdges> = rows.iter().filter(|r| r.status == "failed").collect();
+            for id in with_descendants(&rows, &failed) {
+                queued_steps += tx.execute(
+                    "UPDATE steps SET status='queued', output_text=NULL, worker_id=NULL, attempt=0,
+                            next_attempt_at_ms=NULL, updated_at=?1
+                     WHERE id=?2 AND status != 'running'",
+                    (&now_rfc3339(), &id),
+                )?;
+            }
         }
 
         if run_status != "done" {
@@ -2902,6 +3334,57 @@ fn reemit_workers(engine: &Engine, base_id: Option<&str>) -> anyhow::Result<Reem
     })
 }
 
+/// Re-queues `running` steps whose heartbeat is older than `stale_after_ms` (or missing),
+/// skipping steps this process is executing. Returns the number of steps reaped.
+fn reap_stale_steps(
+    engine: &Engine,
+    in_flight: &HashSet<String>,
+    stale_after_ms: i64,
+) -> anyhow::Result<usize> {
+    let mut conn = engine.open()?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let cutoff = now_ms_i64() - stale_after_ms;
+    let stale: Vec<(String, String, String, Option<String>)> = {
+        let mut stmt = tx.prepare(
+            "SELECT s.id, s.run_id, s.step_id, s.worker_id
+             FROM steps s
+             JOIN runs r ON r.id = s.run_id
+             WHERE s.status='running'
+               AND r.status IN ('queued','running','paused')
+               AND (s.heartbeat_at_ms IS NULL OR s.heartbeat_at_ms < ?1)",
+        )?;
+        let rows = stmt.query_map([cutoff], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        })?;
+        rows.filter_map(Result::ok)
+            .filter(|(id, ..)| !in_flight.contains(id))
+            .collect()
+    };
+    let now = now_rfc3339();
+    for (id, run_id, step_id, worker_id) in &stale {
+        tx.execute(
+            "UPDATE steps SET status='queued', worker_id=NULL, updated_at=?1 WHERE id=?2 AND status='running'",
+            (&now, id),
+        )?;
+        tx.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'step.reaped', ?2, ?3)",
+            (
+                now_ms_i64(),
+                id,
+                serde_json::json!({
+                    "run_id": run_id,
+                    "step_id": step_id,
+                    "worker_id": worker_id,
+                    "stale_after_ms": stale_after_ms,
+                })
+                .to_string(),
+            ),
+        )?;
+    }
+    tx.commit()?;
+    Ok(stale.len())
+}
+
 fn parse_payload(payload_json: &str) -> serde_json::Value {
     serde_json::from_str(payload_json).unwrap_or_else(|_| serde_json::json!({}))
 }
@@ -2959,8 +3442,8 @@ fn matching_bases_by_repo(engine: &Engine, full_name: &str) -> anyhow::Result<Ve
         let Some(repo_path) = repo_path_from_payload(&payload) else {
             continue;
         };
-        if let Ok(name) = repo_full_name(&repo_path) {
-            if name == full_name {
+        if let Ok(remote) = forge::origin_remote(&repo_path) {
+            if remote.path == full_name {
                 out.push(base);
             }
         }
@@ -2968,32 +3451,6 @@ fn matching_bases_by_repo(engine: &Engine, full_name: &str) -> anyhow::Result<Ve
     Ok(out)
 }
 
-fn repo_full_name(repo: &str) -> anyhow::Result<String> {
-    let out = Command::new("git")
-        .arg("-C")
-        .arg(repo)
-        .arg("remote")
-        .arg("get-url")
-        .arg("origin")
-        .output()?;
-    if !out.status.success() {
-        anyhow::bail!("git_remote_missing");
-    }
-    let url = String::from_utf8_lossy(&out.stdout).trim().to_string();
-    parse_github_full_name(&url).ok_or_else(|| anyhow::anyhow!("repo_parse_failed"))
-}
-
-fn parse_github_full_name(url: &str) -> Option<String> {
-    let u = url.trim().trim_end_matches(".git");
-    if let Some(rest) = u.strip_prefix("git@github.com:") {
-        return Some(rest.to_string());
-    }
-    if let Some(i) = u.find("github.com/") {
-        return Some(u[(i + "github.com/".len())..].to_string());
-    }
-    None
-}
-
 fn queue_base_rebase_sweep(
     engine: &Engine,
     base_id: &str,
@@ -3194,6 +3651,7 @@ fn overlaps_any_belt(belts: &[Belt], x: i64, y: i64, w: i64, h: i64) -> bool {
     false
 }
 
+#[allow(clippy::too_many_arguments)]
 fn rects_overlap(ax: i64, ay: i64, aw: i64, ah: i64, bx: i64, by: i64, bw: i64, bh: i64) -> bool {
     let a_r = ax + aw;
     let a_b = ay + ah;
@@ -3492,16 +3950,39 @@ pub async fn serve_listener(
     db_path: PathBuf,
     shutdown: impl std::future::Future<Output = ()> + Send + 'static,
 ) -> anyhow::Result<SocketAddr> {
+    let config = config::ServerConfig::load(None)?.0.finish()?;
+    let engine = Engine::new(db_path);
     let state = AppState {
-        engine: Engine::new(db_path),
+        executors: Arc::new(ExecutorRegistry::from_env()?),
+        workers: Arc::new(WorkerPool::new(WorkerLimits::new(
+            config.workers.max_workers,
+            config.workers.max_workers_per_base,
+        ))),
+        forges: Arc::new(ForgeRegistry::from_config(&config.forge, &engine)),
+        engine,
+        config: Arc::new(config),
     };
+    serve_state(listener, state, shutdown).await
+}
+
+/// Like [`serve_listener`], with a caller-built state (executors, worker limits).
+pub async fn serve_state(
+    listener: tokio::net::TcpListener,
+    state: AppState,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<SocketAddr> {
+    workflow::seed_builtin_workflows(&state.engine)?;
     // Best-effort DB repair: backfill belt paths so belts can occupy tiles even for older rows.
     if let Err(_e) = repair_belt_paths(&state.engine) {
         // Belts are derivable; never fail startup on this.
     }
-    // Background runner: executes pending run steps by invoking OpenClaw agents + local PR tooling.
+    // Background runner: executes pending run steps via the executor registry + local PR tooling.
     let eng = state.engine.clone();
-    tokio::spawn(async move { runloop(eng).await });
+    let executors = state.executors.clone();
+    let forges = state.forges.clone();
+    let workers = state.workers.clone();
+    let config = state.config.clone();
+    tokio::spawn(async move { runloop(eng, executors, forges, workers, config).await });
     let app = build_router(state);
     let addr = listener.local_addr()?;
     axum::serve(
@@ -3513,32 +3994,101 @@ pub async fn serve_listener(
     Ok(addr)
 }
 
-async fn runloop(engine: Engine) {
+async fn runloop(
+    engine: Engine,
+    executors: Arc<ExecutorRegistry>,
+    forges: Arc<ForgeRegistry>,
+    workers: Arc<WorkerPool>,
+    config: Arc<config::ServerConfig>,
+) {
+    let poll = std::time::Duration::from_millis(config.workers.poll_interval_ms);
+    for n in 0..workers.limits.max_workers {
+        let (eng, execs, pool) = (engine.clone(), executors.clone(), workers.clone());
+        let forges = forges.clone();
+        let worker_id = workers.worker_id(n);
+        tokio::spawn(async move { step_worker(eng, execs, forges, pool, worker_id, poll).await });
+    }
+
+    let reap_ticks = config.workers.ticks(REAPER_INTERVAL_MS);
+    let worktree_ticks = config.workers.ticks(WORKTREE_RECONCILE_MS);
+    let merge_queue_ticks = config.workers.ticks(MERGE_QUEUE_TICK_MS);
+    let reemit_ticks = config.workers.ticks(config.workers.reemit_idle_ms);
+    let rebase_ticks = config.workers.ticks(config.workers.rebase_check_idle_ms);
+    let mut ticks: u32 = 0;
     let mut idle_loops: u32 = 0;
     loop {
-        // All DB + process execution work is blocking; keep it off the async runtime.
-        let eng = engine.clone();
-        let ran = tokio::task::spawn_blocking(move || run_one_step_blocking(&eng).unwrap_or(false))
-            .await
-            .unwrap_or(false);
-
-        if ran {
+        tokio::time::sleep(poll).await;
+        ticks = ticks.wrapping_add(1);
+        // Crash recovery: re-queue running steps whose worker stopped heartbeating.
+        if ticks.is_multiple_of(reap_ticks) {
+            let (eng, pool) = (engine.clone(), workers.clone());
+            let _ = tokio::task::spawn_blocking(move || {
+                reap_stale_steps(&eng, &pool.in_flight_ids(), STEP_STALE_AFTER_MS)
+            })
+            .await;
+        }
+        // Worktree GC: prune checkouts of finished runs past retention, restore missing ones.
+        if ticks.is_multiple_of(worktree_ticks) {
+            let (eng, policy) = (
+                engine.clone(),
+                config.worktrees.gc_policy(&config.workspace),
+            );
+            let _ = tokio::task::spawn_blocking(move || {
+                worktrees::reconcile(&eng, &policy, now_ms_i64())
+            })
+            .await;
+        }
+        if ticks.is_multiple_of(merge_queue_ticks) {
+            let (eng, config) = (engine.clone(), config.clone());
+            let _ =
+                tokio::task::spawn_blocking(move || merge_queue::tick(&eng, &config.merge_queue))
+                    .await;
+        }
+        if workers.take_ran() || workers.busy() > 0 {
             idle_loops = 0;
         } else {
             idle_loops = idle_loops.saturating_add(1);
             // Safety net: periodically reemit queued/pending work if workers appear stuck.
-            if idle_loops % 30 == 0 {
+            if idle_loops.is_multiple_of(reemit_ticks) {
                 let eng = engine.clone();
-                let _ = tokio::task::spawn_blocking(move || reemit_workers(&eng, None)).await;
+                let _ =
+                    tokio::task::spawn_blocking(move || reemit_workers(&eng, None, false)).await;
             }
         }
 
-        if idle_loops % 20 == 0 {
+        if idle_loops.is_multiple_of(rebase_ticks) {
             let eng = engine.clone();
             let _ = tokio::task::spawn_blocking(move || periodic_rebase_reconciler(&eng)).await;
         }
+    }
+}
 
-        tokio::time::sleep(std::time::Duration::from_millis(700)).await;
+/// One pool slot: claim a step (respecting the per-base cap), execute it, repeat.
+async fn step_worker(
+    engine: Engine,
+    executors: Arc<ExecutorRegistry>,
+    forges: Arc<ForgeRegistry>,
+    workers: Arc<WorkerPool>,
+    worker_id: String,
+    poll: std::time::Duration,
+) {
+    loop {
+        // All DB + process execution work is blocking; keep it off the async runtime.
+        let (eng, execs, forges, pool, wid) = (
+            engine.clone(),
+            executors.clone(),
+            forges.clone(),
+            workers.clone(),
+            worker_id.clone(),
+        );
+        let ran = tokio::task::spawn_blocking(move || {
+            run_one_step_blocking(&eng, &execs, &forges, &pool, &wid).unwrap_or(false)
+        })
+        .await
+        .unwrap_or(false);
+        if !ran {
+            tokio::time::sleep(poll).await;
+        }
     }
 }
 
@@ -3603,78 +4153,161 @@ struct PendingStep {
     agent_id: String,
     task: String,
     context_json: String,
+    input_json: String,
+    timeout_sec: u64,
+    /// The row's `attempt` once claimed; finalizing only touches the row while it still matches.
+    attempt: i64,
 }
 
-fn run_one_step_blocking(engine: &Engine) -> anyhow::Result<bool> {
-    let Some(step) = claim_next_step(engine)? else {
+fn run_one_step_blocking(
+    engine: &Engine,
+    executors: &ExecutorRegistry,
+    forges: &ForgeRegistry,
+    workers: &WorkerPool,
+    worker_id: &str,
+) -> anyhow::Result<bool> {
+    let Some(step) = claim_next_step(engine, &workers.limits, worker_id)? else {
         return Ok(false);
     };
-    let res = execute_step_blocking(engine, &step);
+    if step.agent_id == approval::APPROVAL_AGENT {
+        approval::park(engine, &step)?;
+        return Ok(true);
+    }
+    let busy = workers.enter(&step.step_row_id, &step.run_id);
+    let heartbeat = Heartbeat::start(
+        engine.clone(),
+        step.step_row_id.clone(),
+        worker_id.to_string(),
+        step.timeout_sec,
+        busy.token.clone(),
+    );
+    let res = execute_step_blocking(engine, executors, forges, &step, &busy.token);
+    let timed_out = heartbeat.finish();
     match res {
-        Ok(out) => finalize_step_done(engine, &step, &out)?,
+        // Internal steps return a URL or summary, not an agent reply.
+        Ok(out) if step.agent_id.starts_with("internal/") => {
+            finalize_step_done(engine, &step, &out, None)?
+        }
+        Ok(out) => {
+            let reply = StepReply::parse(&out);
+            match reply.failure() {
+                None => finalize_step_done(engine, &step, &out, Some(&reply))?,
+                Some(err) => {
+                    record_step_outputs(&engine.open()?, &step, &reply)?;
+                    finalize_step_failed(engine, &step, &format!("{err}\n\n{out}"))?
+                }
+            }
+        }
+        Err(_) if timed_out => finalize_step_failed(
+            engine,
+            &step,
+            &format!("step_timeout: exceeded {}s", step.timeout_sec),
+        )?,
         Err(e) => finalize_step_failed(engine, &step, &e.to_string())?,
     }
     Ok(true)
 }
 
-fn claim_next_step(engine: &Engine) -> anyhow::Result<Option<PendingStep>> {
-    let mut conn = engine.open()?;
-    let tx = conn.transaction()?;
-
-    // Claim the next runnable step (pending, no earlier steps unfinished, and no step already running for the run).
-    let step: Option<PendingStep> = {
-        let mut stmt = tx.prepare(
-            r#"
-SELECT s.id, s.run_id, s.step_id, s.agent_id, r.task, r.context_json
+/// Claim the next runnable step (pending, its dependencies finished, and the run's base below its
+/// concurrency cap). Independent steps of one run may run concurrently.
+fn select_runnable_step(
+    tx: &rusqlite::Transaction,
+    limits: &WorkerLimits,
+) -> anyhow::Result<Option<PendingStep>> {
+    let mut stmt = tx.prepare(
+        r#"
+SELECT s.id, s.run_id, s.step_id, s.agent_id, r.task, r.context_json, s.input_json, s.timeout_sec,
+  s.attempt
 FROM steps s
 JOIN runs r ON r.id = s.run_id
 WHERE s.status IN ('queued','pending')
   AND r.status IN ('queued','running')
-  AND NOT EXISTS (
+  AND (s.next_attempt_at_ms IS NULL OR s.next_attempt_at_ms <= ?2)
+  AND CASE WHEN s.depends_on_json IS NULL THEN NOT EXISTS (
     SELECT 1 FROM steps s2
     WHERE s2.run_id = s.run_id
       AND s2.step_index < s.step_index
       AND s2.status NOT IN ('done','skipped')
-  )
-  AND NOT EXISTS (
-    SELECT 1 FROM steps s3
-    WHERE s3.run_id = s.run_id
-      AND s3.status = 'running'
-  )
+  ) ELSE NOT EXISTS (
+    -- DAG runs: wait on the listed step rows only.
+    SELECT 1 FROM json_each(s.depends_on_json) d
+    JOIN steps s5 ON s5.id = d.value
+    WHERE s5.status NOT IN ('done','skipped')
+  ) END
+  AND (
+    SELECT COUNT(*) FROM steps s4
+    JOIN runs r4 ON r4.id = s4.run_id
+    WHERE s4.status = 'running'
+      -- Runs group by base; runs without a base are their own group.
+      AND COALESCE(json_extract(r4.context_json, '$.base_id'), r4.entity_id, r4.id)
+        = COALESCE(json_extract(r.context_json, '$.base_id'), r.entity_id, r.id)
+  ) < ?1
 ORDER BY r.created_at ASC, s.step_index ASC
 LIMIT 1
 "#,
-        )?;
+    )?;
+
+    let mut rows = stmt.query([limits.max_per_base as i64, now_ms_i64()])?;
+    let row = rows.next()?;
+    Ok(match row {
+        None => None,
+        Some(row) => Some(PendingStep {
+            step_row_id: row.get(0)?,
+            run_id: row.get(1)?,
+            step_id: row.get(2)?,
+            agent_id: row.get(3)?,
+            task: row.get(4)?,
+            context_json: row.get(5)?,
+            input_json: row.get(6)?,
+            timeout_sec: row
+                .get::<_, Option<i64>>(7)?
+                .filter(|t| *t > 0)
+                .map(|t| t as u64)
+                .unwrap_or(DEFAULT_STEP_TIMEOUT_SEC),
+            attempt: row.get(8)?,
+        }),
+    })
+}
 
-        let mut rows = stmt.query([])?;
-        let row = rows.next()?;
-        match row {
-            None => None,
-            Some(row) => Some(PendingStep {
-                step_row_id: row.get(0)?,
-                run_id: row.get(1)?,
-                step_id: row.get(2)?,
-                agent_id: row.get(3)?,
-                task: row.get(4)?,
-                context_json: row.get(5)?,
-            }),
+fn claim_next_step(
+    engine: &Engine,
+    limits: &WorkerLimits,
+    worker_id: &str,
+) -> anyhow::Result<Option<PendingStep>> {
+    let mut conn = engine.open()?;
+    // IMMEDIATE takes the write lock up front so concurrent workers serialize on the claim
+    // instead of racing between the SELECT and the UPDATE.
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+
+    let mut step = select_runnable_step(&tx, limits)?;
+    // Story fan-out happens when the first per-story step becomes runnable, so a gate between
+    // plan and implement sees (and can reject) the stories before they are expanded.
+    if let Some(stories) = step.as_ref().and_then(stories::stories_to_expand) {
+        let run_id = step.as_ref().map(|s| s.run_id.clone()).unwrap_or_default();
+        if stories::expand_stories(&tx, &run_id, &stories)? > 0 {
+            step = select_runnable_step(&tx, limits)?;
         }
-    };
+    }
 
-    let Some(step) = step else {
+    let Some(mut step) = step else {
         tx.commit()?;
         return Ok(None);
     };
 
     let now = now_rfc3339();
+    let now_ms = now_ms_i64();
     let updated = tx.execute(
-        "UPDATE steps SET status='running', updated_at=?1 WHERE id=?2 AND status IN ('queued','pending')",
-        (&now, &step.step_row_id),
+        "UPDATE steps
+         SET status='running', worker_id=?3, started_at_ms=?4, heartbeat_at_ms=?4, updated_at=?1,
+             attempt=attempt+1, next_attempt_at_ms=NULL
+         WHERE id=?2 AND status IN ('queued','pending')",
+        (&now, &step.step_row_id, worker_id, now_ms),
     )?;
     if updated == 0 {
         tx.commit()?;
         return Ok(None);
     }
+    step.attempt += 1;
     tx.execute(
         "UPDATE runs SET status='running', updated_at=?1 WHERE id=?2 AND status='queued'",
         (&now, &step.run_id),
@@ -3706,43 +4339,230 @@ fn now_ms_i64() -> i64 {
         .min(i64::MAX as u128) as i64
 }
 
-fn finalize_step_done(engine: &Engine, step: &PendingStep, out: &str) -> anyhow::Result<()> {
-    let mut conn = engine.open()?;
-    let tx = conn.transaction()?;
-    tx.execute(
-        "UPDATE steps SET status='done', output_text=?1, updated_at=?2 WHERE id=?3",
-        (out, now_rfc3339(), &step.step_row_id),
-    )?;
-    tx.execute(
-        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'step.done', ?2, ?3)",
-        (
-            now_ms_i64(),
-            &step.step_row_id,
-            serde_json::json!({ "run_id": step.run_id, "step_id": step.step_id }).to_string(),
-        ),
-    )?;
-    // If all steps done, mark run done.
-    let remaining: i64 = tx.query_row(
-        "SELECT COUNT(*) FROM steps WHERE run_id=?1 AND status != 'done'",
-        [&step.run_id],
-        |r| r.get(0),
+/// Stores the parsed reply in `step_outputs` (replacing an earlier attempt's record).
+fn record_step_outputs(
+    conn: &rusqlite::Connection,
+    step: &PendingStep,
+    reply: &StepReply,
+) -> anyhow::Result<serde_json::Value> {
+    let outputs = reply.to_json();
+    conn.execute(
+        "INSERT OR REPLACE INTO step_outputs (step_row_id, run_id, step_id, status, outputs_json, created_at_ms)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        (
+            &step.step_row_id,
+            &step.run_id,
+            &step.step_id,
+            &reply.status,
+            outputs.to_string(),
+            now_ms_i64(),
+        ),
+    )?;
+    Ok(outputs)
+}
+
+fn finalize_step_done(
+    engine: &Engine,
+    step: &PendingStep,
+    out: &str,
+    reply: Option<&StepReply>,
+) -> anyhow::Result<()> {
+    let mut conn = engine.open()?;
+    let tx = conn.transaction()?;
+    // A cancel, reemit or reap while the step ran owns the row now (the reaper's requeue may
+    // even have been claimed again); drop the late result.
+    let updated = tx.execute(
+        "UPDATE steps SET status='done', output_text=?1, updated_at=?2
+         WHERE id=?3 AND status='running' AND attempt=?4",
+        (out, now_rfc3339(), &step.step_row_id, step.attempt),
+    )?;
+    if updated == 0 {
+        tx.commit()?;
+        return Ok(());
+    }
+    if let Some(reply) = reply {
+        // Thread the reply forward: later steps read it from the run context.
+        let outputs = record_step_outputs(&tx, step, reply)?;
+        let ctx_json: String = tx.query_row(
+            "SELECT context_json FROM runs WHERE id=?1",
+            [&step.run_id],
+            |r| r.get(0),
+        )?;
+        let mut ctx = parse_payload(&ctx_json);
+        reply::merge_into_context(&mut ctx, &step.step_id, &outputs);
+        tx.execute(
+            "UPDATE runs SET context_json=?1, updated_at=?2 WHERE id=?3",
+            (ctx.to_string(), now_rfc3339(), &step.run_id),
+        )?;
+    }
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'step.done', ?2, ?3)",
+        (
+            now_ms_i64(),
+            &step.step_row_id,
+            serde_json::json!({ "run_id": step.run_id, "step_id": step.step_id }).to_string(),
+        ),
+    )?;
+    // If all steps done, mark run done.
+    let remaining: i64 = tx.query_row(
+        "SELECT COUNT(*) FROM steps WHERE run_id=?1 AND status != 'done'",
+        [&step.run_id],
+        |r| r.get(0),
+    )?;
+    if remaining == 0 {
+        tx.execute(
+            "UPDATE runs SET status='done', updated_at=?1 WHERE id=?2",
+            (&now_rfc3339(), &step.run_id),
+        )?;
+        tx.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.done', ?2, ?3)",
+            (
+                now_ms_i64(),
+                &step.run_id,
+                serde_json::json!({ "run_id": step.run_id }).to_string(),
+            ),
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Applies the step's [`RetryPolicy`] to a failure that was just recorded. Returns whether the
+/// step was re-queued (possibly behind a rewound upstream step).
+fn schedule_retry(
+    tx: &rusqlite::Transaction,
+    step: &PendingStep,
+    err: &str,
+) -> anyhow::Result<bool> {
+    let policy = RetryPolicy::for_step(&step.step_id, &parse_payload(&step.input_json));
+    let class = ErrorClass::classify(err);
+    let (attempt, story_id): (i64, Option<String>) = tx.query_row(
+        "SELECT attempt, story_id FROM steps WHERE id=?1",
+        [&step.step_row_id],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    let attempt = attempt.max(0) as u32;
+    if !policy.should_retry(attempt, class) {
+        return Ok(false);
+    }
+    let now = now_rfc3339();
+    let delay_ms = policy.delay_ms(attempt);
+    let next_at = (delay_ms > 0).then(|| now_ms_i64() + delay_ms);
+    let mut rewound = vec![];
+    if let Some(target) = &policy.rewind_to {
+        rewound = rewind_steps(tx, &step.run_id, target, story_id.as_deref())?;
+        rewound.retain(|id| id != &step.step_row_id);
+        for id in &rewound {
+            tx.execute(
+                "UPDATE steps SET status='queued', output_text=NULL, worker_id=NULL, updated_at=?1
+                 WHERE id=?2 AND status != 'running'",
+                (&now, id),
+            )?;
+        }
+    }
+    // The error stays in output_text until the next attempt replaces it.
+    tx.execute(
+        "UPDATE steps SET status='queued', next_attempt_at_ms=?1, worker_id=NULL, updated_at=?2 WHERE id=?3",
+        (next_at, &now, &step.step_row_id),
+    )?;
+    tx.execute(
+        "UPDATE runs SET status='running', updated_at=?1 WHERE id=?2 AND status != 'paused'",
+        (&now, &step.run_id),
+    )?;
+    tx.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'step.retry_scheduled', ?2, ?3)",
+        (
+            now_ms_i64(),
+            &step.step_row_id,
+            serde_json::json!({
+                "run_id": step.run_id,
+                "step_id": step.step_id,
+                "error": err,
+                "error_class": class.as_str(),
+                "attempt": attempt,
+                "max_attempts": policy.max_attempts,
+                "delay_ms": delay_ms,
+                "rewind_to": policy.rewind_to,
+                "rewound_steps": rewound,
+            })
+            .to_string(),
+        ),
+    )?;
+    Ok(true)
+}
+
+/// Step rows to re-run when rewinding to `target`: the matching rows (the same story's when the
+/// failing step belongs to one) and everything downstream of them.
+fn rewind_steps(
+    tx: &rusqlite::Transaction,
+    run_id: &str,
+    target: &str,
+    story_id: Option<&str>,
+) -> anyhow::Result<Vec<String>> {
+    let rows = load_step_edges(tx, run_id)?;
+    let matching: Vec<&StepEdges> = rows.iter().filter(|r| r.step_id == target).collect();
+    let same_story: Vec<&StepEdges> = matching
+        .iter()
+        .copied()
+        .filter(|r| story_id.is_some() && r.story_id.as_deref() == story_id)
+        .collect();
+    let targets = if same_story.is_empty() {
+        matching
+    } else {
+        same_story
+    };
+    Ok(with_descendants(&rows, &targets))
+}
+
+/// A step row and the edges that decide what runs after it.
+struct StepEdges {
+    id: String,
+    step_id: String,
+    step_index: i64,
+    status: String,
+    story_id: Option<String>,
+    depends_on: Option<Vec<String>>,
+}
+
+fn load_step_edges(conn: &rusqlite::Connection, run_id: &str) -> anyhow::Result<Vec<StepEdges>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, step_id, step_index, status, story_id, depends_on_json
+         FROM steps WHERE run_id=?1 ORDER BY step_index",
     )?;
-    if remaining == 0 {
-        tx.execute(
-            "UPDATE runs SET status='done', updated_at=?1 WHERE id=?2",
-            (&now_rfc3339(), &step.run_id),
-        )?;
-        tx.execute(
-            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.done', ?2, ?3)",
-            (
-                now_ms_i64(),
-                &step.run_id,
-                serde_json::json!({ "run_id": step.run_id }).to_string(),
-            ),
-        )?;
+    let rows = stmt.query_map([run_id], |r| {
+        Ok(StepEdges {
+            id: r.get(0)?,
+            step_id: r.get(1)?,
+            step_index: r.get(2)?,
+            status: r.get(3)?,
+            story_id: r.get(4)?,
+            depends_on: r
+                .get::<_, Option<String>>(5)?
+                .and_then(|d| serde_json::from_str(&d).ok()),
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Ids of `targets` and every row downstream of them.
+fn with_descendants(rows: &[StepEdges], targets: &[&StepEdges]) -> Vec<String> {
+    let Some(first_index) = targets.iter().map(|r| r.step_index).min() else {
+        return vec![];
+    };
+    // Rows are in dependency order, so one pass collects every descendant. Rows without edges
+    // (linear runs) follow everything before them.
+    let mut out: Vec<String> = vec![];
+    for row in rows {
+        let hit = targets.iter().any(|t| t.id == row.id)
+            || match &row.depends_on {
+                Some(deps) => deps.iter().any(|d| out.contains(d)),
+                None => row.step_index > first_index,
+            };
+        if hit {
+            out.push(row.id.clone());
+        }
     }
-    tx.commit()?;
-    Ok(())
+    out
 }
 
 fn finalize_step_failed(engine: &Engine, step: &PendingStep, err: &str) -> anyhow::Result<()> {
@@ -3750,52 +4570,17 @@ fn finalize_step_failed(engine: &Engine, step: &PendingStep, err: &str) -> anyho
     let tx = conn.transaction()?;
     let now = now_rfc3339();
 
-    tx.execute(
-        "UPDATE steps SET status='failed', output_text=?1, updated_at=?2 WHERE id=?3",
-        (err, &now, &step.step_row_id),
+    let updated = tx.execute(
+        "UPDATE steps SET status='failed', output_text=?1, updated_at=?2
+         WHERE id=?3 AND status='running' AND attempt=?4",
+        (err, &now, &step.step_row_id, step.attempt),
     )?;
-
-    let mut requeued = false;
-    if step.step_id == "test" {
-        // Antfarm-like fallback loop: if tests fail, re-open implement->review chain with bounded retries.
-        // Guardrail: cap retries to avoid hot loops.
-        let attempts: i64 = tx.query_row(
-            "SELECT COUNT(*) FROM event_log WHERE kind='run.requeued.test_failed' AND entity_id=?1",
-            [&step.run_id],
-            |r| r.get(0),
-        )?;
-        let max_retries = 2_i64;
-        if attempts < max_retries {
-            tx.execute(
-                "UPDATE steps
-                 SET status='queued', output_text=NULL, updated_at=?1
-                 WHERE run_id=?2 AND step_index >= (
-                    SELECT step_index FROM steps WHERE id=?3
-                 )",
-                (&now, &step.run_id, &step.step_row_id),
-            )?;
-            tx.execute(
-                "UPDATE steps
-                 SET status='queued', updated_at=?1
-                 WHERE run_id=?2 AND step_id='implement'",
-                (&now, &step.run_id),
-            )?;
-            tx.execute(
-                "UPDATE runs SET status='running', updated_at=?1 WHERE id=?2",
-                (&now, &step.run_id),
-            )?;
-            tx.execute(
-                "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.requeued.test_failed', ?2, ?3)",
-                (
-                    now_ms_i64(),
-                    &step.run_id,
-                    serde_json::json!({ "run_id": step.run_id, "error": err, "attempt": attempts + 1, "max_attempts": max_retries }).to_string(),
-                ),
-            )?;
-            requeued = true;
-        }
+    if updated == 0 {
+        tx.commit()?;
+        return Ok(());
     }
 
+    let requeued = schedule_retry(&tx, step, err)?;
     if !requeued {
         tx.execute(
             "UPDATE runs SET status='failed', updated_at=?1 WHERE id=?2",
@@ -3824,9 +4609,28 @@ fn finalize_step_failed(engine: &Engine, step: &PendingStep, err: &str) -> anyho
     Ok(())
 }
 
-fn execute_step_blocking(engine: &Engine, step: &PendingStep) -> anyhow::Result<String> {
-    let ctx: serde_json::Value =
-        serde_json::from_str(&step.context_json).unwrap_or_else(|_| serde_json::json!({}));
+/// Run context as seen by one step: the run's `context_json` plus, for per-story steps, the
+/// `story` and `story_id` from the step row.
+fn step_context(step: &PendingStep) -> serde_json::Value {
+    let mut ctx = parse_payload(&step.context_json);
+    let input = parse_payload(&step.input_json);
+    if let (Some(obj), Some(story)) = (ctx.as_object_mut(), input.get("story")) {
+        obj.insert("story".to_string(), story.clone());
+        if let Some(id) = input.get("story_id") {
+            obj.insert("story_id".to_string(), id.clone());
+        }
+    }
+    ctx
+}
+
+fn execute_step_blocking(
+    engine: &Engine,
+    executors: &ExecutorRegistry,
+    forges: &ForgeRegistry,
+    step: &PendingStep,
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
+    let ctx = step_context(step);
     let repo = ctx
         .get("worktree_path")
         .and_then(|v| v.as_str())
@@ -3844,22 +4648,37 @@ fn execute_step_blocking(engine: &Engine, step: &PendingStep) -> anyhow::Result<
         .to_string();
 
     if step.agent_id == "internal/pr" {
-        let action = ctx.get("action").and_then(|v| v.as_str()).unwrap_or("");
+        // Steps appended to a run may carry their own action; internal runs keep it in the run.
+        let input = parse_payload(&step.input_json);
+        let action = input
+            .get("action")
+            .or_else(|| ctx.get("action"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        if action == "rebase_push" {
+            return conflicts::execute_push(step, &ctx, cancel);
+        }
         if action == "auto_rebase_sweep" {
-            return execute_auto_rebase_sweep(engine, step, &ctx);
+            return execute_auto_rebase_sweep(engine, forges, step, &ctx, cancel);
         }
-        let url = create_pr(&repo, &branch, &step.task)?;
-        // Persist PR URL into run context for review step.
-        let mut conn = engine.open()?;
-        let tx = conn.transaction()?;
-        let mut v: serde_json::Value =
-            serde_json::from_str(&step.context_json).unwrap_or_else(|_| serde_json::json!({}));
-        v["pr_url"] = serde_json::Value::String(url.clone());
-        tx.execute(
-            "UPDATE runs SET context_json=?1, updated_at=?2 WHERE id=?3",
-            (v.to_string(), now_rfc3339(), &step.run_id),
+        if action == "merge_queue" {
+            return merge_queue::execute(engine, forges, step, &ctx, cancel);
+        }
+        let url = create_pr(
+            forges.resolve(&repo).as_ref(),
+            &repo,
+            &branch,
+            &step.task,
+            cancel,
+        )?;
+        // Persist PR URL into run context for review step. Only that key: steps running alongside
+        // may have merged their outputs since this step's snapshot was taken.
+        engine.open()?.execute(
+            "UPDATE runs SET context_json=json_set(COALESCE(NULLIF(context_json, ''), '{}'), '$.pr_url', ?1),
+                    updated_at=?2
+             WHERE id=?3",
+            (&url, now_rfc3339(), &step.run_id),
         )?;
-        tx.commit()?;
         return Ok(url);
     }
 
@@ -3872,67 +4691,71 @@ fn execute_step_blocking(engine: &Engine, step: &PendingStep) -> anyhow::Result<
             msg.push_str(&skill_ctx.prompt);
         }
     }
-    let out = Command::new("openclaw")
-        .arg("agent")
-        .arg("--agent")
-        .arg(&step.agent_id)
-        .arg("--message")
-        .arg(msg)
-        .arg("--json")
-        .arg("--timeout")
-        .arg("3600")
-        .output()?;
-    if !out.status.success() {
-        return Err(anyhow::anyhow!(
-            "openclaw_failed: {}",
-            String::from_utf8_lossy(&out.stderr).trim()
-        ));
-    }
-    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    let req = StepRequest {
+        run_id: step.run_id.clone(),
+        step_id: step.step_id.clone(),
+        agent_id: step.agent_id.clone(),
+        task: step.task.clone(),
+        message: msg,
+        worktree_path: repo,
+        branch,
+        pr_url,
+        context: ctx,
+        timeout_sec: step.timeout_sec,
+        cancel: cancel.clone(),
+    };
+    executors.resolve(&step.agent_id).execute(&req)
 }
 
 fn build_step_message(step: &PendingStep, repo: &str, branch: &str, pr_url: &str) -> String {
-    match step.step_id.as_str() {
-        "plan" => format!(
-            "TASK:\n{task}\n\nREPO:\n{repo}\n\nBRANCH:\n{branch}\n\nReply with:\nSTATUS: done\nSTORIES_JSON: [{{\"id\":\"s1\",\"title\":\"...\",\"acceptance\":[\"...\"],\"tests\":[\"...\"]}}]\n",
-            task = step.task,
-            repo = repo,
-            branch = branch
-        ),
-        "setup" => format!(
-            "Prepare environment.\n\nTASK:\n{task}\n\nREPO: {repo}\nBRANCH: {branch}\n\nInstructions:\n- cd into repo\n- ensure branch exists and is checked out\n- run build/test baseline\n\nReply with:\nSTATUS: done\nBUILD_CMD: <cmd>\nTEST_CMD: <cmd>\nBASELINE: <status>\n",
-            task = step.task,
-            repo = repo,
-            branch = branch
-        ),
-        "implement" => format!(
-            "Implement the task.\n\nTASK:\n{task}\n\nREPO: {repo}\nBRANCH: {branch}\n\nRequirements:\n- implement\n- add tests\n- run tests\n- commit\n\nReply with:\nSTATUS: done\nCHANGES: ...\nTESTS: ...\n",
-            task = step.task,
-            repo = repo,
-            branch = branch
-        ),
-        "verify" => format!(
-            "Verify the developer work.\n\nTASK:\n{task}\n\nREPO: {repo}\nBRANCH: {branch}\n\nReply with:\nSTATUS: done\nNOTES: ...\n",
-            task = step.task,
-            repo = repo,
-            branch = branch
-        ),
-        "test" => format!(
-            "Integration/E2E testing.\n\nTASK:\n{task}\n\nREPO: {repo}\nBRANCH: {branch}\n\nReply with:\nSTATUS: done\nTEST_RESULTS: ...\n",
-            task = step.task,
-            repo = repo,
-            branch = branch
-        ),
-        "review" => format!(
-            "Review the PR.\n\nTASK:\n{task}\n\nPR: {pr}\n\nChecklist:\n- Verify code and tests\n- Verify PR includes at least one screenshot in the PR description\n\nReply with:\nSTATUS: done\nREVIEW: ...\n",
-            task = step.task,
-            pr = pr_url
-        ),
-        _ => format!("TASK:\n{}\n", step.task),
+    // Prompt comes from the workflow snapshot on the step row; rows queued before workflows
+    // existed fall back to the builtin feature-dev templates.
+    let tpl = serde_json::from_str::<serde_json::Value>(&step.input_json)
+        .ok()
+        .and_then(|v| {
+            v.get("prompt_template")
+                .and_then(|t| t.as_str())
+                .map(|t| t.to_string())
+        })
+        .or_else(|| workflow::builtin_step_prompt(&step.step_id))
+        .unwrap_or_else(|| "TASK:\n{task}\n".to_string());
+    let mut msg =
+        workflow::render_prompt(&tpl, &step.task, repo, branch, pr_url, &step_context(step));
+    if let Some(feedback) = parse_payload(&step.input_json)
+        .get("approval_feedback")
+        .and_then(|v| v.as_str())
+    {
+        msg.push_str("\n\nREVIEWER FEEDBACK (your previous result was rejected):\n");
+        msg.push_str(feedback);
+        msg.push('\n');
+    }
+    // Review comments and CI failures gathered by `feedback` for follow-up steps.
+    if let Some(feedback) = parse_payload(&step.input_json)
+        .get("pr_feedback")
+        .and_then(|v| v.as_str())
+    {
+        msg.push_str("\n\nPR FEEDBACK:\n");
+        msg.push_str(feedback.trim_start());
+        msg.push('\n');
     }
+    if let Some(conflicts) = parse_payload(&step.input_json)
+        .get("rebase_conflicts")
+        .and_then(|v| v.as_str())
+    {
+        msg.push_str("\n\nREBASE CONFLICTS:\n");
+        msg.push_str(conflicts);
+        msg.push('\n');
+    }
+    msg
 }
 
-fn create_pr(repo: &str, branch: &str, task: &str) -> anyhow::Result<String> {
+fn create_pr(
+    forge: &dyn Forge,
+    repo: &str,
+    branch: &str,
+    task: &str,
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
     if repo.trim().is_empty() {
         anyhow::bail!("missing_repo: run context has no worktree_path");
     }
@@ -3940,117 +4763,47 @@ fn create_pr(repo: &str, branch: &str, task: &str) -> anyhow::Result<String> {
         anyhow::bail!("missing_branch: run context has no branch");
     }
 
-    let gh_check = Command::new("gh").arg("--version").output();
-    if gh_check.is_err() {
-        anyhow::bail!(
-            "missing_dependency: gh CLI not installed; install GitHub CLI and run gh auth login"
-        );
+    if scratch::git(repo, &["remote", "get-url", "origin"]).is_err() {
+        anyhow::bail!("git_remote_missing: origin remote is required for PR creation");
     }
 
-    let auth = Command::new("gh")
-        .arg("auth")
-        .arg("status")
-        .current_dir(repo)
-        .output()?;
-    if !auth.status.success() {
-        anyhow::bail!(
-            "github_auth_required: {}",
-            String::from_utf8_lossy(&auth.stderr).trim()
-        );
-    }
+    scratch::git_cancellable(repo, &["push", "-u", "origin", branch], cancel)
+        .map_err(|e| anyhow::anyhow!("git_push_failed: {e}"))?;
 
-    let remote = Command::new("git")
-        .arg("-C")
-        .arg(repo)
-        .arg("remote")
-        .arg("get-url")
-        .arg("origin")
-        .output()?;
-    if !remote.status.success() {
-        anyhow::bail!("git_remote_missing: origin remote is required for PR creation");
+    // Forge calls are not interruptible; at least do not start one after a timeout.
+    if cancel.is_cancelled() {
+        anyhow::bail!("cancelled");
     }
-
-    let push = Command::new("git")
-        .arg("-C")
-        .arg(repo)
-        .arg("push")
-        .arg("-u")
-        .arg("origin")
-        .arg(branch)
-        .output()?;
-    if !push.status.success() {
-        anyhow::bail!(
-            "git_push_failed: {}",
-            String::from_utf8_lossy(&push.stderr).trim()
-        );
+    if let Some(existing) = forge.find_pr_by_head(repo, branch)? {
+        return Ok(existing.url);
     }
 
-    let existing = Command::new("gh")
-        .arg("pr")
-        .arg("view")
-        .arg("--head")
-        .arg(branch)
-        .arg("--json")
-        .arg("url")
-        .arg("--jq")
-        .arg(".url")
-        .current_dir(repo)
-        .output()?;
-    if existing.status.success() {
-        let url = String::from_utf8_lossy(&existing.stdout).trim().to_string();
-        if !url.is_empty() {
-            return Ok(url);
-        }
+    if cancel.is_cancelled() {
+        anyhow::bail!("cancelled");
     }
-
-    let base_out = Command::new("git")
-        .arg("-C")
-        .arg(repo)
-        .arg("symbolic-ref")
-        .arg("refs/remotes/origin/HEAD")
-        .output()?;
-    let base_branch = if base_out.status.success() {
-        String::from_utf8_lossy(&base_out.stdout)
-            .trim()
-            .rsplit('/')
-            .next()
-            .unwrap_or("main")
-            .to_string()
-    } else {
-        "main".to_string()
-    };
-
     let title = task.lines().next().unwrap_or("Clawdorio run").trim();
     let body = format!(
         "Clawdorio run for:\n\n{task}\n\n## Screenshots (Required)\n- [ ] Add at least one screenshot showing the implemented result/UI.\n\n## Validation\n- [ ] Tests/build executed for this branch.",
         task = task
     );
-    let pr = Command::new("gh")
-        .arg("pr")
-        .arg("create")
-        .arg("--head")
-        .arg(branch)
-        .arg("--base")
-        .arg(&base_branch)
-        .arg("--title")
-        .arg(title)
-        .arg("--body")
-        .arg(body)
-        .current_dir(repo)
-        .output()?;
-    if !pr.status.success() {
-        anyhow::bail!(
-            "gh_pr_create_failed: {}",
-            String::from_utf8_lossy(&pr.stderr).trim()
-        );
-    }
-    Ok(String::from_utf8_lossy(&pr.stdout).trim().to_string())
+    let pr = forge.create_pr(
+        repo,
+        &forge::NewPullRequest {
+            head: branch.to_string(),
+            base: detect_default_branch(repo).unwrap_or_else(|_| "main".to_string()),
+            title: title.to_string(),
+            body,
+        },
+    )?;
+    Ok(pr.url)
 }
 
 fn execute_auto_rebase_sweep(
     engine: &Engine,
+    forges: &ForgeRegistry,
     step: &PendingStep,
     ctx: &serde_json::Value,
+    cancel: &CancelToken,
 ) -> anyhow::Result<String> {
     let base_id = ctx
         .get("base_id")
@@ -4065,103 +4818,67 @@ fn execute_auto_rebase_sweep(
         .and_then(|v| v.as_str())
         .unwrap_or("main");
 
-    let fetch = Command::new("git")
-        .arg("-C")
-        .arg(repo)
-        .arg("fetch")
-        .arg("origin")
-        .output()?;
-    if !fetch.status.success() {
-        anyhow::bail!(
-            "git_fetch_failed: {}",
-            String::from_utf8_lossy(&fetch.stderr).trim()
-        );
+    // Sweeps never touch the base checkout, but an edit in progress there means someone is
+    // working on this repo right now: stay out of the way until it is committed or stashed.
+    if scratch::has_local_changes(repo)? {
+        engine.open()?.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'auto_rebase.refused', ?2, ?3)",
+            (
+                now_ms_i64(),
+                base_id,
+                serde_json::json!({ "run_id": step.run_id, "reason": "repo_dirty" }).to_string(),
+            ),
+        )?;
+        anyhow::bail!("repo_dirty: {repo} has local changes; commit or stash them");
     }
 
-    let pr_list = Command::new("gh")
-        .arg("pr")
-        .arg("list")
-        .arg("--state")
-        .arg("open")
-        .arg("--json")
-        .arg("headRefName")
-        .current_dir(repo)
-        .output()?;
-    if !pr_list.status.success() {
-        anyhow::bail!(
-            "gh_pr_list_failed: {}",
-            String::from_utf8_lossy(&pr_list.stderr).trim()
-        );
-    }
-    let prs: serde_json::Value =
-        serde_json::from_slice(&pr_list.stdout).unwrap_or_else(|_| serde_json::json!([]));
-    let branches: Vec<String> = prs
-        .as_array()
-        .cloned()
-        .unwrap_or_default()
+    scratch::git_cancellable(repo, &["fetch", "origin"], cancel)
+        .map_err(|e| anyhow::anyhow!("git_fetch_failed: {e}"))?;
+
+    let branches: Vec<String> = forges
+        .resolve(repo)
+        .list_open_prs(repo)?
         .into_iter()
-        .filter_map(|v| {
-            v.get("headRefName")
-                .and_then(|x| x.as_str())
-                .map(|s| s.to_string())
-        })
+        .map(|pr| pr.head)
         .filter(|b| b.starts_with("clawdorio/"))
         .collect();
 
     let mut ok_branches: Vec<String> = vec![];
     let mut failed: Vec<String> = vec![];
+    let mut resolving: Vec<serde_json::Value> = vec![];
+    let mut skipped: Vec<String> = vec![];
+    let mut conn = engine.open()?;
 
     for branch in branches {
-        let checkout = Command::new("git")
-            .arg("-C")
-            .arg(repo)
-            .arg("checkout")
-            .arg(&branch)
-            .output()?;
-        if !checkout.status.success() {
-            failed.push(format!("{branch}: checkout failed"));
-            continue;
+        if cancel.is_cancelled() {
+            anyhow::bail!("cancelled");
         }
-
-        let rebase = Command::new("git")
-            .arg("-C")
-            .arg(repo)
-            .arg("rebase")
-            .arg(format!("origin/{default_branch}"))
-            .output()?;
-        if !rebase.status.success() {
-            let _ = Command::new("git")
-                .arg("-C")
-                .arg(repo)
-                .arg("rebase")
-                .arg("--abort")
-                .output();
-            failed.push(format!(
-                "{branch}: rebase conflict: {}",
-                String::from_utf8_lossy(&rebase.stderr).trim()
-            ));
+        // A run that still has its worktree owns the branch: rebase there, and leave conflicts
+        // in place for a resolution step instead of aborting.
+        if let Some(checkout) = conflicts::run_checkout(&conn, &branch)? {
+            match conflicts::rebase_in_run_worktree(
+                &mut conn,
+                &checkout,
+                &branch,
+                default_branch,
+                cancel,
+            ) {
+                Ok(conflicts::Outcome::Rebased) => ok_branches.push(branch),
+                Ok(conflicts::Outcome::Resolving(step_row_id)) => resolving
+                    .push(serde_json::json!({ "branch": branch, "step_row_id": step_row_id })),
+                Ok(conflicts::Outcome::Skipped(why)) => skipped.push(why),
+                Ok(conflicts::Outcome::Failed(why)) => failed.push(why),
+                Err(e) => failed.push(format!("{branch}: {e}")),
+            }
             continue;
         }
-
-        let push = Command::new("git")
-            .arg("-C")
-            .arg(repo)
-            .arg("push")
-            .arg("--force-with-lease")
-            .arg("origin")
-            .arg(&branch)
-            .output()?;
-        if !push.status.success() {
-            failed.push(format!(
-                "{branch}: push failed: {}",
-                String::from_utf8_lossy(&push.stderr).trim()
-            ));
-            continue;
+        // Everything else is rebased in a throwaway worktree; the base checkout is left alone.
+        match scratch::rebase_branch(repo, &branch, default_branch, None, cancel) {
+            Ok(_) => ok_branches.push(branch),
+            Err(e) => failed.push(format!("{branch}: {e}")),
         }
-        ok_branches.push(branch);
     }
 
-    let mut conn = engine.open()?;
     let tx = conn.transaction()?;
     tx.execute(
         "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'auto_rebase.result', ?2, ?3)",
@@ -4171,6 +4888,8 @@ fn execute_auto_rebase_sweep(
             serde_json::json!({
                 "run_id": step.run_id,
                 "rebased": ok_branches,
+                "resolving": resolving,
+                "skipped": skipped,
                 "failed": failed,
             })
             .to_string(),
@@ -4246,31 +4965,20 @@ fn repair_belt_paths(engine: &Engine) -> anyhow::Result<()> {
 }
 
 async fn ip_allowlist(
+    axum::extract::State(allowed): axum::extract::State<Arc<Vec<config::IpRange>>>,
     axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<SocketAddr>,
     req: axum::http::Request<axum::body::Body>,
     next: axum::middleware::Next,
 ) -> axum::response::Response {
     let ip = peer.ip();
-    if is_allowed_peer_ip(ip) {
+    if is_allowed_peer_ip(ip, &allowed) {
         return next.run(req).await;
     }
     (axum::http::StatusCode::FORBIDDEN, "forbidden").into_response()
 }
 
-fn is_allowed_peer_ip(ip: IpAddr) -> bool {
-    if ip.is_loopback() {
-        return true;
-    }
-
-    // Tailscale CGNAT range (100.64.0.0/10).
-    match ip {
-        IpAddr::V4(v4) => {
-            let o = v4.octets();
-            // 100.64.0.0 - 100.127.255.255
-            o[0] == 100 && (64..=127).contains(&o[1])
-        }
-        IpAddr::V6(_v6) => false,
-    }
+fn is_allowed_peer_ip(ip: IpAddr, allowed: &[config::IpRange]) -> bool {
+    ip.is_loopback() || allowed.iter().any(|r| r.contains(ip))
 }
 
 fn local_only_cors() -> CorsLayer {
@@ -4280,7 +4988,7 @@ fn local_only_cors() -> CorsLayer {
 
     CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PATCH])
-        .allow_headers([header::CONTENT_TYPE])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
         .allow_origin(AllowOrigin::predicate(|origin: &HeaderValue, _req| {
             is_allowed_local_origin(origin)
         }))
@@ -4312,93 +5020,6 @@ fn is_http_origin_for_host(origin: &str, host: &str) -> bool {
     false
 }
 
-fn discover_local_repos() -> Vec<LocalRepo> {
-    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-    let workspace_root = PathBuf::from(home).join(".openclaw").join("workspace");
-    let roots = vec![workspace_root];
-
-    let skip_dirs: std::collections::HashSet<&'static str> = [
-        ".git",
-        "node_modules",
-        "dist",
-        "build",
-        ".next",
-        "target",
-        ".turbo",
-        ".cache",
-        "coverage",
-    ]
-    .into_iter()
-    .collect();
-
-    let mut repos: Vec<(String, String, u128)> = vec![];
-    let mut queue: std::collections::VecDeque<(PathBuf, usize)> = std::collections::VecDeque::new();
-    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
-    for r in roots {
-        queue.push_back((r, 0));
-    }
-
-    let max_depth = 3usize;
-    let max_repos = 200usize;
-
-    while let Some((dir, depth)) = queue.pop_front() {
-        if repos.len() >= max_repos {
-            break;
-        }
-        let dir_s = dir.to_string_lossy().to_string();
-        if dir_s.is_empty() || seen.contains(&dir_s) {
-            continue;
-        }
-        seen.insert(dir_s.clone());
-
-        let git_dir = dir.join(".git");
-        // Workspace root may itself be a git repo; still enumerate child repos/worktrees.
-        if depth > 0 && git_dir.exists() {
-            let name = dir
-                .file_name()
-                .map(|s| s.to_string_lossy().to_string())
-                .unwrap_or_else(|| dir_s.clone());
-            let mtime = std::fs::metadata(&dir)
-                .and_then(|m| m.modified())
-                .ok()
-                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
-                .map(|d| d.as_millis())
-                .unwrap_or(0);
-            repos.push((dir_s, name, mtime));
-            continue;
-        }
-        if depth >= max_depth {
-            continue;
-        }
-        let entries = match std::fs::read_dir(&dir) {
-            Ok(e) => e,
-            Err(_) => continue,
-        };
-        for ent in entries.flatten() {
-            let p = ent.path();
-            let ft = match ent.file_type() {
-                Ok(ft) => ft,
-                Err(_) => continue,
-            };
-            if !ft.is_dir() {
-                continue;
-            }
-            if let Some(name) = p.file_name().and_then(|s| s.to_str()) {
-                if skip_dirs.contains(name) {
-                    continue;
-                }
-            }
-            queue.push_back((p, depth + 1));
-        }
-    }
-
-    repos.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
-    repos
-        .into_iter()
-        .map(|(path, name, _)| LocalRepo { path, name })
-        .collect()
-}
-
 const DASHBOARD_HTML: &str = r###"<!doctype html>
 <html lang="en">
 <head>
@@ -4781,6 +5402,15 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
     const $ = (id) => document.getElementById(id);
 
     const agentsCountEl = $("agentsCount");
+    let placeMaxBaseDist = 12;
+    function renderAgentsCount(st){
+      if (!agentsCountEl) return;
+      agentsCountEl.textContent = String(st.working_agents || 0);
+      const w = st.workers;
+      const btn = $("hudAgents");
+      if (btn && w) btn.title = `${Number(w.busy || 0)}/${Number(w.max_workers || 0)} workers busy (max ${Number(w.max_per_base || 0)} per base)`;
+      if (Number(st.placement_max_base_distance) > 0) placeMaxBaseDist = Number(st.placement_max_base_distance);
+    }
     const hudQuestEl = $("hudQuest");
     const questbookEl = $("questbook");
     const questListEl = $("questList");
@@ -5047,7 +5677,7 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
 	    }
 
     async function loadBuildings(){
-      const r = await fetch("/api/buildings", { cache: "no-store" });
+      const r = await apiFetch("/api/buildings");
       if (!r.ok) throw new Error("buildings_fetch_failed");
       BUILDINGS = await r.json();
       draftKind = null;
@@ -5266,6 +5896,14 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
           <h4>${esc(c.title || "PR")}</h4>
           <div class="row"><span>${esc(c.repo || "repo")}</span><span>${esc(c.branch || "branch")}</span></div>
           <div class="row"><span>${esc(c.status || "")}</span><span>${esc(changed.source || "local")}</span></div>
+          ${c.approval ? `<div class="pr-approval" style="margin-top:6px; border:1px solid #ffd36e77; padding:6px;">
+            <div class="k">Awaiting approval: ${esc(c.approval.step_id || "")}</div>
+            ${Array.isArray(c.approval.stories) ? c.approval.stories.map((st) => `<div class="k">- ${esc(String((st && (st.title || st.id)) || ""))}</div>`).join("") : ""}
+            <div style="display:flex; gap:8px; margin-top:6px;">
+              <button class="btn pr-approval-send" type="button" data-decision="approve" data-gate="${esc(c.approval.step_row_id || "")}" style="flex:1; min-height:38px;">Approve</button>
+              <button class="btn pr-approval-send" type="button" data-decision="reject" data-gate="${esc(c.approval.step_row_id || "")}" style="flex:1; min-height:38px;">Reject</button>
+            </div>
+          </div>` : ""}
           <div class="chip" style="margin-top:6px;">${esc(c.pr_url || "No PR URL")}</div>
           <div class="k" style="margin-top:6px;">${esc(changed.total_files || 0)} files changed</div>
           <div class="pr-files" data-files-for="${esc(c.run_id || "")}">${files || '<div class="k">No changed files summary.</div>'}</div>
@@ -5291,6 +5929,30 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
         });
       });
 
+      prFeedEl.querySelectorAll(".pr-approval-send").forEach((btn) => {
+        btn.addEventListener("click", async () => {
+          const card = btn.closest(".pr-card");
+          if (!card) return;
+          const runId = String(card.getAttribute("data-run-id") || "");
+          const gateId = String(btn.getAttribute("data-gate") || "");
+          const decision = String(btn.getAttribute("data-decision") || "");
+          // Rejections reuse the card's comment box as the feedback for the previous agent.
+          const ta = card.querySelector(".pr-comment");
+          const comment = ta ? String(ta.value || "").trim() : "";
+          if (!runId || !gateId) return;
+          if (decision === "reject" && !comment){
+            if (ta) ta.focus();
+            return;
+          }
+          btn.disabled = true;
+          try{
+            await fetchJson(`/api/runs/${encodeURIComponent(runId)}/steps/${encodeURIComponent(gateId)}/${decision}`, { method:"POST", headers:{"content-type":"application/json"}, body: JSON.stringify({ comment }) });
+            if (ta) ta.value = "";
+          }catch(_e){}
+          refreshPrFeed();
+        });
+      });
+
       prFeedEl.querySelectorAll(".pr-comment-send").forEach((btn) => {
         btn.addEventListener("click", async () => {
           const card = btn.closest(".pr-card");
@@ -5319,11 +5981,46 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
       });
     }
 
+    // Server push: /api/events/stream tails event_log, so we only refetch /api/state when
+    // something actually changed. Panels subscribe via onServerEvent().
+    const serverEventListeners = new Set();
+    let stateDirty = true;
+    let stateWake = null;
+    function onServerEvent(fn){
+      serverEventListeners.add(fn);
+      return () => serverEventListeners.delete(fn);
+    }
+    function markStateDirty(){
+      stateDirty = true;
+      if (stateWake){
+        const wake = stateWake;
+        stateWake = null;
+        wake();
+      }
+    }
+    function connectEventStream(){
+      if (typeof EventSource === "undefined") return false;
+      const tok = apiToken();
+      const auth = tok ? `&access_token=${encodeURIComponent(tok)}` : "";
+      const es = new EventSource(`/api/events/stream?since_seq=${encodeURIComponent(String(lastRev || 0))}${auth}`);
+      es.onmessage = (m) => {
+        let ev = null;
+        try{ ev = JSON.parse(m.data); }catch(_e){ return; }
+        markStateDirty();
+        for (const fn of serverEventListeners){
+          try{ fn(ev); }catch(_e){}
+        }
+      };
+      return true;
+    }
+
     async function stateLoop(){
+      const streaming = connectEventStream();
       for(;;){
+        stateDirty = false;
         try{
           const st = await fetchJson("/api/state");
-          if (agentsCountEl) agentsCountEl.textContent = String(st.working_agents || 0);
+          renderAgentsCount(st);
           quests = Array.isArray(st.quests) ? st.quests : [];
           renderQuestList();
           syncQuestEditor();
@@ -5337,7 +6034,14 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
         }catch(_e){
           // keep last known state
         }
-        await new Promise(res => setTimeout(res, 700));
+        // Streaming: sleep until the next event (or a slow safety refresh). Otherwise poll.
+        if (!stateDirty){
+          await new Promise(res => {
+            stateWake = res;
+            setTimeout(res, streaming ? 5000 : 700);
+          });
+          stateWake = null;
+        }
       }
     }
 
@@ -5535,7 +6239,7 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
 		      const fp = footprintFor(kind);
 		      // Non-base buildings must connect to an existing base.
 		      if (kind !== "base"){
-		        if (!nearAnyBase(x, y, fp.w, fp.h, 12)) return false;
+		        if (!nearAnyBase(x, y, fp.w, fp.h, placeMaxBaseDist)) return false;
 		      }
 		      // Belts occupy tiles; cannot build over them.
 		      for (let dy = 0; dy < fp.h; dy++){
@@ -5657,8 +6361,34 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
 	      return { cx, bottomPad, ax: ax0, ay: ay0, w0, h0 };
 	    }
 
+	    // API token (when the server runs with `[auth] enabled`): `?token=` once, then localStorage.
+	    const TOKEN_KEY = "clawdorio.apiToken";
+	    function apiToken(){
+	      const q = new URLSearchParams(location.search).get("token");
+	      if (q){
+	        try{ localStorage.setItem(TOKEN_KEY, q); }catch(_e){}
+	        history.replaceState(null, "", location.pathname + location.hash);
+	        return q;
+	      }
+	      try{ return localStorage.getItem(TOKEN_KEY) || ""; }catch(_e){ return ""; }
+	    }
+	    async function apiFetch(url, opts){
+	      const o = Object.assign({ cache: "no-store" }, opts || {});
+	      const tok = apiToken();
+	      if (tok) o.headers = Object.assign({}, o.headers || {}, { authorization: `Bearer ${tok}` });
+	      const r = await fetch(url, o);
+	      if (r.status === 401){
+	        const t = window.prompt("Clawdorio API token");
+	        if (t){
+	          try{ localStorage.setItem(TOKEN_KEY, t.trim()); }catch(_e){}
+	          return await apiFetch(url, opts);
+	        }
+	      }
+	      return r;
+	    }
+
 	    async function fetchJson(url, opts){
-	      const r = await fetch(url, Object.assign({ cache: "no-store" }, opts || {}));
+	      const r = await apiFetch(url, opts);
       if (!r.ok){
         const t = await r.text().catch(() => "");
         throw new Error(`${url} ${r.status} ${t}`.trim());
@@ -5739,7 +6469,7 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
     async function deleteEntityById(id){
       await fetchJson(`/api/entities/${encodeURIComponent(String(id))}`, { method: "DELETE" });
       const st = await fetchJson("/api/state");
-      if (agentsCountEl) agentsCountEl.textContent = String(st.working_agents || 0);
+      renderAgentsCount(st);
       quests = Array.isArray(st.quests) ? st.quests : [];
       renderQuestList();
       syncQuestEditor();
@@ -6258,7 +6988,7 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
       }
       try{
         const st = await fetchJson("/api/state");
-        if (agentsCountEl) agentsCountEl.textContent = String(st.working_agents || 0);
+        renderAgentsCount(st);
         quests = Array.isArray(st.quests) ? st.quests : [];
         renderQuestList();
         syncQuestEditor();
@@ -6719,10 +7449,37 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
           <input readonly value="${esc(String(prev))}" placeholder="prompt..." style="flex:1; width:100%; border:1px solid #4f799f; background:#081427; color:var(--ice); padding:8px 10px; font-family:Geist Mono, ui-monospace, SFMono-Regular, Menlo, monospace; font-size:12px;" />
           <button id="featureOpenBuildModalBtn" class="btn" type="button" style="white-space:nowrap;">${knownBusy ? "Working" : "Build"}</button>
         </div>
+        <div style="display:flex; gap:10px; align-items:center; margin-bottom:10px;">
+          <span class="k">Workflow</span>
+          <select id="featureWorkflowSelect" style="flex:1; border:1px solid #4f799f; background:#081427; color:var(--ice); padding:6px 8px; font-family:Geist Mono, ui-monospace, SFMono-Regular, Menlo, monospace; font-size:12px;"></select>
+        </div>
         <div id="featureBuildResult" class="sub"></div>
         <div id="featureRuns" style="margin-top:10px;"></div>
       `;
 
+      const workflowSel = bottomPanel.querySelector("#featureWorkflowSelect");
+      if (workflowSel){
+        const current = String(payload.workflow_id || "feature-dev");
+        fetchJson("/api/workflows").then((rows) => {
+          const list = (Array.isArray(rows) ? rows : []).filter((w) => w && w.definition);
+          if (!list.some((w) => String(w.id) === current)) list.unshift({ id: current, name: current });
+          workflowSel.innerHTML = list
+            .map((w) => `<option value="${esc(String(w.id))}"${String(w.id) === current ? " selected" : ""}>${esc(String(w.name || w.id))}</option>`)
+            .join("");
+        }).catch(() => {});
+        workflowSel.addEventListener("change", async () => {
+          const workflow_id = String(workflowSel.value || "");
+          if (!workflow_id) return;
+          try{
+            await fetchJson(`/api/entities/${encodeURIComponent(key)}/workflow`, {
+              method: "POST",
+              headers: { "content-type": "application/json" },
+              body: JSON.stringify({ workflow_id }),
+            });
+          }catch(_e){}
+        });
+      }
+
       const openBtn = bottomPanel.querySelector("#featureOpenBuildModalBtn");
       const out = bottomPanel.querySelector("#featureBuildResult");
       const runsEl = bottomPanel.querySelector("#featureRuns");
@@ -6743,12 +7500,13 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
           pr: "PR",
           review: "Review",
         };
-        return m[String(s.step_id || "")] || String(s.step_id || "");
+        const base = m[String(s.step_id || "")] || String(s.step_id || "");
+        return s.story_id ? `${base} · ${String(s.story_id)}` : base;
       }
 
       function renderKanban(run, steps){
         if (!runsEl) return;
-        const cols = 7;
+        const cols = Math.max(1, Array.isArray(steps) ? steps.length : 7);
         const cards = (Array.isArray(steps) ? steps : []).map((s) => {
           const st = String(s.status || "");
           const isRun = st === "running";
@@ -6763,6 +7521,7 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
             <h4>${esc(title)}</h4>
             <div class="${cls}" style="margin-bottom:8px;">${esc(st)}</div>
             <div class="k" style="font-size:10px;color:var(--muted);">${esc(small)}</div>
+            ${s.story ? `<div class="k" style="font-size:10px;color:var(--muted);" title="${esc(String(s.story.title || ""))}">story ${esc(String(s.story.steps_done))}/${esc(String(s.story.steps_total))}</div>` : ""}
           </div>`;
         }).join("");
 
@@ -6788,8 +7547,26 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
             </div>`
           : "";
 
+        const controls = [
+          (runStatus === "queued" || runStatus === "running") ? `<button class="btn" type="button" data-run-control="pause">Pause</button>` : "",
+          runStatus === "paused" ? `<button class="btn" type="button" data-run-control="resume">Resume</button>` : "",
+          (runStatus && runStatus !== "done" && runStatus !== "cancelled") ? `<button class="btn" type="button" data-run-control="cancel">Cancel</button>` : "",
+        ].join("");
+        const gate = (Array.isArray(steps) ? steps : []).find((s) => String(s.status) === "awaiting_approval") || null;
+        const approvalBox = gate
+          ? `<div style="margin-top:10px; border:1px solid #ffd36e77; background:#241d0a; padding:8px;">
+              <div class="k" style="margin-bottom:6px;">Approval needed: ${esc(stepLabel(gate))}</div>
+              <textarea id="approvalComment" rows="2" placeholder="Comment (required to reject; sent back to the previous agent)" style="width:100%; resize:vertical; border:1px solid #4f799f; background:#0b1b30; color:var(--ice); padding:8px 10px; font-family:Geist Mono, ui-monospace, SFMono-Regular, Menlo, monospace; font-size:12px;"></textarea>
+              <div style="display:flex; gap:8px; margin-top:8px;">
+                <button class="btn" type="button" data-approval="approve" data-gate="${esc(String(gate.id))}">Approve</button>
+                <button class="btn" type="button" data-approval="reject" data-gate="${esc(String(gate.id))}">Reject</button>
+              </div>
+            </div>`
+          : "";
         runsEl.innerHTML = `
           <div class="row"><span>${esc(run.status || "")}</span><span>${esc(run.id || "")}</span></div>
+          ${controls ? `<div style="display:flex; gap:8px; margin-top:8px;">${controls}</div>` : ""}
+          ${approvalBox}
           <div class="kanban" style="grid-template-columns:repeat(${cols},1fr); margin-top:10px;">${cards}</div>
           <div id="stepOut" style="margin-top:10px;"></div>
           ${prLine}
@@ -6803,6 +7580,42 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
           outEl.innerHTML = txt ? `<pre style="white-space:pre-wrap; word-break:break-word; border:1px solid #4f799f55; background:#040b16; padding:10px; font-size:11px; color:#cfefff; max-height:240px; overflow:auto;">${esc(txt.slice(0, 12000))}</pre>` : "";
         }
 
+        runsEl.querySelectorAll("[data-run-control]").forEach((el) => {
+          el.addEventListener("click", async () => {
+            const action = String(el.getAttribute("data-run-control") || "");
+            if (!action || !run || !run.id) return;
+            if (action === "cancel" && !confirm("Cancel this run? The running agent will be killed.")) return;
+            el.disabled = true;
+            try{
+              await fetchJson(`/api/runs/${encodeURIComponent(String(run.id))}/${action}`, { method: "POST" });
+            }catch(_e){}
+            markStateDirty();
+          });
+        });
+
+        runsEl.querySelectorAll("[data-approval]").forEach((el) => {
+          el.addEventListener("click", async () => {
+            const decision = String(el.getAttribute("data-approval") || "");
+            const gateId = String(el.getAttribute("data-gate") || "");
+            const ta = runsEl.querySelector("#approvalComment");
+            const comment = ta ? String(ta.value || "").trim() : "";
+            if (!run || !run.id || !gateId) return;
+            if (decision === "reject" && !comment){
+              if (ta) ta.focus();
+              return;
+            }
+            el.disabled = true;
+            try{
+              await fetchJson(`/api/runs/${encodeURIComponent(String(run.id))}/steps/${encodeURIComponent(gateId)}/${decision}`, {
+                method: "POST",
+                headers: { "content-type": "application/json" },
+                body: JSON.stringify({ comment }),
+              });
+            }catch(_e){}
+            markStateDirty();
+          });
+        });
+
         runsEl.querySelectorAll("[data-step]").forEach((el) => {
           el.addEventListener("click", async () => {
             activeStepRowId = el.getAttribute("data-step");
@@ -6869,12 +7682,18 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
       }
 
       refreshRuns();
-      // Keep the kanban in sync while this panel is open.
-      const poll = setInterval(() => { refreshRuns(); }, 1100);
+      // Keep the kanban in sync while this panel is open: refresh on run/step events,
+      // with a slow poll as a fallback if the event stream drops.
+      const unsubscribe = onServerEvent((ev) => {
+        const kind = String(ev && ev.kind ? ev.kind : "");
+        if (kind.startsWith("step.") || kind.startsWith("run.")) refreshRuns();
+      });
+      const poll = setInterval(() => { refreshRuns(); }, 5000);
       // Tear down poll if the panel gets replaced.
       const mo = new MutationObserver(() => {
         if (!document.body.contains(runsEl)){
           clearInterval(poll);
+          unsubscribe();
           mo.disconnect();
         }
       });
@@ -6893,7 +7712,7 @@ const DASHBOARD_HTML: &str = r###"<!doctype html>
       }
       // Initial DB-backed world state.
       const st = await fetchJson("/api/state");
-      if (agentsCountEl) agentsCountEl.textContent = String(st.working_agents || 0);
+      renderAgentsCount(st);
       quests = Array.isArray(st.quests) ? st.quests : [];
       renderQuestList();
       syncQuestEditor();
@@ -1,13 +1,285 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use clawdorio_server::auth::{self, Scope};
+use clawdorio_server::backup;
+use clawdorio_server::config::ServerConfig;
+use clawdorio_server::executor::ExecutorRegistry;
+use clawdorio_server::forge::ForgeRegistry;
+use clawdorio_server::pool::{WorkerLimits, WorkerPool};
 use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    match cli.command {
+        None => serve(cli.serve).await,
+        Some(Command::Serve(args)) => serve(args).await,
+        Some(Command::Migrate {
+            config,
+            db,
+            dry_run,
+        }) => {
+            let cfg = load_config(config.as_deref())?;
+            let db_path = resolve_db_path(db, &cfg)?;
+            let engine = clawdorio_engine::Engine::new(&db_path);
+            let before = engine.migration_status()?;
+            if dry_run {
+                for (version, name) in &before.pending {
+                    println!("pending {version:03} {name}");
+                }
+                println!(
+                    "{}: schema {} of {}, {} pending",
+                    db_path.display(),
+                    before.current,
+                    before.latest,
+                    before.pending.len()
+                );
+                return Ok(());
+            }
+            let conn = engine.open()?;
+            for (version, name) in &before.pending {
+                println!("applied {version:03} {name}");
+            }
+            let version: i64 = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+            println!("migrated {} (schema version {version})", db_path.display());
+            Ok(())
+        }
+        Some(Command::CheckConfig { config }) => {
+            let (cfg, file) = ServerConfig::load(config.as_deref())?;
+            cfg.finish()?;
+            match file {
+                Some(p) => println!("ok: {}", p.display()),
+                None => println!("ok: no config file, using defaults"),
+            }
+            Ok(())
+        }
+        Some(Command::Token { config, db, action }) => {
+            let cfg = load_config(config.as_deref())?;
+            let engine = clawdorio_engine::Engine::new(resolve_db_path(db, &cfg)?);
+            match action {
+                TokenAction::Create { name, scope } => {
+                    let scope = Scope::parse(&scope)
+                        .ok_or_else(|| anyhow::anyhow!("scope must be read, operator or admin"))?;
+                    let (info, token) = auth::create_token(&engine, &name, scope)?;
+                    eprintln!(
+                        "created {} ({}); the token is shown only once:",
+                        info.id,
+                        scope.as_str()
+                    );
+                    println!("{token}");
+                }
+                TokenAction::List => {
+                    for t in auth::list_tokens(&engine)? {
+                        let state = if t.revoked_at_ms.is_some() {
+                            "revoked"
+                        } else {
+                            "active"
+                        };
+                        println!("{}\t{}\t{}\t{state}", t.id, t.scope.as_str(), t.name);
+                    }
+                }
+                TokenAction::Revoke { id } => {
+                    if !auth::revoke_token(&engine, &id)? {
+                        anyhow::bail!("no active token {id}");
+                    }
+                    println!("revoked {id}");
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Export {
+            config,
+            db,
+            out,
+            sqlite,
+        }) => {
+            let cfg = load_config(config.as_deref())?;
+            let engine = clawdorio_engine::Engine::new(resolve_db_path(db, &cfg)?);
+            if sqlite {
+                let out = out.ok_or_else(|| anyhow::anyhow!("--sqlite needs --out"))?;
+                backup::snapshot(&engine, &out)?;
+                eprintln!("wrote SQLite backup {}", out.display());
+                return Ok(());
+            }
+            let archive = serde_json::to_string_pretty(&backup::export(&engine)?)?;
+            match out {
+                Some(p) => {
+                    std::fs::write(&p, archive)?;
+                    eprintln!("wrote {}", p.display());
+                }
+                None => println!("{archive}"),
+            }
+            Ok(())
+        }
+        Some(Command::Import { config, db, file }) => {
+            let cfg = load_config(config.as_deref())?;
+            let engine = clawdorio_engine::Engine::new(resolve_db_path(db, &cfg)?);
+            let archive: serde_json::Value = serde_json::from_str(
+                &std::fs::read_to_string(&file)
+                    .map_err(|e| anyhow::anyhow!("read {}: {e}", file.display()))?,
+            )?;
+            let report = backup::import(&engine, &archive)?;
+            for (table, n) in &report.tables {
+                println!("{table}\t{n}");
+            }
+            println!(
+                "imported {} ({} ids remapped, {} workflows reused, {} unfinished runs paused)",
+                file.display(),
+                report.remapped,
+                report.workflows_reused,
+                report.runs_paused
+            );
+            Ok(())
+        }
+        Some(Command::PrintDefaultConfig) => {
+            print!("{}", ServerConfig::default().to_toml()?);
+            Ok(())
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "clawdorio-server")]
+#[command(about = "Clawdorio headless API server", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand the server runs (same as `serve`).
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the API server and workers.
+    Serve(ServeArgs),
+    /// Create or upgrade the SQLite schema, then exit.
+    Migrate {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long)]
+        db: Option<PathBuf>,
+        /// List pending migrations without applying them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Load and validate the config file, then exit.
+    CheckConfig {
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Manage API tokens (works while the server is running).
+    Token {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long)]
+        db: Option<PathBuf>,
+        #[command(subcommand)]
+        action: TokenAction,
+    },
+    /// Write the whole world (board, runs, workflows, library, skills) as a JSON archive.
+    Export {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long)]
+        db: Option<PathBuf>,
+        /// Output file; stdout when omitted.
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Write a raw SQLite backup of the database to --out instead.
+        #[arg(long)]
+        sqlite: bool,
+    },
+    /// Merge a JSON archive from `export` into the database, remapping ids that are taken.
+    Import {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long)]
+        db: Option<PathBuf>,
+        file: PathBuf,
+    },
+    /// Print the built-in defaults as a TOML config file.
+    PrintDefaultConfig,
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenAction {
+    /// Create a token and print it (once).
+    Create {
+        name: String,
+        /// read, operator or admin.
+        #[arg(long, default_value = "read")]
+        scope: String,
+    },
+    /// List tokens (secrets are never shown again).
+    List,
+    /// Revoke a token by id.
+    Revoke { id: String },
+}
+
+/// Flags override the config file (and the environment).
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// Config file (TOML). Defaults to $CLAWDORIO_CONFIG or ~/.clawdorio/config.toml if present.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Host/interface to bind (use 0.0.0.0 to expose on LAN/hosted env). Default 127.0.0.1.
+    #[arg(long)]
+    host: Option<IpAddr>,
+
+    /// Port to bind (use 0 for an ephemeral port). Default 39333.
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// SQLite DB path. Defaults to $CLAWDORIO_DB, `server.db`, or ~/.clawdorio/clawdorio.db
+    #[arg(long)]
+    db: Option<PathBuf>,
+
+    /// Require a bearer token on /api routes (see `token create`).
+    #[arg(long)]
+    require_auth: bool,
+
+    /// Steps that may execute concurrently across all bases. Default 4.
+    #[arg(long)]
+    max_workers: Option<usize>,
 
-    let addr = SocketAddr::new(args.host, args.port);
-    let db_path = resolve_db_path(args.db)?;
+    /// Steps that may execute concurrently for runs on the same base. Default 1.
+    #[arg(long)]
+    max_workers_per_base: Option<usize>,
+
+    /// Hours to keep the worktree of a done, cancelled or merged run before pruning it.
+    #[arg(long)]
+    worktree_retention_hours: Option<u64>,
+
+    /// Also delete a pruned run's local `clawdorio/*` branch.
+    #[arg(long)]
+    prune_branches: bool,
+
+    /// Directory scanned for repositories (repeatable; replaces configured roots).
+    #[arg(long = "workspace-root")]
+    workspace_roots: Vec<PathBuf>,
+
+    /// Directory levels scanned below each workspace root.
+    #[arg(long)]
+    scan_depth: Option<usize>,
+
+    /// Glob for directories the repo scan skips (repeatable; added to configured ones).
+    #[arg(long = "workspace-exclude")]
+    workspace_exclude: Vec<String>,
+
+    /// Where run worktrees are created (default: the first workspace root).
+    #[arg(long)]
+    worktree_root: Option<PathBuf>,
+}
+
+async fn serve(args: ServeArgs) -> anyhow::Result<()> {
+    let cfg = resolve_config(&args)?;
+    let addr = SocketAddr::new(cfg.server.host, cfg.server.port);
+    let db_path = resolve_db_path(args.db.clone(), &cfg)?;
     if let Some(parent) = db_path.parent() {
         std::fs::create_dir_all(parent)?;
     }
@@ -15,33 +287,68 @@ async fn main() -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind(addr).await?;
     let actual = listener.local_addr()?;
     eprintln!("[clawdorio] server listening on http://{actual}");
+    if let Some(warning) = cfg.github.unsigned_warning() {
+        eprintln!("[clawdorio] warning: {warning}");
+    }
 
     let shutdown = async {
         // Best-effort shutdown on Ctrl+C (or SIGINT on unix).
         let _ = tokio::signal::ctrl_c().await;
     };
-    let _ = clawdorio_server::serve_listener(listener, db_path, shutdown).await?;
+    let engine = clawdorio_engine::Engine::new(db_path);
+    let state = clawdorio_server::AppState {
+        forges: Arc::new(ForgeRegistry::from_config(&cfg.forge, &engine)),
+        engine,
+        executors: Arc::new(ExecutorRegistry::from_env()?),
+        workers: Arc::new(WorkerPool::new(WorkerLimits::new(
+            cfg.workers.max_workers,
+            cfg.workers.max_workers_per_base,
+        ))),
+        config: Arc::new(cfg),
+    };
+    let _ = clawdorio_server::serve_state(listener, state, shutdown).await?;
     Ok(())
 }
 
-#[derive(Parser, Debug)]
-#[command(name = "clawdorio-server")]
-#[command(about = "Clawdorio headless API server", long_about = None)]
-struct Args {
-    /// Host/interface to bind (use 0.0.0.0 to expose on LAN/hosted env).
-    #[arg(long, default_value = "127.0.0.1")]
-    host: IpAddr,
-
-    /// Port to bind (use 0 for an ephemeral port).
-    #[arg(long, default_value_t = 39333)]
-    port: u16,
+fn load_config(path: Option<&Path>) -> anyhow::Result<ServerConfig> {
+    ServerConfig::load(path)?.0.finish()
+}
 
-    /// SQLite DB path. Defaults to $CLAWDORIO_DB or ~/.clawdorio/clawdorio.db
-    #[arg(long)]
-    db: Option<PathBuf>,
+/// Config file, then environment, then flags.
+fn resolve_config(args: &ServeArgs) -> anyhow::Result<ServerConfig> {
+    let (mut cfg, _) = ServerConfig::load(args.config.as_deref())?;
+    if let Some(host) = args.host {
+        cfg.server.host = host;
+    }
+    if let Some(port) = args.port {
+        cfg.server.port = port;
+    }
+    cfg.auth.enabled |= args.require_auth;
+    if let Some(n) = args.max_workers {
+        cfg.workers.max_workers = n;
+    }
+    if let Some(n) = args.max_workers_per_base {
+        cfg.workers.max_workers_per_base = n;
+    }
+    if let Some(h) = args.worktree_retention_hours {
+        cfg.worktrees.retention_hours = h;
+    }
+    cfg.worktrees.prune_branches |= args.prune_branches;
+    let ws = &mut cfg.workspace;
+    if !args.workspace_roots.is_empty() {
+        ws.roots = args.workspace_roots.clone();
+    }
+    if let Some(depth) = args.scan_depth {
+        ws.scan_depth = depth;
+    }
+    ws.exclude.extend(args.workspace_exclude.iter().cloned());
+    if let Some(root) = &args.worktree_root {
+        ws.worktree_root = Some(root.clone());
+    }
+    cfg.finish()
 }
 
-fn resolve_db_path(db: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+fn resolve_db_path(db: Option<PathBuf>, cfg: &ServerConfig) -> anyhow::Result<PathBuf> {
     if let Some(p) = db {
         return Ok(p);
     }
@@ -50,6 +357,9 @@ fn resolve_db_path(db: Option<PathBuf>) -> anyhow::Result<PathBuf> {
             return Ok(PathBuf::from(p));
         }
     }
+    if let Some(p) = &cfg.server.db {
+        return Ok(clawdorio_server::workspace::expand_home(p));
+    }
     Ok(dirs::home_dir()
         .unwrap_or_else(|| PathBuf::from("."))
         .join(".clawdorio")
@@ -0,0 +1,559 @@
+//! Per-base merge queue.
+//!
+//! A finished run is enqueued in `merge_queue` when it has an open PR, its `review` step replied
+//! `VERDICT: approve`, and the latest result of every CI check reported for it (see
+//! [`feedback`](super::feedback)) passed; a check still pending holds the run back, as does a
+//! required check (`[merge_queue] required_checks`) that has not reported success. Each base works its queue head first, one entry at a
+//! time: a `merge-queue` run (agent `internal/pr`) rebases the PR branch on the default branch in
+//! a throwaway worktree, re-runs the run's recorded `TEST_CMD` there, pushes the rebased branch
+//! and merges the PR through the forge. A conflict, test failure or forge error marks the entry
+//! `failed`, comments on the PR, and the queue moves on.
+//!
+//! Bases opt in with `merge_queue_enabled` in their payload, which defaults to
+//! `[merge_queue] enabled` when a repo is attached.
+
+use super::executor::CancelToken;
+use super::forge::ForgeRegistry;
+use super::scratch;
+use super::{
+    detect_default_branch, find_base_entity, internal_error, mark_runs_pr_merged, now_ms_i64,
+    now_rfc3339, parse_payload, repo_path_from_payload, AppState, PendingStep,
+};
+use axum::http::StatusCode;
+use axum::Json;
+use clawdorio_engine::Engine;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// `workflow_id` of the runs that work a base's queue.
+const MERGE_QUEUE_WORKFLOW: &str = "merge-queue";
+/// Review verdicts that count as approval.
+const APPROVING_VERDICTS: [&str; 3] = ["approve", "approved", "lgtm"];
+/// Merged and failed entries listed by `GET /api/bases/{id}/merge-queue`.
+const FINISHED_ENTRIES_SHOWN: i64 = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MergeQueueSection {
+    /// Given to a base as `merge_queue_enabled` when its repo is attached.
+    pub enabled: bool,
+    /// Time allowed for rebasing, re-testing and merging one entry.
+    pub test_timeout_sec: u64,
+    /// Checks that must have reported success before a run is enqueued (a check run or status
+    /// context name); a base's `merge_queue_required_checks` replaces the list.
+    pub required_checks: Vec<String>,
+}
+
+impl Default for MergeQueueSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            test_timeout_sec: 1800,
+            required_checks: vec![],
+        }
+    }
+}
+
+pub(crate) fn payload_merge_queue_enabled(payload: &Value) -> bool {
+    payload
+        .get("merge_queue_enabled")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// `VERDICT` from the review step, or, for review prompts without that line (edited builtins,
+/// custom workflows), the first word of its `REVIEW`.
+fn approved(ctx: &Value) -> bool {
+    let verdict = ctx
+        .pointer("/outputs/review/verdict")
+        .and_then(|v| v.as_str())
+        .or_else(|| {
+            ctx.pointer("/outputs/review/review")
+                .and_then(|v| v.as_str())
+                .and_then(|v| {
+                    v.split(|c: char| !c.is_alphanumeric())
+                        .find(|w| !w.is_empty())
+                })
+        });
+    verdict
+        .map(|v| v.trim().to_ascii_lowercase())
+        .is_some_and(|v| APPROVING_VERDICTS.contains(&v.as_str()))
+}
+
+/// True when no check's latest result is a failure or still pending, and every `required`
+/// check's latest result passed. Without required checks, runs without CI pass.
+fn checks_pass(
+    conn: &rusqlite::Connection,
+    run_id: &str,
+    required: &[String],
+) -> anyhow::Result<bool> {
+    let latest: Vec<(String, Option<String>, bool)> = {
+        let mut stmt = conn.prepare(
+            "SELECT COALESCE(f.check_name, ''), f.state, f.actionable FROM run_feedback f
+             WHERE f.run_id=?1 AND f.source='ci'
+               AND f.id = (SELECT MAX(g.id) FROM run_feedback g
+                           WHERE g.run_id=f.run_id AND g.source='ci'
+                             AND COALESCE(g.check_name, '')=COALESCE(f.check_name, ''))",
+        )?;
+        let rows = stmt.query_map([run_id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+    let passed = |(_, state, actionable): &(String, Option<String>, bool)| {
+        !actionable && state.as_deref() != Some("pending")
+    };
+    // Stored names carry their kind (`check_run:tests`, `status:ci/build`).
+    let named = |check: &str, name: &str| {
+        check == name || check.split_once(':').is_some_and(|(_, n)| n == name)
+    };
+    Ok(latest.iter().all(passed)
+        && required
+            .iter()
+            .all(|name| latest.iter().any(|c| named(&c.0, name) && passed(c))))
+}
+
+/// The base's `merge_queue_required_checks`, else the configured list.
+fn required_checks(payload: &Value, cfg: &MergeQueueSection) -> Vec<String> {
+    match payload
+        .get("merge_queue_required_checks")
+        .and_then(|v| v.as_array())
+    {
+        Some(list) => list
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(str::to_string)
+            .collect(),
+        None => cfg.required_checks.clone(),
+    }
+}
+
+/// Enqueues the base's finished, approved and green runs. Returns how many were added.
+fn enqueue_ready(
+    conn: &rusqlite::Connection,
+    base_id: &str,
+    required: &[String],
+) -> anyhow::Result<usize> {
+    let candidates: Vec<(String, String)> = {
+        let mut stmt = conn.prepare(
+            "SELECT id, context_json FROM runs
+             WHERE status='done' AND json_extract(context_json, '$.base_id')=?1
+               AND json_extract(context_json, '$.pr_url') IS NOT NULL
+               AND NOT EXISTS (SELECT 1 FROM merge_queue q WHERE q.run_id=runs.id)
+             ORDER BY updated_at ASC",
+        )?;
+        let rows = stmt.query_map([base_id], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+    let mut added = 0;
+    for (run_id, ctx_json) in candidates {
+        let ctx = parse_payload(&ctx_json);
+        let pr_url = ctx.get("pr_url").and_then(|v| v.as_str()).unwrap_or("");
+        let pr_number = ctx
+            .get("pr_number")
+            .and_then(|v| v.as_i64())
+            .or_else(|| super::forge::pr_number_from_url(pr_url));
+        let branch = ctx.get("branch").and_then(|v| v.as_str()).unwrap_or("");
+        let (Some(pr_number), false) = (pr_number, branch.is_empty()) else {
+            continue;
+        };
+        if ctx.get("pr_merged").and_then(|v| v.as_bool()) == Some(true)
+            || !approved(&ctx)
+            || !checks_pass(conn, &run_id, required)?
+        {
+            continue;
+        }
+        let now = now_ms_i64();
+        conn.execute(
+            "INSERT INTO merge_queue (base_id, run_id, branch, pr_url, pr_number, test_cmd, status, enqueued_at_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'queued', ?7)",
+            (
+                base_id,
+                &run_id,
+                branch,
+                pr_url,
+                pr_number,
+                ctx.get("test_cmd").and_then(|v| v.as_str()),
+                now,
+            ),
+        )?;
+        conn.execute(
+            "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'merge_queue.enqueued', ?2, ?3)",
+            (
+                now,
+                base_id,
+                serde_json::json!({ "run_id": run_id, "pr_url": pr_url }).to_string(),
+            ),
+        )?;
+        added += 1;
+    }
+    Ok(added)
+}
+
+/// Queues a `merge-queue` run for the base when it has waiting entries and none in flight.
+fn queue_worker_run(
+    conn: &rusqlite::Connection,
+    base_id: &str,
+    repo: &str,
+    cfg: &MergeQueueSection,
+) -> anyhow::Result<bool> {
+    let waiting: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM merge_queue WHERE base_id=?1 AND status='queued'",
+        [base_id],
+        |r| r.get(0),
+    )?;
+    let in_flight: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM runs WHERE workflow_id=?1 AND entity_id=?2 AND status IN ('queued','running')",
+        (MERGE_QUEUE_WORKFLOW, base_id),
+        |r| r.get(0),
+    )?;
+    if waiting == 0 || in_flight > 0 {
+        return Ok(false);
+    }
+    let now_ms = now_ms_i64();
+    let ts = now_rfc3339();
+    let run_id = format!("run-merge-queue-{now_ms}");
+    let ctx = serde_json::json!({
+        "action": "merge_queue",
+        "base_id": base_id,
+        "base_repo_path": repo,
+        "default_branch": detect_default_branch(repo).unwrap_or_else(|_| "main".to_string()),
+    })
+    .to_string();
+    conn.execute(
+        "INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at)
+         VALUES (?1, ?2, ?3, 'queued', ?4, ?5, ?6, ?6)",
+        (
+            &run_id,
+            MERGE_QUEUE_WORKFLOW,
+            format!("Merge queue for base {base_id}"),
+            base_id,
+            &ctx,
+            &ts,
+        ),
+    )?;
+    conn.execute(
+        "INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text, timeout_sec, created_at, updated_at)
+         VALUES (?1, ?2, 'merge', 'internal/pr', 0, 'queued', ?3, NULL, ?4, ?5, ?5)",
+        (
+            format!("step-{run_id}"),
+            &run_id,
+            &ctx,
+            cfg.test_timeout_sec.min(i64::MAX as u64) as i64,
+            &ts,
+        ),
+    )?;
+    Ok(true)
+}
+
+/// Periodic pass over every base with the queue enabled: enqueue ready runs, then make sure a
+/// worker run is queued for bases with waiting entries. Returns the number of runs enqueued.
+pub(crate) fn tick(engine: &Engine, cfg: &MergeQueueSection) -> anyhow::Result<usize> {
+    let mut enqueued = 0;
+    for base in engine
+        .list_entities()?
+        .into_iter()
+        .filter(|e| e.kind == "base")
+    {
+        let payload = parse_payload(&base.payload_json);
+        if !payload_merge_queue_enabled(&payload) {
+            continue;
+        }
+        let Some(repo) = repo_path_from_payload(&payload) else {
+            continue;
+        };
+        let mut conn = engine.open()?;
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        // Entries under test whose worker run is gone (restart, cancelled run) wait again.
+        tx.execute(
+            "UPDATE merge_queue SET status='queued', started_at_ms=NULL
+             WHERE base_id=?1 AND status='testing'
+               AND NOT EXISTS (SELECT 1 FROM runs WHERE workflow_id=?2 AND entity_id=?1
+                                 AND status IN ('queued','running'))",
+            (&base.id, MERGE_QUEUE_WORKFLOW),
+        )?;
+        enqueued += enqueue_ready(&tx, &base.id, &required_checks(&payload, cfg))?;
+        queue_worker_run(&tx, &base.id, &repo, cfg)?;
+        tx.commit()?;
+    }
+    Ok(enqueued)
+}
+
+struct Entry {
+    id: i64,
+    run_id: String,
+    branch: String,
+    pr_url: String,
+    pr_number: i64,
+    test_cmd: Option<String>,
+}
+
+/// Rebases, re-tests and pushes the entry's branch in a throwaway worktree; returns the tested
+/// head SHA.
+fn prepare(
+    repo: &str,
+    default_branch: &str,
+    entry: &Entry,
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
+    scratch::git_cancellable(repo, &["fetch", "origin"], cancel)
+        .map_err(|e| anyhow::anyhow!("git_fetch_failed: {e}"))?;
+    scratch::rebase_branch(
+        repo,
+        &entry.branch,
+        default_branch,
+        entry.test_cmd.as_deref(),
+        cancel,
+    )
+}
+
+fn finish_entry(
+    engine: &Engine,
+    base_id: &str,
+    entry: &Entry,
+    result: &anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    let now = now_ms_i64();
+    let conn = engine.open()?;
+    let (status, sha, error) = match result {
+        Ok(sha) => ("merged", Some(sha.as_str()), None),
+        Err(e) => ("failed", None, Some(e.to_string())),
+    };
+    conn.execute(
+        "UPDATE merge_queue SET status=?1, merged_sha=?2, error=?3, finished_at_ms=?4 WHERE id=?5",
+        (status, sha, &error, now, entry.id),
+    )?;
+    conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, ?2, ?3, ?4)",
+        (
+            now,
+            format!("merge_queue.{status}"),
+            base_id,
+            serde_json::json!({
+                "run_id": entry.run_id,
+                "pr_url": entry.pr_url,
+                "merged_sha": sha,
+                "error": error,
+            })
+            .to_string(),
+        ),
+    )?;
+    Ok(())
+}
+
+/// Works the head of a base's queue (the `merge-queue` run's only step).
+pub(crate) fn execute(
+    engine: &Engine,
+    forges: &ForgeRegistry,
+    step: &PendingStep,
+    ctx: &Value,
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
+    let base_id = ctx
+        .get("base_id")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("missing_base_id"))?;
+    let repo = ctx
+        .get("base_repo_path")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("missing_base_repo_path"))?;
+    let default_branch = ctx
+        .get("default_branch")
+        .and_then(|v| v.as_str())
+        .unwrap_or("main");
+
+    let entry = {
+        let mut conn = engine.open()?;
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let entry = tx
+            .query_row(
+                // A `testing` entry was left by a worker that died mid-test; it goes first.
+                "SELECT id, run_id, branch, pr_url, pr_number, test_cmd FROM merge_queue
+                 WHERE base_id=?1 AND status IN ('testing','queued')
+                 ORDER BY status='testing' DESC, id LIMIT 1",
+                [base_id],
+                |r| {
+                    Ok(Entry {
+                        id: r.get(0)?,
+                        run_id: r.get(1)?,
+                        branch: r.get(2)?,
+                        pr_url: r.get(3)?,
+                        pr_number: r.get(4)?,
+                        test_cmd: r.get(5)?,
+                    })
+                },
+            )
+            .optional()?;
+        if let Some(e) = &entry {
+            tx.execute(
+                "UPDATE merge_queue SET status='testing', started_at_ms=?1 WHERE id=?2",
+                (now_ms_i64(), e.id),
+            )?;
+        }
+        tx.commit()?;
+        entry
+    };
+    let Some(entry) = entry else {
+        return Ok(format!("merge queue empty ({})", step.run_id));
+    };
+
+    let forge = forges.resolve(repo);
+    let result = prepare(repo, default_branch, &entry, cancel).and_then(|sha| {
+        forge
+            .merge_pr(repo, entry.pr_number)
+            .map_err(|e| anyhow::anyhow!("merge_failed: {e}"))?;
+        Ok(sha)
+    });
+    // The heartbeat cancels the same token on timeout; only an operator stopping the worker run
+    // puts the entry back in line, otherwise a slow suite would be retried forever.
+    let result = if cancel.is_cancelled() && result.is_err() {
+        let run_status: String = engine.open()?.query_row(
+            "SELECT status FROM runs WHERE id=?1",
+            [&step.run_id],
+            |r| r.get(0),
+        )?;
+        if matches!(run_status.as_str(), "cancelled" | "paused") {
+            engine.open()?.execute(
+                "UPDATE merge_queue SET status='queued', started_at_ms=NULL WHERE id=?1",
+                [entry.id],
+            )?;
+            anyhow::bail!("cancelled");
+        }
+        Err(anyhow::anyhow!(
+            "tests_timed_out: exceeded {}s",
+            step.timeout_sec
+        ))
+    } else {
+        result
+    };
+    finish_entry(engine, base_id, &entry, &result)?;
+    match result {
+        Ok(sha) => {
+            mark_runs_pr_merged(engine, &entry.pr_url)?;
+            Ok(format!("merged {} at {sha}", entry.pr_url))
+        }
+        Err(e) => {
+            let _ = forge.post_comment(
+                repo,
+                entry.pr_number,
+                &format!("Clawdorio merge queue could not merge this PR:\n\n```\n{e}\n```"),
+            );
+            Ok(format!("failed {}: {e}", entry.pr_url))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct EntryView {
+    pub(crate) run_id: String,
+    pub(crate) branch: String,
+    pub(crate) pr_url: String,
+    pub(crate) pr_number: i64,
+    pub(crate) status: String,
+    /// 1 for the next entry to be worked, among `queued` ones.
+    pub(crate) position: Option<i64>,
+    pub(crate) test_cmd: Option<String>,
+    pub(crate) error: Option<String>,
+    pub(crate) merged_sha: Option<String>,
+    pub(crate) enqueued_at_ms: i64,
+    pub(crate) started_at_ms: Option<i64>,
+    pub(crate) finished_at_ms: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct QueueView {
+    pub(crate) base_id: String,
+    pub(crate) enabled: bool,
+    /// Entries being tested, then waiting ones in order, then recently finished ones.
+    pub(crate) entries: Vec<EntryView>,
+}
+
+pub(crate) fn queue_view(
+    engine: &Engine,
+    base_id: &str,
+    enabled: bool,
+) -> anyhow::Result<QueueView> {
+    let conn = engine.open()?;
+    let mut stmt = conn.prepare(
+        "SELECT run_id, branch, pr_url, pr_number, status, test_cmd, error, merged_sha,
+                enqueued_at_ms, started_at_ms, finished_at_ms
+         FROM merge_queue WHERE base_id=?1
+         ORDER BY CASE status WHEN 'testing' THEN 0 WHEN 'queued' THEN 1 ELSE 2 END,
+                  CASE WHEN status IN ('testing','queued') THEN id ELSE -finished_at_ms END
+         LIMIT -1",
+    )?;
+    let mut position = 0;
+    let mut finished = 0;
+    let mut entries = vec![];
+    let rows = stmt.query_map([base_id], |r| {
+        Ok(EntryView {
+            run_id: r.get(0)?,
+            branch: r.get(1)?,
+            pr_url: r.get(2)?,
+            pr_number: r.get(3)?,
+            status: r.get(4)?,
+            position: None,
+            test_cmd: r.get(5)?,
+            error: r.get(6)?,
+            merged_sha: r.get(7)?,
+            enqueued_at_ms: r.get(8)?,
+            started_at_ms: r.get(9)?,
+            finished_at_ms: r.get(10)?,
+        })
+    })?;
+    for row in rows {
+        let mut e = row?;
+        match e.status.as_str() {
+            "queued" => {
+                position += 1;
+                e.position = Some(position);
+            }
+            "testing" => {}
+            _ => {
+                finished += 1;
+                if finished > FINISHED_ENTRIES_SHOWN {
+                    break;
+                }
+            }
+        }
+        entries.push(e);
+    }
+    Ok(QueueView {
+        base_id: base_id.to_string(),
+        enabled,
+        entries,
+    })
+}
+
+pub(crate) async fn api_base_merge_queue(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<QueueView>, (StatusCode, String)> {
+    let base = find_base_entity(&state.engine, &id)?;
+    let enabled = payload_merge_queue_enabled(&parse_payload(&base.payload_json));
+    queue_view(&state.engine, &id, enabled)
+        .map(Json)
+        .map_err(internal_error("merge_queue.view"))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct QueuePatch {
+    pub(crate) enabled: bool,
+}
+
+pub(crate) async fn api_base_merge_queue_patch(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Json(input): Json<QueuePatch>,
+) -> Result<Json<QueueView>, (StatusCode, String)> {
+    let base = find_base_entity(&state.engine, &id)?;
+    let mut payload = parse_payload(&base.payload_json);
+    payload["merge_queue_enabled"] = Value::Bool(input.enabled);
+    state
+        .engine
+        .update_entity_payload(&id, &payload.to_string())
+        .map_err(internal_error("engine.update_entity_payload"))?;
+    queue_view(&state.engine, &id, input.enabled)
+        .map(Json)
+        .map_err(internal_error("merge_queue.view"))
+}
@@ -0,0 +1,207 @@
+//! Step worker pool.
+//!
+//! `max_workers` tasks each claim and execute one step at a time; `max_per_base` is enforced in
+//! the claim query itself (running steps grouped by the run's base), so it holds across workers
+//! and across server restarts.
+//!
+//! While a step executes, a [`Heartbeat`] thread stamps `steps.heartbeat_at_ms` and enforces the
+//! step timeout; the reaper only re-queues `running` rows whose heartbeat went stale.
+
+use crate::executor::CancelToken;
+use clawdorio_engine::Engine;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub(crate) const HEARTBEAT_INTERVAL_MS: u64 = 5_000;
+/// A running step with no heartbeat for this long is presumed dead (12 missed beats).
+pub(crate) const STEP_STALE_AFTER_MS: i64 = 60_000;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WorkerLimits {
+    /// Global number of steps that may execute at once.
+    pub max_workers: usize,
+    /// Steps that may execute at once for runs on the same base.
+    pub max_per_base: usize,
+}
+
+impl Default for WorkerLimits {
+    fn default() -> Self {
+        Self {
+            max_workers: 4,
+            max_per_base: 1,
+        }
+    }
+}
+
+impl WorkerLimits {
+    pub fn new(max_workers: usize, max_per_base: usize) -> Self {
+        Self {
+            max_workers: max_workers.max(1),
+            max_per_base: max_per_base.max(1),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WorkerPool {
+    pub limits: WorkerLimits,
+    busy: AtomicUsize,
+    ran_since_tick: AtomicBool,
+    /// In-flight steps: step row id -> (run id, cancel token).
+    in_flight: Mutex<HashMap<String, (String, CancelToken)>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WorkerPoolView {
+    pub max_workers: usize,
+    pub max_per_base: usize,
+    pub busy: usize,
+}
+
+impl WorkerPool {
+    pub fn new(limits: WorkerLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    pub fn view(&self) -> WorkerPoolView {
+        WorkerPoolView {
+            max_workers: self.limits.max_workers,
+            max_per_base: self.limits.max_per_base,
+            busy: self.busy(),
+        }
+    }
+
+    /// Marks one worker busy on `step_row_id` until the guard drops. The guard's token is
+    /// what [`WorkerPool::cancel_run`] flips.
+    pub(crate) fn enter(&self, step_row_id: &str, run_id: &str) -> BusyGuard<'_> {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+        self.ran_since_tick.store(true, Ordering::Relaxed);
+        let token = CancelToken::default();
+        if let Ok(mut m) = self.in_flight.lock() {
+            m.insert(step_row_id.to_string(), (run_id.to_string(), token.clone()));
+        }
+        BusyGuard {
+            pool: self,
+            step_row_id: step_row_id.to_string(),
+            token,
+        }
+    }
+
+    /// Cancels every in-flight step of `run_id`; returns how many were signalled.
+    pub fn cancel_run(&self, run_id: &str) -> usize {
+        let Ok(m) = self.in_flight.lock() else {
+            return 0;
+        };
+        m.values()
+            .filter(|(r, _)| r == run_id)
+            .inspect(|(_, t)| t.cancel())
+            .count()
+    }
+
+    /// Stable id for pool slot `n`, recorded on the steps it claims.
+    pub fn worker_id(&self, n: usize) -> String {
+        format!("pid{}-w{n}", std::process::id())
+    }
+
+    /// Step rows this process is executing right now (never reaped, whatever their heartbeat).
+    pub(crate) fn in_flight_ids(&self) -> HashSet<String> {
+        self.in_flight
+            .lock()
+            .map(|m| m.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether any worker executed a step since the last call (housekeeping idle detection).
+    pub(crate) fn take_ran(&self) -> bool {
+        self.ran_since_tick.swap(false, Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct BusyGuard<'a> {
+    pool: &'a WorkerPool,
+    step_row_id: String,
+    pub(crate) token: CancelToken,
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut m) = self.pool.in_flight.lock() {
+            m.remove(&self.step_row_id);
+        }
+        self.pool.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Liveness + timeout watchdog for one executing step.
+pub(crate) struct Heartbeat {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Heartbeat {
+    /// Beats every [`HEARTBEAT_INTERVAL_MS`]; once `timeout_sec` elapses it cancels `token`.
+    pub(crate) fn start(
+        engine: Engine,
+        step_row_id: String,
+        worker_id: String,
+        timeout_sec: u64,
+        token: CancelToken,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<()>();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let flag = timed_out.clone();
+        let handle = std::thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(timeout_sec);
+            loop {
+                let wait = deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(Duration::from_millis(HEARTBEAT_INTERVAL_MS));
+                match rx.recv_timeout(wait) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                if Instant::now() >= deadline {
+                    flag.store(true, Ordering::SeqCst);
+                    token.cancel();
+                    return;
+                }
+                let _ = touch_heartbeat(&engine, &step_row_id, &worker_id);
+            }
+        });
+        Self {
+            stop: Some(tx),
+            handle: Some(handle),
+            timed_out,
+        }
+    }
+
+    /// Stops the watchdog; returns whether the step hit its timeout.
+    pub(crate) fn finish(mut self) -> bool {
+        self.stop.take();
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+        self.timed_out.load(Ordering::SeqCst)
+    }
+}
+
+fn touch_heartbeat(engine: &Engine, step_row_id: &str, worker_id: &str) -> anyhow::Result<()> {
+    let conn = engine.open()?;
+    conn.execute(
+        "UPDATE steps SET heartbeat_at_ms=?1 WHERE id=?2 AND status='running' AND worker_id=?3",
+        (crate::now_ms_i64(), step_row_id, worker_id),
+    )?;
+    Ok(())
+}
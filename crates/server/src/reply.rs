@@ -0,0 +1,149 @@
+//! Agent reply parsing.
+//!
+//! Step prompts ask agents to answer with `KEY: value` lines (`STATUS: done`,
+//! `STORIES_JSON: [...]`, `BUILD_CMD: ...`, `TEST_CMD: ...`). [`StepReply::parse`] extracts them
+//! into a structured record that is stored in `step_outputs` and threaded into the run context
+//! so later steps can use planned stories and commands.
+
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Keys promoted to the top level of the run context for later steps' prompts.
+pub(crate) const FORWARDED_KEYS: [&str; 3] = ["stories", "build_cmd", "test_cmd"];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct StepReply {
+    /// Lower-cased `STATUS` value, if the reply had one.
+    pub(crate) status: Option<String>,
+    /// Every `KEY: value` field, keyed by lower-cased key. Multi-line values keep their lines.
+    pub(crate) fields: BTreeMap<String, String>,
+    /// Parsed `STORIES_JSON` array.
+    pub(crate) stories: Option<Vec<Value>>,
+    /// Fields that were present but malformed (e.g. `STORIES_JSON` that is not a JSON array).
+    pub(crate) errors: Vec<String>,
+}
+
+impl StepReply {
+    pub(crate) fn parse(text: &str) -> Self {
+        let mut fields: BTreeMap<String, String> = BTreeMap::new();
+        let mut current: Option<String> = None;
+        for line in text.lines() {
+            // Agents often wrap the block in a code fence.
+            if line.trim_start().starts_with("```") {
+                continue;
+            }
+            if let Some((key, value)) = field_line(line) {
+                // The last occurrence wins: agents sometimes echo the prompt before answering.
+                fields.insert(key.clone(), value.trim().to_string());
+                current = Some(key);
+            } else if let Some(key) = &current {
+                if let Some(v) = fields.get_mut(key) {
+                    v.push('\n');
+                    v.push_str(line);
+                }
+            }
+        }
+        for v in fields.values_mut() {
+            *v = v.trim().to_string();
+        }
+
+        let mut errors = vec![];
+        let stories = fields
+            .get("stories_json")
+            .and_then(|raw| match parse_json_array(raw) {
+                Some(items) => Some(items),
+                None => {
+                    errors.push("invalid STORIES_JSON: expected a JSON array".to_string());
+                    None
+                }
+            });
+        let status = fields.get("status").map(|s| {
+            s.split_whitespace()
+                .next()
+                .unwrap_or("")
+                .trim_matches(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .to_ascii_lowercase()
+        });
+        Self {
+            status,
+            fields,
+            stories,
+            errors,
+        }
+    }
+
+    /// Why the step should fail, or `None` when the agent reported `STATUS: done` and every
+    /// field parsed.
+    pub(crate) fn failure(&self) -> Option<String> {
+        if let Some(err) = self.errors.first() {
+            return Some(format!("step_reply_invalid: {err}"));
+        }
+        match self.status.as_deref() {
+            Some("done") => None,
+            Some(s) => Some(format!("step_not_done: STATUS: {s}")),
+            None => Some("step_not_done: reply has no STATUS line".to_string()),
+        }
+    }
+
+    /// JSON record stored in `step_outputs.outputs_json`.
+    pub(crate) fn to_json(&self) -> Value {
+        let mut m = Map::new();
+        for (k, v) in &self.fields {
+            if k == "stories_json" {
+                continue;
+            }
+            m.insert(k.clone(), Value::String(v.clone()));
+        }
+        if let Some(status) = &self.status {
+            m.insert("status".to_string(), Value::String(status.clone()));
+        }
+        if let Some(stories) = &self.stories {
+            m.insert("stories".to_string(), Value::Array(stories.clone()));
+        }
+        Value::Object(m)
+    }
+}
+
+/// `KEY: value` where KEY is upper-case letters, digits and underscores.
+fn field_line(line: &str) -> Option<(String, &str)> {
+    let (key, value) = line.trim_start().split_once(':')?;
+    let mut chars = key.chars();
+    let first = chars.next()?;
+    if !first.is_ascii_uppercase()
+        || !chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    {
+        return None;
+    }
+    Some((key.to_ascii_lowercase(), value))
+}
+
+fn parse_json_array(raw: &str) -> Option<Vec<Value>> {
+    // Tolerate trailing prose after the array.
+    let mut it = serde_json::Deserializer::from_str(raw).into_iter::<Value>();
+    match it.next()? {
+        Ok(Value::Array(items)) => Some(items),
+        _ => None,
+    }
+}
+
+/// Merges a reply into a run context: `outputs.<step_id>` gets the full record and the
+/// [`FORWARDED_KEYS`] are promoted to the top level.
+pub(crate) fn merge_into_context(ctx: &mut Value, step_id: &str, outputs: &Value) {
+    if !ctx.is_object() {
+        *ctx = Value::Object(Map::new());
+    }
+    let Some(obj) = ctx.as_object_mut() else {
+        return;
+    };
+    let all = obj
+        .entry("outputs")
+        .or_insert_with(|| Value::Object(Map::new()));
+    if let Some(all) = all.as_object_mut() {
+        all.insert(step_id.to_string(), outputs.clone());
+    }
+    for key in FORWARDED_KEYS {
+        if let Some(v) = outputs.get(key) {
+            obj.insert(key.to_string(), v.clone());
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Step retry policies.
+//!
+//! A workflow step may declare `retry: { max_attempts, backoff_sec, rewind_to, retry_on }`.
+//! Runs snapshot the policy into `steps.input_json`; attempts are counted on the step row
+//! (`steps.attempt`, bumped at claim time) and a retry waits until `steps.next_attempt_at_ms`.
+
+use serde::{Deserialize, Serialize};
+
+/// Backoff never grows past this, however many attempts a policy allows.
+const MAX_BACKOFF_SEC: u64 = 3600;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one (1 = never retry).
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubles on every further retry.
+    #[serde(default)]
+    pub backoff_sec: u64,
+    /// Earlier step to re-run (with everything downstream of it) before this step runs again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewind_to: Option<String>,
+    /// Error classes worth retrying; empty retries every class.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retry_on: Vec<ErrorClass>,
+}
+
+fn default_max_attempts() -> u32 {
+    1
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            backoff_sec: 0,
+            rewind_to: None,
+            retry_on: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Network, rate-limit and other failures that usually go away on their own.
+    Transient,
+    /// The step exceeded its `timeout_sec`.
+    Timeout,
+    /// The agent answered, but not with `STATUS: done` (or with malformed fields).
+    NotDone,
+    /// Anything else.
+    Failed,
+}
+
+impl ErrorClass {
+    pub fn classify(err: &str) -> Self {
+        if err.starts_with("step_timeout") {
+            return Self::Timeout;
+        }
+        if err.starts_with("step_not_done") || err.starts_with("step_reply_invalid") {
+            return Self::NotDone;
+        }
+        let lower = err.to_ascii_lowercase();
+        const TRANSIENT: [&str; 14] = [
+            "timed out",
+            "timeout",
+            "connection reset",
+            "connection refused",
+            "could not resolve host",
+            "network is unreachable",
+            "temporary failure",
+            "temporarily unavailable",
+            "rate limit",
+            "502 bad gateway",
+            "503 service unavailable",
+            "504 gateway",
+            "tls handshake",
+            "unexpected eof",
+        ];
+        if TRANSIENT.iter().any(|p| lower.contains(p)) {
+            return Self::Transient;
+        }
+        Self::Failed
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Transient => "transient",
+            Self::Timeout => "timeout",
+            Self::NotDone => "not_done",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy snapshotted on a step row. Rows queued before workflows existed keep the old
+    /// behaviour: `test` rewinds to `implement` (3 attempts) and `pr` retries transient errors.
+    pub(crate) fn for_step(step_id: &str, input: &serde_json::Value) -> Self {
+        if let Some(p) = input
+            .get("retry")
+            .and_then(|v| serde_json::from_value::<Self>(v.clone()).ok())
+        {
+            return p;
+        }
+        if input.get("workflow_id").is_some() {
+            return Self::default();
+        }
+        match step_id {
+            "test" => Self {
+                max_attempts: 3,
+                rewind_to: Some("implement".to_string()),
+                ..Self::default()
+            },
+            "pr" => Self {
+                max_attempts: 3,
+                backoff_sec: 30,
+                retry_on: vec![ErrorClass::Transient, ErrorClass::Timeout],
+                ..Self::default()
+            },
+            _ => Self::default(),
+        }
+    }
+
+    /// Whether a step that has made `attempt` attempts and failed with `class` runs again.
+    pub(crate) fn should_retry(&self, attempt: u32, class: ErrorClass) -> bool {
+        attempt < self.max_attempts && (self.retry_on.is_empty() || self.retry_on.contains(&class))
+    }
+
+    /// Delay before attempt `attempt + 1`.
+    pub(crate) fn delay_ms(&self, attempt: u32) -> i64 {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        (self.backoff_sec.saturating_mul(factor).min(MAX_BACKOFF_SEC) * 1000) as i64
+    }
+}
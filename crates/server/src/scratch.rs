@@ -0,0 +1,116 @@
+//! Throwaway worktrees for rewriting PR branches.
+//!
+//! Auto-rebase sweeps and the merge queue rebase `clawdorio/*` branches without touching the base
+//! repo's own checkout, which is often someone's working copy: the remote branch is checked out
+//! detached in a temporary worktree, rebased there, optionally tested, and pushed back with a
+//! lease on the SHA it was taken from. The worktree is removed whatever the outcome.
+
+use super::executor::{run_child, CancelToken};
+use std::path::Path;
+use std::process::Command;
+
+pub(crate) fn git(dir: &str, args: &[&str]) -> anyhow::Result<String> {
+    let out = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    git_result(args, out)
+}
+
+/// [`git`] for commands that can hang on the network (fetch, push): a step timeout or cancel
+/// kills them.
+pub(crate) fn git_cancellable(
+    dir: &str,
+    args: &[&str],
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(dir).args(args);
+    git_result(args, run_child(cmd, None, cancel)?)
+}
+
+fn git_result(args: &[&str], out: std::process::Output) -> anyhow::Result<String> {
+    if !out.status.success() {
+        anyhow::bail!(
+            "git {}: {}",
+            args.first().copied().unwrap_or(""),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// True when the checkout has staged or unstaged changes to tracked files. Untracked files do
+/// not count.
+pub(crate) fn has_local_changes(repo: &str) -> anyhow::Result<bool> {
+    git(repo, &["status", "--porcelain", "--untracked-files=no"]).map(|out| !out.is_empty())
+}
+
+/// Runs `cmd` with `sh -c` in `dir`; a failure carries the last lines of its output.
+pub(crate) fn run_test_cmd(dir: &str, cmd: &str, cancel: &CancelToken) -> anyhow::Result<()> {
+    if cmd.trim().is_empty() {
+        return Ok(());
+    }
+    let mut sh = Command::new("sh");
+    sh.arg("-c").arg(cmd).current_dir(Path::new(dir));
+    let out = run_child(sh, None, cancel)?;
+    if !out.status.success() {
+        let mut log = String::from_utf8_lossy(&out.stdout).to_string();
+        log.push_str(&String::from_utf8_lossy(&out.stderr));
+        let lines: Vec<&str> = log.lines().collect();
+        let tail = lines[lines.len().saturating_sub(20)..].join("\n");
+        anyhow::bail!("tests_failed: {cmd}\n{tail}");
+    }
+    Ok(())
+}
+
+/// Pushes `HEAD` of `dir` to `branch` on origin, provided the remote branch is still at `lease`.
+/// Returns the pushed SHA.
+pub(crate) fn push_with_lease(
+    dir: &str,
+    branch: &str,
+    lease: &str,
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
+    let lease_arg = format!("--force-with-lease=refs/heads/{branch}:{lease}");
+    let dest = format!("HEAD:refs/heads/{branch}");
+    git_cancellable(dir, &["push", &lease_arg, "origin", &dest], cancel)
+        .map_err(|e| anyhow::anyhow!("git_push_failed: {e}"))?;
+    git(dir, &["rev-parse", "HEAD"])
+}
+
+/// Rebases `origin/<branch>` onto `origin/<default_branch>` in a throwaway worktree of `repo`,
+/// runs `test_cmd` there if given, and pushes the result when it moved. The caller fetches
+/// first. Returns the branch's head SHA afterwards.
+pub(crate) fn rebase_branch(
+    repo: &str,
+    branch: &str,
+    default_branch: &str,
+    test_cmd: Option<&str>,
+    cancel: &CancelToken,
+) -> anyhow::Result<String> {
+    let remote_head = format!("origin/{branch}");
+    let old_sha = git(repo, &["rev-parse", "--verify", &remote_head])
+        .map_err(|_| anyhow::anyhow!("branch_missing: {remote_head}"))?;
+    let dir = std::env::temp_dir().join(format!(
+        "clawdorio-rebase-{}-{}",
+        branch.replace('/', "-"),
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    let dir_s = dir.to_string_lossy().to_string();
+    git(repo, &["worktree", "add", "--detach", &dir_s, &old_sha])?;
+    let result = (|| {
+        let upstream = format!("origin/{default_branch}");
+        if let Err(e) = git(&dir_s, &["rebase", &upstream]) {
+            let _ = git(&dir_s, &["rebase", "--abort"]);
+            anyhow::bail!("rebase_conflict: {e}");
+        }
+        if let Some(cmd) = test_cmd {
+            run_test_cmd(&dir_s, cmd, cancel)?;
+        }
+        let new_sha = git(&dir_s, &["rev-parse", "HEAD"])?;
+        if new_sha == old_sha {
+            return Ok(new_sha);
+        }
+        push_with_lease(&dir_s, branch, &old_sha, cancel)
+    })();
+    let _ = git(repo, &["worktree", "remove", "--force", &dir_s]);
+    result
+}
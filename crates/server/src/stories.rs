@@ -0,0 +1,345 @@
+//! Story fan-out.
+//!
+//! When the first `per_story` step of a run becomes runnable and an earlier step reported
+//! `STORIES_JSON`, the run's queued `per_story` steps are replaced by one chain per story
+//! (implement -> verify for `feature-dev`). Without stories they run once as ordinary steps.
+//! Steps that depended on the replaced rows are rewired to the story chains, every row gets
+//! explicit `depends_on_json` edges, and `step_index` is rewritten in dependency order so lists
+//! still read top to bottom.
+//!
+//! Stories run one after another by default because they share the run's worktree; a story
+//! with an explicit `"depends_on": [...]` (earlier story ids, possibly empty) waits only on those.
+
+use super::{now_ms_i64, now_rfc3339, parse_payload, PendingStep};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+struct RunStep {
+    id: String,
+    step_id: String,
+    agent_id: String,
+    status: String,
+    input: Value,
+    timeout_sec: Option<i64>,
+    story_id: Option<String>,
+    depends_on: Option<Vec<String>>,
+}
+
+impl RunStep {
+    fn is_template(&self) -> bool {
+        self.story_id.is_none()
+            && matches!(self.status.as_str(), "queued" | "pending" | "waiting")
+            && self.input.get("per_story").and_then(|v| v.as_bool()) == Some(true)
+    }
+}
+
+enum Planned<'a> {
+    Existing {
+        id: &'a str,
+        deps: Vec<String>,
+    },
+    Copy {
+        id: String,
+        template: &'a RunStep,
+        story_id: &'a str,
+        story: &'a Value,
+        deps: Vec<String>,
+    },
+}
+
+/// Replaces the run's `per_story` template steps with per-story copies. Returns the number of
+/// stories expanded (0 when the run has no templates left, e.g. a re-run plan).
+pub(crate) fn expand_stories(
+    conn: &Connection,
+    run_id: &str,
+    stories: &[Value],
+) -> anyhow::Result<usize> {
+    if stories.is_empty() {
+        return Ok(0);
+    }
+    let steps: Vec<RunStep> = {
+        let mut stmt = conn.prepare(
+            "SELECT id, step_id, agent_id, status, input_json, timeout_sec, story_id, depends_on_json
+             FROM steps WHERE run_id=?1 ORDER BY step_index ASC",
+        )?;
+        let rows = stmt.query_map([run_id], |r| {
+            Ok(RunStep {
+                id: r.get(0)?,
+                step_id: r.get(1)?,
+                agent_id: r.get(2)?,
+                status: r.get(3)?,
+                input: parse_payload(&r.get::<_, String>(4)?),
+                timeout_sec: r.get(5)?,
+                story_id: r.get(6)?,
+                depends_on: r
+                    .get::<_, Option<String>>(7)?
+                    .and_then(|j| serde_json::from_str(&j).ok()),
+            })
+        })?;
+        rows.collect::<Result<_, _>>()?
+    };
+    if !steps.iter().any(RunStep::is_template) {
+        return Ok(0);
+    }
+    let story_ids = story_ids(stories);
+
+    // Rows without explicit edges (linear runs) depend on the previous row.
+    let deps_of = |i: usize| -> Vec<String> {
+        steps[i].depends_on.clone().unwrap_or_else(|| {
+            i.checked_sub(1)
+                .map(|p| vec![steps[p].id.clone()])
+                .unwrap_or_default()
+        })
+    };
+    // Template row id -> the story tails that replace it as a dependency.
+    let mut replaced: HashMap<String, Vec<String>> = HashMap::new();
+    let rewire = |deps: Vec<String>, replaced: &HashMap<String, Vec<String>>| {
+        let mut out: Vec<String> = vec![];
+        for d in deps {
+            for r in replaced.get(&d).cloned().unwrap_or_else(|| vec![d]) {
+                if !out.contains(&r) {
+                    out.push(r);
+                }
+            }
+        }
+        out
+    };
+
+    let mut planned: Vec<Planned> = vec![];
+    let mut i = 0;
+    while i < steps.len() {
+        if !steps[i].is_template() {
+            planned.push(Planned::Existing {
+                id: &steps[i].id,
+                deps: rewire(deps_of(i), &replaced),
+            });
+            i += 1;
+            continue;
+        }
+        let end = (i..steps.len())
+            .find(|&j| !steps[j].is_template())
+            .unwrap_or(steps.len());
+        let group = &steps[i..end];
+        let entry = rewire(
+            (i..end)
+                .flat_map(deps_of)
+                .filter(|d| !group.iter().any(|g| &g.id == d))
+                .collect(),
+            &replaced,
+        );
+        let mut story_tails: HashMap<&str, String> = HashMap::new();
+        let mut prev_tail: Option<String> = None;
+        let mut waited_on: HashSet<String> = HashSet::new();
+        for (n, (story, story_id)) in stories.iter().zip(&story_ids).enumerate() {
+            let mut deps = entry.clone();
+            match story.get("depends_on").and_then(|v| v.as_array()) {
+                // Only earlier stories can be named, so the expansion is acyclic by construction.
+                Some(list) => deps.extend(
+                    list.iter()
+                        .filter_map(|d| d.as_str())
+                        .filter_map(|d| story_tails.get(d).cloned()),
+                ),
+                None => deps.extend(prev_tail.clone()),
+            }
+            waited_on.extend(deps.iter().cloned());
+            for template in group {
+                let id = format!("{}-s{}", template.id, n + 1);
+                planned.push(Planned::Copy {
+                    id: id.clone(),
+                    template,
+                    story_id,
+                    story,
+                    deps: std::mem::replace(&mut deps, vec![id]),
+                });
+            }
+            let last = deps.pop().unwrap_or_default();
+            story_tails.insert(story_id, last.clone());
+            prev_tail = Some(last);
+        }
+        // Dependents of the group wait on the stories nothing else waits on.
+        let mut tails: Vec<String> = story_tails
+            .into_values()
+            .filter(|t| !waited_on.contains(t))
+            .collect();
+        tails.sort();
+        for template in group {
+            replaced.insert(template.id.clone(), tails.clone());
+        }
+        i = end;
+    }
+
+    let now = now_rfc3339();
+    for step in steps.iter().filter(|s| s.is_template()) {
+        conn.execute("DELETE FROM steps WHERE id=?1", [&step.id])?;
+    }
+    for (idx, p) in planned.iter().enumerate() {
+        match p {
+            Planned::Existing { id, deps } => {
+                conn.execute(
+                    "UPDATE steps SET step_index=?1, depends_on_json=?2 WHERE id=?3",
+                    (idx as i64, serde_json::to_string(deps)?, id),
+                )?;
+            }
+            Planned::Copy {
+                id,
+                template,
+                story_id,
+                story,
+                deps,
+            } => {
+                let mut input = template.input.clone();
+                input["story_id"] = Value::String(story_id.to_string());
+                input["story"] = (*story).clone();
+                conn.execute(
+                    "INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text,
+                                        timeout_sec, story_id, depends_on_json, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, 'queued', ?6, NULL, ?7, ?8, ?9, ?10, ?10)",
+                    rusqlite::params![
+                        id,
+                        run_id,
+                        template.step_id,
+                        template.agent_id,
+                        idx as i64,
+                        input.to_string(),
+                        template.timeout_sec,
+                        story_id,
+                        serde_json::to_string(deps)?,
+                        now,
+                    ],
+                )?;
+            }
+        }
+    }
+    for (pos, (story, story_id)) in stories.iter().zip(&story_ids).enumerate() {
+        conn.execute(
+            "INSERT OR REPLACE INTO run_stories (run_id, story_id, position, title, story_json)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                run_id,
+                story_id,
+                pos as i64,
+                story_title(story, story_id),
+                story.to_string(),
+            ),
+        )?;
+    }
+    conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, 'run.stories.expanded', ?2, ?3)",
+        (
+            now_ms_i64(),
+            run_id,
+            serde_json::json!({ "run_id": run_id, "stories": story_ids, "steps": planned.len() })
+                .to_string(),
+        ),
+    )?;
+    Ok(stories.len())
+}
+
+/// Stories to expand before `step` may run: `step` is a `per_story` template and the run
+/// context holds stories reported by an earlier step.
+pub(crate) fn stories_to_expand(step: &PendingStep) -> Option<Vec<Value>> {
+    let input = parse_payload(&step.input_json);
+    if input.get("per_story").and_then(|v| v.as_bool()) != Some(true)
+        || input.get("story_id").is_some()
+    {
+        return None;
+    }
+    parse_payload(&step.context_json)
+        .get("stories")
+        .and_then(|v| v.as_array())
+        .filter(|list| !list.is_empty())
+        .cloned()
+}
+
+/// Story ids from `STORIES_JSON`; missing or duplicate ids fall back to `s<n>`.
+fn story_ids(stories: &[Value]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    stories
+        .iter()
+        .enumerate()
+        .map(|(n, s)| {
+            let id = s
+                .get("id")
+                .and_then(|v| v.as_str())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .filter(|v| !seen.contains(v))
+                .unwrap_or_else(|| format!("s{}", n + 1));
+            seen.insert(id.clone());
+            id
+        })
+        .collect()
+}
+
+fn story_title(story: &Value, story_id: &str) -> String {
+    story
+        .get("title")
+        .and_then(|v| v.as_str())
+        .unwrap_or(story_id)
+        .to_string()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StoryProgress {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    /// `queued`, `running`, `done` or `failed`, derived from the story's steps.
+    pub(crate) status: String,
+    pub(crate) steps_done: usize,
+    pub(crate) steps_total: usize,
+}
+
+/// Per-story progress for a run, keyed by story id.
+pub(crate) fn load_story_progress(
+    conn: &Connection,
+    run_id: &str,
+) -> rusqlite::Result<HashMap<String, StoryProgress>> {
+    let mut stmt = conn.prepare(
+        "SELECT rs.story_id, rs.title, s.status
+         FROM run_stories rs
+         LEFT JOIN steps s ON s.run_id = rs.run_id AND s.story_id = rs.story_id
+         WHERE rs.run_id = ?1",
+    )?;
+    let rows = stmt.query_map([run_id], |r| {
+        Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, String>(1)?,
+            r.get::<_, Option<String>>(2)?,
+        ))
+    })?;
+    let mut statuses: HashMap<String, (String, Vec<String>)> = HashMap::new();
+    for row in rows {
+        let (id, title, status) = row?;
+        let entry = statuses.entry(id).or_insert_with(|| (title, vec![]));
+        entry.1.extend(status);
+    }
+    Ok(statuses
+        .into_iter()
+        .map(|(id, (title, steps))| {
+            let done = steps
+                .iter()
+                .filter(|s| matches!(s.as_str(), "done" | "skipped"))
+                .count();
+            let status = if steps.iter().any(|s| s == "failed") {
+                "failed"
+            } else if done == steps.len() {
+                "done"
+            } else if done > 0 || steps.iter().any(|s| s == "running") {
+                "running"
+            } else {
+                "queued"
+            };
+            let progress = StoryProgress {
+                id: id.clone(),
+                title,
+                status: status.to_string(),
+                steps_done: done,
+                steps_total: steps.len(),
+            };
+            (id, progress)
+        })
+        .collect())
+}
@@ -39,7 +39,9 @@ fn claim_promotes_run_from_queued() {
     seed_run(&engine, "r1", "e1", "queued");
     seed_step(&engine, "s1", "r1", "plan", 0, "queued");
 
-    let claimed = claim_next_step(&engine).unwrap().expect("claimed");
+    let claimed = claim_next_step(&engine, &WorkerLimits::default(), "w-test")
+        .unwrap()
+        .expect("claimed");
     assert_eq!(claimed.step_row_id, "s1");
 
     let conn = engine.open().unwrap();
@@ -53,6 +55,285 @@ fn claim_promotes_run_from_queued() {
     assert_eq!(step_status, "running");
 }
 
+#[test]
+fn claim_respects_per_base_cap_under_concurrency() {
+    let engine = temp_engine();
+    for (run, base) in [("ra", "b1"), ("rb", "b1"), ("rc", "b2"), ("rd", "b3")] {
+        seed_run(&engine, run, "e1", "queued");
+        seed_step(&engine, &format!("s-{run}"), run, "plan", 0, "queued");
+        engine
+            .open()
+            .unwrap()
+            .execute(
+                "UPDATE runs SET context_json=?1 WHERE id=?2",
+                (serde_json::json!({ "base_id": base }).to_string(), run),
+            )
+            .unwrap();
+    }
+
+    let limits = WorkerLimits::new(8, 1);
+    let handles: Vec<_> = (0..6)
+        .map(|_| {
+            let engine = engine.clone();
+            std::thread::spawn(move || claim_next_step(&engine, &limits, "w-test").unwrap())
+        })
+        .collect();
+    let mut claimed: Vec<String> = handles
+        .into_iter()
+        .filter_map(|h| h.join().unwrap())
+        .map(|p| p.run_id)
+        .collect();
+    claimed.sort();
+    // One per base, never the same step twice.
+    assert_eq!(claimed.len(), 3, "{claimed:?}");
+    assert!(claimed.contains(&"rc".to_string()));
+    assert!(claimed.contains(&"rd".to_string()));
+    assert!(claim_next_step(&engine, &limits, "w-test")
+        .unwrap()
+        .is_none());
+
+    let wider = WorkerLimits::new(8, 2);
+    let next = claim_next_step(&engine, &wider, "w-test")
+        .unwrap()
+        .expect("second b1 slot");
+    assert!(next.run_id == "ra" || next.run_id == "rb");
+    assert!(!claimed.contains(&next.run_id));
+}
+
+#[tokio::test]
+async fn pause_blocks_claims_and_cancel_kills_in_flight_child() {
+    let engine = temp_engine();
+    seed_run(&engine, "rp", "e1", "queued");
+    seed_step(&engine, "rp-0", "rp", "plan", 0, "queued");
+    let state = Arc::new(AppState::new(engine.clone()));
+
+    let _ = api_run_pause(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("rp".to_string()),
+    )
+    .await
+    .unwrap();
+    assert!(claim_next_step(&engine, &WorkerLimits::default(), "w-test")
+        .unwrap()
+        .is_none());
+    let again = api_run_pause(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("rp".to_string()),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(again.0, axum::http::StatusCode::CONFLICT);
+    let _ = api_run_resume(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("rp".to_string()),
+    )
+    .await
+    .unwrap();
+    let claimed = claim_next_step(&engine, &WorkerLimits::default(), "w-test").unwrap();
+    assert_eq!(claimed.unwrap().step_row_id, "rp-0");
+
+    // Cancel: a long-running child is killed and the remaining steps are marked cancelled.
+    seed_run(&engine, "rc", "e2", "queued");
+    seed_step(&engine, "rc-0", "rc", "implement", 0, "queued");
+    seed_step(&engine, "rc-1", "rc", "review", 1, "queued");
+    engine
+        .open()
+        .unwrap()
+        .execute(
+            "UPDATE steps SET agent_id='sleep/dev' WHERE run_id='rc'",
+            [],
+        )
+        .unwrap();
+    let mut reg = executor::ExecutorRegistry::default();
+    reg.register(
+        "sleep/",
+        Arc::new(executor::CommandExecutor {
+            program: "sleep".to_string(),
+            args: vec!["30".to_string()],
+            ..Default::default()
+        }),
+    );
+    let started = std::time::Instant::now();
+    let worker = {
+        let (engine, pool) = (engine.clone(), state.workers.clone());
+        std::thread::spawn(move || {
+            run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test")
+                .unwrap()
+        })
+    };
+    while state.workers.busy() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let Json(out) = api_run_cancel(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("rc".to_string()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(out["killed_in_flight"], 1);
+    assert_eq!(out["cancelled_steps"], 2);
+    assert!(worker.join().unwrap());
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+    let conn = engine.open().unwrap();
+    let run_status: String = conn
+        .query_row("SELECT status FROM runs WHERE id='rc'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(run_status, "cancelled");
+    let statuses: Vec<String> = conn
+        .prepare("SELECT status FROM steps WHERE run_id='rc' ORDER BY step_index")
+        .unwrap()
+        .query_map([], |r| r.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(statuses, vec!["cancelled", "cancelled"]);
+}
+
+#[test]
+fn command_templates_do_not_expand_placeholders_inside_values() {
+    let req = executor::StepRequest {
+        run_id: "r1".to_string(),
+        step_id: "implement".to_string(),
+        agent_id: "cmd/dev".to_string(),
+        task: "print {message} literally".to_string(),
+        message: "the prompt".to_string(),
+        worktree_path: "/repo".to_string(),
+        branch: "feature/{task}".to_string(),
+        pr_url: String::new(),
+        context: serde_json::json!({}),
+        timeout_sec: 60,
+        cancel: executor::CancelToken::default(),
+    };
+    assert_eq!(
+        executor::render_template("{task} | {message} | {branch} | {other}", &req),
+        "print {message} literally | the prompt | feature/{task} | {other}"
+    );
+}
+
+#[test]
+fn reaper_requeues_only_stale_steps_and_timeouts_fail_the_step() {
+    let engine = temp_engine();
+    seed_run(&engine, "rh", "e1", "running");
+    seed_step(&engine, "rh-fresh", "rh", "plan", 0, "running");
+    seed_step(&engine, "rh-dead", "rh", "implement", 1, "running");
+    seed_step(&engine, "rh-mine", "rh", "verify", 2, "running");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE steps SET worker_id='w-other', heartbeat_at_ms=?1 WHERE id='rh-fresh'",
+        [now_ms_i64()],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE steps SET worker_id='w-gone', heartbeat_at_ms=?1 WHERE run_id='rh' AND id<>'rh-fresh'",
+        [now_ms_i64() - 120_000],
+    )
+    .unwrap();
+
+    // reemit no longer touches a running step that is still heartbeating.
+    reemit_workers(&engine, None, false).unwrap();
+    let status = |id: &str| -> String {
+        conn.query_row("SELECT status FROM steps WHERE id=?1", [id], |r| r.get(0))
+            .unwrap()
+    };
+    assert_eq!(status("rh-fresh"), "running");
+
+    // reemit re-queued the stale rows; put them back so the reaper sees them.
+    let in_flight: HashSet<String> = ["rh-mine".to_string()].into_iter().collect();
+    conn.execute("UPDATE steps SET status='running' WHERE run_id='rh'", [])
+        .unwrap();
+    let reaped = reap_stale_steps(&engine, &in_flight, pool::STEP_STALE_AFTER_MS).unwrap();
+    assert_eq!(reaped, 1);
+    assert_eq!(status("rh-fresh"), "running");
+    assert_eq!(status("rh-dead"), "queued");
+    assert_eq!(status("rh-mine"), "running");
+    let kinds: Vec<String> = engine
+        .list_events_since(0, 100)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.kind)
+        .collect();
+    assert!(kinds.iter().any(|k| k == "step.reaped"));
+
+    // A step that outlives its timeout is killed and failed with a timeout error.
+    seed_run(&engine, "rt", "e2", "queued");
+    seed_step(&engine, "rt-0", "rt", "implement", 0, "queued");
+    conn.execute(
+        "UPDATE steps SET agent_id='sleep/dev', timeout_sec=1 WHERE id='rt-0'",
+        [],
+    )
+    .unwrap();
+    let mut reg = executor::ExecutorRegistry::default();
+    reg.register(
+        "sleep/",
+        Arc::new(executor::CommandExecutor {
+            program: "sleep".to_string(),
+            args: vec!["30".to_string()],
+            ..Default::default()
+        }),
+    );
+    let pool = WorkerPool::new(WorkerLimits::new(4, 4));
+    let started = std::time::Instant::now();
+    assert!(
+        run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    );
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    let (st, out, worker): (String, Option<String>, Option<String>) = conn
+        .query_row(
+            "SELECT status, output_text, worker_id FROM steps WHERE id='rt-0'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap();
+    assert_ne!(st, "running");
+    assert!(out.unwrap_or_default().contains("step_timeout"));
+    assert_eq!(worker.as_deref(), Some("w-test"));
+}
+
+#[test]
+fn late_results_from_a_reaped_claim_are_dropped() {
+    let engine = temp_engine();
+    seed_run(&engine, "rr", "e1", "queued");
+    seed_step(&engine, "rr-0", "rr", "plan", 0, "queued");
+    let limits = WorkerLimits::default();
+    let first = claim_next_step(&engine, &limits, "w-slow")
+        .unwrap()
+        .unwrap();
+
+    // The slow worker misses its heartbeats; the reaper requeues and another worker claims.
+    let conn = engine.open().unwrap();
+    conn.execute("UPDATE steps SET heartbeat_at_ms=0 WHERE id='rr-0'", [])
+        .unwrap();
+    let reaped = reap_stale_steps(&engine, &HashSet::new(), pool::STEP_STALE_AFTER_MS).unwrap();
+    assert_eq!(reaped, 1);
+    let second = claim_next_step(&engine, &limits, "w-fast")
+        .unwrap()
+        .unwrap();
+    assert_eq!(second.attempt, first.attempt + 1);
+
+    let row = || -> (String, Option<String>, Option<String>) {
+        conn.query_row(
+            "SELECT status, worker_id, output_text FROM steps WHERE id='rr-0'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap()
+    };
+    finalize_step_done(&engine, &first, "STATUS: done\nlate", None).unwrap();
+    finalize_step_failed(&engine, &first, "late failure").unwrap();
+    assert_eq!(
+        row(),
+        ("running".to_string(), Some("w-fast".to_string()), None)
+    );
+
+    finalize_step_done(&engine, &second, "STATUS: done\n", None).unwrap();
+    assert_eq!(row().0, "done");
+    let run_status: String = conn
+        .query_row("SELECT status FROM runs WHERE id='rr'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(run_status, "done");
+}
+
 #[test]
 fn test_failure_requeues_with_guardrail() {
     let engine = temp_engine();
@@ -69,6 +350,9 @@ fn test_failure_requeues_with_guardrail() {
         agent_id: "feature-dev/tester".to_string(),
         task: "task".to_string(),
         context_json: "{}".to_string(),
+        input_json: "{}".to_string(),
+        timeout_sec: 60,
+        attempt: 0,
     };
 
     finalize_step_failed(&engine, &pending, "boom").unwrap();
@@ -93,522 +377,1401 @@ fn test_failure_requeues_with_guardrail() {
 }
 
 #[test]
-fn reemit_workers_scoped_to_base() {
-    let engine = temp_engine();
-    let conn = engine.open().unwrap();
-    conn.execute(
-        "INSERT INTO entities (id, kind, x, y, w, h, payload_json, created_at_ms, updated_at_ms, rev)
-         VALUES (?1,'feature',0,0,3,4,?2,1,1,1)",
-        ("f1", r#"{"base_id":"b1"}"#),
-    )
-    .unwrap();
-    conn.execute(
-        "INSERT INTO entities (id, kind, x, y, w, h, payload_json, created_at_ms, updated_at_ms, rev)
-         VALUES (?1,'feature',0,0,3,4,?2,1,1,1)",
-        ("f2", r#"{"base_id":"b2"}"#),
+fn retry_policies_backoff_rewind_and_give_up() {
+    let bad = workflow::parse_workflow(
+        "yaml",
+        "id: r\nsteps:\n  - id: a\n    agent: x/a\n    retry: { max_attempts: 2, rewind_to: b }\n  - id: b\n    agent: x/b\n",
     )
-    .unwrap();
-
-    seed_run(&engine, "ra", "f1", "running");
-    seed_run(&engine, "rb", "f2", "running");
-    seed_step(&engine, "sa", "ra", "plan", 0, "running");
-    seed_step(&engine, "sb", "rb", "plan", 0, "running");
+    .unwrap_err();
+    assert!(bad.to_string().contains("not upstream"));
+    assert_eq!(
+        retry::ErrorClass::classify("gh: connection reset by peer"),
+        retry::ErrorClass::Transient
+    );
 
-    let report = reemit_workers(&engine, Some("b1")).unwrap();
-    assert_eq!(report.scanned_runs, 1);
+    let engine = temp_engine();
+    let conn = engine.open().unwrap();
+    let set_input = |id: &str, input: serde_json::Value| {
+        conn.execute(
+            "UPDATE steps SET input_json=?1 WHERE id=?2",
+            (input.to_string(), id),
+        )
+        .unwrap();
+    };
+    let status = |id: &str| -> (String, i64, Option<i64>) {
+        conn.query_row(
+            "SELECT status, attempt, next_attempt_at_ms FROM steps WHERE id=?1",
+            [id],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap()
+    };
 
-    let sa: String = conn
-        .query_row("SELECT status FROM steps WHERE id='sa'", [], |r| r.get(0))
+    // A transient `pr` failure backs off instead of failing the run; a hard one fails it.
+    seed_run(&engine, "rp", "e1", "queued");
+    seed_step(&engine, "rp-pr", "rp", "pr", 0, "queued");
+    set_input(
+        "rp-pr",
+        serde_json::json!({
+            "workflow_id": "feature-dev",
+            "retry": { "max_attempts": 3, "backoff_sec": 30, "retry_on": ["transient"] },
+        }),
+    );
+    let scripted = Arc::new(
+        executor::ScriptedExecutor::default()
+            .fail("pr", "gh: connection reset by peer")
+            .fail("pr", "gh: not authorized"),
+    );
+    let reg = executor::ExecutorRegistry::with_fallback(scripted);
+    let pool = WorkerPool::new(WorkerLimits::new(4, 4));
+    assert!(
+        run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    );
+    let (st, attempt, next_at) = status("rp-pr");
+    assert_eq!((st.as_str(), attempt), ("queued", 1));
+    assert!(next_at.unwrap() > now_ms_i64() + 20_000);
+    assert!(
+        !run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    );
+    conn.execute("UPDATE steps SET next_attempt_at_ms=0 WHERE id='rp-pr'", [])
         .unwrap();
-    let sb: String = conn
-        .query_row("SELECT status FROM steps WHERE id='sb'", [], |r| r.get(0))
+    assert!(
+        run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    );
+    assert_eq!(status("rp-pr").0, "failed");
+    let run_status: String = conn
+        .query_row("SELECT status FROM runs WHERE id='rp'", [], |r| r.get(0))
         .unwrap();
-    assert_eq!(sa, "queued");
-    assert_eq!(sb, "running");
+    assert_eq!(run_status, "failed");
+
+    // `rewind_to` re-runs the upstream step and everything after it, until attempts run out.
+    seed_run(&engine, "rw", "e2", "queued");
+    seed_step(&engine, "rw-impl", "rw", "implement", 0, "queued");
+    seed_step(&engine, "rw-verify", "rw", "verify", 1, "queued");
+    seed_step(&engine, "rw-test", "rw", "test", 2, "queued");
+    set_input(
+        "rw-test",
+        serde_json::json!({
+            "workflow_id": "feature-dev",
+            "retry": { "max_attempts": 2, "rewind_to": "implement" },
+        }),
+    );
+    let scripted = Arc::new(
+        executor::ScriptedExecutor::default()
+            .reply("test", "STATUS: failed\n")
+            .reply("test", "STATUS: failed\n"),
+    );
+    let reg = executor::ExecutorRegistry::with_fallback(scripted.clone());
+    while run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    {
+    }
+    let order: Vec<String> = scripted.calls().into_iter().map(|c| c.step_id).collect();
+    assert_eq!(
+        order,
+        vec!["implement", "verify", "test", "implement", "verify", "test"]
+    );
+    assert_eq!(status("rw-impl").1, 2);
+    assert_eq!(status("rw-test"), ("failed".to_string(), 2, None));
+    let retries = engine
+        .list_events_since(0, 500)
+        .unwrap()
+        .into_iter()
+        .filter(|e| e.kind == "step.retry_scheduled")
+        .count();
+    assert_eq!(retries, 2);
 }
 
-fn init_git_repo() -> std::path::PathBuf {
-    let repo = std::env::temp_dir().join(format!(
-        "clawdorio-server-git-{}",
-        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+#[test]
+fn step_replies_are_parsed_threaded_forward_and_gate_status() {
+    let parsed = reply::StepReply::parse(
+        "Sure.\n```\nSTATUS: Done.\nSTORIES_JSON: [\n  {\"id\":\"s1\",\"title\":\"A\"}\n]\nNOTES: line one\nline two\n```\n",
+    );
+    assert_eq!(parsed.status.as_deref(), Some("done"));
+    assert_eq!(parsed.stories.as_ref().unwrap()[0]["id"], "s1");
+    assert_eq!(parsed.fields["notes"], "line one\nline two");
+    assert!(parsed.failure().is_none());
+    let bad = reply::StepReply::parse("STATUS: done\nSTORIES_JSON: not json\n");
+    assert!(bad.failure().unwrap().starts_with("step_reply_invalid"));
+    // Forwarded replies are inserted verbatim: their braces are not placeholders.
+    let rendered = workflow::render_prompt(
+        "{stories} on {branch} for {task} {unknown}",
+        "fix {repo}",
+        "/repo",
+        "br",
+        "",
+        &serde_json::json!({ "stories": "use {task} and {branch}" }),
+    );
+    assert_eq!(
+        rendered,
+        "use {task} and {branch} on br for fix {repo} {unknown}"
+    );
+
+    let engine = temp_engine();
+    seed_run(&engine, "ro", "e1", "queued");
+    seed_step(&engine, "ro-0", "ro", "plan", 0, "queued");
+    seed_step(&engine, "ro-1", "ro", "setup", 1, "queued");
+    seed_step(&engine, "ro-2", "ro", "implement", 2, "queued");
+    let scripted = Arc::new(
+        executor::ScriptedExecutor::default()
+            .reply(
+                "plan",
+                "STATUS: done\nSTORIES_JSON: [{\"id\":\"s1\",\"title\":\"Login\"}]\n",
+            )
+            .reply(
+                "setup",
+                "STATUS: done\nBUILD_CMD: cargo build\nTEST_CMD: cargo test\n",
+            ),
+    );
+    let reg = executor::ExecutorRegistry::with_fallback(scripted.clone());
+    let pool = WorkerPool::default();
+    while run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    {
+    }
+
+    let calls = scripted.calls();
+    assert_eq!(calls.len(), 3);
+    let implement = &calls[2].message;
+    assert!(implement.contains("FROM EARLIER STEPS:"));
+    assert!(implement.contains("TEST_CMD: cargo test"));
+    assert!(implement.contains("\"title\":\"Login\""));
+    assert_eq!(
+        calls[2].context["outputs"]["setup"]["build_cmd"],
+        "cargo build"
+    );
+
+    let conn = engine.open().unwrap();
+    let Json(steps) = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(api_run_steps(
+            axum::extract::State(Arc::new(AppState::new(engine.clone()))),
+            axum::extract::Path("ro".to_string()),
+        ))
+        .unwrap();
+    assert_eq!(steps[0].outputs.as_ref().unwrap()["stories"][0]["id"], "s1");
+
+    // A blocked planner fails the step (and the run) instead of passing.
+    seed_run(&engine, "rb", "e2", "queued");
+    seed_step(&engine, "rb-0", "rb", "plan", 0, "queued");
+    seed_step(&engine, "rb-1", "rb", "setup", 1, "queued");
+    let blocked = Arc::new(executor::ScriptedExecutor::new(
+        "STATUS: blocked\nREASON: no repo access\n",
     ));
-    std::fs::create_dir_all(&repo).unwrap();
-    std::process::Command::new("git")
-        .arg("init")
-        .arg("-b")
-        .arg("main")
-        .current_dir(&repo)
-        .output()
+    let reg = executor::ExecutorRegistry::with_fallback(blocked);
+    assert!(
+        run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    );
+    let (step_status, out): (String, String) = conn
+        .query_row(
+            "SELECT status, output_text FROM steps WHERE id='rb-0'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
         .unwrap();
-    std::process::Command::new("git")
-        .arg("config")
-        .arg("user.email")
-        .arg("test@example.com")
-        .current_dir(&repo)
-        .output()
+    assert_eq!(step_status, "failed");
+    assert!(out.starts_with("step_not_done: STATUS: blocked"));
+    let run_status: String = conn
+        .query_row("SELECT status FROM runs WHERE id='rb'", [], |r| r.get(0))
         .unwrap();
-    std::process::Command::new("git")
-        .arg("config")
-        .arg("user.name")
-        .arg("Test")
-        .current_dir(&repo)
-        .output()
+    assert_eq!(run_status, "failed");
+    let recorded: String = conn
+        .query_row(
+            "SELECT status FROM step_outputs WHERE step_row_id='rb-0'",
+            [],
+            |r| r.get(0),
+        )
         .unwrap();
-    std::fs::write(
-        repo.join("README.md"),
-        "x
-",
+    assert_eq!(recorded, "blocked");
+}
+
+#[test]
+fn plan_stories_fan_out_into_per_story_steps() {
+    let engine = temp_engine();
+    seed_run(&engine, "rs", "e1", "queued");
+    seed_step(&engine, "rs-0", "rs", "plan", 0, "queued");
+    seed_step(&engine, "rs-1", "rs", "implement", 1, "queued");
+    seed_step(&engine, "rs-2", "rs", "verify", 2, "queued");
+    seed_step(&engine, "rs-3", "rs", "test", 3, "queued");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE steps SET input_json='{\"per_story\":true}' WHERE id IN ('rs-1','rs-2')",
+        [],
     )
     .unwrap();
-    std::process::Command::new("git")
-        .args(["add", "."])
-        .current_dir(&repo)
-        .output()
-        .unwrap();
-    std::process::Command::new("git")
-        .args(["commit", "-m", "init"])
-        .current_dir(&repo)
-        .output()
-        .unwrap();
+    let scripted = Arc::new(executor::ScriptedExecutor::default().reply(
+        "plan",
+        "STATUS: done\nSTORIES_JSON: [{\"id\":\"login\",\"title\":\"Login form\"},{\"id\":\"logout\",\"title\":\"Logout\"}]\n",
+    ));
+    let reg = executor::ExecutorRegistry::with_fallback(scripted.clone());
+    let pool = WorkerPool::default();
 
-    let bare = repo.with_extension("origin.git");
-    std::process::Command::new("git")
-        .arg("init")
-        .arg("--bare")
-        .arg(&bare)
-        .output()
+    // Stories expand when the first per-story step becomes runnable (here: right after plan).
+    assert!(
+        run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    );
+    let steps_now: i64 = conn
+        .query_row("SELECT COUNT(*) FROM steps WHERE run_id='rs'", [], |r| {
+            r.get(0)
+        })
         .unwrap();
-    std::process::Command::new("git")
-        .args(["remote", "add", "origin", bare.to_string_lossy().as_ref()])
-        .current_dir(&repo)
-        .output()
+    assert_eq!(steps_now, 4);
+    assert!(
+        run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    );
+    let order: Vec<(String, Option<String>, Option<String>)> = conn
+        .prepare("SELECT step_id, story_id, depends_on_json FROM steps WHERE run_id='rs' ORDER BY step_index")
+        .unwrap()
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let labels: Vec<String> = order
+        .iter()
+        .map(|(step, story, _)| format!("{step}:{}", story.as_deref().unwrap_or("-")))
+        .collect();
+    assert_eq!(
+        labels,
+        vec![
+            "plan:-",
+            "implement:login",
+            "verify:login",
+            "implement:logout",
+            "verify:logout",
+            "test:-"
+        ]
+    );
+    // Stories are sequential by default; the test step waits on the last story only.
+    assert_eq!(order[3].2.as_deref(), Some("[\"rs-0\",\"rs-2-s1\"]"));
+    assert_eq!(order[5].2.as_deref(), Some("[\"rs-2-s2\"]"));
+
+    while run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    {
+    }
+    let calls = scripted.calls();
+    let seen: Vec<String> = calls
+        .iter()
+        .map(|c| {
+            format!(
+                "{}:{}",
+                c.step_id,
+                c.context["story_id"].as_str().unwrap_or("-")
+            )
+        })
+        .collect();
+    assert_eq!(seen, labels);
+    assert!(calls[1].message.contains("Login form"));
+    assert!(!calls[1].message.contains("{story}"));
+
+    let Json(steps) = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(api_run_steps(
+            axum::extract::State(Arc::new(AppState::new(engine.clone()))),
+            axum::extract::Path("rs".to_string()),
+        ))
         .unwrap();
-    std::process::Command::new("git")
-        .args(["push", "-u", "origin", "main"])
-        .current_dir(&repo)
-        .output()
+    let story = steps[1].story.as_ref().unwrap();
+    assert_eq!(
+        (story.title.as_str(), story.status.as_str()),
+        ("Login form", "done")
+    );
+    assert_eq!((story.steps_done, story.steps_total), (2, 2));
+    let run_status: String = conn
+        .query_row("SELECT status FROM runs WHERE id='rs'", [], |r| r.get(0))
         .unwrap();
-
-    repo
+    assert_eq!(run_status, "done");
 }
 
 #[test]
-fn sync_now_queues_once_idempotent() {
+fn workflow_dependencies_detect_cycles_and_run_concurrently() {
+    let cyclic = workflow::parse_workflow(
+        "yaml",
+        "id: loop\nsteps:\n  - id: a\n    agent: x/a\n    depends_on: [c]\n  - id: b\n    agent: x/b\n  - id: c\n    agent: x/c\n",
+    )
+    .unwrap_err();
+    assert!(cyclic.to_string().contains("dependency cycle"));
+    let unknown = workflow::parse_workflow(
+        "yaml",
+        "id: bad\nsteps:\n  - id: a\n    agent: x/a\n    depends_on: [nope]\n",
+    )
+    .unwrap_err();
+    assert!(unknown.to_string().contains("unknown step nope"));
+
+    let def = workflow::parse_workflow(
+        "toml",
+        r#"
+id = "fanout"
+[[steps]]
+id = "ship"
+agent = "x/ship"
+depends_on = ["lint", "test"]
+[[steps]]
+id = "build"
+agent = "x/build"
+depends_on = []
+[[steps]]
+id = "lint"
+agent = "x/lint"
+depends_on = ["build"]
+[[steps]]
+id = "test"
+agent = "x/test"
+depends_on = ["build"]
+"#,
+    )
+    .unwrap();
+    let order: Vec<&str> = def
+        .topo_order()
+        .unwrap()
+        .into_iter()
+        .map(|i| def.steps[i].id.as_str())
+        .collect();
+    assert_eq!(order, vec!["build", "lint", "test", "ship"]);
+
     let engine = temp_engine();
-    let repo = init_git_repo();
-    engine
-        .create_entity_with_payload(
-            "base",
-            0,
-            0,
-            9,
-            9,
-            &serde_json::json!({
-                "repo_path": repo.to_string_lossy().to_string(),
-                "auto_rebase_enabled": true,
-                "auto_rebase_interval_sec": 120,
-            })
-            .to_string(),
+    seed_run(&engine, "rd", "e1", "queued");
+    seed_step(&engine, "rd-build", "rd", "build", 0, "queued");
+    seed_step(&engine, "rd-lint", "rd", "lint", 1, "queued");
+    seed_step(&engine, "rd-test", "rd", "test", 2, "queued");
+    seed_step(&engine, "rd-ship", "rd", "ship", 3, "queued");
+    let conn = engine.open().unwrap();
+    for (id, deps) in [
+        ("rd-build", "[]"),
+        ("rd-lint", "[\"rd-build\"]"),
+        ("rd-test", "[\"rd-build\"]"),
+        ("rd-ship", "[\"rd-lint\",\"rd-test\"]"),
+    ] {
+        conn.execute(
+            "UPDATE steps SET depends_on_json=?1 WHERE id=?2",
+            (deps, id),
         )
         .unwrap();
-    let base = engine
-        .list_entities()
-        .unwrap()
-        .into_iter()
-        .find(|e| e.kind == "base")
+    }
+    let limits = WorkerLimits::new(4, 4);
+    let claim = || {
+        claim_next_step(&engine, &limits, "w-test")
+            .unwrap()
+            .map(|s| s.step_row_id)
+    };
+    assert_eq!(claim().as_deref(), Some("rd-build"));
+    assert_eq!(claim(), None);
+    conn.execute("UPDATE steps SET status='done' WHERE id='rd-build'", [])
         .unwrap();
+    // lint and test are independent: both are claimable while the other runs.
+    assert_eq!(claim().as_deref(), Some("rd-lint"));
+    assert_eq!(claim().as_deref(), Some("rd-test"));
+    assert_eq!(claim(), None);
+    conn.execute("UPDATE steps SET status='done' WHERE id='rd-lint'", [])
+        .unwrap();
+    assert_eq!(claim(), None);
+    conn.execute("UPDATE steps SET status='done' WHERE id='rd-test'", [])
+        .unwrap();
+    assert_eq!(claim().as_deref(), Some("rd-ship"));
+}
 
-    assert!(queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
-    assert!(!queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
-
+#[tokio::test]
+async fn approval_gate_parks_step_and_reject_feeds_back_to_planner() {
+    let engine = temp_engine();
+    seed_run(&engine, "ra", "e1", "queued");
+    seed_step(&engine, "ra-plan", "ra", "plan", 0, "queued");
+    seed_step(&engine, "ra-gate", "ra", "signoff", 1, "queued");
+    seed_step(&engine, "ra-impl", "ra", "implement", 2, "queued");
     let conn = engine.open().unwrap();
-    let c: i64 = conn
-        .query_row(
-            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase' AND entity_id=?1",
-            [&base.id],
-            |r| r.get(0),
-        )
+    conn.execute(
+        "UPDATE steps SET agent_id='internal/approval' WHERE id='ra-gate'",
+        [],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE steps SET input_json='{\"per_story\":true}' WHERE id='ra-impl'",
+        [],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE runs SET context_json='{\"branch\":\"feat/ra\"}' WHERE id='ra'",
+        [],
+    )
+    .unwrap();
+    let scripted = Arc::new(
+        executor::ScriptedExecutor::default()
+            .reply(
+                "plan",
+                "STATUS: done\nSTORIES_JSON: [{\"id\":\"big\",\"title\":\"Everything\"}]\n",
+            )
+            .reply(
+                "plan",
+                "STATUS: done\nSTORIES_JSON: [{\"id\":\"a\"},{\"id\":\"b\"}]\n",
+            ),
+    );
+    let reg = executor::ExecutorRegistry::with_fallback(scripted.clone());
+    let pool = WorkerPool::default();
+    let state = Arc::new(AppState::new(engine.clone()));
+    let run_status = || -> String {
+        conn.query_row("SELECT status FROM runs WHERE id='ra'", [], |r| r.get(0))
+            .unwrap()
+    };
+    let gate_status = || -> String {
+        conn.query_row("SELECT status FROM steps WHERE id='ra-gate'", [], |r| {
+            r.get(0)
+        })
+        .unwrap()
+    };
+    let decide = |decision: &'static str, comment: Option<&str>| {
+        let state = state.clone();
+        let input = approval::ApprovalInput {
+            comment: comment.map(str::to_string),
+            by: Some("lead".to_string()),
+        };
+        async move {
+            let path = axum::extract::Path(("ra".to_string(), "signoff".to_string()));
+            if decision == "approve" {
+                approval::api_run_step_approve(axum::extract::State(state), path, Some(Json(input)))
+                    .await
+            } else {
+                approval::api_run_step_reject(axum::extract::State(state), path, Some(Json(input)))
+                    .await
+            }
+        }
+    };
+
+    while run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    {
+    }
+    assert_eq!(run_status(), "running");
+    assert_eq!(gate_status(), "awaiting_approval");
+    assert_eq!(scripted.calls().len(), 1);
+
+    let Json(feed) = api_pr_feed(
+        axum::extract::State(state.clone()),
+        axum::extract::Query(PrFeedQuery {
+            base_id: None,
+            limit: Some(10),
+        }),
+    )
+    .await
+    .unwrap();
+    let gate = feed[0].approval.as_ref().expect("approval on card");
+    assert_eq!(gate.step_id, "signoff");
+    assert_eq!(gate.stories.as_ref().unwrap()[0]["id"], "big");
+
+    let err = decide("reject", None).await.unwrap_err();
+    assert_eq!(err.0, axum::http::StatusCode::BAD_REQUEST);
+    let Json(out) = decide("reject", Some("split it into smaller stories"))
+        .await
         .unwrap();
-    assert_eq!(c, 1);
+    assert_eq!(out["rewound_steps"][0], "ra-plan");
+    assert_eq!(run_status(), "running");
+
+    while run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    {
+    }
+    assert_eq!(gate_status(), "awaiting_approval");
+    let calls = scripted.calls();
+    assert_eq!(calls.len(), 2);
+    assert!(calls[1].message.contains("split it into smaller stories"));
+
+    let Json(out) = decide("approve", None).await.unwrap();
+    assert_eq!(out["run_status"], "running");
+    assert_eq!(
+        decide("approve", None).await.unwrap_err().0,
+        axum::http::StatusCode::CONFLICT
+    );
+    while run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    {
+    }
+    let stories: Vec<String> = scripted.calls()[2..]
+        .iter()
+        .map(|c| c.context["story_id"].as_str().unwrap_or("-").to_string())
+        .collect();
+    assert_eq!(stories, vec!["a", "b"]);
+    assert_eq!(run_status(), "done");
 }
 
 #[test]
-fn periodic_reconciler_skips_when_disabled() {
+fn approval_gate_holds_only_its_own_branch_of_a_dag() {
     let engine = temp_engine();
-    let repo = init_git_repo();
-    let _ = engine
-        .create_entity_with_payload(
-            "base",
-            0,
-            0,
-            9,
-            9,
-            &serde_json::json!({
-                "repo_path": repo.to_string_lossy().to_string(),
-                "auto_rebase_enabled": false,
-                "auto_rebase_interval_sec": 30,
-            })
-            .to_string(),
+    seed_run(&engine, "rg", "e1", "queued");
+    seed_step(&engine, "rg-plan", "rg", "plan", 0, "queued");
+    seed_step(&engine, "rg-gate", "rg", "signoff", 1, "queued");
+    seed_step(&engine, "rg-ship", "rg", "ship", 2, "queued");
+    seed_step(&engine, "rg-docs", "rg", "docs", 3, "queued");
+    engine
+        .open()
+        .unwrap()
+        .execute_batch(
+            r#"
+UPDATE steps SET agent_id='internal/approval', depends_on_json='["rg-plan"]' WHERE id='rg-gate';
+UPDATE steps SET depends_on_json='["rg-gate"]' WHERE id='rg-ship';
+UPDATE steps SET depends_on_json='["rg-plan"]' WHERE id='rg-docs';
+"#,
         )
         .unwrap();
+    let reg = executor::ExecutorRegistry::with_fallback(Arc::new(executor::ScriptedExecutor::new(
+        "STATUS: done\n",
+    )));
+    let pool = WorkerPool::default();
+    while run_one_step_blocking(&engine, &reg, &ForgeRegistry::default(), &pool, "w-test").unwrap()
+    {
+    }
 
-    periodic_rebase_reconciler(&engine).unwrap();
     let conn = engine.open().unwrap();
-    let c: i64 = conn
-        .query_row(
-            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase'",
-            [],
+    let status = |table: &str, id: &str| -> String {
+        conn.query_row(
+            &format!("SELECT status FROM {table} WHERE id=?1"),
+            [id],
             |r| r.get(0),
         )
-        .unwrap();
-    assert_eq!(c, 0);
+        .unwrap()
+    };
+    assert_eq!(status("steps", "rg-gate"), "awaiting_approval");
+    assert_eq!(status("steps", "rg-docs"), "done");
+    assert_eq!(status("steps", "rg-ship"), "queued");
+    assert_eq!(status("runs", "rg"), "running");
+    assert!(transition_run(&engine, "rg", RunControl::Pause).is_ok());
+    assert_eq!(status("runs", "rg"), "paused");
 }
 
-#[tokio::test]
-async fn manual_sync_handler_queues_run() {
+#[test]
+fn executor_registry_routes_by_longest_prefix() {
+    use executor::ScriptedExecutor;
+
     let engine = temp_engine();
-    let repo = init_git_repo();
-    let base = engine
-        .create_entity_with_payload(
-            "base",
-            0,
-            0,
-            9,
-            9,
-            &serde_json::json!({
-                "repo_path": repo.to_string_lossy().to_string(),
-                "auto_rebase_enabled": true,
-                "auto_rebase_interval_sec": 120,
-            })
-            .to_string(),
+    seed_run(&engine, "r1", "e1", "queued");
+    seed_step(&engine, "s1", "r1", "plan", 0, "queued");
+    engine
+        .open()
+        .unwrap()
+        .execute(
+            "UPDATE steps SET agent_id='team/fast/planner' WHERE id='s1'",
+            [],
         )
         .unwrap();
-    let state = Arc::new(AppState {
-        engine: engine.clone(),
-    });
-    let _ = api_bases_sync_now(
-        axum::extract::State(state),
-        axum::extract::Path(base.id.clone()),
+
+    let broad = Arc::new(ScriptedExecutor::new("STATUS: done\nFROM: broad\n"));
+    let narrow =
+        Arc::new(ScriptedExecutor::default().reply("plan", "STATUS: done\nFROM: narrow\n"));
+    let mut reg = executor::ExecutorRegistry::with_fallback(broad.clone());
+    reg.register("team/", broad.clone());
+    reg.register("team/fast/", narrow.clone());
+    assert_eq!(reg.resolve("other/agent").name(), "scripted");
+
+    assert!(run_one_step_blocking(
+        &engine,
+        &reg,
+        &ForgeRegistry::default(),
+        &WorkerPool::default(),
+        "w-test"
     )
-    .await
-    .unwrap();
+    .unwrap());
+    assert!(broad.calls().is_empty());
+    let calls = narrow.calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].agent_id, "team/fast/planner");
+    assert!(calls[0].message.contains("TASK:\ntask"));
 
     let conn = engine.open().unwrap();
-    let c: i64 = conn
+    let (status, out): (String, String) = conn
         .query_row(
-            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase' AND entity_id=?1",
-            [&base.id],
-            |r| r.get(0),
+            "SELECT status, output_text FROM steps WHERE id='s1'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
         )
         .unwrap();
-    assert_eq!(c, 1);
+    assert_eq!(status, "done");
+    assert!(out.contains("FROM: narrow"));
+
+    let reg = executor::ExecutorRegistry::from_yaml(
+        r#"
+executors:
+  - prefix: "cat/"
+    kind: command
+    program: cat
+    stdin: "{step_id}|{agent_id}|{task}"
+"#,
+    )
+    .unwrap();
+    let req = executor::StepRequest {
+        run_id: "r1".to_string(),
+        step_id: "verify".to_string(),
+        agent_id: "cat/verifier".to_string(),
+        task: "t".to_string(),
+        message: String::new(),
+        worktree_path: String::new(),
+        branch: String::new(),
+        pr_url: String::new(),
+        context: serde_json::json!({}),
+        timeout_sec: 60,
+        cancel: Default::default(),
+    };
+    let exec = reg.resolve(&req.agent_id);
+    assert_eq!(exec.name(), "command");
+    assert_eq!(exec.execute(&req).unwrap(), "verify|cat/verifier|t");
+    assert_eq!(reg.resolve("feature-dev/planner").name(), "openclaw");
 }
 
-#[tokio::test]
-async fn webhook_push_queues_auto_rebase() {
+#[test]
+fn openclaw_json_envelope_is_unwrapped_before_parsing() {
+    use std::os::unix::fs::PermissionsExt;
+
     let engine = temp_engine();
-    let repo = init_git_repo();
-    std::process::Command::new("git")
-        .args([
-            "remote",
-            "set-url",
-            "origin",
-            "https://github.com/acme/demo.git",
-        ])
-        .current_dir(&repo)
-        .output()
-        .unwrap();
+    seed_run(&engine, "r1", "e1", "queued");
+    seed_step(&engine, "s1", "r1", "plan", 0, "queued");
 
-    let base = engine
-        .create_entity_with_payload(
-            "base",
-            0,
-            0,
-            9,
-            9,
-            &serde_json::json!({
-                "repo_path": repo.to_string_lossy().to_string(),
-                "auto_rebase_enabled": true,
-                "auto_rebase_interval_sec": 120,
-            })
-            .to_string(),
+    // Stand-in for `openclaw agent ... --json`, which prints a JSON envelope, not the reply.
+    let dir = std::env::temp_dir().join(format!(
+        "clawdorio-openclaw-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("openclaw");
+    std::fs::write(
+        &program,
+        "#!/bin/sh\ncat <<'JSON'\n{\"runId\":\"x\",\"status\":\"ok\",\"result\":{\"payloads\":[{\"text\":\"STATUS: done\\nNOTES: from openclaw\"}],\"meta\":{}}}\nJSON\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let reg = executor::ExecutorRegistry::with_fallback(Arc::new(executor::OpenClawExecutor {
+        program: program.display().to_string(),
+    }));
+
+    assert!(run_one_step_blocking(
+        &engine,
+        &reg,
+        &ForgeRegistry::default(),
+        &WorkerPool::default(),
+        "w-test"
+    )
+    .unwrap());
+    let (status, out): (String, String) = engine
+        .open()
+        .unwrap()
+        .query_row(
+            "SELECT status, output_text FROM steps WHERE id='s1'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
         )
         .unwrap();
+    assert_eq!(status, "done");
+    assert_eq!(out, "STATUS: done\nNOTES: from openclaw");
 
-    let state = Arc::new(AppState {
-        engine: engine.clone(),
-    });
-    let mut headers = HeaderMap::new();
-    headers.insert("x-github-event", HeaderValue::from_static("push"));
-    let payload = serde_json::json!({
-        "ref": "refs/heads/main",
-        "after": "abc123",
-        "repository": { "full_name": "acme/demo" }
-    });
-    let _ = api_github_webhook(axum::extract::State(state), headers, Json(payload))
-        .await
-        .unwrap();
+    assert_eq!(
+        executor::openclaw_reply_text("STATUS: done\n"),
+        "STATUS: done\n"
+    );
+    assert_eq!(
+        executor::openclaw_reply_text(r#"{"reply":"STATUS: done"}"#),
+        "STATUS: done"
+    );
+}
 
+#[test]
+fn builtin_workflows_upgrade_unless_the_user_edited_them() {
+    let engine = temp_engine();
+    // Rows as an older build left them: builtin, no recorded seed source.
+    let stale = "id: feature-dev\nname: Feature dev (old)\nsteps:\n  - {id: implement, agent: feature-dev/developer}\n";
+    let edited =
+        "id: bugfix\nname: My bugfix\nsteps:\n  - {id: fix, agent: feature-dev/developer}\n";
     let conn = engine.open().unwrap();
-    let c: i64 = conn
-        .query_row(
-            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase' AND entity_id=?1",
-            [&base.id],
-            |r| r.get(0),
+    for (id, src, rev) in [("feature-dev", stale, 1), ("bugfix", edited, 3)] {
+        conn.execute(
+            "INSERT INTO workflows (id, name, format, source, builtin, created_at_ms, updated_at_ms, rev)
+             VALUES (?1, ?1, 'yaml', ?2, 1, 0, 0, ?3)",
+            (id, src, rev),
         )
         .unwrap();
-    assert_eq!(c, 1);
+    }
+
+    workflow::seed_builtin_workflows(&engine).unwrap();
+    let feature = engine.get_workflow("feature-dev").unwrap().unwrap();
+    let def = workflow::parse_workflow("yaml", &feature.source).unwrap();
+    assert!(def
+        .steps
+        .iter()
+        .any(|s| s.id == "test" && s.retry.is_some()));
+    assert_eq!(feature.rev, 2);
+    assert_eq!(
+        engine.get_workflow("bugfix").unwrap().unwrap().source,
+        edited
+    );
+    assert!(engine.get_workflow("docs-only").unwrap().is_some());
+
+    // Re-seeding is a no-op, and a later user edit to an upgraded builtin is kept.
+    workflow::seed_builtin_workflows(&engine).unwrap();
+    assert_eq!(engine.get_workflow("feature-dev").unwrap().unwrap().rev, 2);
+    let mine = feature
+        .source
+        .replace("Feature development", "My feature flow");
+    engine
+        .upsert_workflow("feature-dev", "mine", "yaml", &mine, true)
+        .unwrap();
+    assert!(!engine
+        .seed_workflow("feature-dev", "Feature dev", "yaml", &feature.source)
+        .unwrap());
+    assert_eq!(
+        engine.get_workflow("feature-dev").unwrap().unwrap().source,
+        mine
+    );
 }
 
 #[tokio::test]
-async fn pr_feed_lists_feature_runs_with_fallback_summary() {
+async fn workflows_crud_and_step_prompts() {
     let engine = temp_engine();
-    let base = engine
-        .create_entity_with_payload(
-            "base",
-            0,
-            0,
-            9,
-            9,
-            &serde_json::json!({"repo_path":"/tmp/no-such-repo"}).to_string(),
-        )
-        .unwrap();
-    let feature = engine
-        .create_entity_with_payload(
-            "feature",
-            12,
-            0,
-            3,
-            4,
-            &serde_json::json!({"base_id": base.id}).to_string(),
-        )
+    workflow::seed_builtin_workflows(&engine).unwrap();
+    let state = Arc::new(AppState::new(engine.clone()));
+
+    let Json(rows) = workflow::api_workflows_list(axum::extract::State(state.clone()))
+        .await
         .unwrap();
-    let conn = engine.open().unwrap();
-    conn.execute(
-        "INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at) VALUES (?1,'feature-dev',?2,'running',?3,?4,?5,?5)",
-        (
-            "r-pr-feed-1",
-            "Implement mobile feed",
-            &feature.id,
-            serde_json::json!({
-                "base_repo_path":"/tmp/no-such-repo",
-                "branch":"clawdorio/r-pr-feed-1",
-                "pr_url":"https://github.com/acme/demo/pull/42"
-            })
-            .to_string(),
-            now_rfc3339(),
-        ),
+    let ids: Vec<String> = rows
+        .iter()
+        .filter_map(|r| r.definition.as_ref().map(|d| d.id.clone()))
+        .collect();
+    for id in ["bugfix", "docs-only", "feature-dev"] {
+        assert!(ids.contains(&id.to_string()), "{id} missing from {ids:?}");
+    }
+
+    let toml_src = r#"
+id = "hotfix"
+name = "Hotfix"
+
+[[steps]]
+id = "implement"
+agent = "feature-dev/developer"
+prompt = "Fix {task} in {repo} on {branch}"
+
+[[steps]]
+id = "pr"
+agent = "internal/pr"
+"#;
+    let input = |src: &str, format: &str| -> workflow::WorkflowInput {
+        serde_json::from_value(serde_json::json!({ "format": format, "source": src })).unwrap()
+    };
+    let Json(created) = workflow::api_workflows_create(
+        axum::extract::State(state.clone()),
+        Json(input(toml_src, "toml")),
     )
+    .await
     .unwrap();
+    assert_eq!(created.definition.as_ref().unwrap().steps.len(), 2);
+    let dup = workflow::api_workflows_create(
+        axum::extract::State(state.clone()),
+        Json(input(toml_src, "toml")),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(dup.0, axum::http::StatusCode::CONFLICT);
 
-    let out = api_pr_feed(
-        axum::extract::State(Arc::new(AppState {
-            engine: engine.clone(),
-        })),
-        axum::extract::Query(PrFeedQuery {
-            base_id: Some(base.id.clone()),
-            limit: Some(10),
-        }),
+    let bad = workflow::api_workflows_update(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("hotfix".to_string()),
+        Json(input(
+            "id: hotfix\nsteps:\n  - {id: a, agent: x}\n  - {id: a, agent: y}\n",
+            "yaml",
+        )),
     )
     .await
-    .unwrap();
-    assert_eq!(out.0.len(), 1);
-    assert_eq!(out.0[0].run_id, "r-pr-feed-1");
-    assert_eq!(out.0[0].pr_number, Some(42));
-    assert_eq!(out.0[0].changed_files.total_files, 0);
-    assert_eq!(out.0[0].changed_files.source, "fallback");
+    .unwrap_err();
+    assert_eq!(bad.0, axum::http::StatusCode::BAD_REQUEST);
+
+    let builtin = workflow::api_workflows_delete(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("feature-dev".to_string()),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(builtin.0, axum::http::StatusCode::CONFLICT);
+
+    let mut step = PendingStep {
+        step_row_id: "s1".to_string(),
+        run_id: "r1".to_string(),
+        step_id: "implement".to_string(),
+        agent_id: "feature-dev/developer".to_string(),
+        task: "the bug".to_string(),
+        context_json: "{}".to_string(),
+        input_json: serde_json::json!({
+            "prompt_template": created.definition.as_ref().unwrap().steps[0].prompt,
+        })
+        .to_string(),
+        timeout_sec: 60,
+        attempt: 0,
+    };
+    assert_eq!(
+        build_step_message(&step, "/repo", "br", ""),
+        "Fix the bug in /repo on br"
+    );
+    step.step_id = "plan".to_string();
+    step.input_json = "{}".to_string();
+    let legacy = build_step_message(&step, "/repo", "br", "");
+    assert!(legacy.starts_with("TASK:\nthe bug\n\nREPO:\n/repo\n\nBRANCH:\nbr\n"));
+    assert!(legacy.contains("STORIES_JSON: [{\"id\":\"s1\""));
+
+    let Json(_) = workflow::api_workflows_delete(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("hotfix".to_string()),
+    )
+    .await
+    .unwrap();
+    assert!(engine.get_workflow("hotfix").unwrap().is_none());
 }
 
 #[test]
-fn skill_graph_import_and_preview_precedence() {
+fn reemit_workers_scoped_to_base() {
     let engine = temp_engine();
-    let skill_root = std::env::temp_dir().join(format!(
-        "clawdorio-skills-{}",
-        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
-    ));
-    std::fs::create_dir_all(&skill_root).unwrap();
-    std::fs::write(
-        skill_root.join("index.md"),
-        "[[GlobalSkill]]\n[[BaseSkill]]\n[[AgentSkill]]\n",
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "INSERT INTO entities (id, kind, x, y, w, h, payload_json, created_at_ms, updated_at_ms, rev)
+         VALUES (?1,'feature',0,0,3,4,?2,1,1,1)",
+        ("f1", r#"{"base_id":"b1"}"#),
     )
     .unwrap();
-    std::fs::write(
-        skill_root.join("GlobalSkill.md"),
-        "---\ntitle: Global Skill\ndescription: global desc\n---\nshared",
+    conn.execute(
+        "INSERT INTO entities (id, kind, x, y, w, h, payload_json, created_at_ms, updated_at_ms, rev)
+         VALUES (?1,'feature',0,0,3,4,?2,1,1,1)",
+        ("f2", r#"{"base_id":"b2"}"#),
     )
     .unwrap();
-    std::fs::write(
-        skill_root.join("BaseSkill.md"),
-        "---\ntitle: Base Skill\ndescription: base desc\n---\n[[GlobalSkill]]",
+
+    seed_run(&engine, "ra", "f1", "running");
+    seed_run(&engine, "rb", "f2", "running");
+    seed_step(&engine, "sa", "ra", "plan", 0, "running");
+    seed_step(&engine, "sb", "rb", "plan", 0, "running");
+
+    let report = reemit_workers(&engine, Some("b1"), true).unwrap();
+    assert_eq!(report.scanned_runs, 1);
+
+    let sa: String = conn
+        .query_row("SELECT status FROM steps WHERE id='sa'", [], |r| r.get(0))
+        .unwrap();
+    let sb: String = conn
+        .query_row("SELECT status FROM steps WHERE id='sb'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(sa, "queued");
+    assert_eq!(sb, "running");
+}
+
+#[test]
+fn idle_reemit_leaves_runs_whose_retries_gave_up_failed() {
+    let engine = temp_engine();
+    seed_run(&engine, "rf", "e1", "failed");
+    seed_step(&engine, "rf-0", "rf", "plan", 0, "done");
+    seed_step(&engine, "rf-1", "rf", "test", 1, "failed");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE steps SET attempt=3, next_attempt_at_ms=5 WHERE id='rf-1'",
+        [],
     )
     .unwrap();
-    std::fs::write(
-        skill_root.join("AgentSkill.md"),
-        "---\ntitle: Agent Skill\ndescription: agent desc\n---\n[[BaseSkill]]",
-    )
+    let state = || -> (String, String, i64, Option<i64>) {
+        conn.query_row(
+            "SELECT r.status, s.status, s.attempt, s.next_attempt_at_ms
+             FROM runs r JOIN steps s ON s.run_id=r.id WHERE s.id='rf-1'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap()
+    };
+
+    let report = reemit_workers(&engine, None, false).unwrap();
+    assert_eq!(report.scanned_runs, 0);
+    assert_eq!(
+        state(),
+        ("failed".to_string(), "failed".to_string(), 3, Some(5))
+    );
+
+    // An operator reemit starts the failed step over with a fresh attempt count.
+    reemit_workers(&engine, None, true).unwrap();
+    assert_eq!(
+        state(),
+        ("queued".to_string(), "queued".to_string(), 0, None)
+    );
+}
+
+#[test]
+fn operator_reemit_requeues_only_the_failed_branch_of_a_dag() {
+    let engine = temp_engine();
+    seed_run(&engine, "rd", "e1", "failed");
+    seed_step(&engine, "rd-plan", "rd", "plan", 0, "done");
+    seed_step(&engine, "rd-a", "rd", "implement", 1, "failed");
+    seed_step(&engine, "rd-b", "rd", "docs", 2, "done");
+    seed_step(&engine, "rd-a2", "rd", "verify", 3, "queued");
+    seed_step(&engine, "rd-c", "rd", "lint", 4, "running");
+    let conn = engine.open().unwrap();
+    conn.execute_batch(&format!(
+        r#"
+UPDATE steps SET output_text='kept' WHERE id IN ('rd-plan', 'rd-b', 'rd-a2');
+UPDATE steps SET depends_on_json='["rd-plan"]' WHERE id IN ('rd-a', 'rd-b', 'rd-c');
+UPDATE steps SET depends_on_json='["rd-a"]' WHERE id='rd-a2';
+UPDATE steps SET worker_id='w-1', heartbeat_at_ms={} WHERE id='rd-c';
+"#,
+        now_ms_i64()
+    ))
     .unwrap();
 
-    let imp = SkillImportInput {
-        pack_name: "pack".to_string(),
-        source_root: skill_root.to_string_lossy().to_string(),
-        index_path: "index.md".to_string(),
-        graph_id: Some("g1".to_string()),
-        title: Some("Pack".to_string()),
-    };
-    import_skill_graph(&engine, &imp).unwrap();
+    reemit_workers(&engine, None, true).unwrap();
+    let steps: Vec<(String, String, Option<String>)> = conn
+        .prepare("SELECT id, status, output_text FROM steps WHERE run_id='rd' ORDER BY step_index")
+        .unwrap()
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let kept = Some("kept".to_string());
+    assert_eq!(
+        steps,
+        vec![
+            ("rd-plan".to_string(), "done".to_string(), kept.clone()),
+            ("rd-a".to_string(), "queued".to_string(), None),
+            ("rd-b".to_string(), "done".to_string(), kept),
+            ("rd-a2".to_string(), "queued".to_string(), None),
+            ("rd-c".to_string(), "running".to_string(), None),
+        ]
+    );
+}
 
-    let base = engine
-        .create_entity_with_payload("base", 0, 0, 9, 9, r#"{"repo_path":"/tmp/repo"}"#)
+fn init_git_repo() -> std::path::PathBuf {
+    let repo = std::env::temp_dir().join(format!(
+        "clawdorio-server-git-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    std::fs::create_dir_all(&repo).unwrap();
+    std::process::Command::new("git")
+        .arg("init")
+        .arg("-b")
+        .arg("main")
+        .current_dir(&repo)
+        .output()
         .unwrap();
-    let feature = engine
-        .create_entity_with_payload(
-            "feature",
-            11,
-            0,
-            3,
-            4,
-            &serde_json::json!({"base_id": base.id}).to_string(),
-        )
+    std::process::Command::new("git")
+        .arg("config")
+        .arg("user.email")
+        .arg("test@example.com")
+        .current_dir(&repo)
+        .output()
+        .unwrap();
+    std::process::Command::new("git")
+        .arg("config")
+        .arg("user.name")
+        .arg("Test")
+        .current_dir(&repo)
+        .output()
+        .unwrap();
+    std::fs::write(
+        repo.join("README.md"),
+        "x
+",
+    )
+    .unwrap();
+    std::process::Command::new("git")
+        .args(["add", "."])
+        .current_dir(&repo)
+        .output()
+        .unwrap();
+    std::process::Command::new("git")
+        .args(["commit", "-m", "init"])
+        .current_dir(&repo)
+        .output()
         .unwrap();
 
-    let conn = engine.open().unwrap();
-    conn.execute("INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at) VALUES ('r-skill','wf','Agent task', 'queued', ?1, '{}', ?2, ?2)", (&feature.id, now_rfc3339())).unwrap();
-    conn.execute("INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text, created_at, updated_at) VALUES ('s-skill','r-skill','implement','feature-dev/developer',0,'queued','{}',NULL,?1,?1)", [now_rfc3339()]).unwrap();
-
-    conn.execute("INSERT INTO skill_assignments (id, graph_id, node_id, scope_kind, scope_ref, created_at_ms, updated_at_ms, rev) VALUES ('a1','g1','g1::globalskill','global',NULL,1,1,1)", []).unwrap();
-    conn.execute("INSERT INTO skill_assignments (id, graph_id, node_id, scope_kind, scope_ref, created_at_ms, updated_at_ms, rev) VALUES ('a2','g1','g1::baseskill','base',?1,1,1,1)", [&base.id]).unwrap();
-    conn.execute("INSERT INTO skill_assignments (id, graph_id, node_id, scope_kind, scope_ref, created_at_ms, updated_at_ms, rev) VALUES ('a3','g1','g1::agentskill','agent','feature-dev/developer',1,1,1)", []).unwrap();
+    let bare = repo.with_extension("origin.git");
+    std::process::Command::new("git")
+        .arg("init")
+        .arg("--bare")
+        .arg(&bare)
+        .output()
+        .unwrap();
+    std::process::Command::new("git")
+        .args(["remote", "add", "origin", bare.to_string_lossy().as_ref()])
+        .current_dir(&repo)
+        .output()
+        .unwrap();
+    std::process::Command::new("git")
+        .args(["push", "-u", "origin", "main"])
+        .current_dir(&repo)
+        .output()
+        .unwrap();
 
-    let preview =
-        resolve_skill_context_preview(&engine, "r-skill", "implement", "agent", 3, 8).unwrap();
-    let ids: Vec<String> = preview.items.iter().map(|x| x.node_id.clone()).collect();
-    assert!(ids.iter().any(|x| x == "g1::globalskill"));
-    assert!(ids.iter().any(|x| x == "g1::baseskill"));
-    assert!(ids.iter().any(|x| x == "g1::agentskill"));
+    repo
 }
 
 #[tokio::test]
-async fn pr_comment_reemit_idempotency_and_rate_limit() {
+async fn worktree_gc_prunes_finished_runs_and_restores_live_ones() {
     let engine = temp_engine();
-    let base = engine
-        .create_entity_with_payload(
-            "base",
-            0,
-            0,
-            9,
-            9,
-            &serde_json::json!({"repo_path":"/tmp/no-such-repo"}).to_string(),
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let conn = engine.open().unwrap();
+    let root = repo.with_extension("worktrees");
+    let add = |run_id: &str, status: &str, updated_at: &str| -> std::path::PathBuf {
+        let path = root.join(run_id);
+        let branch = format!("clawdorio/{run_id}");
+        let out = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&repo)
+            .args(["worktree", "add", "-b", &branch])
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        conn.execute(
+            "INSERT INTO runs (id, workflow_id, task, status, context_json, created_at, updated_at)
+             VALUES (?1, 'wf', 'task', ?2, '{\"base_id\":\"b1\"}', ?3, ?3)",
+            (run_id, status, updated_at),
         )
         .unwrap();
-    let feature = engine
-        .create_entity_with_payload(
-            "feature",
-            12,
-            0,
-            3,
-            4,
-            &serde_json::json!({"base_id": base.id}).to_string(),
+        let observed = serde_json::json!({
+            "path": path.to_string_lossy(),
+            "branch": branch,
+            "base_repo_path": repo_s,
+        });
+        conn.execute(
+            "INSERT INTO worktrees (id, repo_path, run_id, desired_json, observed_json, observed_at_ms, updated_at_ms)
+             VALUES (?1, ?2, ?3, '{}', ?4, 0, 0)",
+            (format!("wt-{run_id}"), &repo_s, run_id, observed.to_string()),
         )
         .unwrap();
-    let conn = engine.open().unwrap();
-    conn.execute(
-        "INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at) VALUES (?1,'feature-dev','task','running',?2,'{}',?3,?3)",
-        ("r-comment-1", &feature.id, now_rfc3339()),
-    )
-    .unwrap();
-    seed_step(
-        &engine,
-        "s-comment-1",
-        "r-comment-1",
-        "implement",
-        0,
-        "running",
+        path
+    };
+    let old = "2020-01-01T00:00:00Z";
+    let done = add("gc-done", "done", old);
+    let dirty = add("gc-dirty", "cancelled", old);
+    std::fs::write(dirty.join("scratch.txt"), "wip").unwrap();
+    let recent = add("gc-recent", "done", &now_rfc3339());
+    let live = add("gc-live", "running", old);
+    std::fs::remove_dir_all(&live).unwrap();
+
+    // Expired rows whose path is not ours to delete: a real worktree outside the root, and a
+    // plain directory under it. Neither has a run on the board.
+    let outside = repo.with_extension("wt-outside");
+    let plain = root.join("not-a-worktree");
+    std::fs::create_dir_all(&plain).unwrap();
+    std::fs::write(plain.join("keep.txt"), "keep").unwrap();
+    let now = now_ms_i64();
+    let day_ms = 24 * 3_600_000;
+    for (id, path, updated_at_ms) in [
+        ("wt-outside", &outside, 0),
+        ("wt-plain", &plain, 0),
+        ("wt-orphan", &root.join("orphan"), now - day_ms / 2),
+    ] {
+        if id != "wt-plain" {
+            let out = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&repo)
+                .args(["worktree", "add", "--detach"])
+                .arg(path)
+                .output()
+                .unwrap();
+            assert!(out.status.success());
+        }
+        let observed = serde_json::json!({ "path": path.to_string_lossy(), "branch": "" });
+        conn.execute(
+            "INSERT INTO worktrees (id, repo_path, run_id, desired_json, observed_json, observed_at_ms, updated_at_ms)
+             VALUES (?1, ?2, NULL, '{}', ?3, 0, ?4)",
+            (id, &repo_s, observed.to_string(), updated_at_ms),
+        )
+        .unwrap();
+    }
+
+    let mut cfg = config::ServerConfig::default();
+    cfg.worktrees.retention_hours = 24;
+    cfg.worktrees.prune_branches = true;
+    cfg.workspace.worktree_root = Some(root.clone());
+    let policy = cfg.worktrees.gc_policy(&cfg.workspace);
+    let report = worktrees::reconcile(&engine, &policy, now).unwrap();
+    assert_eq!(
+        (
+            report.scanned,
+            report.pruned,
+            report.restored,
+            report.skipped_dirty
+        ),
+        (7, 1, 1, 1)
     );
+    assert!(!done.exists() && dirty.exists() && recent.exists() && live.exists());
+    assert!(outside.exists() && plain.join("keep.txt").exists());
+    let refused: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM event_log WHERE kind='worktree.reconcile_failed'
+             AND json_extract(payload_json, '$.error') LIKE 'worktree_unmanaged%'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(refused, 2);
 
-    let state = axum::extract::State(Arc::new(AppState {
-        engine: engine.clone(),
-    }));
-    let first = api_pr_comment(
-        state.clone(),
-        Json(PrCommentInput {
-            run_id: Some("r-comment-1".to_string()),
-            pr_url: None,
-            pr_number: None,
-            comment: "please rerun".to_string(),
-            idempotency_key: Some("idem-1".to_string()),
-        }),
-    )
-    .await
-    .unwrap();
-    assert!(first.0.get("ok").and_then(|v| v.as_bool()).unwrap_or(false));
+    // Observing an orphan does not restart its retention window.
+    let report = worktrees::reconcile(&engine, &policy, now + day_ms * 3 / 4).unwrap();
+    assert_eq!(report.pruned, 1);
+    assert!(!root.join("orphan").exists());
+    let branch_left = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&repo)
+        .args(["branch", "--list", "clawdorio/gc-done"])
+        .output()
+        .unwrap();
+    assert!(branch_left.stdout.is_empty());
+    let pruned: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM event_log WHERE kind='worktree.pruned' AND entity_id='wt-gc-done'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(pruned, 1);
 
-    let replay = api_pr_comment(
-        state.clone(),
-        Json(PrCommentInput {
-            run_id: Some("r-comment-1".to_string()),
-            pr_url: None,
-            pr_number: None,
-            comment: "please rerun".to_string(),
-            idempotency_key: Some("idem-1".to_string()),
+    let mut state = AppState::new(engine.clone());
+    state.config = Arc::new(cfg);
+    let state = Arc::new(state);
+    let Json(list) = worktrees::api_worktrees_list(
+        axum::extract::State(state.clone()),
+        axum::extract::Query(worktrees::WorktreeListQuery {
+            base_id: Some("b1".to_string()),
+            refresh: None,
         }),
     )
     .await
     .unwrap();
-    assert!(replay
-        .0
-        .get("idempotent_replay")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false));
+    let rows = list["worktrees"].as_array().unwrap();
+    assert_eq!(rows.len(), 4);
+    let row = |id: &str| rows.iter().find(|r| r["id"] == id).unwrap().clone();
+    assert_eq!(row("wt-gc-done")["desired_state"], "absent");
+    assert_eq!(row("wt-gc-done")["exists"], false);
+    assert_eq!(row("wt-gc-dirty")["dirty"], true);
+    assert!(row("wt-gc-recent")["prunable_at_ms"].as_i64().unwrap() > now_ms_i64());
+    assert!(row("wt-gc-live")["prunable_at_ms"].is_null());
+    assert!(list["total_disk_bytes"].as_u64().unwrap() > 0);
 
-    let err = api_pr_comment(
-        state,
-        Json(PrCommentInput {
-            run_id: Some("r-comment-1".to_string()),
-            pr_url: None,
-            pr_number: None,
-            comment: "please rerun again".to_string(),
-            idempotency_key: Some("idem-2".to_string()),
-        }),
+    let prune = |id: &str, force: bool| {
+        worktrees::api_worktree_prune(
+            axum::extract::State(state.clone()),
+            axum::extract::Path(id.to_string()),
+            Some(Json(worktrees::PruneInput {
+                force,
+                delete_branch: None,
+            })),
+        )
+    };
+    let status = |r: Result<_, (axum::http::StatusCode, String)>| r.map(|_| ()).unwrap_err();
+    assert_eq!(status(prune("wt-gc-live", false).await).1, "run_active");
+    assert_eq!(
+        status(prune("wt-gc-dirty", false).await).1,
+        "worktree_dirty"
+    );
+    assert_eq!(
+        status(prune("nope", false).await).0,
+        axum::http::StatusCode::NOT_FOUND
+    );
+    let Json(out) = prune("wt-gc-dirty", true).await.unwrap();
+    assert!(out["freed_bytes"].as_u64().unwrap() > 0);
+    assert!(!dirty.exists());
+}
+
+#[tokio::test]
+async fn workspace_config_roots_exclusions_and_base_worktree_override() {
+    let root = std::env::temp_dir().join(format!(
+        "clawdorio-server-ws-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    for dir in [
+        "vol-a/app/.git",
+        "vol-a/team/deep/lib/.git",
+        "vol-a/archive/old/.git",
+        "vol-b/tool/.git",
+        "vol-b/scratch-1/.git",
+    ] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    let cfg_path = root.join("config.toml");
+    std::fs::write(
+        &cfg_path,
+        format!(
+            "[workspace]\nroots = [{:?}, {:?}]\nscan_depth = 2\nexclude = [\"scratch-*\", \"**/archive/**\"]\n",
+            root.join("vol-a"),
+            root.join("vol-b"),
+        ),
+    )
+    .unwrap();
+    let cfg = config::ServerConfig::load(Some(&cfg_path))
+        .unwrap()
+        .0
+        .finish()
+        .unwrap();
+    let mut names: Vec<String> = cfg
+        .workspace
+        .discover_repos()
+        .into_iter()
+        .map(|r| r.name)
+        .collect();
+    names.sort();
+    // `lib` is three levels down; `old` and `scratch-1` are excluded.
+    assert_eq!(names, vec!["app", "tool"]);
+
+    std::fs::write(&cfg_path, "[workspace]\nexclude = [\"[\"]\n").unwrap();
+    let err = config::ServerConfig::load(Some(&cfg_path))
+        .unwrap()
+        .0
+        .finish()
+        .unwrap_err();
+    assert!(err.to_string().contains("workspace.exclude"));
+    std::fs::write(&cfg_path, "[workspace]\nrootz = []\n").unwrap();
+    assert!(config::ServerConfig::load(Some(&cfg_path)).is_err());
+
+    let engine = temp_engine();
+    let base = engine
+        .create_entity_with_payload("base", 0, 0, 9, 9, "{}")
+        .unwrap();
+    let mut state = AppState::new(engine);
+    state.config = Arc::new(cfg);
+    let state = Arc::new(state);
+    let patch = |root: Option<&str>| {
+        api_base_workspace_patch(
+            axum::extract::State(state.clone()),
+            axum::extract::Path(base.id.clone()),
+            Json(BaseWorkspacePatch {
+                worktree_root: root.map(str::to_string),
+            }),
+        )
+    };
+    let Json(view) = api_base_workspace_get(
+        axum::extract::State(state.clone()),
+        axum::extract::Path(base.id.clone()),
     )
     .await
-    .unwrap_err();
-    assert_eq!(err.0, axum::http::StatusCode::TOO_MANY_REQUESTS);
+    .unwrap();
+    assert_eq!(
+        std::path::PathBuf::from(view.effective_worktree_root),
+        root.join("vol-a")
+    );
+    assert_eq!(
+        patch(Some("relative/dir")).await.unwrap_err().0,
+        axum::http::StatusCode::BAD_REQUEST
+    );
+    let Json(view) = patch(Some("/mnt/fast/worktrees")).await.unwrap();
+    assert_eq!(view.effective_worktree_root, "/mnt/fast/worktrees");
+    let Json(view) = patch(None).await.unwrap();
+    assert!(view.worktree_root.is_none());
+}
+
+#[tokio::test]
+async fn server_config_round_trips_validates_and_reaches_handlers() {
+    let defaults = config::ServerConfig::default();
+    let printed = defaults.to_toml().unwrap();
+    assert_eq!(config::ServerConfig::parse(&printed).unwrap(), defaults);
+
+    let cfg = config::ServerConfig::parse(
+        "[server]\nallow_cidrs = [\"10.1.0.0/16\", \"fd00::/8\"]\n[placement]\nmax_base_distance = 30\n[pr_comments]\nreemit_min_interval_ms = 0\n",
+    )
+    .unwrap()
+    .finish()
+    .unwrap();
+    // Untouched sections keep their defaults.
+    assert_eq!(cfg.workers, defaults.workers);
+    let ranges = cfg.allowed_ranges();
+    let allowed = |ip: &str| is_allowed_peer_ip(ip.parse().unwrap(), &ranges);
+    assert!(allowed("127.0.0.1") && allowed("::1"));
+    assert!(allowed("10.1.200.3") && allowed("fd12::1"));
+    assert!(!allowed("10.2.0.1") && !allowed("100.64.0.1"));
+
+    let err = config::ServerConfig::parse(
+        "[workers]\nmax_workers = 0\npoll_interval_ms = 10\n[auto_rebase]\ninterval_sec = 5\n[server]\nallow_cidrs = [\"10.0.0.0/33\", \"nope\"]\n",
+    )
+    .unwrap()
+    .finish()
+    .unwrap_err()
+    .to_string();
+    for needle in [
+        "workers.max_workers",
+        "workers.poll_interval_ms",
+        "auto_rebase.interval_sec",
+        "10.0.0.0/33",
+        "nope",
+    ] {
+        assert!(err.contains(needle), "{needle} missing from {err}");
+    }
+    assert!(config::ServerConfig::parse("[workers]\nmax_worker = 2\n").is_err());
+    assert_eq!(defaults.workers.ticks(21_000), 30);
+    assert_eq!(defaults.workers.ticks(1), 1);
+
+    let mut state = AppState::new(temp_engine());
+    state.config = Arc::new(cfg);
+    let Json(st) = api_state(axum::extract::State(Arc::new(state)))
+        .await
+        .unwrap();
+    assert_eq!(st.placement_max_base_distance, 30);
 }
 
 #[test]
-fn canonical_json_is_stable_and_sorted() {
-    let a: serde_json::Value = serde_json::json!({"b":1,"a":{"d":2,"c":1}});
-    let b: serde_json::Value = serde_json::json!({"a":{"c":1,"d":2},"b":1});
-    assert_eq!(canonical_json_string(&a), canonical_json_string(&b));
+fn sync_now_queues_once_idempotent() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({
+                "repo_path": repo.to_string_lossy().to_string(),
+                "auto_rebase_enabled": true,
+                "auto_rebase_interval_sec": 120,
+            })
+            .to_string(),
+        )
+        .unwrap();
+    let base = engine
+        .list_entities()
+        .unwrap()
+        .into_iter()
+        .find(|e| e.kind == "base")
+        .unwrap();
+
+    assert!(queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
+    assert!(!queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
+
+    let conn = engine.open().unwrap();
+    let c: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase' AND entity_id=?1",
+            [&base.id],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(c, 1);
 }
 
 #[test]
-fn markdown_renderer_is_deterministic() {
-    let doc = DocNode {
-        title: "Root".to_string(),
-        items: vec!["z:2".to_string(), "a:1".to_string()],
-        children: vec![
-            DocNode {
-                title: "B".to_string(),
-                items: vec!["x".to_string()],
-                children: vec![],
-            },
-            DocNode {
-                title: "A".to_string(),
-                items: vec!["y".to_string()],
-                children: vec![],
-            },
-        ],
-    };
-    let one = render_doc_markdown(&doc);
-    let two = render_doc_markdown(&doc);
-    assert_eq!(one, two);
-    assert!(one.find("## A").unwrap_or(usize::MAX) < one.find("## B").unwrap_or(0));
+fn periodic_reconciler_skips_when_disabled() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let _ = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({
+                "repo_path": repo.to_string_lossy().to_string(),
+                "auto_rebase_enabled": false,
+                "auto_rebase_interval_sec": 30,
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+    periodic_rebase_reconciler(&engine).unwrap();
+    let conn = engine.open().unwrap();
+    let c: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(c, 0);
 }
 
 #[tokio::test]
-async fn library_artifact_rebuild_and_latest() {
+async fn manual_sync_handler_queues_run() {
     let engine = temp_engine();
+    let repo = init_git_repo();
     let base = engine
         .create_entity_with_payload(
             "base",
@@ -616,123 +1779,2785 @@ async fn library_artifact_rebuild_and_latest() {
             0,
             9,
             9,
-            &serde_json::json!({"repo_path":"/tmp/repo"}).to_string(),
+            &serde_json::json!({
+                "repo_path": repo.to_string_lossy().to_string(),
+                "auto_rebase_enabled": true,
+                "auto_rebase_interval_sec": 120,
+            })
+            .to_string(),
         )
         .unwrap();
-    let lib = engine
+    let state = Arc::new(AppState::new(engine.clone()));
+    let _ = api_bases_sync_now(
+        axum::extract::State(state),
+        axum::extract::Path(base.id.clone()),
+    )
+    .await
+    .unwrap();
+
+    let conn = engine.open().unwrap();
+    let c: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase' AND entity_id=?1",
+            [&base.id],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(c, 1);
+}
+
+#[tokio::test]
+async fn webhook_push_queues_auto_rebase() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    std::process::Command::new("git")
+        .args([
+            "remote",
+            "set-url",
+            "origin",
+            "https://github.com/acme/demo.git",
+        ])
+        .current_dir(&repo)
+        .output()
+        .unwrap();
+
+    let base = engine
         .create_entity_with_payload(
-            "library",
-            12,
+            "base",
             0,
-            3,
-            4,
-            &serde_json::json!({"base_id":base.id}).to_string(),
+            0,
+            9,
+            9,
+            &serde_json::json!({
+                "repo_path": repo.to_string_lossy().to_string(),
+                "auto_rebase_enabled": true,
+                "auto_rebase_interval_sec": 120,
+            })
+            .to_string(),
         )
         .unwrap();
-    let state = axum::extract::State(Arc::new(AppState {
-        engine: engine.clone(),
-    }));
 
-    let rebuilt = api_library_rebuild(
-        state.clone(),
-        Json(LibraryRebuildInput {
-            agent_id: lib.id.clone(),
-            base_id: Some(base.id.clone()),
-            run_id: None,
-            source_event: Some("test.rebuild".to_string()),
-        }),
+    let mut cfg = config::ServerConfig::default();
+    cfg.github.allow_unsigned = true;
+    let state = Arc::new(AppState {
+        config: Arc::new(cfg),
+        ..AppState::new(engine.clone())
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert("x-github-event", HeaderValue::from_static("push"));
+    let payload = serde_json::json!({
+        "ref": "refs/heads/main",
+        "after": "abc123",
+        "repository": { "full_name": "acme/demo" }
+    });
+    let _ = github::api_github_webhook(
+        axum::extract::State(state),
+        headers,
+        axum::body::Bytes::from(payload.to_string()),
     )
     .await
     .unwrap();
-    assert!(!rebuilt.0.document_md.is_empty());
 
-    let latest = api_library_latest(
-        state,
-        axum::extract::Query(LibraryArtifactQuery {
-            agent_id: Some(lib.id.clone()),
-            base_id: Some(base.id.clone()),
-            run_id: None,
-            limit: None,
-        }),
-    )
-    .await
-    .unwrap();
-    assert_eq!(latest.0.id, rebuilt.0.id);
+    let conn = engine.open().unwrap();
+    let c: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase' AND entity_id=?1",
+            [&base.id],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(c, 1);
 }
 
 #[tokio::test]
-async fn library_memory_list_and_detail_are_deterministic() {
+async fn pr_feed_lists_feature_runs_with_fallback_summary() {
     let engine = temp_engine();
-    let conn = engine.open().unwrap();
-    conn.execute(
-        "INSERT INTO library_artifacts (id, agent_id, base_id, run_id, source_event, hierarchy_json, document_md, content_hash, version, created_at_ms, rev) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 1)",
-        (
-            "lib-b",
-            "agent-1",
-            Some("base-1"),
-            Some("run-1"),
-            Some("seed.b"),
-            "{}",
-            "# B artifact",
-            "hash-b",
-            2_i64,
-            1000_i64,
-        ),
-    )
-    .unwrap();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({"repo_path":"/tmp/no-such-repo"}).to_string(),
+        )
+        .unwrap();
+    let feature = engine
+        .create_entity_with_payload(
+            "feature",
+            12,
+            0,
+            3,
+            4,
+            &serde_json::json!({"base_id": base.id}).to_string(),
+        )
+        .unwrap();
+    let conn = engine.open().unwrap();
     conn.execute(
-        "INSERT INTO library_artifacts (id, agent_id, base_id, run_id, source_event, hierarchy_json, document_md, content_hash, version, created_at_ms, rev) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 1)",
+        "INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at) VALUES (?1,'feature-dev',?2,'running',?3,?4,?5,?5)",
         (
-            "lib-a",
-            "agent-1",
-            Some("base-1"),
-            Some("run-1"),
-            Some("seed.a"),
-            "{}",
-            "# A artifact",
-            "hash-a",
-            1_i64,
-            1000_i64,
+            "r-pr-feed-1",
+            "Implement mobile feed",
+            &feature.id,
+            serde_json::json!({
+                "base_repo_path":"/tmp/no-such-repo",
+                "branch":"clawdorio/r-pr-feed-1",
+                "pr_url":"https://github.com/acme/demo/pull/42"
+            })
+            .to_string(),
+            now_rfc3339(),
         ),
     )
     .unwrap();
 
-    let state = axum::extract::State(Arc::new(AppState {
-        engine: engine.clone(),
-    }));
+    let out = api_pr_feed(
+        axum::extract::State(Arc::new(AppState::new(engine.clone()))),
+        axum::extract::Query(PrFeedQuery {
+            base_id: Some(base.id.clone()),
+            limit: Some(10),
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(out.0.len(), 1);
+    assert_eq!(out.0[0].run_id, "r-pr-feed-1");
+    assert_eq!(out.0[0].pr_number, Some(42));
+    assert_eq!(out.0[0].changed_files.total_files, 0);
+    assert_eq!(out.0[0].changed_files.source, "fallback");
+}
 
-    let list = api_library_memory_list(
+#[test]
+fn skill_graph_import_and_preview_precedence() {
+    let engine = temp_engine();
+    let skill_root = std::env::temp_dir().join(format!(
+        "clawdorio-skills-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    std::fs::create_dir_all(&skill_root).unwrap();
+    std::fs::write(
+        skill_root.join("index.md"),
+        "[[GlobalSkill]]\n[[BaseSkill]]\n[[AgentSkill]]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        skill_root.join("GlobalSkill.md"),
+        "---\ntitle: Global Skill\ndescription: global desc\n---\nshared",
+    )
+    .unwrap();
+    std::fs::write(
+        skill_root.join("BaseSkill.md"),
+        "---\ntitle: Base Skill\ndescription: base desc\n---\n[[GlobalSkill]]",
+    )
+    .unwrap();
+    std::fs::write(
+        skill_root.join("AgentSkill.md"),
+        "---\ntitle: Agent Skill\ndescription: agent desc\n---\n[[BaseSkill]]",
+    )
+    .unwrap();
+
+    let imp = SkillImportInput {
+        pack_name: "pack".to_string(),
+        source_root: skill_root.to_string_lossy().to_string(),
+        index_path: "index.md".to_string(),
+        graph_id: Some("g1".to_string()),
+        title: Some("Pack".to_string()),
+    };
+    import_skill_graph(&engine, &imp).unwrap();
+
+    let base = engine
+        .create_entity_with_payload("base", 0, 0, 9, 9, r#"{"repo_path":"/tmp/repo"}"#)
+        .unwrap();
+    let feature = engine
+        .create_entity_with_payload(
+            "feature",
+            11,
+            0,
+            3,
+            4,
+            &serde_json::json!({"base_id": base.id}).to_string(),
+        )
+        .unwrap();
+
+    let conn = engine.open().unwrap();
+    conn.execute("INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at) VALUES ('r-skill','wf','Agent task', 'queued', ?1, '{}', ?2, ?2)", (&feature.id, now_rfc3339())).unwrap();
+    conn.execute("INSERT INTO steps (id, run_id, step_id, agent_id, step_index, status, input_json, output_text, created_at, updated_at) VALUES ('s-skill','r-skill','implement','feature-dev/developer',0,'queued','{}',NULL,?1,?1)", [now_rfc3339()]).unwrap();
+
+    conn.execute("INSERT INTO skill_assignments (id, graph_id, node_id, scope_kind, scope_ref, created_at_ms, updated_at_ms, rev) VALUES ('a1','g1','g1::globalskill','global',NULL,1,1,1)", []).unwrap();
+    conn.execute("INSERT INTO skill_assignments (id, graph_id, node_id, scope_kind, scope_ref, created_at_ms, updated_at_ms, rev) VALUES ('a2','g1','g1::baseskill','base',?1,1,1,1)", [&base.id]).unwrap();
+    conn.execute("INSERT INTO skill_assignments (id, graph_id, node_id, scope_kind, scope_ref, created_at_ms, updated_at_ms, rev) VALUES ('a3','g1','g1::agentskill','agent','feature-dev/developer',1,1,1)", []).unwrap();
+
+    let preview =
+        resolve_skill_context_preview(&engine, "r-skill", "implement", "agent", 3, 8).unwrap();
+    let ids: Vec<String> = preview.items.iter().map(|x| x.node_id.clone()).collect();
+    assert!(ids.iter().any(|x| x == "g1::globalskill"));
+    assert!(ids.iter().any(|x| x == "g1::baseskill"));
+    assert!(ids.iter().any(|x| x == "g1::agentskill"));
+}
+
+#[tokio::test]
+async fn pr_comment_reemit_idempotency_and_rate_limit() {
+    let engine = temp_engine();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({"repo_path":"/tmp/no-such-repo"}).to_string(),
+        )
+        .unwrap();
+    let feature = engine
+        .create_entity_with_payload(
+            "feature",
+            12,
+            0,
+            3,
+            4,
+            &serde_json::json!({"base_id": base.id}).to_string(),
+        )
+        .unwrap();
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "INSERT INTO runs (id, workflow_id, task, status, entity_id, context_json, created_at, updated_at) VALUES (?1,'feature-dev','task','running',?2,'{}',?3,?3)",
+        ("r-comment-1", &feature.id, now_rfc3339()),
+    )
+    .unwrap();
+    seed_step(
+        &engine,
+        "s-comment-1",
+        "r-comment-1",
+        "implement",
+        0,
+        "running",
+    );
+
+    let state = axum::extract::State(Arc::new(AppState::new(engine.clone())));
+    let first = api_pr_comment(
         state.clone(),
-        axum::extract::Query(LibraryMemoryQuery {
-            agent_id: Some("agent-1".to_string()),
-            base_id: Some("base-1".to_string()),
-            run_id: Some("run-1".to_string()),
-            limit: Some(10),
-            before_created_at_ms: None,
-            before_id: None,
+        Json(PrCommentInput {
+            run_id: Some("r-comment-1".to_string()),
+            pr_url: None,
+            pr_number: None,
+            comment: "please rerun".to_string(),
+            idempotency_key: Some("idem-1".to_string()),
         }),
     )
     .await
     .unwrap();
+    assert!(first.0.get("ok").and_then(|v| v.as_bool()).unwrap_or(false));
 
-    assert_eq!(list.0.len(), 2);
-    assert_eq!(list.0[0].artifact_id, "lib-b");
-    assert_eq!(list.0[1].artifact_id, "lib-a");
+    let replay = api_pr_comment(
+        state.clone(),
+        Json(PrCommentInput {
+            run_id: Some("r-comment-1".to_string()),
+            pr_url: None,
+            pr_number: None,
+            comment: "please rerun".to_string(),
+            idempotency_key: Some("idem-1".to_string()),
+        }),
+    )
+    .await
+    .unwrap();
+    assert!(replay
+        .0
+        .get("idempotent_replay")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false));
 
-    let detail =
-        api_library_memory_detail(state, axum::extract::Path("artifact:lib-a".to_string()))
-            .await
-            .unwrap();
-    assert_eq!(detail.0.record.artifact_id, "lib-a");
-    assert!(detail.0.document_md.contains("A artifact"));
+    let err = api_pr_comment(
+        state,
+        Json(PrCommentInput {
+            run_id: Some("r-comment-1".to_string()),
+            pr_url: None,
+            pr_number: None,
+            comment: "please rerun again".to_string(),
+            idempotency_key: Some("idem-2".to_string()),
+        }),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.0, axum::http::StatusCode::TOO_MANY_REQUESTS);
 }
 
 #[tokio::test]
-async fn library_memory_detail_missing_is_404() {
+async fn event_stream_resumes_from_last_event_id() {
+    use futures_util::StreamExt;
+
     let engine = temp_engine();
-    let state = axum::extract::State(Arc::new(AppState { engine }));
-    let err = api_library_memory_detail(state, axum::extract::Path("artifact:nope".to_string()))
+    let a = engine.create_entity("base", 0, 0, 9, 9).unwrap();
+    let b = engine.create_entity("base", 20, 0, 9, 9).unwrap();
+    let rows = engine.list_events_since(0, 10).unwrap();
+    let first_seq = rows
+        .iter()
+        .find(|r| r.entity_id.as_deref() == Some(a.id.as_str()))
+        .map(|r| r.seq)
+        .unwrap();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "last-event-id",
+        HeaderValue::from_str(&first_seq.to_string()).unwrap(),
+    );
+    let cursor = resolve_stream_cursor(&engine, &headers, Some(0)).unwrap();
+    assert_eq!(cursor, first_seq);
+    assert_eq!(
+        resolve_stream_cursor(&engine, &HeaderMap::new(), None).unwrap(),
+        engine.get_rev().unwrap()
+    );
+
+    let mut stream = Box::pin(event_log_stream(engine.clone(), cursor));
+    let next = tokio::time::timeout(std::time::Duration::from_secs(5), stream.next())
         .await
-        .unwrap_err();
-    assert_eq!(err.0, axum::http::StatusCode::NOT_FOUND);
+        .unwrap()
+        .unwrap();
+    assert_eq!(next.kind, "entity.created");
+    assert_eq!(next.entity_id.as_deref(), Some(b.id.as_str()));
+
+    engine.delete_entity(&a.id).unwrap();
+    let tailed = tokio::time::timeout(std::time::Duration::from_secs(5), stream.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(tailed.kind, "entity.deleted");
+    assert!(tailed.seq > next.seq);
+}
+
+#[test]
+fn ui_updates_target_panels_for_engine_events() {
+    use clawdorio_protocol::{targets, Swap};
+
+    let engine = temp_engine();
+    let ent = engine.create_entity("base", 0, 0, 9, 9).unwrap();
+    engine.update_entity_position(&ent.id, 4, 5).unwrap();
+    seed_run(&engine, "r-ui", &ent.id, "running");
+    seed_step(&engine, "s-ui", "r-ui", "plan", 0, "running");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (1, 'step.running', 's-ui', ?1)",
+        [serde_json::json!({"run_id": "r-ui", "step_id": "plan"}).to_string()],
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (1, 'workers.reemit', NULL, '{}')",
+        [],
+    )
+    .unwrap();
+
+    let events: Vec<StreamEvent> = engine
+        .list_events_since(0, 50)
+        .unwrap()
+        .into_iter()
+        .map(StreamEvent::from)
+        .collect();
+
+    let moved = events.iter().find(|e| e.kind == "entity.moved").unwrap();
+    let up = ui::ui_update_for_event(&engine, moved).unwrap().unwrap();
+    assert_eq!(up.patches[0].target, targets::PANEL_LEFT);
+    assert_eq!(up.patches[0].swap, Swap::Merge);
+    let payload = up.patches[0].payload.clone().unwrap();
+    assert_eq!(payload["entity"]["x"], 4);
+
+    let step = events.iter().find(|e| e.kind == "step.running").unwrap();
+    let up = ui::ui_update_for_event(&engine, step).unwrap().unwrap();
+    assert_eq!(up.event, "step.running");
+    assert_eq!(up.patches[0].target, targets::PANEL_RIGHT);
+    assert_eq!(up.patches[0].swap, Swap::Replace);
+    let payload = up.patches[0].payload.clone().unwrap();
+    assert_eq!(payload["run"]["id"], "r-ui");
+    assert_eq!(payload["steps"][0]["id"], "s-ui");
+    assert_eq!(up.patches[1].target, targets::PANEL_BOTTOM_BAR);
+
+    let bookkeeping = events.iter().find(|e| e.kind == "workers.reemit").unwrap();
+    assert!(ui::ui_update_for_event(&engine, bookkeeping)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn canonical_json_is_stable_and_sorted() {
+    let a: serde_json::Value = serde_json::json!({"b":1,"a":{"d":2,"c":1}});
+    let b: serde_json::Value = serde_json::json!({"a":{"c":1,"d":2},"b":1});
+    assert_eq!(canonical_json_string(&a), canonical_json_string(&b));
+}
+
+#[test]
+fn markdown_renderer_is_deterministic() {
+    let doc = DocNode {
+        title: "Root".to_string(),
+        items: vec!["z:2".to_string(), "a:1".to_string()],
+        children: vec![
+            DocNode {
+                title: "B".to_string(),
+                items: vec!["x".to_string()],
+                children: vec![],
+            },
+            DocNode {
+                title: "A".to_string(),
+                items: vec!["y".to_string()],
+                children: vec![],
+            },
+        ],
+    };
+    let one = render_doc_markdown(&doc);
+    let two = render_doc_markdown(&doc);
+    assert_eq!(one, two);
+    assert!(one.find("## A").unwrap_or(usize::MAX) < one.find("## B").unwrap_or(0));
+}
+
+#[tokio::test]
+async fn library_artifact_rebuild_and_latest() {
+    let engine = temp_engine();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({"repo_path":"/tmp/repo"}).to_string(),
+        )
+        .unwrap();
+    let lib = engine
+        .create_entity_with_payload(
+            "library",
+            12,
+            0,
+            3,
+            4,
+            &serde_json::json!({"base_id":base.id}).to_string(),
+        )
+        .unwrap();
+    let state = axum::extract::State(Arc::new(AppState::new(engine.clone())));
+
+    let rebuilt = api_library_rebuild(
+        state.clone(),
+        Json(LibraryRebuildInput {
+            agent_id: lib.id.clone(),
+            base_id: Some(base.id.clone()),
+            run_id: None,
+            source_event: Some("test.rebuild".to_string()),
+        }),
+    )
+    .await
+    .unwrap();
+    assert!(!rebuilt.0.document_md.is_empty());
+
+    let latest = api_library_latest(
+        state,
+        axum::extract::Query(LibraryArtifactQuery {
+            agent_id: Some(lib.id.clone()),
+            base_id: Some(base.id.clone()),
+            run_id: None,
+            limit: None,
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(latest.0.id, rebuilt.0.id);
+}
+
+#[tokio::test]
+async fn library_memory_list_and_detail_are_deterministic() {
+    let engine = temp_engine();
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "INSERT INTO library_artifacts (id, agent_id, base_id, run_id, source_event, hierarchy_json, document_md, content_hash, version, created_at_ms, rev) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 1)",
+        (
+            "lib-b",
+            "agent-1",
+            Some("base-1"),
+            Some("run-1"),
+            Some("seed.b"),
+            "{}",
+            "# B artifact",
+            "hash-b",
+            2_i64,
+            1000_i64,
+        ),
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO library_artifacts (id, agent_id, base_id, run_id, source_event, hierarchy_json, document_md, content_hash, version, created_at_ms, rev) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 1)",
+        (
+            "lib-a",
+            "agent-1",
+            Some("base-1"),
+            Some("run-1"),
+            Some("seed.a"),
+            "{}",
+            "# A artifact",
+            "hash-a",
+            1_i64,
+            1000_i64,
+        ),
+    )
+    .unwrap();
+
+    let state = axum::extract::State(Arc::new(AppState::new(engine.clone())));
+
+    let list = api_library_memory_list(
+        state.clone(),
+        axum::extract::Query(LibraryMemoryQuery {
+            agent_id: Some("agent-1".to_string()),
+            base_id: Some("base-1".to_string()),
+            run_id: Some("run-1".to_string()),
+            limit: Some(10),
+            before_created_at_ms: None,
+            before_id: None,
+        }),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(list.0.len(), 2);
+    assert_eq!(list.0[0].artifact_id, "lib-b");
+    assert_eq!(list.0[1].artifact_id, "lib-a");
+
+    let detail =
+        api_library_memory_detail(state, axum::extract::Path("artifact:lib-a".to_string()))
+            .await
+            .unwrap();
+    assert_eq!(detail.0.record.artifact_id, "lib-a");
+    assert!(detail.0.document_md.contains("A artifact"));
+}
+
+#[tokio::test]
+async fn library_memory_detail_missing_is_404() {
+    let engine = temp_engine();
+    let state = axum::extract::State(Arc::new(AppState::new(engine)));
+    let err = api_library_memory_detail(state, axum::extract::Path("artifact:nope".to_string()))
+        .await
+        .unwrap_err();
+    assert_eq!(err.0, axum::http::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn token_auth_enforces_scopes_and_audits_mutations() {
+    use tower::ServiceExt;
+
+    let engine = temp_engine();
+    let mut cfg = config::ServerConfig::default();
+    cfg.auth.enabled = true;
+    assert!(!cfg.auth.loopback_bypass);
+    let mut state = AppState::new(engine.clone());
+    state.config = Arc::new(cfg);
+    let app = build_router(state);
+
+    let (reader, read_secret) = auth::create_token(&engine, "ci", auth::Scope::Read).unwrap();
+    let (_, admin_secret) = auth::create_token(&engine, "ops", auth::Scope::Admin).unwrap();
+    let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+    let call = |method: &str, uri: &str, token: Option<&str>| {
+        let mut req = axum::http::Request::builder().method(method).uri(uri);
+        if let Some(t) = token {
+            req = req.header("authorization", format!("Bearer {t}"));
+        }
+        let mut req = req
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(if method == "POST" {
+                r#"{"name":"bot","scope":"operator"}"#
+            } else {
+                ""
+            }))
+            .unwrap();
+        req.extensions_mut()
+            .insert(axum::extract::ConnectInfo(peer));
+        app.clone().oneshot(req)
+    };
+
+    assert_eq!(call("GET", "/health", None).await.unwrap().status(), 200);
+    assert_eq!(call("GET", "/api/state", None).await.unwrap().status(), 401);
+    assert_eq!(
+        call("GET", "/api/state", Some("clw_bogus"))
+            .await
+            .unwrap()
+            .status(),
+        401
+    );
+    assert_eq!(
+        call("GET", "/api/state", Some(&read_secret))
+            .await
+            .unwrap()
+            .status(),
+        200
+    );
+    let q = format!("/api/state?access_token={read_secret}");
+    assert_eq!(call("GET", &q, None).await.unwrap().status(), 200);
+    assert_eq!(
+        call("POST", "/api/workers/reemit", Some(&read_secret))
+            .await
+            .unwrap()
+            .status(),
+        403
+    );
+    assert_eq!(
+        call("GET", "/api/tokens", Some(&read_secret))
+            .await
+            .unwrap()
+            .status(),
+        403
+    );
+
+    let created = call("POST", "/api/tokens", Some(&admin_secret))
+        .await
+        .unwrap();
+    assert_eq!(created.status(), 200);
+    let body = axum::body::to_bytes(created.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(v["token"].as_str().unwrap().starts_with("clw_"));
+    assert_eq!(v["info"]["scope"], "operator");
+
+    // Only hashes are stored.
+    let conn = engine.open().unwrap();
+    let leaked: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM api_tokens WHERE token_hash=?1",
+            [&read_secret],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(leaked, 0);
+
+    let revoke = format!("/api/tokens/{}", reader.id);
+    assert_eq!(
+        call("DELETE", &revoke, Some(&admin_secret))
+            .await
+            .unwrap()
+            .status(),
+        200
+    );
+    assert_eq!(
+        call("GET", "/api/state", Some(&read_secret))
+            .await
+            .unwrap()
+            .status(),
+        401
+    );
+
+    let audits: Vec<serde_json::Value> = conn
+        .prepare("SELECT payload_json FROM event_log WHERE kind='api.audit' ORDER BY seq")
+        .unwrap()
+        .query_map([], |r| r.get::<_, String>(0))
+        .unwrap()
+        .map(|p| serde_json::from_str(&p.unwrap()).unwrap())
+        .collect();
+    assert_eq!(audits.len(), 2);
+    assert_eq!(audits[0]["method"], "POST");
+    assert_eq!(audits[0]["token_name"], "ops");
+    assert_eq!(audits[1]["path"], revoke);
+    assert_eq!(audits[1]["status"], 200);
+
+    let listed = auth::list_tokens(&engine).unwrap();
+    assert_eq!(listed.len(), 3);
+    assert!(listed[0].revoked_at_ms.is_some());
+    assert!(listed[0].last_used_at_ms.is_some());
+
+    // With the bypass on, tokenless loopback calls pass unless a proxy forwarded them.
+    let mut cfg = config::ServerConfig::default();
+    cfg.auth.enabled = true;
+    cfg.auth.loopback_bypass = true;
+    let mut state = AppState::new(engine.clone());
+    state.config = Arc::new(cfg);
+    let app = build_router(state);
+    for (header, want) in [
+        (None, 200),
+        (Some(("x-forwarded-for", "203.0.113.7")), 401),
+        (Some(("forwarded", "for=203.0.113.7")), 401),
+    ] {
+        let mut req = axum::http::Request::builder().uri("/api/state");
+        if let Some((k, v)) = header {
+            req = req.header(k, v);
+        }
+        let mut req = req.body(axum::body::Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(axum::extract::ConnectInfo(peer));
+        assert_eq!(app.clone().oneshot(req).await.unwrap().status(), want);
+    }
+}
+
+/// `X-Hub-Signature-256` value for `body`, as GitHub computes it.
+fn sign_webhook(secret: &str, body: &[u8]) -> String {
+    use hmac::Mac;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+#[tokio::test]
+async fn webhook_verifies_signature_dedupes_and_replays_deliveries() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    std::process::Command::new("git")
+        .args([
+            "remote",
+            "set-url",
+            "origin",
+            "https://github.com/acme/demo.git",
+        ])
+        .current_dir(&repo)
+        .output()
+        .unwrap();
+    engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({
+                "repo_path": repo.to_string_lossy().to_string(),
+                "auto_rebase_enabled": true,
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+    // Without a secret, unsigned intake has to be switched on explicitly.
+    let mut cfg = config::ServerConfig::default();
+    let secretless = Arc::new(AppState {
+        config: Arc::new(cfg.clone()),
+        ..AppState::new(engine.clone())
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert("x-github-event", HeaderValue::from_static("push"));
+    let err = github::api_github_webhook(
+        axum::extract::State(secretless),
+        headers,
+        axum::body::Bytes::from_static(b"{}"),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err,
+        (
+            axum::http::StatusCode::FORBIDDEN,
+            "webhook_secret_not_configured".to_string()
+        )
+    );
+    assert!(cfg.github.unsigned_warning().is_none());
+    cfg.github.allow_unsigned = true;
+    assert!(cfg.github.unsigned_warning().is_some());
+
+    cfg.github.webhook_secret = Some("s3cret".to_string());
+    assert!(cfg.github.unsigned_warning().is_none());
+    let mut state = AppState::new(engine.clone());
+    state.config = Arc::new(cfg);
+    let state = Arc::new(state);
+
+    let body = serde_json::json!({
+        "ref": "refs/heads/main",
+        "after": "abc123",
+        "repository": { "full_name": "acme/demo" }
+    })
+    .to_string();
+    let send = |event: &'static str, delivery: &'static str, sig: Option<String>| {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-github-event", HeaderValue::from_static(event));
+        headers.insert("x-github-delivery", HeaderValue::from_static(delivery));
+        if let Some(sig) = sig {
+            headers.insert("x-hub-signature-256", HeaderValue::from_str(&sig).unwrap());
+        }
+        github::api_github_webhook(
+            axum::extract::State(state.clone()),
+            headers,
+            axum::body::Bytes::from(body.clone()),
+        )
+    };
+
+    let err = send("push", "d1", None).await.unwrap_err();
+    assert_eq!(
+        err,
+        (
+            axum::http::StatusCode::UNAUTHORIZED,
+            "signature_required".to_string()
+        )
+    );
+    let forged = sign_webhook("wrong", body.as_bytes());
+    let err = send("push", "d1", Some(forged)).await.unwrap_err();
+    assert_eq!(err.1, "invalid_signature");
+
+    let sig = sign_webhook("s3cret", body.as_bytes());
+    let first = send("push", "d1", Some(sig.clone())).await.unwrap();
+    assert_eq!(first.0["queued"], 1);
+    let again = send("push", "d1", Some(sig.clone())).await.unwrap();
+    assert_eq!(again.0["duplicate"], true);
+    let ping = send("ping", "d2", Some(sig)).await.unwrap();
+    assert_eq!(ping.0["status"], "ignored");
+
+    let conn = engine.open().unwrap();
+    let runs: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM runs WHERE workflow_id='auto-rebase'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(runs, 1);
+    let rejected: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM event_log WHERE kind='github.delivery_rejected'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(rejected, 2);
+
+    let list = github::api_deliveries_list(
+        axum::extract::State(state.clone()),
+        axum::extract::Query(github::DeliveryListQuery {
+            status: Some("processed".to_string()),
+            event: None,
+            limit: None,
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(list.0.len(), 1);
+    assert_eq!(list.0[0].id, "d1");
+    assert_eq!(list.0[0].duplicates, 1);
+    assert_eq!(list.0[0].outcome["queued"], 1);
+
+    let replayed = github::api_delivery_redeliver(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("d1".to_string()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(replayed.0.status, "processed");
+    assert_eq!(replayed.0.attempts, 2);
+    let missing = github::api_delivery_redeliver(
+        axum::extract::State(state),
+        axum::extract::Path("nope".to_string()),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(missing.0, axum::http::StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn forge_registry_routes_by_host_and_file_forge_tracks_prs() {
+    use forge::{parse_remote, pr_number_from_url, PrState, Remote};
+
+    let gl = parse_remote("ssh://git@gitlab.example.com:2222/group/sub/app.git").unwrap();
+    assert_eq!(
+        gl,
+        Remote {
+            host: "gitlab.example.com".to_string(),
+            path: "group/sub/app".to_string()
+        }
+    );
+    assert_eq!(
+        parse_remote("git@github.com:acme/demo.git").unwrap().path,
+        "acme/demo"
+    );
+    assert_eq!(
+        parse_remote("https://Git.Example.com/acme/demo/")
+            .unwrap()
+            .host,
+        "git.example.com"
+    );
+    assert!(parse_remote("/tmp/origin.git").is_none());
+    assert_eq!(pr_number_from_url("https://github.com/a/b/pull/7"), Some(7));
+    assert_eq!(
+        pr_number_from_url("https://git.example.com/a/b/pulls/8"),
+        Some(8)
+    );
+    assert_eq!(
+        pr_number_from_url("https://gitlab.example.com/a/b/-/merge_requests/9"),
+        Some(9)
+    );
+
+    let mut cfg = config::ServerConfig::default();
+    cfg.forge.hosts.push(forge::ForgeHost {
+        host: "git.example.com".to_string(),
+        kind: forge::ForgeKind::Gitea,
+        api_url: None,
+        token_env: None,
+    });
+    let err = cfg.clone().finish().unwrap_err().to_string();
+    assert!(err.contains("git.example.com needs api_url"), "{err}");
+    cfg.forge.hosts[0].api_url = Some("https://git.example.com/api/v1".to_string());
+    cfg.forge.default = forge::ForgeKind::File;
+    cfg.forge.file_root = std::env::temp_dir().join(format!(
+        "clawdorio-forge-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    let cfg = cfg.finish().unwrap();
+    let forges = ForgeRegistry::from_config(&cfg.forge, &temp_engine());
+
+    // The test repo's origin is a local bare repo, so it goes to the default (file) forge.
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let git = |args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    };
+    git(&["checkout", "-b", "clawdorio/run-1"]);
+    std::fs::write(repo.join("feature.txt"), "one\ntwo\n").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-m", "feature"]);
+
+    let forge = forges.resolve(&repo_s);
+    assert_eq!(forge.name(), "file");
+    let cancel = executor::CancelToken::default();
+    let url = create_pr(
+        forge.as_ref(),
+        &repo_s,
+        "clawdorio/run-1",
+        "Add feature\n\ndetails",
+        &cancel,
+    )
+    .unwrap();
+    assert_eq!(pr_number_from_url(&url), Some(1));
+    // A second attempt finds the open PR instead of opening another.
+    assert_eq!(
+        create_pr(
+            forge.as_ref(),
+            &repo_s,
+            "clawdorio/run-1",
+            "Add feature",
+            &cancel
+        )
+        .unwrap(),
+        url
+    );
+
+    let open = forge.list_open_prs(&repo_s).unwrap();
+    assert_eq!(open.len(), 1);
+    assert_eq!(open[0].title, "Add feature");
+    assert_eq!(open[0].base, "main");
+    let files = forge.changed_files(&repo_s, 1).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(
+        (files[0].path.as_str(), files[0].additions),
+        ("feature.txt", 2)
+    );
+    assert!(files[0].patch.contains("+two"));
+    forge.post_comment(&repo_s, 1, "looks good").unwrap();
+    let status = forge.pr_status(&repo_s, 1).unwrap();
+    assert_eq!(status.state, PrState::Open);
+    assert!(status.head_sha.is_some());
+    assert!(forge.pr_status(&repo_s, 2).is_err());
+
+    let summary = pr_changed_files_summary(forge.as_ref(), &repo_s, 1);
+    assert_eq!((summary.total_files, summary.source.as_str()), (1, "file"));
+}
+
+#[test]
+fn rest_forges_filter_by_head_on_the_server_and_follow_pagination() {
+    use forge::{Forge, RestFlavor, RestForge};
+    use std::io::{BufRead, BufReader, Write};
+
+    // Canned Gitea and GitLab APIs; every request line is recorded.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let seen = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let (log, link_base) = (seen.clone(), base.clone());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let target = line.split_whitespace().nth(1).unwrap_or("").to_string();
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header).unwrap() == 0 || header.trim().is_empty() {
+                    break;
+                }
+            }
+            log.lock().unwrap().push(target.clone());
+            let gitea = |n: i64, head: &str| {
+                serde_json::json!({
+                    "number": n, "state": "open", "title": head,
+                    "head": { "ref": head }, "base": { "ref": "main" },
+                })
+            };
+            let gitlab = |n: i64, head: &str| {
+                serde_json::json!({
+                    "iid": n, "state": "opened", "title": head,
+                    "source_branch": head, "target_branch": "main",
+                })
+            };
+            let gitea_pulls = "/api/v1/repos/acme/demo/pulls?state=open&limit=50";
+            let gitlab_mrs = "/api/v4/projects/acme%2Fdemo/merge_requests";
+            let (headers, body) = match target.as_str() {
+                "/api/v1/repos/acme/demo" => (
+                    String::new(),
+                    serde_json::json!({ "default_branch": "main" }),
+                ),
+                "/api/v1/repos/acme/demo/pulls/main/clawdorio/run-1" => {
+                    (String::new(), gitea(1, "clawdorio/run-1"))
+                }
+                t if t == gitea_pulls => (
+                    format!("Link: <{link_base}{gitea_pulls}&page=2>; rel=\"next\"\r\n"),
+                    serde_json::json!([gitea(1, "clawdorio/run-1")]),
+                ),
+                t if t == format!("{gitea_pulls}&page=2") => (
+                    String::new(),
+                    serde_json::json!([gitea(2, "clawdorio/run-2")]),
+                ),
+                t if t == format!("{gitlab_mrs}?state=opened&per_page=100") => (
+                    "X-Next-Page: 2\r\n".to_string(),
+                    serde_json::json!([gitlab(3, "clawdorio/run-3")]),
+                ),
+                t if t == format!("{gitlab_mrs}?state=opened&per_page=100&page=2") => (
+                    "X-Next-Page: \r\n".to_string(),
+                    serde_json::json!([gitlab(4, "clawdorio/run-4")]),
+                ),
+                t if t == format!("{gitlab_mrs}?state=opened&source_branch=clawdorio%2Frun-4") => (
+                    String::new(),
+                    serde_json::json!([gitlab(4, "clawdorio/run-4")]),
+                ),
+                _ => {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                    continue;
+                }
+            };
+            let body = body.to_string();
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{headers}Connection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    });
+
+    let repo = std::env::temp_dir().join(format!(
+        "clawdorio-rest-forge-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    std::fs::create_dir_all(&repo).unwrap();
+    for args in [
+        &["init", "-q"][..],
+        &[
+            "remote",
+            "add",
+            "origin",
+            "https://git.example.com/acme/demo.git",
+        ],
+    ] {
+        let ok = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .status()
+            .unwrap();
+        assert!(ok.success());
+    }
+    let repo = repo.to_string_lossy().to_string();
+
+    let gitea = RestForge::new(RestFlavor::Gitea, &format!("{base}/api/v1"), None);
+    let pr = gitea
+        .find_pr_by_head(&repo, "clawdorio/run-1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(pr.number, 1);
+    let heads: Vec<String> = gitea
+        .list_open_prs(&repo)
+        .unwrap()
+        .into_iter()
+        .map(|p| p.head)
+        .collect();
+    assert_eq!(heads, vec!["clawdorio/run-1", "clawdorio/run-2"]);
+    // Not open against the default branch: found by paging through every open PR.
+    assert_eq!(
+        gitea
+            .find_pr_by_head(&repo, "clawdorio/run-2")
+            .unwrap()
+            .map(|p| p.number),
+        Some(2)
+    );
+
+    let gitlab = RestForge::new(RestFlavor::Gitlab, &format!("{base}/api/v4"), None);
+    let heads: Vec<String> = gitlab
+        .list_open_prs(&repo)
+        .unwrap()
+        .into_iter()
+        .map(|p| p.head)
+        .collect();
+    assert_eq!(heads, vec!["clawdorio/run-3", "clawdorio/run-4"]);
+    assert_eq!(
+        gitlab
+            .find_pr_by_head(&repo, "clawdorio/run-4")
+            .unwrap()
+            .map(|p| p.number),
+        Some(4)
+    );
+    let seen = seen.lock().unwrap();
+    assert!(seen
+        .iter()
+        .any(|t| t.contains("source_branch=clawdorio%2Frun-4")));
+}
+
+#[tokio::test]
+async fn feature_run_opens_rebases_and_merges_a_pr_on_the_local_forge() {
+    use forge::{LocalForge, PrState};
+
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo_s, "auto_rebase_enabled": true }).to_string(),
+        )
+        .unwrap();
+    let feature = engine
+        .create_entity_with_payload(
+            "feature",
+            11,
+            0,
+            3,
+            4,
+            &serde_json::json!({ "base_id": base.id }).to_string(),
+        )
+        .unwrap();
+    let local = Arc::new(LocalForge::new(engine.clone()));
+    let mut cfg = config::ServerConfig::default();
+    cfg.workspace.worktree_root = Some(repo.with_extension("worktrees"));
+    let state = Arc::new(AppState {
+        forges: Arc::new(ForgeRegistry::with_fallback(local.clone())),
+        config: Arc::new(cfg),
+        ..AppState::new(engine.clone())
+    });
+
+    let Json(out) = api_feature_build(
+        axum::extract::State(state.clone()),
+        Json(FeatureBuildInput {
+            entity_id: feature.id.clone(),
+            prompt: "Add feature.txt".to_string(),
+            workflow_id: None,
+        }),
+    )
+    .await
+    .unwrap();
+    let run_id = out["run_id"].as_str().unwrap().to_string();
+    let worktree = out["worktree_path"].as_str().unwrap().to_string();
+
+    let mut reg = executor::ExecutorRegistry::with_fallback(Arc::new(
+        executor::ScriptedExecutor::new("STATUS: done\n").reply(
+            "plan",
+            "STATUS: done\nSTORIES_JSON: [{\"id\":\"s1\",\"title\":\"Add the file\"}]\n",
+        ),
+    ));
+    reg.register(
+        "feature-dev/developer",
+        Arc::new(executor::CommandExecutor {
+            program: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "echo feature >> feature.txt && git add feature.txt && git commit -qm feature && echo 'STATUS: done'"
+                    .to_string(),
+            ],
+            ..Default::default()
+        }),
+    );
+    let forges = state.forges.clone();
+    let pool = state.workers.clone();
+    let drain = |engine: Engine, reg: Arc<executor::ExecutorRegistry>| {
+        let (forges, pool) = (forges.clone(), pool.clone());
+        std::thread::spawn(move || {
+            while run_one_step_blocking(&engine, &reg, &forges, &pool, "w-test").unwrap() {}
+        })
+    };
+    let reg = Arc::new(reg);
+    drain(engine.clone(), reg.clone()).join().unwrap();
+
+    let conn = engine.open().unwrap();
+    let (status, ctx): (String, String) = conn
+        .query_row(
+            "SELECT status, context_json FROM runs WHERE id=?1",
+            [&run_id],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(status, "done");
+    let pr_url = parse_payload(&ctx)["pr_url"].as_str().unwrap().to_string();
+    assert_eq!(forge::pr_number_from_url(&pr_url), Some(1));
+    let open = local.list_open_prs(&repo_s).unwrap();
+    assert_eq!(open.len(), 1);
+    assert_eq!(
+        (open[0].head.as_str(), open[0].base.as_str()),
+        (format!("clawdorio/{run_id}").as_str(), "main")
+    );
+
+    let Json(cards) = api_pr_feed(
+        axum::extract::State(state.clone()),
+        axum::extract::Query(PrFeedQuery {
+            base_id: Some(base.id.clone()),
+            limit: None,
+        }),
+    )
+    .await
+    .unwrap();
+    let card = cards.iter().find(|c| c.run_id == run_id).unwrap();
+    assert_eq!(card.changed_files.source, "local");
+    assert_eq!(card.changed_files.total_files, 1);
+    let Json(files) = api_pr_feed_files(
+        axum::extract::State(state.clone()),
+        axum::extract::Path(run_id.clone()),
+        axum::extract::Query(PrFilesQuery {
+            max_patch_chars: None,
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(files.len(), 1);
+    assert!(files[0].snippet.contains("+feature"));
+
+    // Upstream moves on; the auto-rebase sweep rebases the PR branch onto it.
+    let git = |args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {args:?}: {out:?}");
+    };
+    git(&["worktree", "remove", "--force", &worktree]);
+    std::fs::write(repo.join("UPSTREAM.md"), "upstream\n").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-m", "upstream"]);
+    git(&["push", "origin", "main"]);
+    let before = local.pr_status(&repo_s, 1).unwrap();
+    assert!(queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
+    drain(engine.clone(), reg).join().unwrap();
+    let after = local.pr_status(&repo_s, 1).unwrap();
+    assert_ne!(after.head_sha, before.head_sha);
+    assert_eq!(after.mergeable, Some(true));
+
+    let merged = local.merge_pr(&repo_s, 1).unwrap();
+    assert_eq!(merged.state, PrState::Merged);
+    assert!(local.list_open_prs(&repo_s).unwrap().is_empty());
+    assert_eq!(mark_runs_pr_merged(&engine, &pr_url).unwrap(), 1);
+}
+
+#[tokio::test]
+async fn review_and_ci_webhooks_become_run_feedback_and_follow_ups() {
+    let engine = temp_engine();
+    seed_run(&engine, "r-fb", "e1", "done");
+    seed_step(&engine, "r-fb-0", "r-fb", "implement", 0, "done");
+    let pr_url = "https://github.com/acme/demo/pull/5";
+    let worktree = std::env::temp_dir().join(format!(
+        "clawdorio-fb-wt-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    std::fs::create_dir_all(&worktree).unwrap();
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE runs SET context_json=?1 WHERE id='r-fb'",
+        [serde_json::json!({
+            "pr_url": pr_url,
+            "branch": "clawdorio/r-fb",
+            "worktree_path": worktree,
+        })
+        .to_string()],
+    )
+    .unwrap();
+    // Same shape, but its worktree has been pruned since the run finished.
+    seed_run(&engine, "r-gone", "e1", "done");
+    conn.execute(
+        "UPDATE runs SET context_json=?1 WHERE id='r-gone'",
+        [serde_json::json!({
+            "pr_url": "https://github.com/acme/demo/pull/6",
+            "branch": "clawdorio/r-gone",
+            "worktree_path": worktree.join("pruned"),
+        })
+        .to_string()],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE steps SET agent_id='codex/feature-dev/developer' WHERE id='r-fb-0'",
+        [],
+    )
+    .unwrap();
+
+    let mut cfg = config::ServerConfig::default();
+    cfg.feedback.review_followup = true;
+    cfg.github.allow_unsigned = true;
+    let state = Arc::new(AppState {
+        config: Arc::new(cfg),
+        ..AppState::new(engine.clone())
+    });
+    let send = |event: &str, delivery: &str, body: serde_json::Value| {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-github-event", HeaderValue::from_str(event).unwrap());
+        headers.insert(
+            "x-github-delivery",
+            HeaderValue::from_str(delivery).unwrap(),
+        );
+        github::api_github_webhook(
+            axum::extract::State(state.clone()),
+            headers,
+            axum::body::Bytes::from(body.to_string()),
+        )
+    };
+    let pr = serde_json::json!({ "html_url": pr_url, "head": { "ref": "clawdorio/r-fb" } });
+    let review = serde_json::json!({
+        "action": "submitted",
+        "pull_request": pr,
+        "review": {
+            "id": 11,
+            "state": "CHANGES_REQUESTED",
+            "body": "Please handle the empty list.",
+            "user": { "login": "alice", "type": "User" },
+        },
+    });
+
+    let Json(out) = send("pull_request_review", "f1", review.clone())
+        .await
+        .unwrap();
+    assert_eq!(out["status"], "processed");
+    let followups: Vec<(String, String, i64)> = conn
+        .prepare("SELECT step_id, agent_id, step_index FROM steps WHERE run_id='r-fb' AND step_index > 0 ORDER BY step_index")
+        .unwrap()
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        followups,
+        vec![
+            (
+                "address_review".to_string(),
+                "codex/feature-dev/developer".to_string(),
+                1
+            ),
+            ("pr_update".to_string(), "internal/pr".to_string(), 2),
+        ]
+    );
+    let run_status: String = conn
+        .query_row("SELECT status FROM runs WHERE id='r-fb'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(run_status, "queued");
+
+    // Redelivered under a new id: stored once. Bot comments are ignored.
+    let _ = send("pull_request_review", "f2", review).await.unwrap();
+    let Json(bot) = send(
+        "pull_request_review_comment",
+        "f3",
+        serde_json::json!({
+            "action": "created",
+            "pull_request": pr,
+            "comment": { "id": 12, "body": "lint", "user": { "login": "ci", "type": "Bot" } },
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(bot["status"], "ignored");
+    // A conversation comment matches by pr_url and joins the queued follow-up.
+    let _ = send(
+        "issue_comment",
+        "f4",
+        serde_json::json!({
+            "action": "created",
+            "issue": { "html_url": pr_url, "pull_request": {} },
+            "comment": { "id": 13, "body": "Also update the docs.", "user": { "login": "bob" } },
+        }),
+    )
+    .await
+    .unwrap();
+    // CI results are stored; with ci_followup off they queue nothing.
+    let _ = send(
+        "check_run",
+        "f5",
+        serde_json::json!({
+            "action": "completed",
+            "check_run": {
+                "id": 14,
+                "name": "test",
+                "conclusion": "failure",
+                "output": { "title": "2 tests failed" },
+                "pull_requests": [{ "head": { "ref": "clawdorio/r-fb" } }],
+            },
+        }),
+    )
+    .await
+    .unwrap();
+
+    let Json(fb) = feedback::api_run_feedback(
+        axum::extract::State(state.clone()),
+        axum::extract::Path("r-fb".to_string()),
+    )
+    .await
+    .unwrap();
+    let kinds: Vec<(&str, &str, bool)> = fb
+        .iter()
+        .map(|f| {
+            (
+                f.source.as_str(),
+                f.kind.as_str(),
+                f.followup_step_id.is_some(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ("review", "pull_request_review", true),
+            ("review", "issue_comment", true),
+            ("ci", "check_run", false),
+        ]
+    );
+    assert_eq!(fb[2].body, "test: failure\n2 tests failed");
+    let step_count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM steps WHERE run_id='r-fb'", [], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(step_count, 3);
+
+    // Feedback on a run whose worktree is gone is stored but queues nothing.
+    let Json(out) = send(
+        "pull_request_review",
+        "f6",
+        serde_json::json!({
+            "action": "submitted",
+            "pull_request": {
+                "html_url": "https://github.com/acme/demo/pull/6",
+                "head": { "ref": "clawdorio/r-gone" },
+            },
+            "review": {
+                "id": 15,
+                "state": "CHANGES_REQUESTED",
+                "body": "Rename this.",
+                "user": { "login": "alice", "type": "User" },
+            },
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(out["status"], "processed");
+    let (gone_status, gone_steps, unrunnable): (String, i64, i64) = conn
+        .query_row(
+            "SELECT status, (SELECT COUNT(*) FROM steps WHERE run_id='r-gone'),
+                    (SELECT COUNT(*) FROM event_log WHERE kind='run.feedback_unrunnable' AND entity_id='r-gone')
+             FROM runs WHERE id='r-gone'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        (gone_status.as_str(), gone_steps, unrunnable),
+        ("done", 0, 1)
+    );
+
+    let step = claim_next_step(&engine, &WorkerLimits::default(), "w-test")
+        .unwrap()
+        .expect("follow-up claimed");
+    assert_eq!(step.step_id, "address_review");
+    let msg = build_step_message(&step, "/tmp/wt", "clawdorio/r-fb", pr_url);
+    assert!(msg.contains("Address the review feedback"));
+    assert!(msg.contains(&format!("PR: {pr_url}")));
+    assert!(msg.contains(
+        "PR FEEDBACK:\n- [pull_request_review changes_requested] @alice: Please handle the empty list."
+    ));
+    assert!(msg.contains("@bob: Also update the docs."));
+}
+
+#[tokio::test]
+async fn merge_queue_rebases_retests_and_merges_approved_runs_in_order() {
+    use forge::{LocalForge, NewPullRequest, PrState};
+
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo_s, "merge_queue_enabled": true }).to_string(),
+        )
+        .unwrap();
+    let local = Arc::new(LocalForge::new(engine.clone()));
+    let state = Arc::new(AppState {
+        forges: Arc::new(ForgeRegistry::with_fallback(local.clone())),
+        ..AppState::new(engine.clone())
+    });
+    let git = |args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {args:?}: {out:?}");
+        String::from_utf8_lossy(&out.stdout).trim().to_string()
+    };
+
+    // Three approved runs: one green, one with red CI, one whose tests fail after rebasing.
+    let runs = [
+        ("r-mq-a", "test -f a.txt && test -f UPSTREAM.md"),
+        ("r-mq-b", "true"),
+        ("r-mq-c", "false"),
+    ];
+    for (run_id, test_cmd) in runs {
+        let branch = format!("clawdorio/{run_id}");
+        git(&["checkout", "-q", "-b", &branch, "main"]);
+        std::fs::write(repo.join(format!("{run_id}.txt")), run_id).unwrap();
+        if run_id == "r-mq-a" {
+            std::fs::write(repo.join("a.txt"), "a\n").unwrap();
+        }
+        git(&["add", "."]);
+        git(&["commit", "-qm", run_id]);
+        git(&["push", "-q", "origin", &branch]);
+        git(&["checkout", "-q", "main"]);
+        let pr = local
+            .create_pr(
+                &repo_s,
+                &NewPullRequest {
+                    head: branch.clone(),
+                    base: "main".to_string(),
+                    title: run_id.to_string(),
+                    body: String::new(),
+                },
+            )
+            .unwrap();
+        seed_run(&engine, run_id, "e1", "done");
+        engine
+            .open()
+            .unwrap()
+            .execute(
+                "UPDATE runs SET context_json=?1 WHERE id=?2",
+                (
+                    serde_json::json!({
+                        "base_id": base.id,
+                        "branch": branch,
+                        "pr_url": pr.url,
+                        "test_cmd": test_cmd,
+                        "outputs": { "review": { "verdict": "approve" } },
+                    })
+                    .to_string(),
+                    run_id,
+                ),
+            )
+            .unwrap();
+    }
+    // CI on r-mq-b: lint recovered, but tests are still red.
+    let conn = engine.open().unwrap();
+    for (ext, check, actionable) in [
+        ("c1", "check_run:lint", 1),
+        ("c2", "check_run:tests", 1),
+        ("c3", "check_run:lint", 0),
+    ] {
+        conn.execute(
+            "INSERT INTO run_feedback (run_id, source, kind, external_id, body, actionable, check_name, created_at_ms)
+             VALUES ('r-mq-b', 'ci', 'check_run', ?1, '', ?2, ?3, 0)",
+            (ext, actionable, check),
+        )
+        .unwrap();
+    }
+
+    std::fs::write(repo.join("UPSTREAM.md"), "upstream\n").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-qm", "upstream"]);
+    git(&["push", "-q", "origin", "main"]);
+
+    let reg = Arc::new(executor::ExecutorRegistry::with_fallback(Arc::new(
+        executor::ScriptedExecutor::new("STATUS: done\n"),
+    )));
+    let cfg = merge_queue::MergeQueueSection {
+        enabled: true,
+        ..Default::default()
+    };
+    assert_eq!(merge_queue::tick(&engine, &cfg).unwrap(), 2);
+    for _ in 0..3 {
+        merge_queue::tick(&engine, &cfg).unwrap();
+        while run_one_step_blocking(&engine, &reg, &state.forges, &state.workers, "w-test").unwrap()
+        {
+        }
+    }
+
+    let Json(view) = merge_queue::api_base_merge_queue(
+        axum::extract::State(state.clone()),
+        axum::extract::Path(base.id.clone()),
+    )
+    .await
+    .unwrap();
+    assert!(view.enabled);
+    let entries: Vec<(&str, &str)> = view
+        .entries
+        .iter()
+        .map(|e| (e.run_id.as_str(), e.status.as_str()))
+        .collect();
+    assert_eq!(entries.len(), 2);
+    assert!(entries.contains(&("r-mq-a", "merged")));
+    assert!(entries.contains(&("r-mq-c", "failed")));
+    let failed = view.entries.iter().find(|e| e.run_id == "r-mq-c").unwrap();
+    assert!(failed.error.as_deref().unwrap().starts_with("tests_failed"));
+
+    // The merged branch was rebased onto upstream before landing on main.
+    assert_eq!(local.pr_status(&repo_s, 1).unwrap().state, PrState::Merged);
+    assert_eq!(local.pr_status(&repo_s, 3).unwrap().state, PrState::Open);
+    assert_eq!(local.comments(&repo_s, 3).unwrap().len(), 1);
+    git(&["fetch", "-q", "origin"]);
+    git(&[
+        "merge-base",
+        "--is-ancestor",
+        "origin/clawdorio/r-mq-a",
+        "origin/main",
+    ]);
+    git(&[
+        "merge-base",
+        "--is-ancestor",
+        "main",
+        "origin/clawdorio/r-mq-a",
+    ]);
+    let ctx: String = conn
+        .query_row("SELECT context_json FROM runs WHERE id='r-mq-a'", [], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(parse_payload(&ctx)["pr_merged"], true);
+}
+
+#[test]
+fn pr_step_keeps_outputs_merged_into_the_run_while_it_ran() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let git = |args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {args:?}: {out:?}");
+    };
+    git(&["checkout", "-q", "-b", "clawdorio/r-pr"]);
+    std::fs::write(repo.join("pr.txt"), "pr\n").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-qm", "pr"]);
+
+    seed_run(&engine, "r-pr", "e1", "running");
+    seed_step(&engine, "r-pr-0", "r-pr", "pr", 0, "queued");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE runs SET context_json=?1 WHERE id='r-pr'",
+        [serde_json::json!({ "worktree_path": repo_s, "branch": "clawdorio/r-pr" }).to_string()],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE steps SET agent_id='internal/pr' WHERE id='r-pr-0'",
+        [],
+    )
+    .unwrap();
+    let step = claim_next_step(&engine, &WorkerLimits::default(), "w-test")
+        .unwrap()
+        .unwrap();
+
+    // A parallel step finishes after the PR step took its snapshot.
+    conn.execute(
+        "UPDATE runs SET context_json=json_set(context_json, '$.test_cmd', 'cargo test') WHERE id='r-pr'",
+        [],
+    )
+    .unwrap();
+    let forges = ForgeRegistry::with_fallback(Arc::new(forge::LocalForge::new(engine.clone())));
+    let url = execute_step_blocking(
+        &engine,
+        &executor::ExecutorRegistry::default(),
+        &forges,
+        &step,
+        &executor::CancelToken::default(),
+    )
+    .unwrap();
+    let ctx: String = conn
+        .query_row("SELECT context_json FROM runs WHERE id='r-pr'", [], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    let ctx = parse_payload(&ctx);
+    assert_eq!(ctx["pr_url"], url.as_str());
+    assert_eq!(ctx["test_cmd"], "cargo test");
+}
+
+#[test]
+fn local_forge_opens_one_pr_per_head_under_concurrent_creates() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let out = std::process::Command::new("git")
+        .args(["push", "-q", "origin", "main:refs/heads/clawdorio/race"])
+        .current_dir(&repo)
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{out:?}");
+
+    let local = Arc::new(forge::LocalForge::new(engine.clone()));
+    let results: Vec<anyhow::Result<forge::PullRequest>> = (0..6)
+        .map(|_| {
+            let (local, repo_s) = (local.clone(), repo_s.clone());
+            std::thread::spawn(move || {
+                local.create_pr(
+                    &repo_s,
+                    &forge::NewPullRequest {
+                        head: "clawdorio/race".to_string(),
+                        base: "main".to_string(),
+                        title: "Race".to_string(),
+                        body: String::new(),
+                    },
+                )
+            })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .collect();
+
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    for err in results.iter().filter_map(|r| r.as_ref().err()) {
+        assert!(err.to_string().starts_with("pr_exists: #1"), "{err}");
+    }
+    assert_eq!(local.list_open_prs(&repo_s).unwrap().len(), 1);
+}
+
+#[test]
+fn pr_step_stops_at_the_push_once_cancelled() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    seed_run(&engine, "r-pc", "e1", "running");
+    seed_step(&engine, "r-pc-0", "r-pc", "pr", 0, "queued");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE runs SET context_json=?1 WHERE id='r-pc'",
+        [serde_json::json!({ "worktree_path": repo_s, "branch": "main" }).to_string()],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE steps SET agent_id='internal/pr' WHERE id='r-pc-0'",
+        [],
+    )
+    .unwrap();
+    let step = claim_next_step(&engine, &WorkerLimits::default(), "w-test")
+        .unwrap()
+        .unwrap();
+
+    let cancel = executor::CancelToken::default();
+    cancel.cancel();
+    let forges = ForgeRegistry::with_fallback(Arc::new(forge::LocalForge::new(engine.clone())));
+    let err = execute_step_blocking(
+        &engine,
+        &executor::ExecutorRegistry::default(),
+        &forges,
+        &step,
+        &cancel,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("cancelled"), "{err}");
+    let prs: i64 = conn
+        .query_row("SELECT COUNT(*) FROM forge_pull_requests", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(prs, 0);
+}
+
+#[test]
+fn merge_queue_waits_for_pending_and_required_checks() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo, "merge_queue_enabled": true }).to_string(),
+        )
+        .unwrap();
+    for run_id in ["r-ci-a", "r-ci-b"] {
+        seed_run(&engine, run_id, "e1", "done");
+        engine
+            .open()
+            .unwrap()
+            .execute(
+                "UPDATE runs SET context_json=?1 WHERE id=?2",
+                (
+                    serde_json::json!({
+                        "base_id": base.id,
+                        "branch": format!("clawdorio/{run_id}"),
+                        "pr_url": format!("https://github.com/acme/demo/pull/{}", run_id.len()),
+                        "outputs": { "review": { "verdict": "approve" } },
+                    })
+                    .to_string(),
+                    run_id,
+                ),
+            )
+            .unwrap();
+    }
+    let cfg = config::ServerConfig::default();
+    let report = |event: &str, payload: serde_json::Value| {
+        let fb = feedback::from_webhook(event, &payload).expect("ci feedback");
+        feedback::ingest(&engine, &cfg.feedback, &fb).unwrap();
+    };
+    let status = |id: i64, state: &str| {
+        serde_json::json!({
+            "id": id,
+            "state": state,
+            "context": "ci/build",
+            "branches": [{ "name": "clawdorio/r-ci-a" }],
+        })
+    };
+    let enqueued = |run_id: &str| -> bool {
+        engine
+            .open()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM merge_queue WHERE run_id=?1",
+                [run_id],
+                |r| r.get::<_, i64>(0),
+            )
+            .unwrap()
+            > 0
+    };
+    let mq = merge_queue::MergeQueueSection {
+        enabled: true,
+        ..Default::default()
+    };
+
+    // r-ci-a's build is still running; r-ci-b has reported nothing yet.
+    report("status", status(1, "pending"));
+    let tests_run = |action: &str, status: &str, conclusion: Option<&str>| {
+        serde_json::json!({
+            "action": action,
+            "check_run": {
+                "id": 7,
+                "name": "tests",
+                "status": status,
+                "conclusion": conclusion,
+                "started_at": "2026-01-01T00:00:00Z",
+                "pull_requests": [{ "head": { "ref": "clawdorio/r-ci-b" } }],
+            },
+        })
+    };
+    report("check_run", tests_run("created", "in_progress", None));
+    assert_eq!(merge_queue::tick(&engine, &mq).unwrap(), 0);
+
+    // The build goes green, but r-ci-a never reports the required tests.
+    report("status", status(2, "success"));
+    let required = merge_queue::MergeQueueSection {
+        required_checks: vec!["tests".to_string()],
+        ..mq.clone()
+    };
+    assert_eq!(merge_queue::tick(&engine, &required).unwrap(), 0);
+    assert_eq!(merge_queue::tick(&engine, &mq).unwrap(), 1);
+    assert!(enqueued("r-ci-a") && !enqueued("r-ci-b"));
+
+    report(
+        "check_run",
+        tests_run("completed", "completed", Some("success")),
+    );
+    assert_eq!(merge_queue::tick(&engine, &required).unwrap(), 1);
+    assert!(enqueued("r-ci-b"));
+}
+
+#[test]
+fn merge_queue_approval_survives_review_prompts_from_before_verdict() {
+    // A database seeded before review prompts asked for `VERDICT:`.
+    let engine = temp_engine();
+    workflow::seed_builtin_workflows(&engine).unwrap();
+    let current = engine.get_workflow("feature-dev").unwrap().unwrap().source;
+    let old: String = current
+        .lines()
+        .filter(|l| !l.trim_start().starts_with("VERDICT:"))
+        .map(|l| format!("{l}\n"))
+        .collect();
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE workflows SET source=?1, builtin_source=NULL, rev=1 WHERE id='feature-dev'",
+        [&old],
+    )
+    .unwrap();
+
+    // Startup brings the unedited builtin's review prompt up to date.
+    workflow::seed_builtin_workflows(&engine).unwrap();
+    let review = workflow::load_workflow(&engine, "feature-dev")
+        .unwrap()
+        .unwrap()
+        .steps
+        .into_iter()
+        .find(|s| s.id == "review")
+        .unwrap();
+    assert!(review.prompt.unwrap().contains("VERDICT: approve"));
+
+    // Reviews recorded without a VERDICT fall back to the first word of REVIEW.
+    let repo = init_git_repo();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo, "merge_queue_enabled": true }).to_string(),
+        )
+        .unwrap();
+    for (run_id, review) in [
+        ("r-lgtm", "LGTM, tests cover the change"),
+        ("r-changes", "Request changes: no screenshot"),
+    ] {
+        seed_run(&engine, run_id, "e1", "done");
+        conn.execute(
+            "UPDATE runs SET context_json=?1 WHERE id=?2",
+            (
+                serde_json::json!({
+                    "base_id": base.id,
+                    "branch": format!("clawdorio/{run_id}"),
+                    "pr_url": format!("https://example.test/pr/{run_id}"),
+                    "pr_number": 1,
+                    "outputs": { "review": { "status": "done", "review": review } },
+                })
+                .to_string(),
+                run_id,
+            ),
+        )
+        .unwrap();
+    }
+    let cfg = merge_queue::MergeQueueSection {
+        enabled: true,
+        ..Default::default()
+    };
+    assert_eq!(merge_queue::tick(&engine, &cfg).unwrap(), 1);
+    let queued: String = conn
+        .query_row("SELECT run_id FROM merge_queue", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(queued, "r-lgtm");
+}
+
+#[test]
+fn merge_queue_entry_that_outlives_its_timeout_fails_instead_of_requeueing() {
+    use forge::{LocalForge, NewPullRequest};
+
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo_s, "merge_queue_enabled": true }).to_string(),
+        )
+        .unwrap();
+    let local = Arc::new(LocalForge::new(engine.clone()));
+    let forges = ForgeRegistry::with_fallback(local.clone());
+    let git = |args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {args:?}: {out:?}");
+    };
+    let branch = "clawdorio/r-mq-slow";
+    git(&["checkout", "-q", "-b", branch, "main"]);
+    std::fs::write(repo.join("slow.txt"), "slow\n").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-qm", "slow"]);
+    git(&["push", "-q", "origin", branch]);
+    git(&["checkout", "-q", "main"]);
+    let pr = local
+        .create_pr(
+            &repo_s,
+            &NewPullRequest {
+                head: branch.to_string(),
+                base: "main".to_string(),
+                title: "slow".to_string(),
+                body: String::new(),
+            },
+        )
+        .unwrap();
+    seed_run(&engine, "r-mq-slow", "e1", "done");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE runs SET context_json=?1 WHERE id='r-mq-slow'",
+        [serde_json::json!({
+            "base_id": base.id,
+            "branch": branch,
+            "pr_url": pr.url,
+            "test_cmd": "sleep 30",
+            "outputs": { "review": { "verdict": "approve" } },
+        })
+        .to_string()],
+    )
+    .unwrap();
+
+    let reg = executor::ExecutorRegistry::with_fallback(Arc::new(executor::ScriptedExecutor::new(
+        "STATUS: done\n",
+    )));
+    let pool = WorkerPool::default();
+    let cfg = merge_queue::MergeQueueSection {
+        enabled: true,
+        test_timeout_sec: 1,
+        ..Default::default()
+    };
+    assert_eq!(merge_queue::tick(&engine, &cfg).unwrap(), 1);
+    let started = std::time::Instant::now();
+    while run_one_step_blocking(&engine, &reg, &forges, &pool, "w-test").unwrap() {}
+    assert!(started.elapsed() < std::time::Duration::from_secs(20));
+
+    let (status, error): (String, String) = conn
+        .query_row(
+            "SELECT status, error FROM merge_queue WHERE run_id='r-mq-slow'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(status, "failed");
+    assert!(error.starts_with("tests_timed_out"), "{error}");
+
+    // Nothing is waiting, so the next tick does not start another worker run.
+    merge_queue::tick(&engine, &cfg).unwrap();
+    assert!(!run_one_step_blocking(&engine, &reg, &forges, &pool, "w-test").unwrap());
+}
+
+#[test]
+fn merge_queue_entries_left_testing_by_a_dead_worker_are_picked_up_again() {
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo, "merge_queue_enabled": true }).to_string(),
+        )
+        .unwrap();
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "INSERT INTO merge_queue (base_id, run_id, branch, pr_url, pr_number, status, enqueued_at_ms, started_at_ms)
+         VALUES (?1, 'r-mq-lost', 'clawdorio/r-mq-lost', 'local://pr/1', 1, 'testing', 1, 2)",
+        [&base.id],
+    )
+    .unwrap();
+    let entry = || -> String {
+        conn.query_row(
+            "SELECT status FROM merge_queue WHERE run_id='r-mq-lost'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap()
+    };
+    let cfg = merge_queue::MergeQueueSection {
+        enabled: true,
+        ..Default::default()
+    };
+
+    // No worker run is left for the base: the entry waits again and a worker is queued.
+    merge_queue::tick(&engine, &cfg).unwrap();
+    assert_eq!(entry(), "queued");
+
+    // The worker claims it and dies; the reaper's retry resumes the same entry.
+    conn.execute(
+        "UPDATE merge_queue SET status='testing' WHERE run_id='r-mq-lost'",
+        [],
+    )
+    .unwrap();
+    merge_queue::tick(&engine, &cfg).unwrap();
+    assert_eq!(entry(), "testing");
+    let reg = executor::ExecutorRegistry::with_fallback(Arc::new(executor::ScriptedExecutor::new(
+        "STATUS: done\n",
+    )));
+    let forges = ForgeRegistry::with_fallback(Arc::new(forge::LocalForge::new(engine.clone())));
+    assert!(
+        run_one_step_blocking(&engine, &reg, &forges, &WorkerPool::default(), "w-test").unwrap()
+    );
+    // The branch was never pushed, so the resumed entry fails instead of staying stuck.
+    assert_eq!(entry(), "failed");
+}
+
+#[tokio::test]
+async fn auto_rebase_conflict_is_resolved_by_an_agent_in_the_run_worktree() {
+    use forge::{LocalForge, NewPullRequest};
+
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo_s, "auto_rebase_enabled": true }).to_string(),
+        )
+        .unwrap();
+    let local = Arc::new(LocalForge::new(engine.clone()));
+    let forges = Arc::new(ForgeRegistry::with_fallback(local.clone()));
+    let git = |dir: &std::path::Path, args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {args:?}: {out:?}");
+        String::from_utf8_lossy(&out.stdout).trim().to_string()
+    };
+
+    // The run's branch and upstream both rewrite README.md.
+    let branch = "clawdorio/r-cf";
+    let wt = repo.with_extension("wt-r-cf");
+    let wt_s = wt.to_string_lossy().to_string();
+    git(
+        &repo,
+        &["worktree", "add", "-q", "-b", branch, &wt_s, "main"],
+    );
+    std::fs::write(wt.join("README.md"), "feature\n").unwrap();
+    git(&wt, &["commit", "-qam", "feature"]);
+    git(&wt, &["push", "-q", "origin", branch]);
+    let before = git(&wt, &["rev-parse", "HEAD"]);
+    let pr = local
+        .create_pr(
+            &repo_s,
+            &NewPullRequest {
+                head: branch.to_string(),
+                base: "main".to_string(),
+                title: "feature".to_string(),
+                body: String::new(),
+            },
+        )
+        .unwrap();
+    seed_run(&engine, "r-cf", "e1", "done");
+    seed_step(&engine, "r-cf-0", "r-cf", "implement", 0, "done");
+    let conn = engine.open().unwrap();
+    conn.execute(
+        "UPDATE runs SET task='Say feature in the README', context_json=?1 WHERE id='r-cf'",
+        [serde_json::json!({
+            "branch": branch,
+            "worktree_path": wt_s,
+            "pr_url": pr.url,
+            "test_cmd": "grep -q 'feature and upstream' README.md",
+        })
+        .to_string()],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE steps SET agent_id='feature-dev/developer' WHERE id='r-cf-0'",
+        [],
+    )
+    .unwrap();
+    std::fs::write(repo.join("README.md"), "upstream\n").unwrap();
+    git(&repo, &["commit", "-qam", "upstream"]);
+    git(&repo, &["push", "-q", "origin", "main"]);
+
+    let mut reg = executor::ExecutorRegistry::with_fallback(Arc::new(
+        executor::ScriptedExecutor::new("STATUS: done\n"),
+    ));
+    reg.register(
+        "feature-dev/developer",
+        Arc::new(executor::CommandExecutor {
+            program: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "msg=$(cat) && echo \"$msg\" | grep -q '<<<<<<<' && echo \"$msg\" | grep -q 'Say feature' \
+                 && echo 'feature and upstream' > README.md && git add README.md \
+                 && GIT_EDITOR=true git rebase --continue >/dev/null && echo 'STATUS: done'"
+                    .to_string(),
+            ],
+            stdin: Some("{message}".to_string()),
+            ..Default::default()
+        }),
+    );
+    let pool = pool::WorkerPool::default();
+    assert!(queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
+    while run_one_step_blocking(&engine, &reg, &forges, &pool, "w-test").unwrap() {}
+
+    let result: String = conn
+        .query_row(
+            "SELECT payload_json FROM event_log WHERE kind='auto_rebase.result'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    let result = parse_payload(&result);
+    assert_eq!(result["resolving"][0]["branch"], branch);
+    assert_eq!(result["failed"].as_array().unwrap().len(), 0);
+    let steps: Vec<(String, String)> = {
+        let mut stmt = conn
+            .prepare("SELECT step_id, status FROM steps WHERE run_id='r-cf' ORDER BY step_index")
+            .unwrap();
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    };
+    let steps: Vec<(&str, &str)> = steps
+        .iter()
+        .map(|(a, b)| (a.as_str(), b.as_str()))
+        .collect();
+    assert_eq!(
+        steps,
+        vec![
+            ("implement", "done"),
+            ("resolve_conflicts", "done"),
+            ("rebase_push", "done")
+        ]
+    );
+    let status: String = conn
+        .query_row("SELECT status FROM runs WHERE id='r-cf'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(status, "done");
+
+    // The resolved branch sits on top of upstream on the remote, and the PR is mergeable again.
+    git(&repo, &["fetch", "-q", "origin"]);
+    git(
+        &repo,
+        &[
+            "merge-base",
+            "--is-ancestor",
+            "origin/main",
+            &format!("origin/{branch}"),
+        ],
+    );
+    assert_ne!(
+        git(&repo, &["rev-parse", &format!("origin/{branch}")]),
+        before
+    );
+    assert_eq!(local.pr_status(&repo_s, 1).unwrap().mergeable, Some(true));
+}
+
+#[tokio::test]
+async fn auto_rebase_in_run_worktree_keeps_commits_pushed_from_elsewhere() {
+    use forge::{LocalForge, NewPullRequest};
+
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo_s, "auto_rebase_enabled": true }).to_string(),
+        )
+        .unwrap();
+    let local = Arc::new(LocalForge::new(engine.clone()));
+    let forges = Arc::new(ForgeRegistry::with_fallback(local.clone()));
+    let git = |dir: &std::path::Path, args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {args:?}: {out:?}");
+        String::from_utf8_lossy(&out.stdout).trim().to_string()
+    };
+
+    let branch = "clawdorio/r-ff";
+    let wt = repo.with_extension("wt-r-ff");
+    let wt_s = wt.to_string_lossy().to_string();
+    git(
+        &repo,
+        &["worktree", "add", "-q", "-b", branch, &wt_s, "main"],
+    );
+    std::fs::write(wt.join("feature.txt"), "feature\n").unwrap();
+    git(&wt, &["add", "feature.txt"]);
+    git(&wt, &["commit", "-qm", "feature"]);
+    git(&wt, &["push", "-q", "origin", branch]);
+    local
+        .create_pr(
+            &repo_s,
+            &NewPullRequest {
+                head: branch.to_string(),
+                base: "main".to_string(),
+                title: "feature".to_string(),
+                body: String::new(),
+            },
+        )
+        .unwrap();
+    seed_run(&engine, "r-ff", "e1", "done");
+    engine
+        .open()
+        .unwrap()
+        .execute(
+            "UPDATE runs SET context_json=?1 WHERE id='r-ff'",
+            [serde_json::json!({ "branch": branch, "worktree_path": wt_s }).to_string()],
+        )
+        .unwrap();
+
+    // A teammate pushes a fixup to the PR branch that the run worktree has never seen.
+    let mate = repo.with_extension("wt-mate");
+    let mate_s = mate.to_string_lossy().to_string();
+    git(
+        &repo,
+        &["worktree", "add", "-q", "--detach", &mate_s, branch],
+    );
+    std::fs::write(mate.join("fixup.txt"), "fixup\n").unwrap();
+    git(&mate, &["add", "fixup.txt"]);
+    git(&mate, &["commit", "-qm", "fixup"]);
+    git(&mate, &["push", "-q", "origin", &format!("HEAD:{branch}")]);
+    std::fs::write(repo.join("UPSTREAM.md"), "upstream\n").unwrap();
+    git(&repo, &["add", "UPSTREAM.md"]);
+    git(&repo, &["commit", "-qm", "upstream"]);
+    git(&repo, &["push", "-q", "origin", "main"]);
+
+    let reg = executor::ExecutorRegistry::with_fallback(Arc::new(executor::ScriptedExecutor::new(
+        "STATUS: done\n",
+    )));
+    let pool = pool::WorkerPool::default();
+    let conn = engine.open().unwrap();
+    let sweep = || {
+        let ent = find_base_entity(&engine, &base.id).unwrap();
+        let mut payload = parse_payload(&ent.payload_json);
+        payload["auto_rebase_last_enqueued_ms"] = serde_json::json!(0);
+        engine
+            .update_entity_payload(&base.id, &payload.to_string())
+            .unwrap();
+        assert!(queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
+        while run_one_step_blocking(&engine, &reg, &forges, &pool, "w-test").unwrap() {}
+        let result: String = conn
+            .query_row(
+                "SELECT payload_json FROM event_log WHERE kind='auto_rebase.result'
+                 ORDER BY seq DESC LIMIT 1",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        parse_payload(&result)
+    };
+
+    // The worktree catches up with the fixup before rebasing, so the push keeps it.
+    let result = sweep();
+    assert_eq!(result["rebased"][0], branch);
+    git(&repo, &["fetch", "-q", "origin"]);
+    let files = git(
+        &repo,
+        &["ls-tree", "--name-only", &format!("origin/{branch}")],
+    );
+    for f in ["feature.txt", "fixup.txt", "UPSTREAM.md"] {
+        assert!(files.lines().any(|l| l == f), "{f} missing: {files}");
+    }
+
+    // A worktree with its own unpushed commit has diverged: nothing is rebased or pushed.
+    git(&mate, &["fetch", "-q", "origin"]);
+    git(
+        &mate,
+        &["checkout", "-q", "--detach", &format!("origin/{branch}")],
+    );
+    std::fs::write(mate.join("second.txt"), "second\n").unwrap();
+    git(&mate, &["add", "second.txt"]);
+    git(&mate, &["commit", "-qm", "second fixup"]);
+    git(&mate, &["push", "-q", "origin", &format!("HEAD:{branch}")]);
+    std::fs::write(wt.join("local.txt"), "local\n").unwrap();
+    git(&wt, &["add", "local.txt"]);
+    git(&wt, &["commit", "-qm", "local only"]);
+    let remote_before = git(&mate, &["rev-parse", "HEAD"]);
+    let result = sweep();
+    assert!(result["failed"][0].as_str().unwrap().contains("diverged"));
+    git(&repo, &["fetch", "-q", "origin"]);
+    assert_eq!(
+        git(&repo, &["rev-parse", &format!("origin/{branch}")]),
+        remote_before
+    );
+}
+
+#[tokio::test]
+async fn auto_rebase_sweep_leaves_the_base_checkout_alone_and_refuses_local_changes() {
+    use forge::{LocalForge, NewPullRequest};
+
+    let engine = temp_engine();
+    let repo = init_git_repo();
+    let repo_s = repo.to_string_lossy().to_string();
+    let base = engine
+        .create_entity_with_payload(
+            "base",
+            0,
+            0,
+            9,
+            9,
+            &serde_json::json!({ "repo_path": repo_s, "auto_rebase_enabled": true }).to_string(),
+        )
+        .unwrap();
+    let local = Arc::new(LocalForge::new(engine.clone()));
+    let forges = Arc::new(ForgeRegistry::with_fallback(local.clone()));
+    let git = |args: &[&str]| {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {args:?}: {out:?}");
+        String::from_utf8_lossy(&out.stdout).trim().to_string()
+    };
+
+    let branch = "clawdorio/r-sweep";
+    git(&["checkout", "-q", "-b", branch]);
+    std::fs::write(repo.join("feature.txt"), "feature\n").unwrap();
+    git(&["add", "feature.txt"]);
+    git(&["commit", "-qm", "feature"]);
+    git(&["push", "-q", "origin", branch]);
+    local
+        .create_pr(
+            &repo_s,
+            &NewPullRequest {
+                head: branch.to_string(),
+                base: "main".to_string(),
+                title: "feature".to_string(),
+                body: String::new(),
+            },
+        )
+        .unwrap();
+    git(&["checkout", "-q", "main"]);
+    std::fs::write(repo.join("UPSTREAM.md"), "upstream\n").unwrap();
+    git(&["add", "UPSTREAM.md"]);
+    git(&["commit", "-qm", "upstream"]);
+    git(&["push", "-q", "origin", "main"]);
+    // The developer is on their own branch with an untracked scratch file.
+    git(&["checkout", "-q", "-b", "wip"]);
+    std::fs::write(repo.join("notes.txt"), "todo\n").unwrap();
+    let local_branch_before = git(&["rev-parse", branch]);
+
+    let reg = executor::ExecutorRegistry::with_fallback(Arc::new(executor::ScriptedExecutor::new(
+        "STATUS: done\n",
+    )));
+    let pool = pool::WorkerPool::default();
+    let sweep = || {
+        let ent = find_base_entity(&engine, &base.id).unwrap();
+        let mut payload = parse_payload(&ent.payload_json);
+        payload["auto_rebase_last_enqueued_ms"] = serde_json::json!(0);
+        engine
+            .update_entity_payload(&base.id, &payload.to_string())
+            .unwrap();
+        assert!(queue_base_rebase_sweep(&engine, &base.id, "test", None).unwrap());
+        while run_one_step_blocking(&engine, &reg, &forges, &pool, "w-test").unwrap() {}
+    };
+    sweep();
+
+    assert_eq!(git(&["symbolic-ref", "--short", "HEAD"]), "wip");
+    assert_eq!(git(&["status", "--porcelain"]), "?? notes.txt");
+    assert_eq!(git(&["rev-parse", branch]), local_branch_before);
+    git(&["fetch", "-q", "origin"]);
+    git(&[
+        "merge-base",
+        "--is-ancestor",
+        "origin/main",
+        &format!("origin/{branch}"),
+    ]);
+    assert!(!git(&["worktree", "list"]).contains("clawdorio-rebase-"));
+    let conn = engine.open().unwrap();
+    let result: String = conn
+        .query_row(
+            "SELECT payload_json FROM event_log WHERE kind='auto_rebase.result'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(parse_payload(&result)["rebased"][0], branch);
+
+    // An uncommitted edit to a tracked file stops the next sweep before it does anything.
+    std::fs::write(repo.join("README.md"), "editing\n").unwrap();
+    let head_before = git(&["rev-parse", &format!("origin/{branch}")]);
+    sweep();
+    let (status, output): (String, String) = conn
+        .query_row(
+            "SELECT status, COALESCE(output_text, '') FROM steps
+             WHERE step_id='auto-rebase' ORDER BY created_at DESC, id DESC LIMIT 1",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(status, "failed", "{output}");
+    let refused: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM event_log WHERE kind='auto_rebase.refused'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(refused, 1);
+    assert_eq!(
+        std::fs::read_to_string(repo.join("README.md")).unwrap(),
+        "editing\n"
+    );
+    git(&["fetch", "-q", "origin"]);
+    assert_eq!(
+        git(&["rev-parse", &format!("origin/{branch}")]),
+        head_before
+    );
+}
+
+#[tokio::test]
+async fn export_round_trips_a_world_and_import_remaps_taken_ids() {
+    use axum::response::IntoResponse;
+
+    let src = temp_engine();
+    let base = src
+        .create_entity_with_payload("base", 0, 0, 9, 9, r#"{"repo_path":"/tmp/x"}"#)
+        .unwrap();
+    let feature = src.create_entity("feature", 11, 0, 3, 4).unwrap();
+    src.create_belt(&base.id, &feature.id, "link", "[]")
+        .unwrap();
+    src.upsert_quest(None, "Ship it", "human", "open", "")
+        .unwrap();
+    src.upsert_workflow("custom", "Custom", "yaml", "id: custom\n", false)
+        .unwrap();
+    seed_run(&src, "r-x", &feature.id, "done");
+    seed_step(&src, "r-x-0", "r-x", "plan", 0, "done");
+    seed_step(&src, "r-x-1", "r-x", "implement", 1, "done");
+    seed_run(&src, "r-y", &feature.id, "running");
+    seed_step(&src, "r-y-0", "r-y", "plan", 0, "running");
+    let conn = src.open().unwrap();
+    conn.execute_batch(&format!(
+        r#"
+UPDATE steps SET worker_id='w-1', heartbeat_at_ms=1, started_at_ms=1 WHERE id='r-y-0';
+UPDATE runs SET workflow_id='custom', context_json='{{"base_id":"{base}","branch":"clawdorio/r-x"}}' WHERE id='r-x';
+UPDATE steps SET depends_on_json='["r-x-0"]' WHERE id='r-x-1';
+INSERT INTO step_outputs (step_row_id, run_id, step_id, status, outputs_json, created_at_ms)
+  VALUES ('r-x-1', 'r-x', 'implement', 'done', '{{}}', 1);
+INSERT INTO run_feedback (run_id, source, kind, external_id, body, actionable, created_at_ms)
+  VALUES ('r-x', 'review', 'comment', 'c1', 'nit', 0, 1);
+INSERT INTO library_artifacts (id, agent_id, base_id, run_id, content_hash, created_at_ms)
+  VALUES ('lib-1-1', 'a', '{base}', 'r-x', 'h', 1);
+INSERT INTO skill_graphs (id, pack_name, title, source_root, index_path, created_at_ms, updated_at_ms)
+  VALUES ('sg-1-1', 'pack', 'Pack', '/s', '/s/index.md', 1, 1);
+INSERT INTO skill_nodes (id, graph_id, title, slug, file_path, created_at_ms, updated_at_ms)
+  VALUES ('sn-1-1', 'sg-1-1', 'A', 'a', 'a.md', 1, 1), ('sn-1-2', 'sg-1-1', 'B', 'b', 'b.md', 1, 1);
+INSERT INTO skill_edges (graph_id, from_node_id, to_node_id) VALUES ('sg-1-1', 'sn-1-1', 'sn-1-2');
+INSERT INTO skill_assignments (id, graph_id, node_id, scope_kind, scope_ref, created_at_ms, updated_at_ms)
+  VALUES ('sa-1-1', 'sg-1-1', 'sn-1-1', 'base', '{base}', 1, 1);
+"#,
+        base = base.id
+    ))
+    .unwrap();
+    // Machine-local state stays behind.
+    auth::create_token(&src, "t", auth::Scope::Admin).unwrap();
+
+    let res = backup::api_export(axum::extract::State(Arc::new(AppState::new(src.clone()))))
+        .await
+        .unwrap()
+        .into_response();
+    assert!(res.headers()["content-disposition"]
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let archive: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(archive["format"], backup::FORMAT);
+    assert!(archive["tables"].get("api_tokens").is_none());
+
+    // Into an empty database: ids are kept.
+    let dst = temp_engine();
+    let report = backup::import(&dst, &archive).unwrap();
+    assert_eq!(report.remapped, 0);
+    assert_eq!(report.tables["entities"], 2);
+    assert_eq!(report.tables["skill_edges"], 1);
+    // Unfinished runs arrive paused, with nothing claimed by the exporting machine's workers.
+    assert_eq!(report.runs_paused, 1);
+    let (status, step_status, claimed): (String, String, i64) = dst
+        .open()
+        .unwrap()
+        .query_row(
+            "SELECT runs.status, steps.status,
+                    (steps.worker_id IS NOT NULL) + (steps.heartbeat_at_ms IS NOT NULL)
+             FROM runs JOIN steps ON steps.run_id=runs.id WHERE runs.id='r-y'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        (status.as_str(), step_status.as_str(), claimed),
+        ("paused", "queued", 0)
+    );
+    let status: String = dst
+        .open()
+        .unwrap()
+        .query_row("SELECT status FROM runs WHERE id='r-x'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(status, "done");
+    let ctx: String = dst
+        .open()
+        .unwrap()
+        .query_row("SELECT context_json FROM runs WHERE id='r-x'", [], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(parse_payload(&ctx)["base_id"], base.id.as_str());
+
+    // Again into the same database: every taken id is replaced and references follow.
+    let report = backup::import(&dst, &archive).unwrap();
+    assert_eq!(report.workflows_reused, 1);
+    assert!(report.remapped >= 12, "{report:?}");
+    let conn = dst.open().unwrap();
+    let (run_id, entity_id, ctx): (String, String, String) = conn
+        .query_row(
+            "SELECT id, entity_id, context_json FROM runs
+             WHERE id NOT IN ('r-x', 'r-y') AND workflow_id='custom'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap();
+    let new_base = parse_payload(&ctx)["base_id"].as_str().unwrap().to_string();
+    assert_ne!(new_base, base.id);
+    assert_ne!(entity_id, feature.id);
+    let kinds: Vec<String> = {
+        let mut stmt = conn
+            .prepare("SELECT kind FROM entities WHERE id IN (?1, ?2) ORDER BY kind")
+            .unwrap();
+        stmt.query_map([&new_base, &entity_id], |r| r.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    };
+    assert_eq!(kinds, vec!["base", "feature"]);
+    let belts: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM belts WHERE a_id=?1 AND b_id=?2",
+            [&new_base, &entity_id],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(belts, 1);
+    let (first, deps): (String, String) = conn
+        .query_row(
+            "SELECT (SELECT id FROM steps WHERE run_id=?1 AND step_index=0), depends_on_json
+             FROM steps WHERE run_id=?1 AND step_index=1",
+            [&run_id],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(deps, serde_json::json!([first]).to_string());
+    let linked: i64 = conn
+        .query_row(
+            "SELECT (SELECT COUNT(*) FROM step_outputs WHERE run_id=?1)
+                  + (SELECT COUNT(*) FROM run_feedback WHERE run_id=?1)
+                  + (SELECT COUNT(*) FROM library_artifacts WHERE run_id=?1 AND base_id=?2)
+                  + (SELECT COUNT(*) FROM skill_assignments WHERE scope_ref=?2)",
+            [&run_id, &new_base],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(linked, 4);
+    let workflow: String = conn
+        .query_row("SELECT workflow_id FROM runs WHERE id=?1", [&run_id], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(workflow, "custom");
+
+    // Archives from a newer schema are refused before anything is written.
+    let mut newer = archive.clone();
+    newer["schema_version"] = serde_json::json!(9999);
+    let err = backup::import(&dst, &newer).unwrap_err().to_string();
+    assert!(err.starts_with("archive_schema_too_new"), "{err}");
+    let runs: i64 = conn
+        .query_row("SELECT COUNT(*) FROM runs", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(runs, 4);
+}
+
+#[test]
+fn migrations_upgrade_legacy_dbs_once_and_refuse_newer_schemas() {
+    let p = std::env::temp_dir().join(format!(
+        "clawdorio-migrate-test-{}.db",
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    let engine = Engine::new(&p);
+
+    // Dry run against a missing file lists every migration and creates nothing.
+    let status = engine.migration_status().unwrap();
+    assert_eq!(status.current, 0);
+    assert_eq!(
+        status.pending.len() as i64,
+        clawdorio_engine::SCHEMA_VERSION
+    );
+    assert!(!p.exists());
+
+    // A pre-framework DB: `user_version` 1, no history table, columns from later changes missing.
+    {
+        let conn = rusqlite::Connection::open(&p).unwrap();
+        conn.execute_batch(
+            r#"
+CREATE TABLE entities (id TEXT PRIMARY KEY, kind TEXT NOT NULL, x INTEGER NOT NULL, y INTEGER NOT NULL,
+  payload_json TEXT NOT NULL DEFAULT '{}', created_at_ms INTEGER NOT NULL, updated_at_ms INTEGER NOT NULL);
+CREATE TABLE runs (id TEXT PRIMARY KEY, workflow_id TEXT NOT NULL, task TEXT NOT NULL,
+  status TEXT NOT NULL DEFAULT 'running', context_json TEXT NOT NULL DEFAULT '{}',
+  created_at TEXT NOT NULL, updated_at TEXT NOT NULL);
+INSERT INTO entities (id, kind, x, y, created_at_ms, updated_at_ms) VALUES ('b1', 'base', 0, 0, 1, 1);
+PRAGMA user_version = 1;
+"#,
+        )
+        .unwrap();
+    }
+    let status = engine.migration_status().unwrap();
+    assert_eq!(status.current, 1);
+    assert!(status.applied.is_empty());
+    assert_eq!(
+        status.pending.len() as i64,
+        clawdorio_engine::SCHEMA_VERSION
+    );
+
+    let conn = engine.open().unwrap();
+    let (w, entity_index): (i64, i64) = conn
+        .query_row(
+            "SELECT (SELECT w FROM entities WHERE id='b1'),
+                    (SELECT COUNT(*) FROM sqlite_master WHERE name='idx_runs_entity_id')",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((w, entity_index), (9, 1));
+    let status = engine.migration_status().unwrap();
+    assert_eq!(status.current, clawdorio_engine::SCHEMA_VERSION);
+    assert!(status.pending.is_empty());
+    let versions: Vec<i64> = status.applied.iter().map(|a| a.version).collect();
+    assert_eq!(
+        versions,
+        (1..=clawdorio_engine::SCHEMA_VERSION).collect::<Vec<_>>()
+    );
+
+    // Reopening applies nothing again.
+    drop(conn);
+    let _ = engine.open().unwrap();
+    let conn = rusqlite::Connection::open(&p).unwrap();
+    let rows: i64 = conn
+        .query_row("SELECT COUNT(*) FROM schema_migrations", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(rows, clawdorio_engine::SCHEMA_VERSION);
+
+    // A DB written by a newer build is refused rather than opened.
+    conn.execute(
+        "INSERT INTO schema_migrations (version, name, applied_at_ms) VALUES (?1, 'from_the_future', 0)",
+        [clawdorio_engine::SCHEMA_VERSION + 1],
+    )
+    .unwrap();
+    conn.pragma_update(None, "user_version", clawdorio_engine::SCHEMA_VERSION + 1)
+        .unwrap();
+    drop(conn);
+    let err = engine.open().unwrap_err().to_string();
+    assert!(err.starts_with("db_schema_newer"), "{err}");
+    let err = engine.migration_status().unwrap_err().to_string();
+    assert!(err.starts_with("db_schema_newer"), "{err}");
 }
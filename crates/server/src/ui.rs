@@ -0,0 +1,159 @@
+//! Server-driven UI patches.
+//!
+//! Engine events from `event_log` are translated into `clawdorio_protocol::UiUpdate` messages
+//! aimed at the dashboard panels, so thin clients (Tauri, mobile, terminal) can apply patches
+//! instead of re-deriving state from `/api/state`.
+
+use super::{
+    internal_error, load_run_steps, resolve_stream_cursor, AppState, EventStreamQuery, StreamEvent,
+};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use clawdorio_engine::Engine;
+use clawdorio_protocol::{targets, Patch, Swap, UiUpdate};
+use futures_util::{Stream, StreamExt};
+use std::convert::Infallible;
+use std::sync::Arc;
+
+pub(crate) async fn api_ui_stream(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: HeaderMap,
+    axum::extract::Query(q): axum::extract::Query<EventStreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (axum::http::StatusCode, String)> {
+    let cursor = resolve_stream_cursor(&state.engine, &headers, q.since_seq)
+        .map_err(internal_error("resolve_stream_cursor"))?;
+    let engine = state.engine.clone();
+    let stream = super::event_log_stream(state.engine.clone(), cursor)
+        .then(move |ev| {
+            let engine = engine.clone();
+            async move {
+                let seq = ev.seq;
+                let update = tokio::task::spawn_blocking(move || ui_update_for_event(&engine, &ev))
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .flatten();
+                (seq, update)
+            }
+        })
+        .filter_map(|(seq, update)| async move {
+            let update = update?;
+            Some(Ok(Event::default()
+                .id(seq.to_string())
+                .event(update.event.clone())
+                .json_data(&update)
+                .unwrap_or_else(|_| {
+                    Event::default().id(seq.to_string()).data("{}")
+                })))
+        });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Maps one `event_log` row to the panel patches a client needs. Rows that have no visible
+/// effect (audit/bookkeeping kinds) map to `None`.
+pub(crate) fn ui_update_for_event(
+    engine: &Engine,
+    ev: &StreamEvent,
+) -> anyhow::Result<Option<UiUpdate>> {
+    let kind = ev.kind.as_str();
+    let mut patches = vec![];
+    let event = if kind.starts_with("entity.") {
+        let id = ev.entity_id.clone().unwrap_or_default();
+        let payload = if kind == "entity.deleted" {
+            serde_json::json!({ "removed": id })
+        } else {
+            match engine.get_entity(&id)? {
+                Some(ent) => serde_json::json!({ "entity": ent }),
+                None => serde_json::json!({ "removed": id }),
+            }
+        };
+        patches.push(Patch {
+            target: targets::PANEL_LEFT.to_string(),
+            swap: Swap::Merge,
+            html: None,
+            payload: Some(payload),
+            trigger: Some(kind.to_string()),
+        });
+        kind.to_string()
+    } else if kind.starts_with("step.") || kind.starts_with("run.") {
+        let Some(run_id) = ev
+            .payload
+            .get("run_id")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+        else {
+            return Ok(None);
+        };
+        patches.push(Patch {
+            target: targets::PANEL_RIGHT.to_string(),
+            swap: Swap::Replace,
+            html: None,
+            payload: Some(run_panel_payload(engine, &run_id)?),
+            trigger: Some(kind.to_string()),
+        });
+        patches.push(status_bar_patch(engine, ev.seq)?);
+        kind.to_string()
+    } else if kind == "library.artifact.generated" {
+        patches.push(Patch {
+            target: targets::PANEL_RIGHT.to_string(),
+            swap: Swap::Merge,
+            html: None,
+            payload: Some(serde_json::json!({ "library_artifact": ev.payload })),
+            trigger: Some(kind.to_string()),
+        });
+        kind.to_string()
+    } else {
+        return Ok(None);
+    };
+
+    let mut update = UiUpdate::new(event, patches);
+    update.payload = Some(serde_json::json!({
+        "seq": ev.seq,
+        "ts_ms": ev.ts_ms,
+        "kind": ev.kind,
+        "entity_id": ev.entity_id,
+    }));
+    Ok(Some(update))
+}
+
+/// Full run panel content (run header + every step), so clients can swap it in wholesale.
+fn run_panel_payload(engine: &Engine, run_id: &str) -> anyhow::Result<serde_json::Value> {
+    let conn = engine.open()?;
+    let run: Option<(String, String, String, Option<String>, String)> = conn
+        .query_row(
+            "SELECT status, workflow_id, task, entity_id, updated_at FROM runs WHERE id=?1",
+            [run_id],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+        )
+        .ok();
+    let Some((status, workflow_id, task, entity_id, updated_at)) = run else {
+        return Ok(
+            serde_json::json!({ "run": { "id": run_id, "status": "missing" }, "steps": [] }),
+        );
+    };
+    let steps = load_run_steps(&conn, run_id)?;
+    Ok(serde_json::json!({
+        "run": {
+            "id": run_id,
+            "status": status,
+            "workflow_id": workflow_id,
+            "task": task,
+            "entity_id": entity_id,
+            "updated_at": updated_at,
+        },
+        "steps": steps,
+    }))
+}
+
+fn status_bar_patch(engine: &Engine, rev: i64) -> anyhow::Result<Patch> {
+    Ok(Patch {
+        target: targets::PANEL_BOTTOM_BAR.to_string(),
+        swap: Swap::Merge,
+        html: None,
+        payload: Some(serde_json::json!({
+            "rev": rev,
+            "working_agents": engine.count_working_agents()?,
+        })),
+        trigger: None,
+    })
+}
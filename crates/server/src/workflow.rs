@@ -0,0 +1,695 @@
+//! Declarative workflows.
+//!
+//! A workflow is a YAML or TOML document listing steps (id, agent, prompt template). Documents
+//! live in the `workflows` table; Feature Forges pick one via `workflow_id` in their payload and
+//! each run snapshots its steps (including the prompt template) into `steps.input_json`, so
+//! editing a workflow never changes runs that are already queued.
+
+use super::retry::RetryPolicy;
+use super::{approval, internal_error, reply, AppState};
+use axum::Json;
+use clawdorio_engine::{Engine, Workflow};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+pub(crate) const DEFAULT_WORKFLOW_ID: &str = "feature-dev";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowDef {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Default step timeout for this workflow (seconds).
+    #[serde(default)]
+    pub timeout_sec: Option<u64>,
+    pub steps: Vec<WorkflowStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowStep {
+    pub id: String,
+    pub agent: String,
+    /// Prompt template; `{task}`, `{repo}`, `{branch}`, `{pr}`, `{story}` and earlier steps'
+    /// `{stories}`, `{build_cmd}` and `{test_cmd}` are substituted at run time.
+    #[serde(default)]
+    pub prompt: Option<String>,
+    /// Overrides the workflow timeout for this step (seconds).
+    #[serde(default)]
+    pub timeout_sec: Option<u64>,
+    /// Repeat this step once per story when an earlier step reports `STORIES_JSON`.
+    /// Consecutive `per_story` steps form one chain per story (e.g. implement -> verify).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub per_story: bool,
+    /// Step ids that must finish first. Omitted means "the previous step"; `[]` makes the step a
+    /// root, so independent steps can run concurrently within a run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depends_on: Option<Vec<String>>,
+    /// Retry policy; without one a failure fails the run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+}
+
+impl WorkflowDef {
+    /// Effective dependencies of each step (indexes into `steps`), resolving the implicit
+    /// "previous step" default. Unknown ids are reported by [`validate_workflow`].
+    pub(crate) fn dependencies(&self) -> Vec<Vec<usize>> {
+        let index: HashMap<&str, usize> = self
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.id.as_str(), i))
+            .collect();
+        self.steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| match &step.depends_on {
+                Some(ids) => ids
+                    .iter()
+                    .filter_map(|id| index.get(id.as_str()).copied())
+                    .collect(),
+                None => i.checked_sub(1).into_iter().collect(),
+            })
+            .collect()
+    }
+
+    /// Steps in dependency order (stable with respect to the document order), or an error
+    /// naming a step on a cycle.
+    pub(crate) fn topo_order(&self) -> anyhow::Result<Vec<usize>> {
+        let deps = self.dependencies();
+        let mut done = vec![false; deps.len()];
+        let mut order = Vec::with_capacity(deps.len());
+        while order.len() < deps.len() {
+            let next = (0..deps.len()).find(|&i| !done[i] && deps[i].iter().all(|&d| done[d]));
+            let Some(i) = next else {
+                // Walk unfinished dependencies until one repeats: that step is on the cycle.
+                let mut cur = (0..deps.len()).find(|&i| !done[i]).unwrap_or(0);
+                let mut seen = HashSet::new();
+                while seen.insert(cur) {
+                    cur = deps[cur].iter().copied().find(|&d| !done[d]).unwrap_or(cur);
+                }
+                anyhow::bail!(
+                    "workflow_invalid: dependency cycle through step {}",
+                    self.steps[cur].id
+                );
+            };
+            done[i] = true;
+            order.push(i);
+        }
+        Ok(order)
+    }
+}
+
+const BUILTIN_FEATURE_DEV: &str = r#"id: feature-dev
+name: Feature development
+description: Plan, implement, verify, test, open a PR and review it.
+steps:
+  - id: plan
+    agent: feature-dev/planner
+    prompt: |
+      TASK:
+      {task}
+
+      REPO:
+      {repo}
+
+      BRANCH:
+      {branch}
+
+      Reply with:
+      STATUS: done
+      STORIES_JSON: [{"id":"s1","title":"...","acceptance":["..."],"tests":["..."]}]
+  - id: setup
+    agent: feature-dev/setup
+    prompt: |
+      Prepare environment.
+
+      TASK:
+      {task}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Instructions:
+      - cd into repo
+      - ensure branch exists and is checked out
+      - run build/test baseline
+
+      Reply with:
+      STATUS: done
+      BUILD_CMD: <cmd>
+      TEST_CMD: <cmd>
+      BASELINE: <status>
+  - id: implement
+    agent: feature-dev/developer
+    per_story: true
+    prompt: |
+      Implement the task.
+
+      TASK:
+      {task}
+
+      STORY:
+      {story}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Requirements:
+      - implement
+      - add tests
+      - run tests
+      - commit
+
+      Reply with:
+      STATUS: done
+      CHANGES: ...
+      TESTS: ...
+  - id: verify
+    agent: feature-dev/verifier
+    per_story: true
+    prompt: |
+      Verify the developer work.
+
+      TASK:
+      {task}
+
+      STORY:
+      {story}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Reply with:
+      STATUS: done
+      NOTES: ...
+  - id: test
+    agent: feature-dev/tester
+    retry:
+      max_attempts: 3
+      rewind_to: implement
+    prompt: |
+      Integration/E2E testing.
+
+      TASK:
+      {task}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Reply with:
+      STATUS: done
+      TEST_RESULTS: ...
+  - id: pr
+    agent: internal/pr
+    retry:
+      max_attempts: 3
+      backoff_sec: 30
+      retry_on: [transient, timeout]
+  - id: review
+    agent: feature-dev/reviewer
+    prompt: |
+      Review the PR.
+
+      TASK:
+      {task}
+
+      PR: {pr}
+
+      Checklist:
+      - Verify code and tests
+      - Verify PR includes at least one screenshot in the PR description
+
+      Reply with:
+      STATUS: done
+      VERDICT: approve | request_changes
+      REVIEW: ...
+"#;
+
+const BUILTIN_BUGFIX: &str = r#"id: bugfix
+name: Bugfix
+description: Reproduce, fix with a regression test, open a PR and review it.
+steps:
+  - id: setup
+    agent: feature-dev/setup
+    prompt: |
+      Prepare environment and reproduce the bug.
+
+      BUG:
+      {task}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Reply with:
+      STATUS: done
+      BUILD_CMD: <cmd>
+      TEST_CMD: <cmd>
+      REPRO: <steps or failing test>
+  - id: implement
+    agent: feature-dev/developer
+    prompt: |
+      Fix the bug with the smallest change that works.
+
+      BUG:
+      {task}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Requirements:
+      - add a regression test that fails before the fix
+      - fix
+      - run tests
+      - commit
+
+      Reply with:
+      STATUS: done
+      CHANGES: ...
+      TESTS: ...
+  - id: test
+    agent: feature-dev/tester
+    retry:
+      max_attempts: 3
+      rewind_to: implement
+    prompt: |
+      Confirm the fix and check for regressions.
+
+      BUG:
+      {task}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Reply with:
+      STATUS: done
+      TEST_RESULTS: ...
+  - id: pr
+    agent: internal/pr
+    retry:
+      max_attempts: 3
+      backoff_sec: 30
+      retry_on: [transient, timeout]
+  - id: review
+    agent: feature-dev/reviewer
+    prompt: |
+      Review the bugfix PR.
+
+      BUG:
+      {task}
+
+      PR: {pr}
+
+      Reply with:
+      STATUS: done
+      VERDICT: approve | request_changes
+      REVIEW: ...
+"#;
+
+const BUILTIN_DOCS_ONLY: &str = r#"id: docs-only
+name: Docs only
+description: Documentation change, PR and review; no build/test steps.
+steps:
+  - id: implement
+    agent: feature-dev/developer
+    prompt: |
+      Update documentation only. Do not change code.
+
+      TASK:
+      {task}
+
+      REPO: {repo}
+      BRANCH: {branch}
+
+      Requirements:
+      - edit docs
+      - commit
+
+      Reply with:
+      STATUS: done
+      CHANGES: ...
+  - id: pr
+    agent: internal/pr
+    retry:
+      max_attempts: 3
+      backoff_sec: 30
+      retry_on: [transient, timeout]
+  - id: review
+    agent: feature-dev/reviewer
+    prompt: |
+      Review the documentation PR for accuracy and clarity.
+
+      TASK:
+      {task}
+
+      PR: {pr}
+
+      Reply with:
+      STATUS: done
+      VERDICT: approve | request_changes
+      REVIEW: ...
+"#;
+
+fn builtin_sources() -> [&'static str; 3] {
+    [BUILTIN_FEATURE_DEV, BUILTIN_BUGFIX, BUILTIN_DOCS_ONLY]
+}
+
+/// Parse and validate a workflow document. `format` is `yaml` or `toml`.
+pub fn parse_workflow(format: &str, source: &str) -> anyhow::Result<WorkflowDef> {
+    let def: WorkflowDef = match format {
+        "yaml" | "yml" => serde_yaml::from_str(source)
+            .map_err(|e| anyhow::anyhow!("workflow_parse_failed: {e}"))?,
+        "toml" => {
+            toml::from_str(source).map_err(|e| anyhow::anyhow!("workflow_parse_failed: {e}"))?
+        }
+        other => anyhow::bail!("workflow_format_unsupported: {other}"),
+    };
+    validate_workflow(&def)?;
+    Ok(def)
+}
+
+fn validate_workflow(def: &WorkflowDef) -> anyhow::Result<()> {
+    if def.id.trim().is_empty()
+        || !def
+            .id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!("workflow_invalid: id must be non-empty [A-Za-z0-9_-]");
+    }
+    if def.timeout_sec == Some(0) {
+        anyhow::bail!("workflow_invalid: timeout_sec must be > 0");
+    }
+    if def.steps.is_empty() {
+        anyhow::bail!("workflow_invalid: at least one step is required");
+    }
+    let mut seen = HashSet::new();
+    for step in &def.steps {
+        if step.id.trim().is_empty() {
+            anyhow::bail!("workflow_invalid: step id is required");
+        }
+        if !seen.insert(step.id.as_str()) {
+            anyhow::bail!("workflow_invalid: duplicate step id {}", step.id);
+        }
+        if step.timeout_sec == Some(0) {
+            anyhow::bail!("workflow_invalid: step {} timeout_sec must be > 0", step.id);
+        }
+        if step.agent.trim().is_empty() {
+            anyhow::bail!("workflow_invalid: step {} has no agent", step.id);
+        }
+        if step.per_story && step.agent.starts_with("internal/") {
+            anyhow::bail!(
+                "workflow_invalid: step {} is per_story but uses internal agent {}",
+                step.id,
+                step.agent
+            );
+        }
+        for dep in step.depends_on.iter().flatten() {
+            if dep == &step.id {
+                anyhow::bail!("workflow_invalid: step {} depends on itself", step.id);
+            }
+            if !def.steps.iter().any(|s| &s.id == dep) {
+                anyhow::bail!(
+                    "workflow_invalid: step {} depends on unknown step {dep}",
+                    step.id
+                );
+            }
+        }
+        if step.agent.starts_with("internal/")
+            && step.agent != "internal/pr"
+            && step.agent != approval::APPROVAL_AGENT
+        {
+            anyhow::bail!(
+                "workflow_invalid: step {} uses unknown internal agent {}",
+                step.id,
+                step.agent
+            );
+        }
+    }
+    def.topo_order()?;
+    let deps = def.dependencies();
+    for (i, step) in def.steps.iter().enumerate() {
+        let Some(retry) = &step.retry else {
+            continue;
+        };
+        if retry.max_attempts == 0 {
+            anyhow::bail!(
+                "workflow_invalid: step {} retry.max_attempts must be >= 1",
+                step.id
+            );
+        }
+        if let Some(target) = &retry.rewind_to {
+            // Rewinding only makes sense to a step this one (transitively) depends on.
+            let mut upstream = HashSet::new();
+            let mut stack = deps[i].clone();
+            while let Some(d) = stack.pop() {
+                if upstream.insert(d) {
+                    stack.extend(deps[d].iter().copied());
+                }
+            }
+            if !upstream.iter().any(|&d| &def.steps[d].id == target) {
+                anyhow::bail!(
+                    "workflow_invalid: step {} rewinds to {target}, which is not upstream of it",
+                    step.id
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Insert builtin workflows that are not in the DB yet and bring unedited ones up to date with
+/// this build (edits to builtins are kept).
+pub(crate) fn seed_builtin_workflows(engine: &Engine) -> anyhow::Result<()> {
+    for src in builtin_sources() {
+        let def = parse_workflow("yaml", src)?;
+        engine.seed_workflow(&def.id, &def.name, "yaml", src)?;
+    }
+    Ok(())
+}
+
+/// Resolve a workflow by id, falling back to the compiled-in builtin when the row is missing.
+pub(crate) fn load_workflow(engine: &Engine, id: &str) -> anyhow::Result<Option<WorkflowDef>> {
+    if let Some(wf) = engine.get_workflow(id)? {
+        return parse_workflow(&wf.format, &wf.source).map(Some);
+    }
+    Ok(builtin_sources()
+        .into_iter()
+        .filter_map(|src| parse_workflow("yaml", src).ok())
+        .find(|def| def.id == id))
+}
+
+/// Prompt for steps whose row carries no template (runs queued before workflows existed).
+pub(crate) fn builtin_step_prompt(step_id: &str) -> Option<String> {
+    parse_workflow("yaml", BUILTIN_FEATURE_DEV)
+        .ok()?
+        .steps
+        .into_iter()
+        .find(|s| s.id == step_id)
+        .and_then(|s| s.prompt)
+}
+
+/// Replaces each `{key}` of `values` in one pass over `tpl`; substituted text is never scanned
+/// again, and unknown `{...}` is left as is.
+pub(crate) fn fill_placeholders(tpl: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(tpl.len());
+    let mut rest = tpl;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let tail = &rest[open + 1..];
+        let hit = tail
+            .find('}')
+            .and_then(|close| values.iter().find(|(k, _)| *k == &tail[..close]));
+        match hit {
+            Some((key, value)) => {
+                out.push_str(value);
+                rest = &tail[key.len() + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = tail;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Renders a step prompt. Values earlier steps reported (see [`reply::FORWARDED_KEYS`]) fill
+/// `{stories}`, `{build_cmd}` and `{test_cmd}`, and per-story steps get `{story}`; any the
+/// template does not mention are appended so custom workflows still see them.
+///
+/// Placeholders are filled in one pass (see [`fill_placeholders`]), so braces inside agent
+/// replies or the task are never expanded.
+pub(crate) fn render_prompt(
+    tpl: &str,
+    task: &str,
+    repo: &str,
+    branch: &str,
+    pr: &str,
+    ctx: &serde_json::Value,
+) -> String {
+    let mut values: Vec<(&str, String)> = vec![
+        ("task", task.to_string()),
+        ("repo", repo.to_string()),
+        ("branch", branch.to_string()),
+        ("pr", pr.to_string()),
+    ];
+    let mut carried = vec![];
+    for key in reply::FORWARDED_KEYS.into_iter().chain(["story"]) {
+        let value = match ctx.get(key) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(v) if !v.is_null() => v.to_string(),
+            _ => String::new(),
+        };
+        if tpl.contains(&format!("{{{key}}}")) {
+            values.push((key, value));
+        } else if !value.is_empty() {
+            carried.push(format!("{}: {value}", key.to_ascii_uppercase()));
+        }
+    }
+    let values: Vec<(&str, &str)> = values.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    let mut out = fill_placeholders(tpl, &values);
+    if !carried.is_empty() {
+        out.push_str("\n\nFROM EARLIER STEPS:\n");
+        out.push_str(&carried.join("\n"));
+        out.push('\n');
+    }
+    out
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct WorkflowView {
+    #[serde(flatten)]
+    pub(crate) row: Workflow,
+    pub(crate) definition: Option<WorkflowDef>,
+    pub(crate) error: Option<String>,
+}
+
+impl From<Workflow> for WorkflowView {
+    fn from(row: Workflow) -> Self {
+        let (definition, error) = match parse_workflow(&row.format, &row.source) {
+            Ok(def) => (Some(def), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        Self {
+            row,
+            definition,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WorkflowInput {
+    #[serde(default)]
+    format: Option<String>,
+    source: String,
+}
+
+pub(crate) async fn api_workflows_list(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Result<Json<Vec<WorkflowView>>, (axum::http::StatusCode, String)> {
+    let rows = state
+        .engine
+        .list_workflows()
+        .map_err(internal_error("engine.list_workflows"))?;
+    Ok(Json(rows.into_iter().map(WorkflowView::from).collect()))
+}
+
+pub(crate) async fn api_workflows_get(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<WorkflowView>, (axum::http::StatusCode, String)> {
+    let row = state
+        .engine
+        .get_workflow(&id)
+        .map_err(internal_error("engine.get_workflow"))?
+        .ok_or((axum::http::StatusCode::NOT_FOUND, "not_found".to_string()))?;
+    Ok(Json(row.into()))
+}
+
+pub(crate) async fn api_workflows_create(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(input): Json<WorkflowInput>,
+) -> Result<Json<WorkflowView>, (axum::http::StatusCode, String)> {
+    let (format, def) = parse_input(&input)?;
+    let exists = state
+        .engine
+        .get_workflow(&def.id)
+        .map_err(internal_error("engine.get_workflow"))?
+        .is_some();
+    if exists {
+        return Err((
+            axum::http::StatusCode::CONFLICT,
+            "workflow_exists".to_string(),
+        ));
+    }
+    let row = state
+        .engine
+        .upsert_workflow(&def.id, &def.name, &format, &input.source, false)
+        .map_err(internal_error("engine.upsert_workflow"))?;
+    Ok(Json(row.into()))
+}
+
+pub(crate) async fn api_workflows_update(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Json(input): Json<WorkflowInput>,
+) -> Result<Json<WorkflowView>, (axum::http::StatusCode, String)> {
+    let (format, def) = parse_input(&input)?;
+    if def.id != id {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "workflow_id_mismatch".to_string(),
+        ));
+    }
+    let row = state
+        .engine
+        .upsert_workflow(&def.id, &def.name, &format, &input.source, false)
+        .map_err(internal_error("engine.upsert_workflow"))?;
+    Ok(Json(row.into()))
+}
+
+pub(crate) async fn api_workflows_delete(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    let row = state
+        .engine
+        .get_workflow(&id)
+        .map_err(internal_error("engine.get_workflow"))?
+        .ok_or((axum::http::StatusCode::NOT_FOUND, "not_found".to_string()))?;
+    if row.builtin {
+        return Err((
+            axum::http::StatusCode::CONFLICT,
+            "builtin_workflow".to_string(),
+        ));
+    }
+    let deleted = state
+        .engine
+        .delete_workflow(&id)
+        .map_err(internal_error("engine.delete_workflow"))?;
+    Ok(Json(serde_json::json!({ "ok": deleted })))
+}
+
+fn parse_input(
+    input: &WorkflowInput,
+) -> Result<(String, WorkflowDef), (axum::http::StatusCode, String)> {
+    let format = input
+        .format
+        .as_deref()
+        .map(|f| f.trim().to_ascii_lowercase())
+        .filter(|f| !f.is_empty())
+        .unwrap_or_else(|| "yaml".to_string());
+    let format = if format == "yml" {
+        "yaml".to_string()
+    } else {
+        format
+    };
+    let def = parse_workflow(&format, &input.source)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((format, def))
+}
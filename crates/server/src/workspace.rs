@@ -0,0 +1,218 @@
+//! Workspace roots.
+//!
+//! Where the repo picker (`GET /api/local-repos`) looks for git repositories and where run
+//! worktrees are created: the `[workspace]` table of the server config (see `config`), overridden
+//! by `CLAWDORIO_*` environment variables and then CLI flags. A base can place its worktrees
+//! elsewhere with a `worktree_root` in its payload.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Directory names never descended into, on top of the configured exclusions.
+const SKIP_DIRS: [&str; 9] = [
+    ".git",
+    "node_modules",
+    "dist",
+    "build",
+    ".next",
+    "target",
+    ".turbo",
+    ".cache",
+    "coverage",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkspaceConfig {
+    /// Directories scanned for repositories, in order.
+    pub roots: Vec<PathBuf>,
+    /// How many directory levels below a root are scanned.
+    pub scan_depth: usize,
+    /// The scan stops after this many repositories.
+    pub max_repos: usize,
+    /// Globs for directories to skip. Patterns without a `/` match a directory name, others the
+    /// full path (e.g. `**/archive/**`).
+    pub exclude: Vec<String>,
+    /// Where run worktrees are created; defaults to the first root.
+    pub worktree_root: Option<PathBuf>,
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        Self {
+            roots: vec![PathBuf::from("~/.openclaw/workspace")],
+            scan_depth: 3,
+            max_repos: 200,
+            exclude: vec![],
+            worktree_root: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LocalRepo {
+    pub(crate) path: String,
+    pub(crate) name: String,
+}
+
+fn home_dir() -> PathBuf {
+    dirs::home_dir().unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Expands a leading `~/` so config files and env vars can use it.
+pub fn expand_home(p: &Path) -> PathBuf {
+    match p.strip_prefix("~") {
+        Ok(rest) => home_dir().join(rest),
+        Err(_) => p.to_path_buf(),
+    }
+}
+
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+impl WorkspaceConfig {
+    /// Applies `CLAWDORIO_WORKSPACE_ROOTS` (a path list, `:`-separated on unix),
+    /// `CLAWDORIO_SCAN_DEPTH`, `CLAWDORIO_WORKSPACE_EXCLUDE` (comma-separated globs, appended)
+    /// and `CLAWDORIO_WORKTREE_ROOT`.
+    pub fn apply_env(&mut self) -> anyhow::Result<()> {
+        if let Some(v) = env_var("CLAWDORIO_WORKSPACE_ROOTS") {
+            self.roots = std::env::split_paths(&v).collect();
+        }
+        if let Some(v) = env_var("CLAWDORIO_SCAN_DEPTH") {
+            self.scan_depth = v
+                .parse()
+                .map_err(|_| anyhow::anyhow!("CLAWDORIO_SCAN_DEPTH must be a number"))?;
+        }
+        if let Some(v) = env_var("CLAWDORIO_WORKSPACE_EXCLUDE") {
+            self.exclude.extend(
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|g| !g.is_empty())
+                    .map(str::to_string),
+            );
+        }
+        if let Some(v) = env_var("CLAWDORIO_WORKTREE_ROOT") {
+            self.worktree_root = Some(PathBuf::from(v));
+        }
+        Ok(())
+    }
+
+    /// Expands `~` and checks the settings; called once the layers are applied.
+    pub fn finish(mut self) -> anyhow::Result<Self> {
+        self.roots = self.roots.iter().map(|r| expand_home(r)).collect();
+        self.worktree_root = self.worktree_root.as_deref().map(expand_home);
+        if self.roots.is_empty() && self.worktree_root.is_none() {
+            anyhow::bail!("workspace: set at least one root or a worktree_root");
+        }
+        self.exclusions()?;
+        Ok(self)
+    }
+
+    fn exclusions(&self) -> anyhow::Result<Exclusions> {
+        let mut names = globset::GlobSetBuilder::new();
+        let mut paths = globset::GlobSetBuilder::new();
+        for pattern in &self.exclude {
+            let glob = globset::Glob::new(pattern)
+                .map_err(|e| anyhow::anyhow!("workspace.exclude: {pattern}: {e}"))?;
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+        Ok(Exclusions {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    /// Directory that holds worktrees for runs on a base: the base's `worktree_root`, else the
+    /// configured `worktree_root`, else the first workspace root.
+    pub(crate) fn worktree_root_for(&self, base_payload: &serde_json::Value) -> PathBuf {
+        base_payload
+            .get("worktree_root")
+            .and_then(|v| v.as_str())
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| expand_home(Path::new(s)))
+            .or_else(|| self.worktree_root.as_deref().map(expand_home))
+            .or_else(|| self.roots.first().map(|r| expand_home(r)))
+            .unwrap_or_else(|| home_dir().join(".openclaw").join("workspace"))
+    }
+
+    /// Git repositories under the roots, most recently modified first.
+    pub(crate) fn discover_repos(&self) -> Vec<LocalRepo> {
+        let exclusions = self.exclusions().unwrap_or_default();
+        let mut repos: Vec<(String, String, u128)> = vec![];
+        let mut queue: VecDeque<(PathBuf, usize)> =
+            self.roots.iter().map(|r| (expand_home(r), 0)).collect();
+        let mut seen: HashSet<String> = HashSet::new();
+
+        while let Some((dir, depth)) = queue.pop_front() {
+            if repos.len() >= self.max_repos {
+                break;
+            }
+            let dir_s = dir.to_string_lossy().to_string();
+            if dir_s.is_empty() || seen.contains(&dir_s) {
+                continue;
+            }
+            seen.insert(dir_s.clone());
+
+            let git_dir = dir.join(".git");
+            // Workspace root may itself be a git repo; still enumerate child repos/worktrees.
+            if depth > 0 && git_dir.exists() {
+                let name = dir
+                    .file_name()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_else(|| dir_s.clone());
+                let mtime = std::fs::metadata(&dir)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map(|d| d.as_millis())
+                    .unwrap_or(0);
+                repos.push((dir_s, name, mtime));
+                continue;
+            }
+            if depth >= self.scan_depth {
+                continue;
+            }
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for ent in entries.flatten() {
+                let p = ent.path();
+                if !ent.file_type().is_ok_and(|ft| ft.is_dir()) || exclusions.skips(&p) {
+                    continue;
+                }
+                queue.push_back((p, depth + 1));
+            }
+        }
+
+        repos.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        repos
+            .into_iter()
+            .map(|(path, name, _)| LocalRepo { path, name })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Exclusions {
+    names: globset::GlobSet,
+    paths: globset::GlobSet,
+}
+
+impl Exclusions {
+    fn skips(&self, dir: &Path) -> bool {
+        let name = dir.file_name().and_then(|s| s.to_str()).unwrap_or("");
+        SKIP_DIRS.contains(&name) || self.names.is_match(name) || self.paths.is_match(dir)
+    }
+}
@@ -0,0 +1,652 @@
+//! Worktree lifecycle and garbage collection.
+//!
+//! `api_feature_build` checks out one `git worktree` per run and records it in `worktrees`:
+//! `desired_json.state` says whether the checkout should exist (`present`/`absent`) and
+//! `observed_json` is what the reconciler last saw on disk (existence, size, dirtiness).
+//! [`reconcile`] moves the two together: checkouts of runs that are done, cancelled or whose PR
+//! was merged are removed once the retention window has passed, and missing checkouts of live
+//! runs are re-added from their branch. Failed runs keep their checkout for inspection.
+
+use super::workspace::WorkspaceConfig;
+use super::{internal_error, now_ms_i64, parse_payload, AppState, Command};
+use axum::http::StatusCode;
+use axum::Json;
+use clawdorio_engine::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const DEFAULT_RETENTION_HOURS: u64 = 72;
+/// Only branches the server created are ever deleted.
+const RUN_BRANCH_PREFIX: &str = "clawdorio/";
+
+#[derive(Debug, Clone)]
+pub struct GcPolicy {
+    /// How long a finished run's checkout is kept.
+    pub retention_ms: i64,
+    /// Also delete the run's local `clawdorio/*` branch when its checkout is pruned.
+    pub delete_branches: bool,
+    /// Where checkouts may be created; nothing outside these roots is ever deleted.
+    pub workspace: WorkspaceConfig,
+}
+
+impl Default for GcPolicy {
+    fn default() -> Self {
+        Self::from_hours(DEFAULT_RETENTION_HOURS, false)
+    }
+}
+
+impl GcPolicy {
+    pub fn from_hours(hours: u64, delete_branches: bool) -> Self {
+        Self {
+            retention_ms: (hours.saturating_mul(3_600_000)).min(i64::MAX as u64) as i64,
+            delete_branches,
+            workspace: WorkspaceConfig::default(),
+        }
+    }
+}
+
+struct WorktreeRow {
+    id: String,
+    repo_path: String,
+    run_id: Option<String>,
+    path: String,
+    branch: String,
+    desired: Value,
+    observed: Value,
+    observed_at_ms: i64,
+    updated_at_ms: i64,
+}
+
+struct RunInfo {
+    status: String,
+    base_id: Option<String>,
+    updated_at_ms: Option<i64>,
+    pr_merged: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct WorktreeView {
+    pub(crate) id: String,
+    pub(crate) run_id: Option<String>,
+    pub(crate) run_status: Option<String>,
+    pub(crate) base_id: Option<String>,
+    pub(crate) repo_path: String,
+    pub(crate) path: String,
+    pub(crate) branch: String,
+    /// `present` or `absent`.
+    pub(crate) desired_state: String,
+    pub(crate) exists: bool,
+    pub(crate) dirty: bool,
+    pub(crate) disk_bytes: u64,
+    pub(crate) observed_at_ms: i64,
+    /// When retention allows the reconciler to remove the checkout; `None` while the run is live.
+    pub(crate) prunable_at_ms: Option<i64>,
+    pub(crate) pruned_at_ms: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct ReconcileReport {
+    pub(crate) scanned: usize,
+    pub(crate) pruned: usize,
+    pub(crate) restored: usize,
+    /// Expired checkouts left alone because they have uncommitted changes.
+    pub(crate) skipped_dirty: usize,
+    pub(crate) freed_bytes: u64,
+}
+
+fn load_rows(conn: &rusqlite::Connection, id: Option<&str>) -> rusqlite::Result<Vec<WorktreeRow>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, COALESCE(repo_path,''), run_id, desired_json, observed_json, observed_at_ms, updated_at_ms
+         FROM worktrees WHERE ?1 IS NULL OR id=?1 ORDER BY updated_at_ms DESC",
+    )?;
+    let rows = stmt.query_map([id], |r| {
+        let desired = parse_payload(&r.get::<_, String>(3)?);
+        let observed = parse_payload(&r.get::<_, String>(4)?);
+        let str_of = |k: &str| {
+            observed
+                .get(k)
+                .or_else(|| desired.get(k))
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+        let (path, branch) = (str_of("path"), str_of("branch"));
+        // Rows written before `run_id` existed are matched through the run branch name.
+        let run_id = r.get::<_, Option<String>>(2)?.or_else(|| {
+            branch
+                .strip_prefix(RUN_BRANCH_PREFIX)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+        });
+        Ok(WorktreeRow {
+            id: r.get(0)?,
+            repo_path: r.get(1)?,
+            run_id,
+            path,
+            branch,
+            desired,
+            observed,
+            observed_at_ms: r.get(5)?,
+            updated_at_ms: r.get(6)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn run_info(conn: &rusqlite::Connection, run_id: &str) -> Option<RunInfo> {
+    conn.query_row(
+        "SELECT status, context_json, updated_at FROM runs WHERE id=?1",
+        [run_id],
+        |r| {
+            let ctx = parse_payload(&r.get::<_, String>(1)?);
+            let updated_at: String = r.get(2)?;
+            Ok(RunInfo {
+                status: r.get(0)?,
+                base_id: ctx
+                    .get("base_id")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string),
+                updated_at_ms: parse_rfc3339_ms(&updated_at),
+                pr_merged: ctx.get("pr_merged").and_then(|v| v.as_bool()) == Some(true),
+            })
+        },
+    )
+    .ok()
+}
+
+fn parse_rfc3339_ms(s: &str) -> Option<i64> {
+    time::OffsetDateTime::parse(s, &time::format_description::well_known::Rfc3339)
+        .ok()
+        .map(|t| (t.unix_timestamp_nanos() / 1_000_000) as i64)
+}
+
+/// When the row's checkout may be removed: retention after the run finished (or after the row
+/// was last touched, for runs that no longer exist).
+fn prunable_at(row: &WorktreeRow, run: Option<&RunInfo>, policy: &GcPolicy) -> Option<i64> {
+    match run {
+        Some(run) if run.pr_merged || matches!(run.status.as_str(), "done" | "cancelled") => run
+            .updated_at_ms
+            .map(|t| t.saturating_add(policy.retention_ms)),
+        Some(_) => None,
+        None => Some(row.updated_at_ms.saturating_add(policy.retention_ms)),
+    }
+}
+
+fn is_live(run: Option<&RunInfo>) -> bool {
+    run.is_some_and(|r| {
+        !r.pr_merged
+            && matches!(
+                r.status.as_str(),
+                "queued" | "running" | "paused" | "awaiting_approval"
+            )
+    })
+}
+
+/// Apparent size of everything under `path`, without following symlinks.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| dir_size(&e.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+pub(crate) fn is_dirty(path: &str) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(["status", "--porcelain"])
+        .output()
+        .map(|o| o.status.success() && !o.stdout.iter().all(u8::is_ascii_whitespace))
+        .unwrap_or(false)
+}
+
+fn branch_exists(repo: &str, branch: &str) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("refs/heads/{branch}"))
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+fn observe(row: &WorktreeRow) -> Value {
+    let exists = !row.path.is_empty() && Path::new(&row.path).is_dir();
+    let mut observed = row.observed.clone();
+    if !observed.is_object() {
+        observed = serde_json::json!({});
+    }
+    observed["path"] = Value::String(row.path.clone());
+    observed["branch"] = Value::String(row.branch.clone());
+    observed["base_repo_path"] = Value::String(row.repo_path.clone());
+    observed["exists"] = Value::Bool(exists);
+    observed["dirty"] = Value::Bool(exists && is_dirty(&row.path));
+    observed["disk_bytes"] = serde_json::json!(if exists {
+        dir_size(Path::new(&row.path))
+    } else {
+        0
+    });
+    observed
+}
+
+/// Worktree roots in effect: the configured one and every base's override.
+fn managed_roots(conn: &rusqlite::Connection, workspace: &WorkspaceConfig) -> Vec<PathBuf> {
+    let mut roots = vec![workspace.worktree_root_for(&Value::Null)];
+    if let Ok(mut stmt) = conn.prepare("SELECT payload_json FROM entities WHERE kind='base'") {
+        if let Ok(rows) = stmt.query_map([], |r| r.get::<_, String>(0)) {
+            roots.extend(
+                rows.filter_map(Result::ok)
+                    .map(|p| workspace.worktree_root_for(&parse_payload(&p))),
+            );
+        }
+    }
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Only a checkout under a worktree root that its repo still lists as a worktree is ours to
+/// delete; the path comes from the DB and must not reach `remove_dir_all` otherwise.
+fn ensure_managed(row: &WorktreeRow, roots: &[PathBuf]) -> anyhow::Result<()> {
+    let path = canonical(Path::new(&row.path));
+    if !roots
+        .iter()
+        .any(|root| path != canonical(root) && path.starts_with(canonical(root)))
+    {
+        anyhow::bail!(
+            "worktree_unmanaged: {} is outside the worktree roots",
+            row.path
+        );
+    }
+    let listed = Command::new("git")
+        .arg("-C")
+        .arg(&row.repo_path)
+        .args(["worktree", "list", "--porcelain"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .is_some_and(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .filter_map(|l| l.strip_prefix("worktree "))
+                .any(|p| canonical(Path::new(p)) == path)
+        });
+    if !listed {
+        anyhow::bail!(
+            "worktree_unmanaged: {} is not a worktree of {}",
+            row.path,
+            row.repo_path
+        );
+    }
+    Ok(())
+}
+
+/// Removes the checkout (and, if asked, its run branch). Returns whether the branch was deleted.
+fn remove_checkout(
+    row: &WorktreeRow,
+    roots: &[PathBuf],
+    delete_branch: bool,
+) -> anyhow::Result<bool> {
+    if Path::new(&row.path).exists() {
+        ensure_managed(row, roots)?;
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(&row.repo_path)
+            .args(["worktree", "remove", "--force"])
+            .arg(&row.path)
+            .output();
+        if !out.as_ref().is_ok_and(|o| o.status.success()) {
+            // `git worktree remove` can refuse (e.g. a locked worktree); the directory still goes.
+            std::fs::remove_dir_all(&row.path)?;
+        }
+    }
+    let _ = Command::new("git")
+        .arg("-C")
+        .arg(&row.repo_path)
+        .args(["worktree", "prune"])
+        .output();
+    if !delete_branch
+        || !row.branch.starts_with(RUN_BRANCH_PREFIX)
+        || !branch_exists(&row.repo_path, &row.branch)
+    {
+        return Ok(false);
+    }
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(&row.repo_path)
+        .args(["branch", "-D"])
+        .arg(&row.branch)
+        .output()?;
+    Ok(out.status.success())
+}
+
+fn restore_checkout(row: &WorktreeRow) -> anyhow::Result<()> {
+    let _ = Command::new("git")
+        .arg("-C")
+        .arg(&row.repo_path)
+        .args(["worktree", "prune"])
+        .output();
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(&row.repo_path)
+        .args(["worktree", "add"])
+        .arg(&row.path)
+        .arg(&row.branch)
+        .output()?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "git_worktree_failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Records what the reconciler saw. `updated_at_ms` only moves when `touched` (a prune), since
+/// retention of rows without a run counts from it.
+fn store(
+    conn: &rusqlite::Connection,
+    row: &WorktreeRow,
+    desired: &Value,
+    observed: &Value,
+    now: i64,
+    touched: bool,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE worktrees SET run_id=COALESCE(run_id, ?1), desired_json=?2, observed_json=?3,
+                observed_at_ms=?4, updated_at_ms=CASE WHEN ?5 THEN ?4 ELSE updated_at_ms END,
+                rev=rev+1
+         WHERE id=?6",
+        (
+            &row.run_id,
+            desired.to_string(),
+            observed.to_string(),
+            now,
+            touched,
+            &row.id,
+        ),
+    )?;
+    Ok(())
+}
+
+fn log_event(conn: &rusqlite::Connection, kind: &str, row: &WorktreeRow, extra: Value) {
+    let mut payload = serde_json::json!({
+        "worktree_id": row.id,
+        "run_id": row.run_id,
+        "path": row.path,
+        "branch": row.branch,
+    });
+    if let (Some(p), Some(extra)) = (payload.as_object_mut(), extra.as_object()) {
+        p.extend(extra.clone());
+    }
+    let _ = conn.execute(
+        "INSERT INTO event_log (ts_ms, kind, entity_id, payload_json) VALUES (?1, ?2, ?3, ?4)",
+        (now_ms_i64(), kind, &row.id, payload.to_string()),
+    );
+}
+
+/// Marks the row pruned after its checkout was removed.
+fn record_pruned(
+    conn: &rusqlite::Connection,
+    row: &WorktreeRow,
+    freed: u64,
+    branch_deleted: bool,
+    reason: &str,
+    now: i64,
+) -> rusqlite::Result<()> {
+    let mut desired = row.desired.clone();
+    desired["state"] = Value::String("absent".to_string());
+    let mut observed = row.observed.clone();
+    observed["exists"] = Value::Bool(false);
+    observed["dirty"] = Value::Bool(false);
+    observed["disk_bytes"] = serde_json::json!(0);
+    observed["pruned_at_ms"] = serde_json::json!(now);
+    observed["branch_deleted"] = Value::Bool(branch_deleted);
+    store(conn, row, &desired, &observed, now, true)?;
+    log_event(
+        conn,
+        "worktree.pruned",
+        row,
+        serde_json::json!({ "reason": reason, "freed_bytes": freed, "branch_deleted": branch_deleted }),
+    );
+    Ok(())
+}
+
+/// One reconciliation pass over every worktree row. A row that fails is logged as
+/// `worktree.reconcile_failed` and the pass moves on.
+pub(crate) fn reconcile(
+    engine: &Engine,
+    policy: &GcPolicy,
+    now: i64,
+) -> anyhow::Result<ReconcileReport> {
+    let conn = engine.open()?;
+    let roots = managed_roots(&conn, &policy.workspace);
+    let mut report = ReconcileReport::default();
+    for mut row in load_rows(&conn, None)? {
+        report.scanned += 1;
+        if let Err(e) = reconcile_row(&conn, &mut row, policy, &roots, now, &mut report) {
+            log_event(
+                &conn,
+                "worktree.reconcile_failed",
+                &row,
+                serde_json::json!({ "error": e.to_string() }),
+            );
+        }
+    }
+    Ok(report)
+}
+
+fn reconcile_row(
+    conn: &rusqlite::Connection,
+    row: &mut WorktreeRow,
+    policy: &GcPolicy,
+    roots: &[PathBuf],
+    now: i64,
+    report: &mut ReconcileReport,
+) -> anyhow::Result<()> {
+    let run = row.run_id.as_deref().and_then(|id| run_info(conn, id));
+    let prunable = prunable_at(row, run.as_ref(), policy);
+    let expired = prunable.is_some_and(|t| now >= t);
+    let mut desired = row.desired.clone();
+    desired["state"] = Value::String(if expired { "absent" } else { "present" }.to_string());
+    row.observed = observe(row);
+    let exists = row.observed["exists"].as_bool() == Some(true);
+
+    if expired && exists {
+        if row.observed["dirty"].as_bool() == Some(true) {
+            report.skipped_dirty += 1;
+        } else {
+            let freed = row.observed["disk_bytes"].as_u64().unwrap_or(0);
+            let branch_deleted = remove_checkout(row, roots, policy.delete_branches)?;
+            row.desired = desired;
+            record_pruned(conn, row, freed, branch_deleted, "retention", now)?;
+            report.pruned += 1;
+            report.freed_bytes += freed;
+            return Ok(());
+        }
+    } else if !exists
+        && is_live(run.as_ref())
+        && row.observed.get("pruned_at_ms").is_none()
+        && !row.path.is_empty()
+        && branch_exists(&row.repo_path, &row.branch)
+    {
+        match restore_checkout(row) {
+            Ok(()) => {
+                row.observed = observe(row);
+                log_event(conn, "worktree.restored", row, serde_json::json!({}));
+                report.restored += 1;
+            }
+            Err(e) => log_event(
+                conn,
+                "worktree.restore_failed",
+                row,
+                serde_json::json!({ "error": e.to_string() }),
+            ),
+        }
+    }
+    store(conn, row, &desired, &row.observed, now, false)?;
+    Ok(())
+}
+
+fn view(conn: &rusqlite::Connection, row: &WorktreeRow, policy: &GcPolicy) -> WorktreeView {
+    let run = row.run_id.as_deref().and_then(|id| run_info(conn, id));
+    let o = &row.observed;
+    WorktreeView {
+        id: row.id.clone(),
+        run_id: row.run_id.clone(),
+        run_status: run.as_ref().map(|r| r.status.clone()),
+        base_id: run.as_ref().and_then(|r| r.base_id.clone()),
+        repo_path: row.repo_path.clone(),
+        path: row.path.clone(),
+        branch: row.branch.clone(),
+        desired_state: row.desired["state"]
+            .as_str()
+            .unwrap_or("present")
+            .to_string(),
+        // Rows the reconciler has not visited yet predate existence tracking.
+        exists: o["exists"]
+            .as_bool()
+            .unwrap_or_else(|| Path::new(&row.path).is_dir()),
+        dirty: o["dirty"].as_bool().unwrap_or(false),
+        disk_bytes: o["disk_bytes"].as_u64().unwrap_or(0),
+        observed_at_ms: row.observed_at_ms,
+        prunable_at_ms: prunable_at(row, run.as_ref(), policy),
+        pruned_at_ms: o["pruned_at_ms"].as_i64(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WorktreeListQuery {
+    #[serde(default)]
+    pub(crate) base_id: Option<String>,
+    /// Re-observe every checkout (existence, size, dirtiness) before listing; prunes nothing.
+    #[serde(default)]
+    pub(crate) refresh: Option<bool>,
+}
+
+pub(crate) async fn api_worktrees_list(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(q): axum::extract::Query<WorktreeListQuery>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let engine = state.engine.clone();
+    let policy = state.config.worktrees.gc_policy(&state.config.workspace);
+    let retention_ms = policy.retention_ms;
+    let refresh = q.refresh.unwrap_or(false);
+    let rows = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<WorktreeView>> {
+        let conn = engine.open()?;
+        let mut out = vec![];
+        for mut row in load_rows(&conn, None)? {
+            if refresh {
+                row.observed = observe(&row);
+                let now = now_ms_i64();
+                store(&conn, &row, &row.desired, &row.observed, now, false)?;
+                row.observed_at_ms = now;
+            }
+            out.push(view(&conn, &row, &policy));
+        }
+        Ok(out)
+    })
+    .await
+    .map_err(|e| internal_error("worktrees.join")(e.into()))?
+    .map_err(internal_error("worktrees.list"))?;
+    let rows: Vec<WorktreeView> = rows
+        .into_iter()
+        .filter(|w| q.base_id.is_none() || w.base_id == q.base_id)
+        .collect();
+    let total: u64 = rows.iter().map(|w| w.disk_bytes).sum();
+    Ok(Json(serde_json::json!({
+        "worktrees": rows,
+        "total_disk_bytes": total,
+        "retention_ms": retention_ms,
+    })))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct PruneInput {
+    /// Prune even if the run is still live or the checkout has uncommitted changes.
+    #[serde(default)]
+    pub(crate) force: bool,
+    /// Overrides the server's branch deletion policy for this prune.
+    #[serde(default)]
+    pub(crate) delete_branch: Option<bool>,
+}
+
+pub(crate) async fn api_worktree_prune(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    input: Option<Json<PruneInput>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let input = input.map(|Json(i)| i).unwrap_or_default();
+    let engine = state.engine.clone();
+    let delete_branch = input
+        .delete_branch
+        .unwrap_or(state.config.worktrees.prune_branches);
+    let workspace = state.config.workspace.clone();
+    tokio::task::spawn_blocking(move || {
+        prune_one(&engine, &workspace, &id, input.force, delete_branch)
+    })
+    .await
+    .map_err(|e| internal_error("worktrees.join")(e.into()))?
+    .map(Json)
+}
+
+fn prune_one(
+    engine: &Engine,
+    workspace: &WorkspaceConfig,
+    id: &str,
+    force: bool,
+    delete_branch: bool,
+) -> Result<Value, (StatusCode, String)> {
+    let db = |ctx: &'static str| move |e: rusqlite::Error| internal_error(ctx)(e.into());
+    let conn = engine.open().map_err(internal_error("engine.open"))?;
+    let Some(mut row) = load_rows(&conn, Some(id))
+        .map_err(db("db.load_worktree"))?
+        .into_iter()
+        .next()
+    else {
+        return Err((StatusCode::NOT_FOUND, "not_found".to_string()));
+    };
+    let run = row.run_id.as_deref().and_then(|r| run_info(&conn, r));
+    if is_live(run.as_ref()) && !force {
+        return Err((StatusCode::CONFLICT, "run_active".to_string()));
+    }
+    row.observed = observe(&row);
+    if row.observed["dirty"].as_bool() == Some(true) && !force {
+        return Err((StatusCode::CONFLICT, "worktree_dirty".to_string()));
+    }
+    let freed = row.observed["disk_bytes"].as_u64().unwrap_or(0);
+    let branch_deleted = remove_checkout(&row, &managed_roots(&conn, workspace), delete_branch)
+        .map_err(|e| {
+            if e.to_string().starts_with("worktree_unmanaged") {
+                (StatusCode::CONFLICT, e.to_string())
+            } else {
+                internal_error("worktree.remove")(e)
+            }
+        })?;
+    record_pruned(&conn, &row, freed, branch_deleted, "manual", now_ms_i64())
+        .map_err(db("db.update_worktree"))?;
+    Ok(serde_json::json!({
+        "ok": true,
+        "id": row.id,
+        "run_id": row.run_id,
+        "freed_bytes": freed,
+        "branch_deleted": branch_deleted,
+    }))
+}